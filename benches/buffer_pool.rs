@@ -0,0 +1,96 @@
+//! Buffer-pool memory against throughput, with and without the OS page cache
+//!
+//! The same database is read with a range of cache budgets, once through
+//! buffered I/O and once with `O_DIRECT`. Buffered reads that miss the pool
+//! are often served from the kernel's copy of the file, so buffered mode
+//! looks fast at small budgets while holding most pages twice; direct mode
+//! shows what the pool alone delivers for its memory.
+
+use std::hint::black_box;
+use std::path::Path;
+use std::time::Duration;
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use qpdb::Database;
+
+/// Entries in the benchmark database, about 12 MiB of pages
+const ENTRIES: u32 = 100_000;
+
+/// Point lookups per iteration
+const LOOKUPS: u64 = 512;
+
+/// Cache budgets measured, in MiB
+const BUDGETS: [usize; 4] = [1, 4, 16, 64];
+
+fn create(path: &Path) {
+    if path.exists() {
+        return;
+    }
+    let db = Database::open(path).unwrap();
+    for chunk in (0..ENTRIES).collect::<Vec<_>>().chunks(10_000) {
+        let mut txn = db.begin_write().unwrap();
+        for &n in chunk {
+            txn.insert(&key(n), &[n as u8; 100]).unwrap();
+        }
+        txn.commit().unwrap();
+    }
+}
+
+fn key(n: u32) -> [u8; 4] {
+    n.to_be_bytes()
+}
+
+fn open(path: &Path, budget: usize, direct: bool) -> Database {
+    Database::builder()
+        .cache_size(budget << 20)
+        .direct_io(direct)
+        .open(path)
+        .unwrap()
+}
+
+fn bench(c: &mut Criterion) {
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("buffer_pool.qpdb");
+    create(&path);
+
+    let mut group = c.benchmark_group("point_reads");
+    group
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(3))
+        .throughput(Throughput::Elements(LOOKUPS));
+    for budget in BUDGETS {
+        for (mode, direct) in [("buffered", false), ("direct", true)] {
+            let db = open(&path, budget, direct);
+            // xorshift keeps the key sequence the same across runs
+            let mut state = 0x2545_f491_u32;
+            group.bench_function(BenchmarkId::new(mode, format!("{budget}MiB")), |b| {
+                b.iter(|| {
+                    for _ in 0..LOOKUPS {
+                        state ^= state << 13;
+                        state ^= state >> 17;
+                        state ^= state << 5;
+                        black_box(db.get(&key(state % ENTRIES)).unwrap());
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("scan");
+    group
+        .sample_size(10)
+        .measurement_time(Duration::from_secs(3))
+        .throughput(Throughput::Elements(ENTRIES.into()));
+    for budget in BUDGETS {
+        for (mode, direct) in [("buffered", false), ("direct", true)] {
+            let db = open(&path, budget, direct);
+            group.bench_function(BenchmarkId::new(mode, format!("{budget}MiB")), |b| {
+                b.iter(|| black_box(db.iter().count()))
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
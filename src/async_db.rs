@@ -0,0 +1,400 @@
+//! Async access from tokio
+//!
+//! An [`AsyncDatabase`] shares a [`Database`] between tasks. Anything that
+//! may wait on the disk runs on tokio's blocking pool rather than on a
+//! runtime worker: loading cold pages, committing (which syncs the log), and
+//! flushing. A lookup whose path is swizzled all the way down is answered
+//! synchronously by the lock-free reader instead, which costs no more than it
+//! does through [`Database::get`].
+//!
+//! A write transaction lives on a blocking thread of its own for as long as
+//! it is open, since it holds the writer lock, and runs the operations sent
+//! to it there. Range streams read a snapshot in batches, each fetched on the
+//! blocking pool.
+
+use std::collections::VecDeque;
+use std::ops::{Bound, Deref, RangeBounds};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+
+use futures_core::Stream;
+use parking_lot::Mutex;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::btree::{self, BTree, RangeState};
+use crate::txn::Snapshot;
+use crate::{Builder, Database, Durability, Error, KeyValue, Result};
+
+/// Entries a range stream reads per trip to the blocking pool
+const STREAM_BATCH: usize = 256;
+
+/// Handle for using a [`Database`] from async code
+///
+/// Cloning the handle is cheap and shares the database. Must be used from
+/// within a tokio runtime.
+///
+/// Dropping the last handle, transaction or stream sharing the database
+/// closes it on the blocking pool, so the final checkpoint does not hold up
+/// the runtime; use
+/// [`close`](AsyncDatabase::close) to wait for it and see whether it worked.
+#[derive(Clone)]
+pub struct AsyncDatabase {
+    db: Arc<Shared>,
+}
+
+/// The database behind the handles, transactions and streams, which the last
+/// of them to go hands over to a waiting [`AsyncDatabase::close`] or else
+/// closes on the blocking pool
+struct Shared {
+    /// Taken only on drop
+    db: Option<Database>,
+    closer: Mutex<Option<oneshot::Sender<Database>>>,
+}
+
+impl Deref for Shared {
+    type Target = Database;
+
+    fn deref(&self) -> &Database {
+        self.db.as_ref().expect("database is taken only on drop")
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        let Some(db) = self.db.take() else {
+            return;
+        };
+        let db = match self.closer.get_mut().take() {
+            Some(closer) => match closer.send(db) {
+                Ok(()) => return,
+                // The closer gave up waiting
+                Err(db) => db,
+            },
+            None => db,
+        };
+        // Closing checkpoints, which would hold up a runtime worker
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn_blocking(move || drop(db));
+        }
+    }
+}
+
+impl Builder {
+    /// Open a database for async use, as [`Builder::open`] would, without
+    /// blocking the runtime
+    pub async fn open_async(&self, path: impl AsRef<Path>) -> Result<AsyncDatabase> {
+        let builder = self.clone();
+        let path = path.as_ref().to_owned();
+        let db = blocking(move || builder.open(path)).await?;
+        Ok(AsyncDatabase::new(db))
+    }
+}
+
+impl From<Database> for AsyncDatabase {
+    fn from(db: Database) -> Self {
+        Self::new(db)
+    }
+}
+
+impl AsyncDatabase {
+    /// Open a database at the given path with the default options, creating
+    /// it if it does not exist
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Builder::new().open_async(path).await
+    }
+
+    /// Share an open database with async code
+    pub fn new(db: Database) -> Self {
+        Self {
+            db: Arc::new(Shared {
+                db: Some(db),
+                closer: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// The database itself, for calls that are fine to make synchronously
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+
+    /// Look up the latest committed value stored under `key`
+    ///
+    /// Returns without leaving the task when every page on the way to the
+    /// key is in memory.
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = btree::optimistic::get(&self.db.root, None, key) {
+            return Ok(value);
+        }
+        let db = Arc::clone(&self.db);
+        let key = key.to_vec();
+        blocking(move || db.get(&key)).await
+    }
+
+    /// Insert or replace `key` and commit, returning the previous value
+    pub async fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let db = Arc::clone(&self.db);
+        let (key, value) = (key.to_vec(), value.to_vec());
+        blocking(move || db.insert(&key, &value)).await
+    }
+
+    /// Remove `key` and commit, returning its value if it was present
+    pub async fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let db = Arc::clone(&self.db);
+        let key = key.to_vec();
+        blocking(move || db.remove(&key)).await
+    }
+
+    /// Stream all committed entries in key order
+    pub fn iter(&self) -> RangeStream {
+        self.range::<&[u8]>(..)
+    }
+
+    /// Stream the committed entries whose keys fall within `range`, in key
+    /// order
+    ///
+    /// The stream reads the snapshot current when it was created, whatever
+    /// is committed while it runs.
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> RangeStream {
+        let owned = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(key.as_ref().to_vec()),
+            Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_vec()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let snapshot = self.db.snapshots.current();
+        RangeStream {
+            db: Arc::clone(&self.db),
+            tree: BTree::new(snapshot.root),
+            _snapshot: snapshot,
+            state: Some(RangeState::new(
+                owned(range.start_bound()),
+                owned(range.end_bound()),
+            )),
+            fetching: None,
+            buffered: VecDeque::new(),
+        }
+    }
+
+    /// Start the write transaction, waiting for any other one to finish
+    pub async fn begin_write(&self) -> Result<AsyncWriteTransaction> {
+        let db = Arc::clone(&self.db);
+        let (ready_tx, ready) = oneshot::channel();
+        let (commands, mut requests) = mpsc::unbounded_channel();
+        tokio::task::spawn_blocking(move || {
+            let mut txn = match db.begin_write() {
+                Ok(txn) => txn,
+                Err(err) => {
+                    let _ = ready_tx.send(Err(err));
+                    return;
+                }
+            };
+            if ready_tx.send(Ok(())).is_err() {
+                return;
+            }
+            // Dropping the handle closes the channel, rolling back
+            while let Some(command) = requests.blocking_recv() {
+                match command {
+                    Command::SetDurability(durability) => txn.set_durability(durability),
+                    Command::Get(key, reply) => {
+                        let _ = reply.send(txn.get(&key));
+                    }
+                    Command::Insert(key, value, reply) => {
+                        let _ = reply.send(txn.insert(&key, &value));
+                    }
+                    Command::Remove(key, reply) => {
+                        let _ = reply.send(txn.remove(&key));
+                    }
+                    Command::Commit(reply) => {
+                        let result = txn.commit();
+                        // Let go of the database first, so that a `close`
+                        // right after the commit finds no one else using it
+                        drop(db);
+                        let _ = reply.send(result);
+                        return;
+                    }
+                }
+            }
+        });
+        ready.await.expect("write transaction thread replies")?;
+        Ok(AsyncWriteTransaction { commands })
+    }
+
+    /// Write all dirty pages to the data file, as [`Database::flush`] does
+    pub async fn flush(&self) -> Result<()> {
+        let db = Arc::clone(&self.db);
+        blocking(move || db.flush()).await
+    }
+
+    /// Checkpoint and close the database, once no other handle, transaction
+    /// or stream shares it
+    ///
+    /// Waits for every other handle, transaction and stream to be dropped,
+    /// so it must not be awaited while the same task holds on to one. Returns
+    /// once the file is unlocked, with the checkpoint's error, which dropping
+    /// the handles would ignore. Fails with [`Error::Closing`] if another
+    /// handle is already closing the database.
+    pub async fn close(self) -> Result<()> {
+        let (closer, closed) = oneshot::channel();
+        {
+            let mut waiting = self.db.closer.lock();
+            if waiting.as_ref().is_some_and(|other| !other.is_closed()) {
+                return Err(Error::Closing);
+            }
+            *waiting = Some(closer);
+        }
+        drop(self);
+        let db = closed
+            .await
+            .expect("last reference hands the database over");
+        blocking(move || {
+            let result = db.flush();
+            drop(db);
+            result
+        })
+        .await
+    }
+}
+
+/// Operations run by a write transaction's thread
+enum Command {
+    SetDurability(Durability),
+    Get(Vec<u8>, oneshot::Sender<Result<Option<Vec<u8>>>>),
+    Insert(Vec<u8>, Vec<u8>, oneshot::Sender<Result<Option<Vec<u8>>>>),
+    Remove(Vec<u8>, oneshot::Sender<Result<Option<Vec<u8>>>>),
+    Commit(oneshot::Sender<Result<()>>),
+}
+
+/// The single open read-write transaction, for async use
+///
+/// Like a [`WriteTransaction`](crate::WriteTransaction), its changes become
+/// durable and visible to others on [`commit`](AsyncWriteTransaction::commit),
+/// and dropping it rolls them back.
+pub struct AsyncWriteTransaction {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl AsyncWriteTransaction {
+    /// Choose what the commit promises (default [`Durability::Immediate`])
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.commands
+            .send(Command::SetDurability(durability))
+            .expect("write transaction thread runs until commit");
+    }
+
+    /// Look up the value stored under `key`, including uncommitted changes
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.run(|reply| Command::Get(key.to_vec(), reply)).await
+    }
+
+    /// Insert or replace `key`, returning the previous value
+    pub async fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.run(|reply| Command::Insert(key.to_vec(), value.to_vec(), reply))
+            .await
+    }
+
+    /// Remove `key`, returning its value if it was present
+    pub async fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.run(|reply| Command::Remove(key.to_vec(), reply)).await
+    }
+
+    /// Make every change durable and visible to new readers
+    pub async fn commit(self) -> Result<()> {
+        self.run(Command::Commit).await
+    }
+
+    /// Discard every change; equivalent to dropping the transaction
+    pub fn abort(self) {}
+
+    /// Send the command made by `command` to the transaction's thread and
+    /// wait for its reply
+    async fn run<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> Command) -> T {
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .expect("write transaction thread runs until commit");
+        response.await.expect("write transaction thread replies")
+    }
+}
+
+/// Stream of the entries in a key range of a snapshot, in key order
+///
+/// Entries are read ahead in batches on the blocking pool.
+pub struct RangeStream {
+    db: Arc<Shared>,
+    tree: BTree,
+    /// Keeps the pages of `tree` from being reclaimed
+    _snapshot: Arc<Snapshot>,
+    /// Position in the range; away with the batch being fetched
+    state: Option<RangeState>,
+    fetching: Option<JoinHandle<(RangeState, Result<Vec<KeyValue>>)>>,
+    buffered: VecDeque<KeyValue>,
+}
+
+impl RangeStream {
+    /// Next entry, or `None` once the range is exhausted
+    pub async fn next(&mut self) -> Option<Result<KeyValue>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+}
+
+impl Stream for RangeStream {
+    type Item = Result<KeyValue>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(entry) = this.buffered.pop_front() {
+                return Poll::Ready(Some(Ok(entry)));
+            }
+            if let Some(fetching) = &mut this.fetching {
+                let (state, batch) = match ready!(Pin::new(fetching).poll(cx)) {
+                    Ok(fetched) => fetched,
+                    Err(err) => std::panic::resume_unwind(err.into_panic()),
+                };
+                this.fetching = None;
+                let batch = match batch {
+                    Ok(batch) => batch,
+                    // The stream ends after an error
+                    Err(err) => return Poll::Ready(Some(Err(err))),
+                };
+                // A short batch means the range is exhausted
+                if batch.len() == STREAM_BATCH {
+                    this.state = Some(state);
+                } else if batch.is_empty() {
+                    return Poll::Ready(None);
+                }
+                this.buffered.extend(batch);
+                continue;
+            }
+            let Some(mut state) = this.state.take() else {
+                return Poll::Ready(None);
+            };
+            let (db, tree) = (Arc::clone(&this.db), this.tree);
+            this.fetching = Some(tokio::task::spawn_blocking(move || {
+                let mut batch = Vec::with_capacity(STREAM_BATCH);
+                while batch.len() < STREAM_BATCH {
+                    // Locked a step at a time, like `Range`, so writers
+                    // proceed in between
+                    let next = state.next(&tree, &mut db.inner.lock().pager);
+                    match next {
+                        Ok(Some(entry)) => batch.push(entry),
+                        Ok(None) => break,
+                        Err(err) => return (state, Err(err)),
+                    }
+                }
+                (state, Ok(batch))
+            }));
+        }
+    }
+}
+
+/// Run `f` on the blocking pool and wait for it without blocking the runtime
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> T {
+    match tokio::task::spawn_blocking(f).await {
+        Ok(value) => value,
+        Err(err) => std::panic::resume_unwind(err.into_panic()),
+    }
+}
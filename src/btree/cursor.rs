@@ -0,0 +1,448 @@
+//! Ordered traversal along root-to-leaf paths
+//!
+//! A [`Position`] names one entry in a leaf together with the inner pages
+//! leading to it. Seeks descend from the root once; stepping past either end
+//! of a leaf climbs only to the nearest ancestor with a further child and
+//! descends along that child's edge, so a scan reads every page once.
+//!
+//! Copy-on-write pages rule out leaf sibling links (see the [module
+//! docs](super)), so the path stands in for them: moving to the next leaf
+//! reads only pages already on the path plus the new leaf's own edge, and
+//! reading ahead along the parent keeps scans sequential.
+
+use std::ops::Bound;
+
+use super::node::{Entry, child_pos};
+use super::{BTree, PageStore, child_at, not_a_node};
+use crate::Result;
+use crate::buffer::{Page, PageId, PageKind};
+
+/// Inner pages from the root down, each with the child position taken
+type Path = Vec<(PageId, usize)>;
+
+/// Children of an inner page read ahead when a scan moves on to one of them
+const PREFETCH: usize = 16;
+
+/// Location of an entry: a leaf page and a slot within it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Position {
+    path: Path,
+    leaf: PageId,
+    index: usize,
+}
+
+impl BTree {
+    /// Position of the smallest entry
+    pub fn first(&self, store: &mut impl PageStore) -> Result<Option<Position>> {
+        self.descend(store, |_| 0)?
+            .map_or(Ok(None), |(path, leaf)| {
+                normalize_forward(store, path, leaf, 0)
+            })
+    }
+
+    /// Position of the largest entry
+    pub fn last(&self, store: &mut impl PageStore) -> Result<Option<Position>> {
+        let Some((path, leaf)) = self.descend(store, Page::slot_count)? else {
+            return Ok(None);
+        };
+        let count = store.read(leaf)?.slot_count();
+        normalize_backward(store, path, leaf, count)
+    }
+
+    /// Position of the first entry with a key `>= key`
+    pub fn seek(&self, store: &mut impl PageStore, key: &[u8]) -> Result<Option<Position>> {
+        let Some((path, leaf)) = self.descend(store, |page| child_pos(page, key))? else {
+            return Ok(None);
+        };
+        let index = match store.read(leaf)?.search(key) {
+            Ok(index) | Err(index) => index,
+        };
+        normalize_forward(store, path, leaf, index)
+    }
+
+    /// Position of the last entry with a key `<= key`
+    pub fn seek_for_prev(
+        &self,
+        store: &mut impl PageStore,
+        key: &[u8],
+    ) -> Result<Option<Position>> {
+        let Some((path, leaf)) = self.descend(store, |page| child_pos(page, key))? else {
+            return Ok(None);
+        };
+        let end = match store.read(leaf)?.search(key) {
+            Ok(index) => index + 1,
+            Err(index) => index,
+        };
+        normalize_backward(store, path, leaf, end)
+    }
+
+    /// Path to and id of the leaf reached by taking child `choose(page)` at
+    /// every level
+    fn descend(
+        &self,
+        store: &mut impl PageStore,
+        choose: impl Fn(&Page) -> usize,
+    ) -> Result<Option<(Path, PageId)>> {
+        if self.root == 0 {
+            return Ok(None);
+        }
+        let mut path = Vec::new();
+        let leaf = descend_from(store, &mut path, self.root, choose)?;
+        Ok(Some((path, leaf)))
+    }
+}
+
+/// Entry following `pos`
+pub(crate) fn next(store: &mut impl PageStore, pos: &Position) -> Result<Option<Position>> {
+    normalize_forward(store, pos.path.clone(), pos.leaf, pos.index + 1)
+}
+
+/// Entry preceding `pos`
+pub(crate) fn prev(store: &mut impl PageStore, pos: &Position) -> Result<Option<Position>> {
+    normalize_backward(store, pos.path.clone(), pos.leaf, pos.index)
+}
+
+/// Copy out the entry at `pos`
+pub(crate) fn entry(store: &mut impl PageStore, pos: &Position) -> Result<Entry> {
+    let page = store.read(pos.leaf)?;
+    Ok((
+        page.key_at(pos.index).to_vec(),
+        page.value_at(pos.index).to_vec(),
+    ))
+}
+
+/// Descend from page `id` to a leaf, taking child `choose(page)` at every
+/// level and recording the inner pages passed in `path`
+fn descend_from(
+    store: &mut impl PageStore,
+    path: &mut Path,
+    mut id: PageId,
+    choose: impl Fn(&Page) -> usize,
+) -> Result<PageId> {
+    let mut page = store.read(id)?;
+    loop {
+        match page.kind() {
+            Some(PageKind::Leaf) => return Ok(id),
+            Some(PageKind::Inner) => {
+                let pos = choose(page);
+                path.push((id, pos));
+                (id, page) = store.child(id, pos)?;
+            }
+            _ => return Err(not_a_node(id)),
+        }
+    }
+}
+
+/// First entry at or after slot `index` of `leaf`, moving on to the leaves
+/// to its right
+fn normalize_forward(
+    store: &mut impl PageStore,
+    mut path: Path,
+    mut leaf: PageId,
+    mut index: usize,
+) -> Result<Option<Position>> {
+    loop {
+        if index < store.read(leaf)?.slot_count() {
+            return Ok(Some(Position { path, leaf, index }));
+        }
+        // Climb to the nearest ancestor with a child further right
+        let (parent, pos) = loop {
+            let Some((parent, pos)) = path.pop() else {
+                return Ok(None);
+            };
+            if pos < store.read(parent)?.slot_count() {
+                break (parent, pos + 1);
+            }
+        };
+        path.push((parent, pos));
+        prefetch(store, parent, pos, true)?;
+        let (child, _) = store.child(parent, pos)?;
+        leaf = descend_from(store, &mut path, child, |_| 0)?;
+        index = 0;
+    }
+}
+
+/// Last entry strictly before slot `end` of `leaf`, moving on to the leaves
+/// to its left
+fn normalize_backward(
+    store: &mut impl PageStore,
+    mut path: Path,
+    mut leaf: PageId,
+    mut end: usize,
+) -> Result<Option<Position>> {
+    loop {
+        if end > 0 {
+            let index = end - 1;
+            return Ok(Some(Position { path, leaf, index }));
+        }
+        // Climb to the nearest ancestor with a child further left
+        let (parent, pos) = loop {
+            let Some((parent, pos)) = path.pop() else {
+                return Ok(None);
+            };
+            if pos > 0 {
+                break (parent, pos - 1);
+            }
+        };
+        path.push((parent, pos));
+        prefetch(store, parent, pos, false)?;
+        let (child, _) = store.child(parent, pos)?;
+        leaf = descend_from(store, &mut path, child, Page::slot_count)?;
+        end = store.read(leaf)?.slot_count();
+    }
+}
+
+/// Hint the store at the children of `parent` a scan moving forward or
+/// backward from child `pos` reaches next
+fn prefetch(store: &mut impl PageStore, parent: PageId, pos: usize, forward: bool) -> Result<()> {
+    let page = store.read(parent)?;
+    let ids: Vec<_> = if forward {
+        (pos..=page.slot_count())
+            .take(PREFETCH)
+            .map(|pos| child_at(page, pos))
+            .collect()
+    } else {
+        (0..=pos)
+            .rev()
+            .take(PREFETCH)
+            .map(|pos| child_at(page, pos))
+            .collect()
+    };
+    store.prefetch(&ids);
+    Ok(())
+}
+
+/// Double-ended iteration state over a key range
+///
+/// Yielding an entry from either end tightens the corresponding bound, so the
+/// two ends stop as soon as they meet.
+pub(crate) struct RangeState {
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    front: Option<Position>,
+    back: Option<Position>,
+    done: bool,
+}
+
+impl RangeState {
+    pub fn new(start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> Self {
+        Self {
+            start,
+            end,
+            front: None,
+            back: None,
+            done: false,
+        }
+    }
+
+    pub fn next(&mut self, tree: &BTree, store: &mut impl PageStore) -> Result<Option<Entry>> {
+        if self.done {
+            return Ok(None);
+        }
+        let pos = match self.front {
+            Some(ref pos) => next(store, pos),
+            None => match &self.start {
+                Bound::Included(key) | Bound::Excluded(key) => tree.seek(store, key),
+                Bound::Unbounded => tree.first(store),
+            },
+        };
+        let result = self.step(store, pos, true);
+        if !matches!(result, Ok(Some(_))) {
+            self.done = true;
+        }
+        result
+    }
+
+    pub fn next_back(&mut self, tree: &BTree, store: &mut impl PageStore) -> Result<Option<Entry>> {
+        if self.done {
+            return Ok(None);
+        }
+        let pos = match self.back {
+            Some(ref pos) => prev(store, pos),
+            None => match &self.end {
+                Bound::Included(key) | Bound::Excluded(key) => tree.seek_for_prev(store, key),
+                Bound::Unbounded => tree.last(store),
+            },
+        };
+        let result = self.step(store, pos, false);
+        if !matches!(result, Ok(Some(_))) {
+            self.done = true;
+        }
+        result
+    }
+
+    fn step(
+        &mut self,
+        store: &mut impl PageStore,
+        pos: Result<Option<Position>>,
+        forward: bool,
+    ) -> Result<Option<Entry>> {
+        let Some(mut pos) = pos? else {
+            return Ok(None);
+        };
+        let mut entry = entry(store, &pos)?;
+        // A seek lands on an excluded bound key at most once; step past it
+        let skip = if forward { &self.start } else { &self.end };
+        if matches!(skip, Bound::Excluded(key) if *key == entry.0) {
+            let moved = if forward {
+                next(store, &pos)?
+            } else {
+                prev(store, &pos)?
+            };
+            let Some(moved) = moved else {
+                return Ok(None);
+            };
+            pos = moved;
+            entry = self::entry(store, &pos)?;
+        }
+
+        let key = entry.0.as_slice();
+        let in_range = if forward {
+            match &self.end {
+                Bound::Included(end) => key <= end.as_slice(),
+                Bound::Excluded(end) => key < end.as_slice(),
+                Bound::Unbounded => true,
+            }
+        } else {
+            match &self.start {
+                Bound::Included(start) => key >= start.as_slice(),
+                Bound::Excluded(start) => key > start.as_slice(),
+                Bound::Unbounded => true,
+            }
+        };
+        if !in_range {
+            return Ok(None);
+        }
+
+        if forward {
+            self.front = Some(pos);
+            self.start = Bound::Excluded(entry.0.clone());
+        } else {
+            self.back = Some(pos);
+            self.end = Bound::Excluded(entry.0.clone());
+        }
+        Ok(Some(entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::Pager;
+    use crate::storage::MemoryBackend;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    fn tree_with(keys: impl IntoIterator<Item = u32>) -> (Pager, BTree) {
+        let mut store = Pager::new(Box::new(MemoryBackend::new()), 1, 1024);
+        let mut tree = BTree::new(0);
+        for n in keys {
+            tree.insert(&mut store, &n.to_be_bytes(), &[n as u8; 40])
+                .unwrap();
+        }
+        (store, tree)
+    }
+
+    fn key_at(store: &mut Pager, pos: Option<Position>) -> Option<u32> {
+        pos.map(|pos| u32::from_be_bytes(entry(store, &pos).unwrap().0.try_into().unwrap()))
+    }
+
+    #[test]
+    fn empty_tree_has_no_positions() {
+        let (mut store, tree) = tree_with([]);
+        assert_eq!(tree.first(&mut store).unwrap(), None);
+        assert_eq!(tree.last(&mut store).unwrap(), None);
+        assert_eq!(tree.seek(&mut store, b"a").unwrap(), None);
+        assert_eq!(tree.seek_for_prev(&mut store, b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn walk_across_leaves() {
+        let (mut store, tree) = tree_with((0..3000).map(|n| n * 2));
+        let mut pos = tree.first(&mut store).unwrap();
+        let mut expected = 0;
+        while let Some(p) = pos {
+            pos = next(&mut store, &p).unwrap();
+            assert_eq!(key_at(&mut store, Some(p)), Some(expected));
+            expected += 2;
+        }
+        assert_eq!(expected, 6000);
+
+        let mut pos = tree.last(&mut store).unwrap();
+        let mut count = 0;
+        while let Some(p) = pos {
+            count += 1;
+            pos = prev(&mut store, &p).unwrap();
+        }
+        assert_eq!(count, 3000);
+    }
+
+    #[test]
+    fn seek_between_keys() {
+        let (mut store, tree) = tree_with((0..3000).map(|n| n * 2));
+        let seek = |store: &mut Pager, n: u32| {
+            let pos = tree.seek(store, &n.to_be_bytes()).unwrap();
+            key_at(store, pos)
+        };
+        let seek_prev = |store: &mut Pager, n: u32| {
+            let pos = tree.seek_for_prev(store, &n.to_be_bytes()).unwrap();
+            key_at(store, pos)
+        };
+        assert_eq!(seek(&mut store, 0), Some(0));
+        assert_eq!(seek(&mut store, 1001), Some(1002));
+        assert_eq!(seek(&mut store, 5998), Some(5998));
+        assert_eq!(seek(&mut store, 5999), None);
+        assert_eq!(seek_prev(&mut store, 1001), Some(1000));
+        assert_eq!(seek_prev(&mut store, 1002), Some(1002));
+        assert_eq!(seek_prev(&mut store, u32::MAX), Some(5998));
+        assert_eq!(tree.seek_for_prev(&mut store, b"").unwrap(), None);
+    }
+
+    fn bound(kind: u8, key: u16) -> Bound<Vec<u8>> {
+        match kind % 3 {
+            0 => Bound::Included(key.to_be_bytes().to_vec()),
+            1 => Bound::Excluded(key.to_be_bytes().to_vec()),
+            _ => Bound::Unbounded,
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn range_matches_btreemap(
+            keys in prop::collection::btree_set(0u16..1000, 0..600),
+            start in (any::<u8>(), 0u16..1000),
+            end in (any::<u8>(), 0u16..1000),
+            directions in prop::collection::vec(any::<bool>(), 0..700),
+        ) {
+            let mut store = Pager::new(Box::new(MemoryBackend::new()), 1, 1024);
+            let mut tree = BTree::new(0);
+            let mut model = BTreeMap::new();
+            for k in keys {
+                let key = k.to_be_bytes().to_vec();
+                tree.insert(&mut store, &key, &[k as u8; 30]).unwrap();
+                model.insert(key, vec![k as u8; 30]);
+            }
+            let (start, end) = (bound(start.0, start.1), bound(end.0, end.1));
+            // BTreeMap::range panics on inverted or doubly-excluded empty ranges
+            let valid = match (&start, &end) {
+                (Bound::Excluded(s), Bound::Excluded(e)) => s < e,
+                (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => s <= e,
+                _ => true,
+            };
+            prop_assume!(valid);
+
+            let mut expected = model.range((start.clone(), end.clone()));
+            let mut state = RangeState::new(start, end);
+            for forward in directions {
+                let (got, want) = if forward {
+                    (state.next(&tree, &mut store).unwrap(), expected.next())
+                } else {
+                    (state.next_back(&tree, &mut store).unwrap(), expected.next_back())
+                };
+                prop_assert_eq!(got, want.map(|(k, v)| (k.clone(), v.clone())));
+            }
+        }
+    }
+}
@@ -0,0 +1,731 @@
+//! B+-tree over slotted pages
+//!
+//! Leaves hold the key/value pairs; inner pages hold separator keys and child
+//! page ids. Pages are split when an insert does not fit and merged with (or
+//! rebalanced against) a sibling when a remove leaves them less than a quarter
+//! full.
+//!
+//! Modifications are copy-on-write: a page is [shadowed](PageStore::shadow)
+//! before it is written, so pages reachable from a committed root never
+//! change and readers of that root keep a consistent snapshot. Each modified
+//! node passes its (possibly new) id back up, and the parent is shadowed in
+//! turn to point at it, up to a new root.
+//!
+//! Leaves carry no sibling links. A link names its neighbour's page id, so
+//! shadowing one leaf would mean shadowing both neighbours to repoint them,
+//! then theirs in turn, along the whole leaf level. Ordered scans instead
+//! keep the path from the root and step through the parents (see
+//! [`Position`]).
+
+mod cursor;
+mod node;
+pub(crate) mod optimistic;
+
+use std::collections::HashSet;
+
+use crate::buffer::{Page, PageId, PageKind, SLOT_SIZE};
+use crate::{Error, Result};
+pub(crate) use cursor::{Position, RangeState, entry, next, prev};
+use node::{
+    Entry, USABLE_SPACE, child_at, child_pos, decode_child, encode_child, entries, fill,
+    is_underfull, split_point, total_size,
+};
+
+/// Largest supported key plus value size in bytes
+///
+/// Bounded so any four cells (plus an inner cell's child pointer) fit on one
+/// page, which guarantees both halves of a split fit.
+pub const MAX_ENTRY_SIZE: usize = USABLE_SPACE / 4 - SLOT_SIZE - 2 - 8;
+
+/// Page access used by the B-tree
+pub(crate) trait PageStore {
+    /// Borrow a page for reading
+    fn read(&mut self, id: PageId) -> Result<&Page>;
+    /// Borrow child `pos` of inner page `parent` for reading, returning its id
+    ///
+    /// Stores that swizzle child swips follow (and swizzle) the parent's slot
+    /// here instead of looking the child up by id.
+    fn child(&mut self, parent: PageId, pos: usize) -> Result<(PageId, &Page)> {
+        let id = child_at(self.read(parent)?, pos);
+        Ok((id, self.read(id)?))
+    }
+    /// Page that may be written in place of `id`: `id` itself if it was
+    /// allocated since the last commit, otherwise a fresh copy, with `id`
+    /// freed once no snapshot can reach it
+    fn shadow(&mut self, id: PageId) -> Result<PageId>;
+    /// Borrow a page returned by [`PageStore::shadow`] or
+    /// [`PageStore::allocate`] for modification, marking it dirty
+    fn write(&mut self, id: PageId) -> Result<&mut Page>;
+    /// Allocate a fresh page initialized as `kind`
+    fn allocate(&mut self, kind: PageKind) -> Result<PageId>;
+    /// Return a page to the allocator
+    fn free(&mut self, id: PageId) -> Result<()>;
+    /// Hint that pages `ids` are about to be read, so stores with a cache
+    /// may load them together
+    fn prefetch(&mut self, _ids: &[PageId]) {}
+}
+
+/// Separator key and new right sibling produced by a split
+type Split = (Vec<u8>, PageId);
+
+/// Handle to a B+-tree identified by its root page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BTree {
+    root: PageId,
+}
+
+impl BTree {
+    /// Open the tree rooted at `root` (0 = empty tree)
+    pub fn new(root: PageId) -> Self {
+        Self { root }
+    }
+
+    /// Current root page (0 = empty tree)
+    pub fn root(&self) -> PageId {
+        self.root
+    }
+
+    /// Look up the value stored under `key`
+    pub fn get(&self, store: &mut impl PageStore, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.root == 0 {
+            return Ok(None);
+        }
+        let mut id = self.root;
+        let mut page = store.read(id)?;
+        loop {
+            match page.kind() {
+                Some(PageKind::Leaf) => return Ok(page.lookup(key).map(<[u8]>::to_vec)),
+                Some(PageKind::Inner) => {
+                    let pos = child_pos(page, key);
+                    (id, page) = store.child(id, pos)?;
+                }
+                _ => return Err(not_a_node(id)),
+            }
+        }
+    }
+
+    /// Insert or replace `key`, returning the previous value
+    pub fn insert(
+        &mut self,
+        store: &mut impl PageStore,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        let size = key.len() + value.len();
+        if size > MAX_ENTRY_SIZE {
+            return Err(Error::ValueTooLarge {
+                size,
+                max: MAX_ENTRY_SIZE,
+            });
+        }
+        if self.root == 0 {
+            self.root = store.allocate(PageKind::Leaf)?;
+        }
+
+        let (root, old, split) = insert_into(store, self.root, key, value)?;
+        self.root = root;
+        if let Some((separator, right)) = split {
+            let root = store.allocate(PageKind::Inner)?;
+            let page = store.write(root)?;
+            page.set_lower(self.root);
+            page.insert_at(0, &separator, &encode_child(right));
+            self.root = root;
+        }
+        Ok(old)
+    }
+
+    /// Remove `key`, returning its value if it was present
+    pub fn remove(&mut self, store: &mut impl PageStore, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.root == 0 {
+            return Ok(None);
+        }
+        let (root, old) = remove_from(store, self.root, key)?;
+        self.root = root;
+        if old.is_some() {
+            // Collapse inner roots left with a single child, and drop an empty leaf root
+            loop {
+                let page = store.read(self.root)?;
+                if page.slot_count() > 0 {
+                    break;
+                }
+                let next_root = match page.kind() {
+                    Some(PageKind::Inner) => child_at(page, 0),
+                    _ => 0,
+                };
+                store.free(self.root)?;
+                self.root = next_root;
+                if next_root == 0 {
+                    break;
+                }
+            }
+        }
+        Ok(old)
+    }
+
+    /// Free every page of the tree, leaving it empty
+    pub fn clear(&mut self, store: &mut impl PageStore) -> Result<()> {
+        if self.root != 0 {
+            free_subtree(store, self.root)?;
+            self.root = 0;
+        }
+        Ok(())
+    }
+
+    /// Add the id of every page of the tree to `pages`
+    pub fn collect_pages(
+        &self,
+        store: &mut impl PageStore,
+        pages: &mut HashSet<PageId>,
+    ) -> Result<()> {
+        let mut stack = vec![self.root];
+        while let Some(id) = stack.pop() {
+            if id == 0 || !pages.insert(id) {
+                continue;
+            }
+            let page = store.read(id)?;
+            match page.kind() {
+                Some(PageKind::Leaf) => {}
+                Some(PageKind::Inner) => {
+                    stack.extend((0..=page.slot_count()).map(|pos| child_at(page, pos)))
+                }
+                _ => return Err(not_a_node(id)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Inner pages from the root down to page `id`, each with the child
+    /// position taken, or `None` if `id` is not part of the tree
+    ///
+    /// `key` must be a key within the page's range (any key for the root),
+    /// as it is used to find the page.
+    pub fn path_to(
+        &self,
+        store: &mut impl PageStore,
+        key: &[u8],
+        id: PageId,
+    ) -> Result<Option<Vec<(PageId, usize)>>> {
+        if self.root == 0 {
+            return Ok(None);
+        }
+        let mut path = Vec::new();
+        let mut current = self.root;
+        while current != id {
+            let page = store.read(current)?;
+            match page.kind() {
+                Some(PageKind::Inner) => {
+                    let pos = child_pos(page, key);
+                    path.push((current, pos));
+                    current = child_at(page, pos);
+                }
+                Some(PageKind::Leaf) => return Ok(None),
+                _ => return Err(not_a_node(current)),
+            }
+        }
+        Ok(Some(path))
+    }
+
+    /// Number of levels, 0 for an empty tree
+    pub fn height(&self, store: &mut impl PageStore) -> Result<usize> {
+        let mut height = 0;
+        let mut id = self.root;
+        while id != 0 {
+            height += 1;
+            let page = store.read(id)?;
+            id = match page.kind() {
+                Some(PageKind::Inner) => child_at(page, 0),
+                Some(PageKind::Leaf) => 0,
+                _ => return Err(not_a_node(id)),
+            };
+        }
+        Ok(height)
+    }
+
+    /// Copy page `id` and the inner pages on `path` to it, as returned by
+    /// [`BTree::path_to`], so each lands where the store allocates
+    ///
+    /// Parents are updated to the copies bottom-up, the page itself being
+    /// copied first.
+    pub fn relocate(
+        &mut self,
+        store: &mut impl PageStore,
+        id: PageId,
+        path: Vec<(PageId, usize)>,
+    ) -> Result<()> {
+        let mut child = store.shadow(id)?;
+        for (parent, pos) in path.into_iter().rev() {
+            let parent = store.shadow(parent)?;
+            store.write(parent)?.set_child(pos, child);
+            child = parent;
+        }
+        self.root = child;
+        Ok(())
+    }
+}
+
+fn not_a_node(id: PageId) -> Error {
+    Error::Corruption(format!("page {} is not a B-tree node", id))
+}
+
+fn free_subtree(store: &mut impl PageStore, id: PageId) -> Result<()> {
+    let page = store.read(id)?;
+    let children: Vec<PageId> = match page.kind() {
+        Some(PageKind::Leaf) => Vec::new(),
+        Some(PageKind::Inner) => (0..=page.slot_count())
+            .map(|pos| child_at(page, pos))
+            .collect(),
+        _ => return Err(not_a_node(id)),
+    };
+    for child in children {
+        free_subtree(store, child)?;
+    }
+    store.free(id)
+}
+
+/// Insert into the subtree at `id`, returning the subtree's id after
+/// shadowing, the previous value and any split
+fn insert_into(
+    store: &mut impl PageStore,
+    id: PageId,
+    key: &[u8],
+    value: &[u8],
+) -> Result<(PageId, Option<Vec<u8>>, Option<Split>)> {
+    let page = store.read(id)?;
+    match page.kind() {
+        Some(PageKind::Leaf) => {
+            let old = page.lookup(key).map(<[u8]>::to_vec);
+            let id = store.shadow(id)?;
+            let page = store.write(id)?;
+            if page.insert(key, value) {
+                return Ok((id, old, None));
+            }
+            let mut cells = entries(page);
+            match cells.binary_search_by(|(k, _)| k.as_slice().cmp(key)) {
+                Ok(index) => cells[index].1 = value.to_vec(),
+                Err(index) => cells.insert(index, (key.to_vec(), value.to_vec())),
+            }
+            Ok((id, old, Some(split_leaf(store, id, cells)?)))
+        }
+        Some(PageKind::Inner) => {
+            let pos = child_pos(page, key);
+            let child = child_at(page, pos);
+            let (new_child, old, split) = insert_into(store, child, key, value)?;
+            if new_child == child && split.is_none() {
+                return Ok((id, old, None));
+            }
+
+            let id = store.shadow(id)?;
+            let page = store.write(id)?;
+            if new_child != child {
+                page.set_child(pos, new_child);
+            }
+            let Some((separator, right)) = split else {
+                return Ok((id, old, None));
+            };
+            // The new right sibling sits directly after `child`
+            if page.insert_at(pos, &separator, &encode_child(right)) {
+                return Ok((id, old, None));
+            }
+            let mut cells = entries(page);
+            cells.insert(pos, (separator, encode_child(right).to_vec()));
+            Ok((id, old, Some(split_inner(store, id, cells)?)))
+        }
+        _ => Err(not_a_node(id)),
+    }
+}
+
+/// Spread `cells` over leaf `left` and a new right sibling
+fn split_leaf(store: &mut impl PageStore, left: PageId, cells: Vec<Entry>) -> Result<Split> {
+    let mid = (split_point(&cells) + 1).min(cells.len() - 1);
+    let right = store.allocate(PageKind::Leaf)?;
+    fill(store.write(right)?, &cells[mid..]);
+    fill(store.write(left)?, &cells[..mid]);
+    Ok((cells[mid].0.clone(), right))
+}
+
+/// Spread `cells` over inner page `left` and a new right sibling, pushing the
+/// middle separator up to the parent
+fn split_inner(store: &mut impl PageStore, left: PageId, cells: Vec<Entry>) -> Result<Split> {
+    let mid = split_point(&cells);
+    let (separator, mid_child) = cells[mid].clone();
+    let right = store.allocate(PageKind::Inner)?;
+
+    let page = store.write(right)?;
+    page.set_lower(decode_child(&mid_child));
+    fill(page, &cells[mid + 1..]);
+
+    fill(store.write(left)?, &cells[..mid]);
+    Ok((separator, right))
+}
+
+/// Remove from the subtree at `id`, returning the subtree's id after
+/// shadowing and the removed value
+fn remove_from(
+    store: &mut impl PageStore,
+    id: PageId,
+    key: &[u8],
+) -> Result<(PageId, Option<Vec<u8>>)> {
+    let page = store.read(id)?;
+    match page.kind() {
+        Some(PageKind::Leaf) => {
+            let Ok(index) = page.search(key) else {
+                return Ok((id, None));
+            };
+            let old = page.value_at(index).to_vec();
+            let id = store.shadow(id)?;
+            store.write(id)?.remove_at(index);
+            Ok((id, Some(old)))
+        }
+        Some(PageKind::Inner) => {
+            let pos = child_pos(page, key);
+            let child = child_at(page, pos);
+            let (new_child, old) = remove_from(store, child, key)?;
+            if old.is_none() {
+                return Ok((id, None));
+            }
+            let id = store.shadow(id)?;
+            if new_child != child {
+                store.write(id)?.set_child(pos, new_child);
+            }
+            if is_underfull(store.read(new_child)?) {
+                rebalance(store, id, pos)?;
+            }
+            Ok((id, old))
+        }
+        _ => Err(not_a_node(id)),
+    }
+}
+
+/// Merge the underfull child at `pos` of writable page `parent` with a
+/// sibling, or even out their contents
+///
+/// Redistribution is skipped if the new separator would not fit in the parent;
+/// the child is then left underfull, which costs space but not correctness.
+fn rebalance(store: &mut impl PageStore, parent: PageId, pos: usize) -> Result<()> {
+    let page = store.read(parent)?;
+    if page.slot_count() == 0 {
+        return Ok(());
+    }
+    // Pair the child with its left sibling if it has one, else its right
+    let left_pos = pos.saturating_sub(1);
+    let separator_index = left_pos;
+    let left = child_at(page, left_pos);
+    let right = child_at(page, left_pos + 1);
+    let separator = page.key_at(separator_index).to_vec();
+
+    let left_page = store.read(left)?;
+    let kind = left_page.kind();
+    let mut cells = entries(left_page);
+    let right_page = store.read(right)?;
+    let right_lower = child_at(right_page, 0);
+    if kind == Some(PageKind::Inner) {
+        cells.push((separator.clone(), encode_child(right_lower).to_vec()));
+    }
+    cells.extend(entries(right_page));
+
+    if total_size(&cells) <= USABLE_SPACE {
+        // Merge right into left
+        let left = store.shadow(left)?;
+        fill(store.write(left)?, &cells);
+        store.free(right)?;
+        let page = store.write(parent)?;
+        page.set_child(left_pos, left);
+        page.remove_at(separator_index);
+        return Ok(());
+    }
+
+    let (mid, new_separator) = match kind {
+        Some(PageKind::Leaf) => {
+            let mid = (split_point(&cells) + 1).min(cells.len() - 1);
+            (mid, cells[mid].0.clone())
+        }
+        _ => {
+            let mid = split_point(&cells);
+            (mid, cells[mid].0.clone())
+        }
+    };
+    let child_value = encode_child(right);
+    let parent_page = store.read(parent)?;
+    let old_cell = Page::cell_size(&separator, &child_value);
+    let new_cell = Page::cell_size(&new_separator, &child_value);
+    if parent_page.free_space() + old_cell < new_cell {
+        return Ok(());
+    }
+
+    let left = store.shadow(left)?;
+    let right = store.shadow(right)?;
+    match kind {
+        Some(PageKind::Leaf) => {
+            fill(store.write(left)?, &cells[..mid]);
+            fill(store.write(right)?, &cells[mid..]);
+        }
+        _ => {
+            fill(store.write(left)?, &cells[..mid]);
+            let page = store.write(right)?;
+            page.set_lower(decode_child(&cells[mid].1));
+            fill(page, &cells[mid + 1..]);
+        }
+    }
+    let page = store.write(parent)?;
+    page.set_child(left_pos, left);
+    page.remove_at(separator_index);
+    page.insert_at(separator_index, &new_separator, &encode_child(right));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pager::Pager;
+    use crate::storage::MemoryBackend;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    fn pager() -> Pager {
+        Pager::new(Box::new(MemoryBackend::new()), 1, 1024)
+    }
+
+    fn key(n: u32) -> Vec<u8> {
+        format!("key-{:08}", n).into_bytes()
+    }
+
+    /// Walk the tree checking ordering, separator bounds and uniform depth;
+    /// returns all entries in order
+    fn check(store: &mut Pager, tree: &BTree) -> Vec<Entry> {
+        #[derive(Default)]
+        struct Walk {
+            leaf_depth: Option<usize>,
+            out: Vec<Entry>,
+        }
+
+        impl Walk {
+            fn node(
+                &mut self,
+                store: &mut Pager,
+                id: PageId,
+                bounds: (Option<&[u8]>, Option<&[u8]>),
+                depth: usize,
+            ) {
+                let (low, high) = bounds;
+                let page = store.read(id).unwrap();
+                let cells = entries(page);
+                for pair in cells.windows(2) {
+                    assert!(pair[0].0 < pair[1].0, "keys out of order in page {}", id);
+                }
+                if let Some(first) = cells.first() {
+                    assert!(low.is_none_or(|low| first.0.as_slice() >= low));
+                }
+                if let Some(last) = cells.last() {
+                    assert!(high.is_none_or(|high| last.0.as_slice() < high));
+                }
+                match page.kind() {
+                    Some(PageKind::Leaf) => {
+                        assert_eq!(*self.leaf_depth.get_or_insert(depth), depth, "uneven depth");
+                        self.out.extend(cells);
+                    }
+                    Some(PageKind::Inner) => {
+                        let lower = child_at(page, 0);
+                        let bound = cells.first().map(|c| c.0.as_slice());
+                        self.node(store, lower, (low, bound), depth + 1);
+                        for (i, (sep, child)) in cells.iter().enumerate() {
+                            let next = cells.get(i + 1).map(|c| c.0.as_slice()).or(high);
+                            self.node(store, decode_child(child), (Some(sep), next), depth + 1);
+                        }
+                    }
+                    kind => panic!("unexpected page kind {:?}", kind),
+                }
+            }
+        }
+
+        if tree.root() == 0 {
+            return Vec::new();
+        }
+        let mut walk = Walk::default();
+        walk.node(store, tree.root(), (None, None), 0);
+        walk.out
+    }
+
+    #[test]
+    fn empty_tree() {
+        let mut store = pager();
+        let mut tree = BTree::new(0);
+        assert_eq!(tree.get(&mut store, b"a").unwrap(), None);
+        assert_eq!(tree.remove(&mut store, b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn insert_get_replace() {
+        let mut store = pager();
+        let mut tree = BTree::new(0);
+        assert_eq!(tree.insert(&mut store, b"a", b"1").unwrap(), None);
+        assert_eq!(
+            tree.insert(&mut store, b"a", b"2").unwrap(),
+            Some(b"1".to_vec())
+        );
+        assert_eq!(tree.get(&mut store, b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(tree.get(&mut store, b"b").unwrap(), None);
+    }
+
+    #[test]
+    fn splits_grow_the_tree() {
+        let mut store = pager();
+        let mut tree = BTree::new(0);
+        let value = [0xabu8; 100];
+        for n in 0..5000 {
+            tree.insert(&mut store, &key(n), &value).unwrap();
+        }
+        assert_eq!(
+            store.read(tree.root()).unwrap().kind(),
+            Some(PageKind::Inner)
+        );
+        let all = check(&mut store, &tree);
+        assert_eq!(all.len(), 5000);
+        for n in (0..5000).step_by(97) {
+            assert_eq!(tree.get(&mut store, &key(n)).unwrap(), Some(value.to_vec()));
+        }
+    }
+
+    #[test]
+    fn removes_shrink_the_tree() {
+        let mut store = pager();
+        let mut tree = BTree::new(0);
+        for n in 0..3000 {
+            tree.insert(&mut store, &key(n), &[1u8; 64]).unwrap();
+        }
+        let grown = store.page_count();
+        for n in 0..3000 {
+            assert!(tree.remove(&mut store, &key(n)).unwrap().is_some());
+            if n % 500 == 0 {
+                check(&mut store, &tree);
+            }
+        }
+        assert_eq!(tree.root(), 0);
+
+        // All pages were returned and are reused
+        for n in 0..3000 {
+            tree.insert(&mut store, &key(n), &[1u8; 64]).unwrap();
+        }
+        assert_eq!(store.page_count(), grown);
+    }
+
+    #[test]
+    fn clear_frees_every_page() {
+        let mut store = pager();
+        let mut tree = BTree::new(0);
+        for n in 0..3000 {
+            tree.insert(&mut store, &key(n), &[1u8; 64]).unwrap();
+        }
+        let grown = store.page_count();
+        tree.clear(&mut store).unwrap();
+        assert_eq!(tree.root(), 0);
+        assert_eq!(tree.get(&mut store, &key(0)).unwrap(), None);
+
+        for n in 0..3000 {
+            tree.insert(&mut store, &key(n), &[1u8; 64]).unwrap();
+        }
+        assert_eq!(store.page_count(), grown);
+    }
+
+    #[test]
+    fn relocate_copies_the_path_to_a_page() {
+        let mut wal = crate::wal::Wal::open(Box::new(MemoryBackend::new())).unwrap();
+        let mut store = pager();
+        let mut tree = BTree::new(0);
+        for n in 0..3000 {
+            tree.insert(&mut store, &key(n), &[1u8; 64]).unwrap();
+        }
+        let expected = check(&mut store, &tree);
+        store.log(&mut wal, 1).unwrap();
+
+        let edge = |store: &mut Pager, tree: &BTree, last: bool| {
+            let mut path = vec![tree.root()];
+            loop {
+                let page = store.read(*path.last().unwrap()).unwrap();
+                if page.kind() == Some(PageKind::Leaf) {
+                    return path;
+                }
+                path.push(child_at(page, if last { page.slot_count() } else { 0 }));
+            }
+        };
+        let path = edge(&mut store, &tree, false);
+        let leaf = *path.last().unwrap();
+        let first = store.read(leaf).unwrap().key_at(0).to_vec();
+        let inner = tree.path_to(&mut store, &first, leaf).unwrap().unwrap();
+        assert_eq!(inner.len(), path.len() - 1);
+        tree.relocate(&mut store, leaf, inner).unwrap();
+        assert_ne!(tree.root(), path[0]);
+        assert_eq!(
+            store.take_freed(),
+            path.iter().rev().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(check(&mut store, &tree), expected);
+
+        // A page off the path to the key is not found
+        let last = *edge(&mut store, &tree, true).last().unwrap();
+        assert_eq!(tree.path_to(&mut store, &first, last).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_oversized_entries() {
+        let mut store = pager();
+        let mut tree = BTree::new(0);
+        let value = vec![0u8; MAX_ENTRY_SIZE];
+        assert!(matches!(
+            tree.insert(&mut store, b"k", &value),
+            Err(Error::ValueTooLarge { size, max: MAX_ENTRY_SIZE }) if size == MAX_ENTRY_SIZE + 1
+        ));
+        tree.insert(&mut store, b"k", &value[1..]).unwrap();
+    }
+
+    #[test]
+    fn large_keys_split_inner_nodes() {
+        let mut store = pager();
+        let mut tree = BTree::new(0);
+        for n in 0..2000u32 {
+            let mut key = vec![b'k'; MAX_ENTRY_SIZE - 8];
+            key.extend_from_slice(&n.to_be_bytes());
+            tree.insert(&mut store, &key, b"v").unwrap();
+        }
+        assert_eq!(check(&mut store, &tree).len(), 2000);
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Insert(u16, usize),
+        Remove(u16),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            3 => (0u16..2000, 0usize..400).prop_map(|(k, len)| Op::Insert(k, len)),
+            2 => (0u16..2000).prop_map(Op::Remove),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn matches_btreemap(ops in prop::collection::vec(op(), 1..1500)) {
+            let mut store = pager();
+            let mut tree = BTree::new(0);
+            let mut model = BTreeMap::new();
+            for op in ops {
+                match op {
+                    Op::Insert(k, len) => {
+                        let key = k.to_be_bytes().to_vec();
+                        let value = vec![k as u8; len];
+                        let old = tree.insert(&mut store, &key, &value).unwrap();
+                        prop_assert_eq!(old, model.insert(key, value));
+                    }
+                    Op::Remove(k) => {
+                        let key = k.to_be_bytes().to_vec();
+                        prop_assert_eq!(tree.remove(&mut store, &key).unwrap(), model.remove(&key));
+                    }
+                }
+            }
+            let expected: Vec<Entry> = model.into_iter().collect();
+            prop_assert_eq!(check(&mut store, &tree), expected);
+        }
+    }
+}
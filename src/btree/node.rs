@@ -0,0 +1,90 @@
+//! Node-level helpers shared by insert and remove
+//!
+//! Leaf cells map keys to values. Inner cells map a separator key to the child
+//! holding keys `>= separator`; keys below the first separator live in the
+//! page's `lower` child. Child positions are numbered `0..=slot_count`, where
+//! position 0 is `lower` and position `i` is the child of cell `i - 1`.
+
+use crate::buffer::{HEADER_SIZE, PAGE_SIZE, Page, PageId, PageKind};
+
+/// Bytes available for slots and cells on a page
+pub(crate) const USABLE_SPACE: usize = PAGE_SIZE - HEADER_SIZE;
+
+/// Pages using less than this many bytes are merged or rebalanced
+pub(crate) const UNDERFLOW_THRESHOLD: usize = USABLE_SPACE / 4;
+
+/// An owned key/value cell
+pub(crate) type Entry = (Vec<u8>, Vec<u8>);
+
+/// Child position that may contain `key`
+pub(crate) fn child_pos(page: &Page, key: &[u8]) -> usize {
+    match page.search(key) {
+        Ok(index) => index + 1,
+        Err(index) => index,
+    }
+}
+
+/// Child page at position `pos`, resolving swizzled swips
+pub(crate) fn child_at(page: &Page, pos: usize) -> PageId {
+    page.child_id(pos)
+}
+
+/// Decode the value of an inner cell
+pub(crate) fn decode_child(value: &[u8]) -> PageId {
+    PageId::from_le_bytes(value.try_into().expect("inner cell value is a page id"))
+}
+
+/// Encode a child page id as an inner cell value
+pub(crate) fn encode_child(id: PageId) -> [u8; 8] {
+    id.to_le_bytes()
+}
+
+/// Copy all cells out of a page
+///
+/// Child values of inner pages are copied unswizzled: a hot swip must never
+/// end up anywhere but the slot the buffer manager swizzled.
+pub(crate) fn entries(page: &Page) -> Vec<Entry> {
+    let inner = page.kind() == Some(PageKind::Inner);
+    (0..page.slot_count())
+        .map(|i| {
+            let value = if inner {
+                encode_child(child_at(page, i + 1)).to_vec()
+            } else {
+                page.value_at(i).to_vec()
+            };
+            (page.key_at(i).to_vec(), value)
+        })
+        .collect()
+}
+
+/// Replace a page's cells with `entries`, keeping its kind and links
+pub(crate) fn fill(page: &mut Page, entries: &[Entry]) {
+    page.clear();
+    for (index, (key, value)) in entries.iter().enumerate() {
+        let inserted = page.insert_at(index, key, value);
+        debug_assert!(inserted, "entries exceed page capacity");
+    }
+}
+
+/// Total page space consumed by `entries`
+pub(crate) fn total_size(entries: &[Entry]) -> usize {
+    entries.iter().map(|(k, v)| Page::cell_size(k, v)).sum()
+}
+
+/// First index at which the running size reaches half of the total
+pub(crate) fn split_point(entries: &[Entry]) -> usize {
+    let half = total_size(entries) / 2;
+    let mut running = 0;
+    for (index, (key, value)) in entries.iter().enumerate() {
+        running += Page::cell_size(key, value);
+        if running >= half {
+            return index;
+        }
+    }
+    entries.len() - 1
+}
+
+/// Whether a page holds too little data and should be merged or rebalanced
+pub(crate) fn is_underfull(page: &Page) -> bool {
+    page.used_space() < UNDERFLOW_THRESHOLD
+}
@@ -0,0 +1,206 @@
+//! Point lookups by optimistic lock coupling
+//!
+//! Readers descend from the root swip through swizzled child slots without
+//! taking any lock or writing to shared memory. Each frame is copied and then
+//! validated against the version read from its latch before the copy is
+//! trusted; the child's version is read before the parent is validated again,
+//! so a child frame that was unswizzled, evicted or reused in between forces a
+//! restart. A cold swip ends the attempt, as loading a page needs the buffer
+//! manager, and so does a frame latched exclusively: the writer holding it
+//! may keep it until its transaction ends, and readers must not wait on it.
+
+use std::hint;
+use std::ptr;
+use std::sync::atomic::Ordering;
+
+use super::node::child_pos;
+use crate::buffer::{AtomicSwip, Page, PageId, PageKind, Swip};
+
+/// Attempts before giving up on a descent that keeps failing validation
+const MAX_RESTARTS: usize = 64;
+
+/// Outcome of one descent
+enum Attempt {
+    Done(Option<Vec<u8>>),
+    /// Reached a cold swip or a page that is not a node
+    Cold,
+    /// A frame changed while it was being read
+    Restart,
+}
+
+/// Look up `key` in the tree behind `root` if its path is hot
+///
+/// With `expected` set, the lookup only proceeds if `root` still refers to
+/// that page. Returns `None` when the lookup needs the buffer manager: a page
+/// on the path is not resident or is latched by a writer, the root moved on,
+/// or writers kept invalidating the descent.
+pub(crate) fn get(
+    root: &AtomicSwip,
+    expected: Option<PageId>,
+    key: &[u8],
+) -> Option<Option<Vec<u8>>> {
+    if expected == Some(0) {
+        return Some(None);
+    }
+    let mut scratch = Page::new(0);
+    for _ in 0..MAX_RESTARTS {
+        match descend(root, expected, key, &mut scratch) {
+            Attempt::Done(value) => return Some(value),
+            Attempt::Cold => return None,
+            Attempt::Restart => hint::spin_loop(),
+        }
+    }
+    None
+}
+
+fn descend(root: &AtomicSwip, expected: Option<PageId>, key: &[u8], scratch: &mut Page) -> Attempt {
+    let swip = root.load(Ordering::Acquire);
+    let Some(mut frame) = swip.as_ptr() else {
+        return if swip == Swip::cold(0) && expected.is_none() {
+            Attempt::Done(None)
+        } else {
+            Attempt::Cold
+        };
+    };
+    // SAFETY: frames are never freed while the tree is open
+    let Some(mut version) = unsafe { &(*frame).latch }.try_read_optimistic() else {
+        return Attempt::Cold;
+    };
+    // SAFETY: as above; a torn read is caught by validation
+    let id = unsafe { ptr::read_volatile(ptr::addr_of!((*frame).id)) };
+    // SAFETY: as above
+    if root.load(Ordering::Acquire) != swip || !unsafe { &(*frame).latch }.validate(version) {
+        return Attempt::Restart;
+    }
+    if expected.is_some_and(|expected| expected != id) {
+        return Attempt::Cold;
+    }
+    loop {
+        // SAFETY: as above. The copy may be torn by a concurrent writer, which
+        // validation detects before any of it is used.
+        scratch.data = unsafe { ptr::read_volatile(ptr::addr_of!((*frame).data)) };
+        // SAFETY: as above
+        let latch = unsafe { &(*frame).latch };
+        if !latch.validate(version) {
+            return Attempt::Restart;
+        }
+        match scratch.kind() {
+            Some(PageKind::Leaf) => {
+                return Attempt::Done(scratch.lookup(key).map(<[u8]>::to_vec));
+            }
+            Some(PageKind::Inner) => {
+                let Some(child) = scratch.child_swip(child_pos(scratch, key)).as_ptr() else {
+                    return Attempt::Cold;
+                };
+                // SAFETY: the validated copy held the hot swip, so the frame
+                // is live
+                let child_version = unsafe { &(*child).latch }.try_read_optimistic();
+                if !latch.validate(version) {
+                    return Attempt::Restart;
+                }
+                let Some(child_version) = child_version else {
+                    return Attempt::Cold;
+                };
+                (frame, version) = (child, child_version);
+            }
+            _ => return Attempt::Cold,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::btree::{BTree, PageStore, node};
+    use crate::pager::Pager;
+    use crate::storage::MemoryBackend;
+
+    fn tree(count: u32) -> (Pager, BTree) {
+        let mut pager = Pager::new(Box::new(MemoryBackend::new()), 1, 1024);
+        let mut tree = BTree::new(0);
+        for i in 0..count {
+            tree.insert(&mut pager, &i.to_be_bytes(), &[7; 100])
+                .unwrap();
+        }
+        pager.publish(tree.root());
+        (pager, tree)
+    }
+
+    #[test]
+    fn empty_tree_needs_no_pages() {
+        let (pager, _) = tree(0);
+        assert_eq!(get(&pager.root_swip(), None, b"k"), Some(None));
+    }
+
+    #[test]
+    fn hot_path_is_read_without_the_store() {
+        let (mut pager, tree) = tree(2000);
+        let root = pager.root_swip();
+        assert!(root.load(Ordering::Acquire).is_hot());
+        let key = 1234u32.to_be_bytes();
+        // The path is swizzled by the first lookup through the store
+        tree.get(&mut pager, &key).unwrap();
+        let stats = pager.stats();
+        assert_eq!(get(&root, None, &key), Some(Some(vec![7; 100])));
+        // Missing keys are answered too, as long as their leaf is hot
+        assert_eq!(get(&root, None, &[&key[..], &[0]].concat()), Some(None));
+        assert_eq!(pager.stats(), stats);
+    }
+
+    #[test]
+    fn cold_children_defer_to_the_store() {
+        let (mut pager, tree) = tree(2000);
+        let root = pager.root_swip();
+        let key = 1234u32.to_be_bytes();
+        tree.get(&mut pager, &key).unwrap();
+        assert!(get(&root, None, &key).is_some());
+        // Rewriting the root unswizzles every child slot
+        let page = pager.write(tree.root()).unwrap();
+        let entries = node::entries(page);
+        node::fill(page, &entries);
+        pager.publish(tree.root());
+        assert_eq!(get(&root, None, &key), None);
+    }
+
+    #[test]
+    fn other_roots_defer_to_the_store() {
+        let (pager, tree) = tree(10);
+        let root = pager.root_swip();
+        let key = 3u32.to_be_bytes();
+        assert!(get(&root, Some(tree.root()), &key).is_some());
+        assert_eq!(get(&root, Some(tree.root() + 1), &key), None);
+        assert_eq!(get(&root, Some(0), &key), Some(None));
+    }
+
+    #[test]
+    fn latched_frames_defer_to_the_store() {
+        let (mut pager, tree) = tree(2000);
+        let root = pager.root_swip();
+        let key = 1234u32.to_be_bytes();
+        tree.get(&mut pager, &key).unwrap();
+        let frame = root.load(Ordering::Acquire).as_ptr().unwrap();
+        // SAFETY: the path stays resident for the test
+        let child = unsafe { (*frame).child_swip(child_pos(&*frame, &key)) };
+        let child = child.as_ptr().unwrap();
+        // SAFETY: as above
+        let guard = unsafe { &(*child).latch }.lock_exclusive();
+        assert_eq!(get(&root, None, &key), None);
+        drop(guard);
+        assert_eq!(get(&root, None, &key), Some(Some(vec![7; 100])));
+    }
+
+    #[test]
+    fn writers_invalidate_readers() {
+        let (mut pager, tree) = tree(10);
+        let root = pager.root_swip();
+        let frame = root.load(Ordering::Acquire).as_ptr().unwrap();
+        // SAFETY: the root frame stays resident for the test
+        let version = unsafe { &(*frame).latch }.read_optimistic();
+        pager.write(tree.root()).unwrap();
+        // SAFETY: as above
+        assert_eq!(unsafe { &(*frame).latch }.try_read_optimistic(), None);
+        pager.publish(tree.root());
+        // SAFETY: as above
+        assert!(!unsafe { &(*frame).latch }.validate(version));
+    }
+}
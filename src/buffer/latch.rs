@@ -0,0 +1,303 @@
+//! Optimistic latch (version counter plus lock bits)
+//!
+//! ```text
+//! 63                          16 15            1   0
+//! +------------------------------+---------------+---+
+//! |           version            | shared count  | X |
+//! +------------------------------+---------------+---+
+//! ```
+//!
+//! Optimistic readers take no atomic writes: they remember the version, read
+//! the protected data and then [`validate`](OptimisticLatch::validate) that the
+//! version is unchanged. Every exclusive unlock bumps the version, so any read
+//! that overlapped a modification fails validation and must be retried. Shared
+//! holders block exclusive lockers but not optimistic readers.
+
+use std::hint;
+use std::sync::atomic::{AtomicU64, Ordering, fence};
+use std::thread;
+
+const EXCLUSIVE: u64 = 1;
+const SHARED_ONE: u64 = 1 << 1;
+const SHARED_MASK: u64 = 0xfffe;
+const VERSION_ONE: u64 = 1 << 16;
+
+/// Spins before yielding the thread while waiting for a latch
+const SPINS_BEFORE_YIELD: u32 = 64;
+
+/// Version latch supporting optimistic, shared and exclusive access
+#[derive(Debug, Default)]
+pub struct OptimisticLatch {
+    word: AtomicU64,
+}
+
+impl OptimisticLatch {
+    /// Create an unlocked latch at version 0
+    pub const fn new() -> Self {
+        Self {
+            word: AtomicU64::new(0),
+        }
+    }
+
+    /// Start an optimistic read, waiting while the latch is held exclusively
+    ///
+    /// Returns the version to pass to [`OptimisticLatch::validate`].
+    pub fn read_optimistic(&self) -> u64 {
+        wait(|| self.try_read_optimistic())
+    }
+
+    /// Start an optimistic read, or `None` if the latch is held exclusively
+    pub fn try_read_optimistic(&self) -> Option<u64> {
+        let word = self.word.load(Ordering::Acquire);
+        (word & EXCLUSIVE == 0).then_some(word & !SHARED_MASK)
+    }
+
+    /// Whether nothing was modified since `version` was read
+    pub fn validate(&self, version: u64) -> bool {
+        // Order the protected reads before the version check
+        fence(Ordering::Acquire);
+        self.word.load(Ordering::Relaxed) & !SHARED_MASK == version
+    }
+
+    /// Acquire the latch in shared mode, waiting for an exclusive holder
+    pub fn lock_shared(&self) -> SharedGuard<'_> {
+        wait(|| {
+            let word = self.word.load(Ordering::Relaxed);
+            if word & EXCLUSIVE != 0 {
+                return None;
+            }
+            assert!(word & SHARED_MASK != SHARED_MASK, "too many shared holders");
+            self.word
+                .compare_exchange_weak(
+                    word,
+                    word + SHARED_ONE,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .ok()
+        });
+        SharedGuard { latch: self }
+    }
+
+    /// Acquire the latch exclusively, waiting for all other holders
+    pub fn lock_exclusive(&self) -> ExclusiveGuard<'_> {
+        wait(|| self.try_lock_exclusive_raw().then_some(()));
+        ExclusiveGuard { latch: self }
+    }
+
+    /// Upgrade an optimistic read to exclusive access
+    ///
+    /// Fails if the latch was modified since `version` or is held by anyone.
+    pub fn try_upgrade(&self, version: u64) -> Option<ExclusiveGuard<'_>> {
+        self.word
+            .compare_exchange(
+                version,
+                version | EXCLUSIVE,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()
+            .map(|_| ExclusiveGuard { latch: self })
+    }
+
+    /// Acquire the latch exclusively without a guard
+    ///
+    /// For holders whose critical section does not follow a lexical scope;
+    /// must be paired with [`OptimisticLatch::unlock_exclusive`].
+    pub(crate) fn lock_exclusive_raw(&self) {
+        wait(|| self.try_lock_exclusive_raw().then_some(()));
+    }
+
+    /// Release an exclusive hold taken by
+    /// [`OptimisticLatch::lock_exclusive_raw`], bumping the version
+    pub(crate) fn unlock_exclusive(&self) {
+        debug_assert!(self.word.load(Ordering::Relaxed) & EXCLUSIVE != 0);
+        self.word
+            .fetch_add(VERSION_ONE - EXCLUSIVE, Ordering::Release);
+    }
+
+    fn try_lock_exclusive_raw(&self) -> bool {
+        let word = self.word.load(Ordering::Relaxed);
+        word & (EXCLUSIVE | SHARED_MASK) == 0
+            && self
+                .word
+                .compare_exchange_weak(word, word | EXCLUSIVE, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+    }
+}
+
+/// Shared hold on an [`OptimisticLatch`], released on drop
+#[must_use = "the latch is released when the guard is dropped"]
+pub struct SharedGuard<'a> {
+    latch: &'a OptimisticLatch,
+}
+
+impl<'a> SharedGuard<'a> {
+    /// Upgrade to exclusive access if this is the only shared holder
+    pub fn try_upgrade(self) -> Result<ExclusiveGuard<'a>, Self> {
+        let word = self.latch.word.load(Ordering::Relaxed);
+        let sole = (word & !SHARED_MASK) | SHARED_ONE;
+        let upgraded = (word & !SHARED_MASK) | EXCLUSIVE;
+        match self
+            .latch
+            .word
+            .compare_exchange(sole, upgraded, Ordering::Acquire, Ordering::Relaxed)
+        {
+            Ok(_) => {
+                let latch = self.latch;
+                std::mem::forget(self);
+                Ok(ExclusiveGuard { latch })
+            }
+            Err(_) => Err(self),
+        }
+    }
+}
+
+impl Drop for SharedGuard<'_> {
+    fn drop(&mut self) {
+        self.latch.word.fetch_sub(SHARED_ONE, Ordering::Release);
+    }
+}
+
+/// Exclusive hold on an [`OptimisticLatch`], released on drop
+#[must_use = "the latch is released when the guard is dropped"]
+pub struct ExclusiveGuard<'a> {
+    latch: &'a OptimisticLatch,
+}
+
+impl Drop for ExclusiveGuard<'_> {
+    fn drop(&mut self) {
+        self.latch.unlock_exclusive();
+    }
+}
+
+/// Retry `attempt` until it succeeds, spinning briefly before yielding
+fn wait<T>(mut attempt: impl FnMut() -> Option<T>) -> T {
+    let mut spins = 0;
+    loop {
+        if let Some(value) = attempt() {
+            return value;
+        }
+        if spins < SPINS_BEFORE_YIELD {
+            spins += 1;
+            hint::spin_loop();
+        } else {
+            thread::yield_now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::UnsafeCell;
+    use std::sync::Arc;
+
+    #[test]
+    fn exclusive_unlock_invalidates_readers() {
+        let latch = OptimisticLatch::new();
+        let version = latch.read_optimistic();
+        assert!(latch.validate(version));
+        drop(latch.lock_exclusive());
+        assert!(!latch.validate(version));
+        assert!(latch.validate(latch.read_optimistic()));
+    }
+
+    #[test]
+    fn exclusive_holder_blocks_optimistic_reads() {
+        let latch = OptimisticLatch::new();
+        let guard = latch.lock_exclusive();
+        assert_eq!(latch.try_read_optimistic(), None);
+        drop(guard);
+        assert!(latch.try_read_optimistic().is_some());
+    }
+
+    #[test]
+    fn shared_holders_do_not_invalidate_readers() {
+        let latch = OptimisticLatch::new();
+        let version = latch.read_optimistic();
+        let first = latch.lock_shared();
+        let second = latch.lock_shared();
+        assert!(latch.validate(version));
+        assert_eq!(latch.try_read_optimistic(), Some(version));
+        assert!(latch.try_upgrade(version).is_none());
+
+        drop(first);
+        let guard = second.try_upgrade().ok().expect("sole shared holder");
+        assert_eq!(latch.try_read_optimistic(), None);
+        drop(guard);
+        assert!(!latch.validate(version));
+    }
+
+    #[test]
+    fn upgrade_fails_after_modification() {
+        let latch = OptimisticLatch::new();
+        let stale = latch.read_optimistic();
+        drop(latch.lock_exclusive());
+        assert!(latch.try_upgrade(stale).is_none());
+
+        let fresh = latch.read_optimistic();
+        let guard = latch.try_upgrade(fresh).expect("version unchanged");
+        drop(guard);
+        assert!(!latch.validate(fresh));
+    }
+
+    #[test]
+    fn shared_upgrade_needs_sole_holder() {
+        let latch = OptimisticLatch::new();
+        let first = latch.lock_shared();
+        let _second = latch.lock_shared();
+        assert!(first.try_upgrade().is_err());
+    }
+
+    /// Two counters that writers always keep equal
+    struct Pair {
+        latch: OptimisticLatch,
+        values: UnsafeCell<[u64; 2]>,
+    }
+
+    // SAFETY: `values` is only written under the exclusive latch; racing reads
+    // are validated before use, as in a seqlock
+    unsafe impl Sync for Pair {}
+
+    #[test]
+    fn readers_never_observe_torn_updates() {
+        let pair = Arc::new(Pair {
+            latch: OptimisticLatch::new(),
+            values: UnsafeCell::new([0, 0]),
+        });
+        let writer = {
+            let pair = Arc::clone(&pair);
+            thread::spawn(move || {
+                for _ in 0..10_000 {
+                    let _guard = pair.latch.lock_exclusive();
+                    // SAFETY: exclusive latch held
+                    let values = unsafe { &mut *pair.values.get() };
+                    values[0] += 1;
+                    values[1] += 1;
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let pair = Arc::clone(&pair);
+                thread::spawn(move || {
+                    let mut validated = 0;
+                    while validated < 1_000 {
+                        let version = pair.latch.read_optimistic();
+                        // SAFETY: a torn read is discarded by `validate`
+                        let values = unsafe { std::ptr::read_volatile(pair.values.get()) };
+                        if pair.latch.validate(version) {
+                            assert_eq!(values[0], values[1]);
+                            validated += 1;
+                        }
+                    }
+                })
+            })
+            .collect();
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,998 @@
+//! Buffer manager: page frames, swizzling and the memory budget
+//!
+//! A hot page is reached through hot [`Swip`]s: one in the page table, which
+//! maps every page the manager has seen to its swip, and, once a reader has
+//! descended to it through [`BufferManager::fix_child`], one swizzled in place
+//! in its parent's child slot. Loading a cold page claims a frame and reads
+//! the page into it.
+//!
+//! The number of resident frames is bounded by a budget fixed at construction.
+//! Under memory pressure pages pass through a cooling stage before they leave
+//! (LeanStore's second chance):
+//!
+//! ```text
+//!  load    +-----+   clock hand   +---------+   FIFO head   +------+
+//! -------> | hot | -------------> | cooling | ------------> | cold |
+//!          +-----+                +---------+               +------+
+//!             ^          touched again   |
+//!             +--------------------------+
+//! ```
+//!
+//! A clock hand sweeping the hot pages picks candidates for cooling; this
+//! stands in for LeanStore's random pick and visits every page in turn.
+//! Cooling unswizzles both swips but keeps the frame, so a page touched while
+//! cooling is rescued without I/O. Pages are evicted from the head of the
+//! cooling FIFO, after being written back if dirty. Pages whose own child
+//! slots are swizzled are never cooled, so a parent always outlives its hot
+//! children.
+//!
+//! Copy-on-write leaves a child reachable from both the old and the new
+//! version of its parent. Its swip is swizzled in at most one of them, so
+//! unswizzling that one slot is enough before its frame is released.
+//!
+//! Pages always reach the file and the log through [`Page::cold_image`], so
+//! hot swips never leave memory and every page written carries a checksum.
+//! Loading a page verifies it, so a page damaged on disk is reported rather
+//! than read.
+//!
+//! Readers may also walk hot frames without the manager, from the root swip
+//! down through swizzled child slots, validating each frame's
+//! [`OptimisticLatch`](super::OptimisticLatch) as they go. So that they can, any
+//! change a reader could observe happens under the frame's exclusive latch:
+//! the writer latches the pages it modifies until [`BufferManager::unlatch_all`],
+//! and the manager latches a parent to (un)swizzle its slots and a frame to
+//! release it. Frames are recycled through the spare list rather than freed,
+//! so a stale pointer always reaches a frame whose latch tells it to restart.
+
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use super::{AlignedPage, AtomicSwip, PAGE_SIZE, Page, PageId, PageKind, Swip};
+use crate::storage::StorageBackend;
+use crate::wal::Lsn;
+use crate::{Error, Result};
+
+/// Counters describing page cache activity
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Pages currently held in memory
+    pub resident: usize,
+    /// Accesses that found the page resident
+    pub hits: u64,
+    /// Accesses that had to read the page from disk
+    pub misses: u64,
+    /// Pages unswizzled and released to make room
+    pub evictions: u64,
+    /// Dirty pages written to the file by eviction
+    pub write_backs: u64,
+    /// Cooling pages touched again and swizzled back before eviction
+    pub rescues: u64,
+    /// Pages read ahead of use, in batches, by range scans
+    pub prefetched: u64,
+}
+
+/// Percentage of the frame budget kept in the cooling stage under pressure
+const COOLING_PERCENT: usize = 10;
+
+/// Most pages handed to the storage in one batch of writes
+const WRITE_BATCH: usize = 64;
+
+/// Fixed-budget pool of page frames over a database file
+pub(crate) struct BufferManager {
+    storage: Arc<dyn StorageBackend>,
+    /// Hot swip for every resident page outside the cooling stage; a page
+    /// without one is cold
+    swips: HashMap<PageId, Swip>,
+    /// Page whose child slot holds a hot swip to the key page
+    parents: HashMap<PageId, PageId>,
+    /// Hot pages in the order the clock hand visits them. May hold stale ids
+    /// of pages that have since been cooled or dropped.
+    clock: VecDeque<PageId>,
+    /// Frames of cooling pages, whose swips are all cold
+    cooling: HashMap<PageId, *mut Page>,
+    /// Cooling pages, oldest first. May hold stale ids of rescued pages.
+    cooling_queue: VecDeque<PageId>,
+    /// Resident pages that differ from their copy on disk
+    dirty: BTreeSet<PageId>,
+    /// Pages that must stay resident regardless of the budget
+    pinned: HashSet<PageId>,
+    /// Pages a checkpoint is writing outside the manager, which eviction
+    /// must not write under it
+    writing: HashSet<PageId>,
+    /// Frames released by eviction, ready for reuse
+    spare: Vec<Box<Page>>,
+    /// Swip to the tree root, shared with readers that bypass the manager
+    root: Arc<AtomicSwip>,
+    root_id: PageId,
+    /// Frames held under their exclusive latch until `unlatch_all`
+    latched: HashSet<PageId>,
+    resident: usize,
+    capacity: usize,
+    /// Dirty pages with a newer LSN must not reach the file yet
+    durable_lsn: Lsn,
+    /// Pages were written back since the file was last synced
+    unsynced: bool,
+    stats: CacheStats,
+}
+
+// SAFETY: every hot swip and cooling entry points at a frame exclusively owned
+// by the manager, and frames are only reached through `&self`/`&mut self`
+unsafe impl Send for BufferManager {}
+
+impl BufferManager {
+    /// Manage pages of `storage`, keeping at most `capacity` frames resident
+    ///
+    /// Pinned pages may push the pool past its budget until they are unpinned.
+    pub fn new(storage: Box<dyn StorageBackend>, capacity: usize) -> Self {
+        Self {
+            storage: Arc::from(storage),
+            swips: HashMap::new(),
+            parents: HashMap::new(),
+            clock: VecDeque::new(),
+            cooling: HashMap::new(),
+            cooling_queue: VecDeque::new(),
+            dirty: BTreeSet::new(),
+            pinned: HashSet::new(),
+            writing: HashSet::new(),
+            spare: Vec::new(),
+            root: Arc::new(AtomicSwip::new(Swip::cold(0))),
+            root_id: 0,
+            latched: HashSet::new(),
+            resident: 0,
+            capacity: capacity.max(1),
+            durable_lsn: 0,
+            unsynced: false,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Underlying database file
+    pub fn storage(&self) -> &dyn StorageBackend {
+        &*self.storage
+    }
+
+    /// Shared handle to the file, for writes made without the manager
+    pub fn storage_handle(&self) -> Arc<dyn StorageBackend> {
+        Arc::clone(&self.storage)
+    }
+
+    /// Activity counters since construction
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            resident: self.resident,
+            ..self.stats
+        }
+    }
+
+    /// Whether the file is behind the pool, either because pages are dirty or
+    /// because evicted pages were written without a sync
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty() || self.unsynced
+    }
+
+    /// Most frames kept resident, pinned pages aside
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Record that the WAL is durable up to `lsn`
+    pub fn set_durable_lsn(&mut self, lsn: Lsn) {
+        self.durable_lsn = lsn;
+    }
+
+    /// Highest LSN among dirty pages, or 0 if none are dirty
+    pub fn max_dirty_lsn(&self) -> Lsn {
+        self.dirty
+            .iter()
+            .filter_map(|&id| self.frame(id))
+            // SAFETY: resident frames are live
+            .map(|ptr| unsafe { (*ptr).lsn() })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Swip to the root page, hot while the root is resident
+    pub fn root_swip(&self) -> Arc<AtomicSwip> {
+        Arc::clone(&self.root)
+    }
+
+    /// Make `id` the page the root swip refers to (0 = empty tree)
+    pub fn set_root(&mut self, id: PageId) {
+        if id == self.root_id {
+            return;
+        }
+        self.root_id = id;
+        let swip = self.swips.get(&id).copied().unwrap_or(Swip::cold(id));
+        self.root.store(swip, Ordering::Release);
+    }
+
+    /// Keep `id` resident until [`BufferManager::unpin`] is called
+    pub fn pin(&mut self, id: PageId) {
+        self.pinned.insert(id);
+    }
+
+    /// Make `id` evictable again
+    pub fn unpin(&mut self, id: PageId) {
+        self.pinned.remove(&id);
+    }
+
+    /// Make the page resident and return it
+    pub fn fix(&mut self, id: PageId) -> Result<&Page> {
+        Ok(self.load(id)?)
+    }
+
+    /// Make the page resident and return it for modification, marking it dirty
+    pub fn fix_mut(&mut self, id: PageId) -> Result<&mut Page> {
+        self.dirty.insert(id);
+        self.load(id)
+    }
+
+    /// Make the page resident for modification under its exclusive latch,
+    /// which is held until [`BufferManager::unlatch_all`]
+    pub fn fix_exclusive(&mut self, id: PageId) -> Result<&mut Page> {
+        let page: *mut Page = self.fix_mut(id)?;
+        if self.latched.insert(id) {
+            // SAFETY: the frame was just made resident
+            unsafe { &(*page).latch }.lock_exclusive_raw();
+        }
+        // SAFETY: as above, and `&mut self` rules out other references
+        Ok(unsafe { &mut *page })
+    }
+
+    /// Release every latch taken by [`BufferManager::fix_exclusive`],
+    /// publishing the modifications to optimistic readers
+    pub fn unlatch_all(&mut self) {
+        for id in std::mem::take(&mut self.latched) {
+            if let Some(ptr) = self.frame(id) {
+                // SAFETY: resident frames are live
+                unsafe { &(*ptr).latch }.unlock_exclusive();
+            }
+        }
+    }
+
+    /// Follow child `pos` of inner page `parent`, returning the child's id
+    /// and page
+    ///
+    /// A hot child swip is followed directly. A cold one is loaded and then
+    /// swizzled in place, so the next descent skips the page table.
+    pub fn fix_child(&mut self, parent: PageId, pos: usize) -> Result<(PageId, &Page)> {
+        let frame: *mut Page = self.load(parent)?;
+        // SAFETY: `frame` is resident and no reference into it is live
+        let (offset, swip) = unsafe { ((*frame).child_offset(pos), (*frame).child_swip(pos)) };
+        if let Some(child) = swip.as_ptr() {
+            self.stats.hits += 1;
+            // SAFETY: hot child swips point at live frames
+            let child = unsafe { &*child };
+            return Ok((child.id, child));
+        }
+        let id = swip.page_id().expect("swip is cold");
+
+        // The parent must survive any eviction the load triggers
+        let pinned = self.pinned.insert(parent);
+        let child = self.load(id).map(|page| page as *mut Page);
+        if pinned {
+            self.pinned.remove(&parent);
+        }
+        let child = child?;
+        if !self.swizzled_elsewhere(id, parent, child) {
+            self.store_slot(parent, frame, offset, Swip::hot(child));
+            self.parents.insert(id, parent);
+        }
+        // SAFETY: `child` was just made resident
+        Ok((id, unsafe { &*child }))
+    }
+
+    /// Claim a zeroed, dirty frame for a page that has no contents on disk yet
+    pub fn create(&mut self, id: PageId) -> Result<&mut Page> {
+        debug_assert!(self.frame(id).is_none(), "page {id} is already resident");
+        let mut page = self.claim()?;
+        page.id = id;
+        page.data.fill(0);
+        self.dirty.insert(id);
+        Ok(self.install(page))
+    }
+
+    /// Forget a page that is no longer part of the database
+    ///
+    /// Its frame is released without writing it back.
+    pub fn discard(&mut self, id: PageId) {
+        let hot = self.swips.remove(&id).and_then(Swip::as_ptr);
+        if let Some(ptr) = hot {
+            self.unswizzle_parent(id, ptr);
+        }
+        if id == self.root_id {
+            self.root.store(Swip::cold(id), Ordering::Release);
+        }
+        if let Some(ptr) = hot.or_else(|| self.cooling.remove(&id)) {
+            // SAFETY: the frame was owned by the swip or cooling entry just removed
+            let latch = unsafe { &(*ptr).latch };
+            if !self.latched.remove(&id) {
+                latch.lock_exclusive_raw();
+            }
+            latch.unlock_exclusive();
+            // SAFETY: as above
+            self.spare.push(unsafe { Box::from_raw(ptr) });
+            self.resident -= 1;
+        }
+        self.dirty.remove(&id);
+        self.pinned.remove(&id);
+    }
+
+    /// Write every dirty page back in `PageId` order and sync the file
+    ///
+    /// Pages go to the storage in batches, so backends that can have several
+    /// writes in flight submit each batch at once.
+    ///
+    /// The caller must have made the WAL durable up to
+    /// [`BufferManager::max_dirty_lsn`].
+    pub fn write_back(&mut self) -> Result<()> {
+        let frames: Vec<_> = self
+            .dirty
+            .iter()
+            .filter_map(|&id| Some((id, self.frame(id)?)))
+            .collect();
+        for batch in frames.chunks(WRITE_BATCH) {
+            let images: Vec<_> = batch
+                .iter()
+                // SAFETY: resident frames are live
+                .map(|&(id, ptr)| (unsafe { &*ptr }.cold_image(), id * PAGE_SIZE as u64))
+                .collect();
+            let writes: Vec<_> = images
+                .iter()
+                .map(|(image, offset)| (&image[..], *offset))
+                .collect();
+            self.storage.write_batch(&writes)?;
+        }
+        self.storage.sync()?;
+        self.dirty.clear();
+        self.unsynced = false;
+        Ok(())
+    }
+
+    /// Copy the images of the next dirty pages after `after`, in `PageId`
+    /// order, that may reach the file: unpinned and covered by the durable
+    /// log
+    ///
+    /// Returns each page with its LSN and image. The pages cannot be evicted
+    /// until [`BufferManager::end_write`], so an eviction never writes a
+    /// page that the caller's write could then overwrite with an older image.
+    pub fn begin_write(&mut self, after: PageId) -> Vec<(PageId, Lsn, Box<AlignedPage>)> {
+        let mut batch = Vec::new();
+        for &id in self.dirty.range(after + 1..) {
+            if batch.len() == WRITE_BATCH {
+                break;
+            }
+            let Some(ptr) = self.frame(id) else {
+                continue;
+            };
+            // SAFETY: resident frames are live
+            let page = unsafe { &*ptr };
+            if self.pinned.contains(&id) || page.lsn() > self.durable_lsn {
+                continue;
+            }
+            batch.push((id, page.lsn(), page.cold_image()));
+        }
+        self.writing.extend(batch.iter().map(|&(id, ..)| id));
+        batch
+    }
+
+    /// Finish a write started by [`BufferManager::begin_write`], marking the
+    /// pages clean if it `succeeded` and they were not replaced meanwhile
+    ///
+    /// The caller must sync the file before relying on the pages.
+    pub fn end_write(&mut self, batch: &[(PageId, Lsn, Box<AlignedPage>)], succeeded: bool) {
+        for &(id, lsn, _) in batch {
+            self.writing.remove(&id);
+            // A page freed and reused since carries another record's LSN
+            let unchanged = self
+                .frame(id)
+                // SAFETY: resident frames are live
+                .is_some_and(|ptr| unsafe { (*ptr).lsn() } == lsn);
+            if succeeded && unchanged && !self.pinned.contains(&id) {
+                self.dirty.remove(&id);
+            }
+        }
+    }
+
+    /// Load the cold pages among `ids` in a single batch of reads, ahead of
+    /// their use
+    ///
+    /// Only a hint: at most a quarter of the budget is read ahead at once so
+    /// that prefetched pages do not push each other out, and pages that fail
+    /// to read or verify are skipped, to be reported when they are used.
+    pub fn prefetch(&mut self, ids: &[PageId]) {
+        let mut pages: Vec<Box<Page>> = Vec::new();
+        for &id in ids {
+            if pages.len() >= self.capacity / 4 {
+                break;
+            }
+            if self.frame(id).is_some() || pages.iter().any(|page| page.id == id) {
+                continue;
+            }
+            let Ok(mut page) = self.claim() else {
+                break;
+            };
+            page.id = id;
+            pages.push(page);
+        }
+        if pages.is_empty() {
+            return;
+        }
+        let mut reads: Vec<_> = pages
+            .iter_mut()
+            .map(|page| (&mut page.data[..], page.id * PAGE_SIZE as u64))
+            .collect();
+        let read = self.storage.read_batch(&mut reads);
+        for page in pages {
+            if read.is_ok() && page.header().checksum == page.compute_checksum() {
+                self.stats.prefetched += 1;
+                self.install(page);
+            } else {
+                self.spare.push(page);
+            }
+        }
+    }
+
+    /// Hot page behind `id`, if any
+    fn hot(&self, id: PageId) -> Option<&Page> {
+        let ptr = self.swips.get(&id)?.as_ptr()?;
+        // SAFETY: hot swips point at live frames owned by the manager
+        Some(unsafe { &*ptr })
+    }
+
+    /// Frame holding `id`, whether hot or cooling
+    fn frame(&self, id: PageId) -> Option<*mut Page> {
+        self.swips
+            .get(&id)
+            .and_then(|swip| swip.as_ptr())
+            .or_else(|| self.cooling.get(&id).copied())
+    }
+
+    /// Follow the page table swip for `id`, rescuing the page from the
+    /// cooling stage or loading it if it is cold
+    fn load(&mut self, id: PageId) -> Result<&mut Page> {
+        if let Some(ptr) = self.swips.get(&id).and_then(|swip| swip.as_ptr()) {
+            self.stats.hits += 1;
+            // SAFETY: hot swips point at live frames owned by the manager, and
+            // `&mut self` guarantees no other reference to the frame exists
+            return Ok(unsafe { &mut *ptr });
+        }
+        if let Some(ptr) = self.cooling.remove(&id) {
+            self.stats.hits += 1;
+            self.stats.rescues += 1;
+            self.swips.insert(id, Swip::hot(ptr));
+            self.clock.push_back(id);
+            if id == self.root_id {
+                self.root.store(Swip::hot(ptr), Ordering::Release);
+            }
+            // SAFETY: cooling frames are live and now owned by the hot swip
+            return Ok(unsafe { &mut *ptr });
+        }
+        self.stats.misses += 1;
+        let mut page = self.claim()?;
+        page.id = id;
+        let offset = id * PAGE_SIZE as u64;
+        if let Err(err) = self.storage.read_at(&mut page.data, offset) {
+            self.spare.push(page);
+            return Err(err.into());
+        }
+        let (expected, actual) = (page.header().checksum, page.compute_checksum());
+        if expected != actual {
+            self.spare.push(page);
+            return Err(Error::ChecksumMismatch {
+                page: id,
+                offset,
+                expected,
+                actual,
+            });
+        }
+        Ok(self.install(page))
+    }
+
+    /// Hand out a frame, evicting pages first if the pool is at its budget
+    fn claim(&mut self) -> Result<Box<Page>> {
+        if self.resident >= self.capacity {
+            while self.resident >= self.capacity {
+                if !self.evict_cooled()? && !self.cool_one() {
+                    // Everything resident is pinned, a parent of hot children
+                    // or not yet durable in the WAL
+                    break;
+                }
+            }
+            // Refill the cooling stage so the pages evicted next have had a
+            // chance to be rescued
+            let target = (self.capacity * COOLING_PERCENT / 100).max(1);
+            while self.cooling.len() < target && self.cool_one() {}
+        }
+        Ok(self.spare.pop().unwrap_or_else(|| Box::new(Page::new(0))))
+    }
+
+    /// Swizzle the page table swip for `page.id` to point at `page`
+    fn install(&mut self, page: Box<Page>) -> &mut Page {
+        let id = page.id;
+        let ptr = Box::into_raw(page);
+        self.swips.insert(id, Swip::hot(ptr));
+        self.clock.push_back(id);
+        self.resident += 1;
+        if id == self.root_id {
+            self.root.store(Swip::hot(ptr), Ordering::Release);
+        }
+        // SAFETY: the frame was just leaked into the page table
+        unsafe { &mut *ptr }
+    }
+
+    /// Advance the clock hand to the next page that may cool and move it to
+    /// the cooling stage, returning whether one was found
+    fn cool_one(&mut self) -> bool {
+        for _ in 0..self.clock.len() {
+            let id = self.clock.pop_front().expect("clock is not empty");
+            let Some(page) = self.hot(id) else {
+                continue;
+            };
+            let blocked = self.pinned.contains(&id)
+                || has_hot_children(page)
+                || (self.dirty.contains(&id) && page.lsn() > self.durable_lsn);
+            if blocked {
+                self.clock.push_back(id);
+                continue;
+            }
+            let swip = self.swips.remove(&id).expect("page is hot");
+            let ptr = swip.as_ptr().expect("page is hot");
+            self.unswizzle_parent(id, ptr);
+            if id == self.root_id {
+                self.root.store(Swip::cold(id), Ordering::Release);
+            }
+            self.cooling.insert(id, ptr);
+            self.cooling_queue.push_back(id);
+            return true;
+        }
+        false
+    }
+
+    /// Evict the oldest cooling page, returning whether there was one
+    fn evict_cooled(&mut self) -> Result<bool> {
+        for _ in 0..self.cooling_queue.len() {
+            let id = self.cooling_queue.pop_front().expect("queue is not empty");
+            if !self.cooling.contains_key(&id) {
+                continue;
+            }
+            if self.pinned.contains(&id) || self.writing.contains(&id) {
+                self.cooling_queue.push_back(id);
+                continue;
+            }
+            self.evict(id)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Write back cooling page `id` if needed and release its frame
+    fn evict(&mut self, id: PageId) -> Result<()> {
+        let ptr = self.cooling[&id];
+        if self.dirty.contains(&id) {
+            // SAFETY: cooling frames are live
+            let image = unsafe { &*ptr }.cold_image();
+            if let Err(err) = self.storage.write_at(&image[..], id * PAGE_SIZE as u64) {
+                self.cooling_queue.push_front(id);
+                return Err(err.into());
+            }
+            self.dirty.remove(&id);
+            self.unsynced = true;
+            self.stats.write_backs += 1;
+        }
+        self.cooling.remove(&id);
+        // Readers still holding a pointer into the frame must restart
+        // SAFETY: cooling frames are live
+        drop(unsafe { &(*ptr).latch }.lock_exclusive());
+        // SAFETY: the page is unswizzled everywhere, so the frame is unreachable
+        self.spare.push(unsafe { Box::from_raw(ptr) });
+        self.resident -= 1;
+        self.stats.evictions += 1;
+        Ok(())
+    }
+
+    /// Whether a page other than `parent` holds the hot swip to `id`
+    fn swizzled_elsewhere(&self, id: PageId, parent: PageId, ptr: *mut Page) -> bool {
+        match self.parents.get(&id) {
+            Some(&other) if other != parent => self
+                .hot(other)
+                .is_some_and(|page| child_pos_of(page, Swip::hot(ptr)).is_some()),
+            _ => false,
+        }
+    }
+
+    /// Turn the hot swip to `ptr` in `id`'s parent back into a page id
+    fn unswizzle_parent(&mut self, id: PageId, ptr: *mut Page) {
+        let Some(parent) = self.parents.remove(&id) else {
+            return;
+        };
+        let Some(frame) = self.swips.get(&parent).and_then(|swip| swip.as_ptr()) else {
+            return;
+        };
+        // The parent may have been rewritten since, taking the swip with it
+        let offset = {
+            // SAFETY: hot swips point at live frames owned by the manager
+            let page = unsafe { &*frame };
+            match child_pos_of(page, Swip::hot(ptr)) {
+                Some(pos) => page.child_offset(pos),
+                None => return,
+            }
+        };
+        self.store_slot(parent, frame, offset, Swip::cold(id));
+    }
+
+    /// Store `swip` in the child slot at `offset` of resident page `id`,
+    /// under the page's exclusive latch unless it is already held
+    fn store_slot(&self, id: PageId, frame: *mut Page, offset: usize, swip: Swip) {
+        // SAFETY: `frame` is resident and `offset` is one of its child slots
+        let slot = unsafe { slot(frame, offset) };
+        if self.latched.contains(&id) {
+            slot.store(swip, Ordering::Release);
+        } else {
+            // SAFETY: as above
+            let _guard = unsafe { &(*frame).latch }.lock_exclusive();
+            slot.store(swip, Ordering::Release);
+        }
+    }
+}
+
+impl Drop for BufferManager {
+    fn drop(&mut self) {
+        let hot = self.swips.drain().filter_map(|(_, swip)| swip.as_ptr());
+        for ptr in hot.chain(self.cooling.drain().map(|(_, ptr)| ptr)) {
+            // SAFETY: each frame is owned by exactly one hot swip or cooling entry
+            drop(unsafe { Box::from_raw(ptr) });
+        }
+    }
+}
+
+/// Whether any child slot of `page` is swizzled
+fn has_hot_children(page: &Page) -> bool {
+    page.kind() == Some(PageKind::Inner)
+        && (0..page.child_count()).any(|pos| page.child_swip(pos).is_hot())
+}
+
+/// Child position of `page` whose slot holds `swip`, if it is an inner page
+fn child_pos_of(page: &Page, swip: Swip) -> Option<usize> {
+    if page.kind() != Some(PageKind::Inner) {
+        return None;
+    }
+    (0..page.child_count()).find(|&pos| page.child_swip(pos) == swip)
+}
+
+/// Atomic view of the child swip at `offset` within `frame`
+///
+/// # Safety
+///
+/// `frame` must be a live frame, `offset` one of its child slot offsets, and
+/// no reference into the frame's data may be live while the swip is written.
+unsafe fn slot<'a>(frame: *mut Page, offset: usize) -> &'a AtomicSwip {
+    // SAFETY: child slot offsets are 8-byte aligned within the page-aligned
+    // data array, and the caller guarantees the frame is live
+    unsafe {
+        AtomicSwip::from_ptr(
+            ptr::addr_of_mut!((*frame).data)
+                .cast::<u8>()
+                .add(offset)
+                .cast(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+
+    /// Byte of each test page that holds its id
+    const MARK: usize = PAGE_SIZE - 1;
+
+    /// File holding `count` free pages marked with their id
+    fn file_with_pages(count: u64) -> MemoryBackend {
+        let file = MemoryBackend::new();
+        for id in 0..count {
+            let mut page = Page::new(id);
+            page.data[MARK] = id as u8;
+            file.write_at(&page.cold_image()[..], id * PAGE_SIZE as u64)
+                .unwrap();
+        }
+        file
+    }
+
+    fn is_hot(buffers: &BufferManager, id: PageId) -> bool {
+        buffers.swips.get(&id).is_some_and(Swip::is_hot)
+    }
+
+    #[test]
+    fn cold_pages_are_swizzled_on_first_access() {
+        let mut buffers = BufferManager::new(Box::new(file_with_pages(4)), 4);
+        assert!(!is_hot(&buffers, 2));
+        assert_eq!(buffers.fix(2).unwrap().data[MARK], 2);
+        assert!(is_hot(&buffers, 2));
+        buffers.fix(2).unwrap();
+        assert_eq!(buffers.stats().misses, 1);
+        assert_eq!(buffers.stats().hits, 1);
+    }
+
+    #[test]
+    fn budget_is_enforced_by_unswizzling() {
+        let mut buffers = BufferManager::new(Box::new(file_with_pages(8)), 3);
+        for id in 0..8 {
+            assert_eq!(buffers.fix(id).unwrap().data[MARK], id as u8);
+            assert!(buffers.stats().resident <= 3);
+        }
+        assert!(!buffers.swips.contains_key(&0));
+        assert!(is_hot(&buffers, 7));
+        assert_eq!(buffers.stats().evictions, 5);
+        // Evicted pages come back from disk
+        assert_eq!(buffers.fix(0).unwrap().data[MARK], 0);
+    }
+
+    #[test]
+    fn page_table_is_bounded_by_the_budget() {
+        let mut buffers = BufferManager::new(Box::new(file_with_pages(200)), 8);
+        for id in 0..200 {
+            buffers.fix(id).unwrap();
+            assert!(buffers.swips.len() + buffers.cooling.len() <= 8);
+        }
+        assert_eq!(buffers.stats().evictions, 192);
+    }
+
+    #[test]
+    fn dirty_pages_are_written_back_on_eviction() {
+        let file = file_with_pages(3);
+        let mut buffers = BufferManager::new(Box::new(file.clone()), 1);
+        let page = buffers.fix_mut(1).unwrap();
+        page.data[MARK] = 0xee;
+        page.set_lsn(5);
+        // Not yet durable in the WAL, so it must stay
+        buffers.fix(2).unwrap();
+        assert!(is_hot(&buffers, 1));
+        assert_eq!(buffers.stats().resident, 2);
+
+        buffers.set_durable_lsn(5);
+        buffers.fix(0).unwrap();
+        assert!(!is_hot(&buffers, 1));
+        assert_eq!(buffers.stats().write_backs, 1);
+        assert!(buffers.is_dirty());
+        let mut byte = [0];
+        file.read_at(&mut byte, (PAGE_SIZE + MARK) as u64).unwrap();
+        assert_eq!(byte, [0xee]);
+    }
+
+    #[test]
+    fn damaged_pages_fail_to_load() {
+        let file = file_with_pages(3);
+        file.write_at(&[0xab], 2 * PAGE_SIZE as u64 + 100).unwrap();
+        let mut buffers = BufferManager::new(Box::new(file), 3);
+        match buffers.fix(2) {
+            Err(Error::ChecksumMismatch {
+                page,
+                offset,
+                expected,
+                actual,
+            }) => {
+                assert_eq!((page, offset), (2, 2 * PAGE_SIZE as u64));
+                assert_ne!(expected, actual);
+            }
+            other => panic!(
+                "expected a checksum mismatch, got {:?}",
+                other.map(|page| page.id)
+            ),
+        }
+        assert!(!is_hot(&buffers, 2));
+        assert_eq!(buffers.fix(1).unwrap().data[MARK], 1);
+    }
+
+    #[test]
+    fn prefetched_pages_are_hits() {
+        let file = file_with_pages(8);
+        file.write_at(&[0xab], 3 * PAGE_SIZE as u64 + 100).unwrap();
+        let mut buffers = BufferManager::new(Box::new(file), 16);
+        buffers.fix(1).unwrap();
+        buffers.prefetch(&[1, 2, 2, 3, 4, 5, 6, 7]);
+        // A quarter of the budget is read at most, skipping resident pages,
+        // and damaged pages are left out
+        assert_eq!(buffers.stats().prefetched, 3);
+        assert!(!is_hot(&buffers, 3) && !is_hot(&buffers, 6));
+        assert_eq!(buffers.fix(5).unwrap().data[MARK], 5);
+        assert_eq!(buffers.stats().misses, 1);
+        assert!(matches!(
+            buffers.fix(3),
+            Err(Error::ChecksumMismatch { page: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn pinned_pages_stay_resident() {
+        let mut buffers = BufferManager::new(Box::new(file_with_pages(4)), 1);
+        buffers.pin(1);
+        buffers.fix(1).unwrap();
+        buffers.fix(2).unwrap();
+        buffers.fix(3).unwrap();
+        assert!(is_hot(&buffers, 1));
+        assert!(!is_hot(&buffers, 2));
+
+        buffers.unpin(1);
+        buffers.fix(0).unwrap();
+        assert!(!is_hot(&buffers, 1));
+        assert_eq!(buffers.stats().resident, 1);
+    }
+
+    #[test]
+    fn discarded_pages_are_not_written() {
+        let file = file_with_pages(2);
+        let mut buffers = BufferManager::new(Box::new(file.clone()), 2);
+        buffers.create(1).unwrap().data[MARK] = 0xff;
+        buffers.discard(1);
+        buffers.write_back().unwrap();
+        assert_eq!(buffers.stats().resident, 0);
+        let mut byte = [0];
+        file.read_at(&mut byte, (PAGE_SIZE + MARK) as u64).unwrap();
+        assert_eq!(byte, [1]);
+    }
+
+    /// File with an inner page 1 whose children are leaves 2 and 3
+    fn file_with_tree() -> MemoryBackend {
+        let file = MemoryBackend::new();
+        let mut inner = Page::new(1);
+        inner.init(PageKind::Inner);
+        inner.set_lower(2);
+        inner.insert(b"m", &3u64.to_le_bytes());
+        file.write_at(&inner.cold_image()[..], PAGE_SIZE as u64)
+            .unwrap();
+        for id in [2, 3] {
+            let mut leaf = Page::new(id);
+            leaf.init(PageKind::Leaf);
+            file.write_at(&leaf.cold_image()[..], id * PAGE_SIZE as u64)
+                .unwrap();
+        }
+        file
+    }
+
+    #[test]
+    fn child_swips_are_swizzled_in_place() {
+        let mut buffers = BufferManager::new(Box::new(file_with_tree()), 4);
+        let (id, page) = buffers.fix_child(1, 1).unwrap();
+        assert_eq!((id, page.id), (3, 3));
+        let parent = buffers.fix(1).unwrap();
+        assert!(parent.child_swip(1).is_hot());
+        assert!(parent.child_swip(0).is_cold());
+        assert_eq!(parent.child_id(1), 3);
+
+        let misses = buffers.stats().misses;
+        assert_eq!(buffers.fix_child(1, 1).unwrap().0, 3);
+        assert_eq!(buffers.stats().misses, misses);
+    }
+
+    #[test]
+    fn eviction_unswizzles_the_parent() {
+        let mut buffers = BufferManager::new(Box::new(file_with_tree()), 2);
+        buffers.fix_child(1, 0).unwrap();
+        // The parent has a hot child, so the child is evicted instead
+        buffers.fix(3).unwrap();
+        assert!(buffers.frame(1).is_some());
+        assert!(buffers.frame(2).is_none());
+        assert!(buffers.fix(1).unwrap().child_swip(0).is_cold());
+        assert_eq!(buffers.fix_child(1, 0).unwrap().0, 2);
+    }
+
+    #[test]
+    fn hot_swips_never_reach_the_file() {
+        let file = file_with_tree();
+        let mut buffers = BufferManager::new(Box::new(file.clone()), 4);
+        buffers.fix_child(1, 1).unwrap();
+        buffers.fix_mut(1).unwrap().set_lsn(1);
+        buffers.write_back().unwrap();
+        assert!(buffers.fix(1).unwrap().child_swip(1).is_hot());
+
+        let mut page = Page::new(1);
+        file.read_at(&mut page.data, PAGE_SIZE as u64).unwrap();
+        assert_eq!(page.child_swip(1), Swip::cold(3));
+    }
+
+    #[test]
+    fn checkpoint_writes_take_only_durable_unpinned_pages() {
+        let file = file_with_pages(8);
+        let mut buffers = BufferManager::new(Box::new(file.clone()), 16);
+        for id in 1..6 {
+            let page = buffers.fix_mut(id).unwrap();
+            page.data[MARK] = 0xee;
+            page.set_lsn(id);
+        }
+        buffers.pin(2);
+        buffers.set_durable_lsn(4);
+        let batch = buffers.begin_write(1);
+        assert_eq!(batch.iter().map(|&(id, ..)| id).collect::<Vec<_>>(), [3, 4]);
+
+        // Page 4 is freed and reused while the batch is written
+        buffers.discard(4);
+        buffers.create(4).unwrap().set_lsn(7);
+        for (id, _, image) in &batch {
+            file.write_at(&image[..], id * PAGE_SIZE as u64).unwrap();
+        }
+        buffers.end_write(&batch, true);
+        assert_eq!(
+            buffers.dirty.iter().copied().collect::<Vec<_>>(),
+            [1, 2, 4, 5]
+        );
+
+        let mut page = Page::new(3);
+        file.read_at(&mut page.data, 3 * PAGE_SIZE as u64).unwrap();
+        assert_eq!(page.data[MARK], 0xee);
+    }
+
+    #[test]
+    fn touched_cooling_pages_are_rescued() {
+        let mut buffers = BufferManager::new(Box::new(file_with_pages(12)), 10);
+        for id in 0..10 {
+            buffers.fix(id).unwrap();
+        }
+        // Page 0 is cooled and evicted at once; page 1 waits in the cooling stage
+        buffers.fix(10).unwrap();
+        assert!(!is_hot(&buffers, 1));
+        assert!(buffers.cooling.contains_key(&1));
+
+        let misses = buffers.stats().misses;
+        assert_eq!(buffers.fix(1).unwrap().data[MARK], 1);
+        assert_eq!(buffers.stats().misses, misses);
+        assert_eq!(buffers.stats().rescues, 1);
+        assert!(is_hot(&buffers, 1));
+
+        // The next victim is the following page on the clock, not the rescued one
+        buffers.fix(11).unwrap();
+        assert!(is_hot(&buffers, 1));
+        assert!(buffers.frame(2).is_none());
+        assert!(buffers.stats().resident <= 10);
+    }
+
+    #[test]
+    fn cooling_unswizzles_the_parent_slot() {
+        let mut buffers = BufferManager::new(Box::new(file_with_tree()), 3);
+        buffers.fix_child(1, 0).unwrap();
+        buffers.fix_child(1, 1).unwrap();
+        assert!(buffers.cool_one());
+        // The parent has hot children, so the clock skips it
+        assert!(is_hot(&buffers, 1));
+        assert!(buffers.cooling.contains_key(&2));
+        assert!(buffers.fix(1).unwrap().child_swip(0).is_cold());
+
+        // Descending again rescues the child and swizzles the slot once more
+        assert_eq!(buffers.fix_child(1, 0).unwrap().0, 2);
+        assert!(buffers.fix(1).unwrap().child_swip(0).is_hot());
+        assert_eq!(buffers.stats().rescues, 1);
+    }
+
+    #[test]
+    fn root_swip_follows_the_root_frame() {
+        let mut buffers = BufferManager::new(Box::new(file_with_pages(4)), 1);
+        let root = buffers.root_swip();
+        buffers.set_root(1);
+        assert_eq!(root.load(Ordering::Acquire), Swip::cold(1));
+        let frame: *mut Page = buffers.fix_mut(1).unwrap();
+        assert_eq!(root.load(Ordering::Acquire), Swip::hot(frame));
+
+        buffers.fix(2).unwrap();
+        assert_eq!(root.load(Ordering::Acquire), Swip::cold(1));
+    }
+
+    #[test]
+    fn releasing_a_frame_invalidates_its_readers() {
+        let mut buffers = BufferManager::new(Box::new(file_with_pages(4)), 1);
+        let frame: *mut Page = buffers.fix_mut(1).unwrap();
+        // SAFETY: frames stay allocated until the manager is dropped
+        let latch = unsafe { &(*frame).latch };
+        let version = latch.read_optimistic();
+        buffers.fix(2).unwrap();
+        assert!(!latch.validate(version));
+
+        let version = latch.read_optimistic();
+        let page = buffers.fix_exclusive(2).unwrap() as *mut Page;
+        assert_eq!(page, frame);
+        assert_eq!(latch.try_read_optimistic(), None);
+        buffers.unlatch_all();
+        assert!(!latch.validate(version));
+    }
+}
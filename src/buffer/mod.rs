@@ -1,7 +1,15 @@
 //! Buffer pool management with pointer swizzling
 
+mod latch;
+mod manager;
 mod page;
+mod slotted;
 mod swip;
 
-pub use page::{Page, PageId};
-pub use swip::Swip;
+pub use latch::{ExclusiveGuard, OptimisticLatch, SharedGuard};
+pub(crate) use manager::BufferManager;
+pub use manager::CacheStats;
+pub(crate) use page::AlignedPage;
+pub use page::{PAGE_SIZE, Page, PageId};
+pub use slotted::{FREE_IDS_PER_PAGE, HEADER_SIZE, PageHeader, PageKind, SLOT_SIZE};
+pub use swip::{AtomicSwip, Swip};
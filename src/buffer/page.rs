@@ -1,23 +1,58 @@
 //! Page structure
 
+use std::ops::{Deref, DerefMut};
+
+use super::OptimisticLatch;
+
+/// Size of a page in bytes (on disk and in memory)
+pub const PAGE_SIZE: usize = 4096;
+
 /// Page ID type
 pub type PageId = u64;
 
+/// Copy of a page's bytes, aligned like a frame so that it can be written
+/// with direct I/O
+#[derive(Clone, PartialEq, Eq)]
+#[repr(C, align(4096))]
+pub(crate) struct AlignedPage(pub [u8; PAGE_SIZE]);
+
+impl Deref for AlignedPage {
+    type Target = [u8; PAGE_SIZE];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for AlignedPage {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
 /// In-memory page
-#[repr(align(4096))]
+///
+/// `data` comes first so it starts on the page-aligned frame boundary, which
+/// keeps every 8-byte field inside it naturally aligned. The frame header
+/// (id and latch) lives in the alignment padding after it.
+#[repr(C, align(4096))]
 pub struct Page {
+    /// Page data
+    pub data: [u8; PAGE_SIZE],
     /// Page identifier
     pub id: PageId,
-    /// Page data
-    pub data: [u8; 4096],
+    /// Latch guarding the frame; survives the frame being reused for another
+    /// page, so stale optimistic readers always fail validation
+    pub latch: OptimisticLatch,
 }
 
 impl Page {
     /// Create a new page
     pub fn new(id: PageId) -> Self {
         Self {
+            data: [0; PAGE_SIZE],
             id,
-            data: [0; 4096],
+            latch: OptimisticLatch::new(),
         }
     }
 }
@@ -0,0 +1,673 @@
+//! Slotted page layout
+//!
+//! ```text
+//! +--------+----------------------+-----------+-------------------------+
+//! | header | slot directory  -->  |   free    |  <--  key/value cells   |
+//! +--------+----------------------+-----------+-------------------------+
+//! 0        HEADER_SIZE            free_start  free_end                  PAGE_SIZE
+//! ```
+//!
+//! The slot directory grows up from the header and is kept sorted by key; cells
+//! grow down from the end of the page. Each slot is a `(offset, len)` pair
+//! pointing at a cell of the form `key_len: u16 | key | value`.
+//!
+//! Every cell ends on an 8-byte boundary, padded below its start. An inner
+//! page's 8-byte child values are therefore aligned and can be accessed as
+//! [`AtomicSwip`](super::AtomicSwip)s, like the `lower` header field.
+//!
+//! Deleting or shrinking a cell leaves a hole that is counted in `fragmented`.
+//! Inserts that do not fit in the contiguous gap but do fit in the total free
+//! space compact the cell area first, so free space is never lost.
+//!
+//! Free-list pages share the header but hold no cells: `slot_count` page ids
+//! follow the header as little-endian `u64`s, and `next` links to the next
+//! page of the list.
+
+use super::{AlignedPage, PAGE_SIZE, Page, PageId, Swip};
+
+/// Size of the page header in bytes
+pub const HEADER_SIZE: usize = 40;
+
+/// Size of one slot directory entry in bytes
+pub const SLOT_SIZE: usize = 4;
+
+/// Per-cell overhead: the key length prefix
+const CELL_HEADER_SIZE: usize = 2;
+
+/// Alignment of cell ends
+const CELL_ALIGN: usize = 8;
+
+/// Size of an inner cell's child value
+const CHILD_SIZE: usize = 8;
+
+/// Number of page ids a free-list page holds
+pub const FREE_IDS_PER_PAGE: usize = (PAGE_SIZE - HEADER_SIZE) / 8;
+
+// Header field offsets
+const CHECKSUM: usize = 0;
+const KIND: usize = 4;
+const SLOT_COUNT: usize = 6;
+const FREE_START: usize = 8;
+const FREE_END: usize = 10;
+const FRAGMENTED: usize = 12;
+const LSN: usize = 16;
+const NEXT: usize = 24;
+const LOWER: usize = 32;
+
+/// What a page is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PageKind {
+    /// Unused / uninitialized page
+    Free = 0,
+    /// B+-tree leaf node
+    Leaf = 1,
+    /// B+-tree inner node
+    Inner = 2,
+    /// Page of the persisted free list
+    FreeList = 3,
+}
+
+impl PageKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PageKind::Free),
+            1 => Some(PageKind::Leaf),
+            2 => Some(PageKind::Inner),
+            3 => Some(PageKind::FreeList),
+            _ => None,
+        }
+    }
+}
+
+/// Typed copy of a page header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageHeader {
+    /// Checksum of the page contents (see [`Page::compute_checksum`])
+    pub checksum: u32,
+    /// Page kind (`None` if the kind byte is unrecognized)
+    pub kind: Option<PageKind>,
+    /// Number of slots in the slot directory
+    pub slot_count: u16,
+    /// End of the slot directory
+    pub free_start: u16,
+    /// Start of the cell area
+    pub free_end: u16,
+    /// Bytes held by dead cells inside the cell area
+    pub fragmented: u16,
+    /// Log sequence number of the last modification
+    pub lsn: u64,
+    /// Next page of the free list (free-list pages, 0 = none)
+    pub next: PageId,
+    /// Leftmost child (inner pages)
+    pub lower: PageId,
+}
+
+impl Page {
+    /// Format the page as an empty slotted page of the given kind
+    pub fn init(&mut self, kind: PageKind) {
+        self.data[..HEADER_SIZE].fill(0);
+        self.data[KIND] = kind as u8;
+        self.set_u16(FREE_START, HEADER_SIZE as u16);
+        self.set_u16(FREE_END, PAGE_SIZE as u16);
+    }
+
+    /// Read the page header
+    pub fn header(&self) -> PageHeader {
+        PageHeader {
+            checksum: u32::from_le_bytes(self.data[CHECKSUM..CHECKSUM + 4].try_into().unwrap()),
+            kind: self.kind(),
+            slot_count: self.get_u16(SLOT_COUNT),
+            free_start: self.get_u16(FREE_START),
+            free_end: self.get_u16(FREE_END),
+            fragmented: self.get_u16(FRAGMENTED),
+            lsn: self.lsn(),
+            next: self.next(),
+            lower: self.lower(),
+        }
+    }
+
+    /// Page kind (`None` if the kind byte is unrecognized)
+    pub fn kind(&self) -> Option<PageKind> {
+        PageKind::from_u8(self.data[KIND])
+    }
+
+    /// Log sequence number of the last modification
+    pub fn lsn(&self) -> u64 {
+        self.get_u64(LSN)
+    }
+
+    /// Set the log sequence number of the last modification
+    pub fn set_lsn(&mut self, lsn: u64) {
+        self.set_u64(LSN, lsn);
+    }
+
+    /// Next page of the free list (0 = none)
+    pub fn next(&self) -> PageId {
+        self.get_u64(NEXT)
+    }
+
+    /// Set the next page of the free list
+    pub fn set_next(&mut self, id: PageId) {
+        self.set_u64(NEXT, id);
+    }
+
+    /// Leftmost child of an inner page, holding keys below the first separator
+    ///
+    /// This is the raw field; on a page whose children may be swizzled, use
+    /// [`Page::child_swip`] instead.
+    pub fn lower(&self) -> PageId {
+        self.get_u64(LOWER)
+    }
+
+    /// Set the leftmost child of an inner page
+    pub fn set_lower(&mut self, id: PageId) {
+        self.set_u64(LOWER, id);
+    }
+
+    /// CRC32C over everything except the checksum field itself
+    pub fn compute_checksum(&self) -> u32 {
+        crc32c::crc32c(&self.data[CHECKSUM + 4..])
+    }
+
+    /// Store the current checksum in the header
+    pub fn update_checksum(&mut self) {
+        let crc = self.compute_checksum();
+        self.data[CHECKSUM..CHECKSUM + 4].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Check the stored checksum against the page contents
+    pub fn verify_checksum(&self) -> bool {
+        self.header().checksum == self.compute_checksum()
+    }
+
+    /// Page ids stored on a free-list page
+    pub fn free_ids(&self) -> Vec<PageId> {
+        let count = self.slot_count().min(FREE_IDS_PER_PAGE);
+        (0..count)
+            .map(|i| self.get_u64(HEADER_SIZE + i * 8))
+            .collect()
+    }
+
+    /// Store `ids` on a free-list page, replacing its previous contents
+    pub fn set_free_ids(&mut self, ids: &[PageId]) {
+        assert!(ids.len() <= FREE_IDS_PER_PAGE, "too many ids for one page");
+        self.set_u16(SLOT_COUNT, ids.len() as u16);
+        for (i, &id) in ids.iter().enumerate() {
+            self.set_u64(HEADER_SIZE + i * 8, id);
+        }
+    }
+
+    /// Number of cells on the page
+    pub fn slot_count(&self) -> usize {
+        self.get_u16(SLOT_COUNT) as usize
+    }
+
+    /// Total bytes available for new cells and slots, including fragmented space
+    pub fn free_space(&self) -> usize {
+        self.contiguous_free() + self.get_u16(FRAGMENTED) as usize
+    }
+
+    /// Bytes used by live cells and their slots
+    pub fn used_space(&self) -> usize {
+        PAGE_SIZE - HEADER_SIZE - self.free_space()
+    }
+
+    /// Space a key/value pair consumes, including its slot and padding
+    pub fn cell_size(key: &[u8], value: &[u8]) -> usize {
+        SLOT_SIZE + padded(CELL_HEADER_SIZE + key.len() + value.len())
+    }
+
+    /// Whether a new key/value pair of the given size would fit
+    pub fn fits(&self, key: &[u8], value: &[u8]) -> bool {
+        Self::cell_size(key, value) <= self.free_space()
+    }
+
+    /// Key stored in slot `index`
+    pub fn key_at(&self, index: usize) -> &[u8] {
+        let (offset, _) = self.slot(index);
+        let key_len = self.get_u16(offset) as usize;
+        let start = offset + CELL_HEADER_SIZE;
+        &self.data[start..start + key_len]
+    }
+
+    /// Value stored in slot `index`
+    pub fn value_at(&self, index: usize) -> &[u8] {
+        let (offset, len) = self.slot(index);
+        let key_len = self.get_u16(offset) as usize;
+        &self.data[offset + CELL_HEADER_SIZE + key_len..offset + len]
+    }
+
+    /// Binary search the slot directory for `key`
+    ///
+    /// Returns `Ok(index)` if found, or `Err(index)` with the insert position.
+    pub fn search(&self, key: &[u8]) -> Result<usize, usize> {
+        let (mut lo, mut hi) = (0, self.slot_count());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.key_at(mid).cmp(key) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return Ok(mid),
+            }
+        }
+        Err(lo)
+    }
+
+    /// Look up the value stored under `key`
+    pub fn lookup(&self, key: &[u8]) -> Option<&[u8]> {
+        self.search(key).ok().map(|i| self.value_at(i))
+    }
+
+    /// Insert or replace `key`, keeping slots sorted
+    ///
+    /// Returns `false` (leaving the page unchanged) if there is not enough space.
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> bool {
+        match self.search(key) {
+            Ok(index) => {
+                let (_, old_len) = self.slot(index);
+                let new_len = CELL_HEADER_SIZE + key.len() + value.len();
+                if padded(new_len) > self.free_space() + padded(old_len) {
+                    return false;
+                }
+                self.remove_at(index);
+                self.insert_at(index, key, value)
+            }
+            Err(index) => self.insert_at(index, key, value),
+        }
+    }
+
+    /// Insert a cell at slot `index`, shifting later slots right
+    ///
+    /// The caller is responsible for keeping the directory sorted. Returns
+    /// `false` (leaving the page unchanged) if there is not enough space.
+    pub fn insert_at(&mut self, index: usize, key: &[u8], value: &[u8]) -> bool {
+        debug_assert!(index <= self.slot_count());
+        if !self.fits(key, value) {
+            return false;
+        }
+        let cell_len = CELL_HEADER_SIZE + key.len() + value.len();
+        if SLOT_SIZE + padded(cell_len) > self.contiguous_free() {
+            self.compact();
+        }
+
+        let end = self.get_u16(FREE_END) as usize;
+        let offset = end - cell_len;
+        self.set_u16(offset, key.len() as u16);
+        let key_start = offset + CELL_HEADER_SIZE;
+        self.data[key_start..key_start + key.len()].copy_from_slice(key);
+        self.data[key_start + key.len()..end].copy_from_slice(value);
+        self.set_u16(FREE_END, (end - padded(cell_len)) as u16);
+
+        let count = self.slot_count();
+        let dir = HEADER_SIZE + index * SLOT_SIZE;
+        let dir_end = HEADER_SIZE + count * SLOT_SIZE;
+        self.data.copy_within(dir..dir_end, dir + SLOT_SIZE);
+        self.set_slot(index, offset, cell_len);
+        self.set_u16(SLOT_COUNT, (count + 1) as u16);
+        self.set_u16(FREE_START, (dir_end + SLOT_SIZE) as u16);
+        true
+    }
+
+    /// Delete `key`, returning whether it was present
+    pub fn delete(&mut self, key: &[u8]) -> bool {
+        match self.search(key) {
+            Ok(index) => {
+                self.remove_at(index);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Remove slot `index`, shifting later slots left
+    pub fn remove_at(&mut self, index: usize) {
+        let count = self.slot_count();
+        debug_assert!(index < count);
+        let (offset, len) = self.slot(index);
+        let end = offset + len;
+        if end - padded(len) == self.get_u16(FREE_END) as usize {
+            // Cell sits at the edge of the free gap: reclaim it directly
+            self.set_u16(FREE_END, end as u16);
+        } else {
+            let fragmented = self.get_u16(FRAGMENTED) as usize + padded(len);
+            self.set_u16(FRAGMENTED, fragmented as u16);
+        }
+
+        let dir = HEADER_SIZE + index * SLOT_SIZE;
+        let dir_end = HEADER_SIZE + count * SLOT_SIZE;
+        self.data.copy_within(dir + SLOT_SIZE..dir_end, dir);
+        self.set_u16(SLOT_COUNT, (count - 1) as u16);
+        self.set_u16(FREE_START, (dir_end - SLOT_SIZE) as u16);
+        if count == 1 {
+            // Empty page: everything is free again
+            self.set_u16(FREE_END, PAGE_SIZE as u16);
+            self.set_u16(FRAGMENTED, 0);
+        }
+    }
+
+    /// Remove every cell, keeping the kind, LSN and links
+    pub fn clear(&mut self) {
+        self.set_u16(SLOT_COUNT, 0);
+        self.set_u16(FREE_START, HEADER_SIZE as u16);
+        self.set_u16(FREE_END, PAGE_SIZE as u16);
+        self.set_u16(FRAGMENTED, 0);
+    }
+
+    /// Rewrite the cell area so all free space is contiguous
+    pub fn compact(&mut self) {
+        if self.get_u16(FRAGMENTED) == 0 {
+            return;
+        }
+        let count = self.slot_count();
+        let mut scratch = [0u8; PAGE_SIZE];
+        let mut end = PAGE_SIZE;
+        for index in 0..count {
+            let (offset, len) = self.slot(index);
+            let start = end - len;
+            scratch[start..end].copy_from_slice(&self.data[offset..offset + len]);
+            self.set_slot(index, start, len);
+            end -= padded(len);
+        }
+        self.data[end..].copy_from_slice(&scratch[end..]);
+        self.set_u16(FREE_END, end as u16);
+        self.set_u16(FRAGMENTED, 0);
+    }
+
+    /// Number of child positions on an inner page
+    pub fn child_count(&self) -> usize {
+        self.slot_count() + 1
+    }
+
+    /// Offset within `data` of the swip for child position `pos` of an inner
+    /// page: 0 is `lower`, `i` is the value of cell `i - 1`
+    ///
+    /// The offset is always 8-byte aligned.
+    pub fn child_offset(&self, pos: usize) -> usize {
+        let offset = if pos == 0 {
+            LOWER
+        } else {
+            let (offset, len) = self.slot(pos - 1);
+            debug_assert!(
+                len - CELL_HEADER_SIZE - self.get_u16(offset) as usize == CHILD_SIZE,
+                "inner cell value is not a swip"
+            );
+            offset + len - CHILD_SIZE
+        };
+        debug_assert_eq!(offset % CELL_ALIGN, 0);
+        offset
+    }
+
+    /// Swip for child position `pos` of an inner page
+    pub fn child_swip(&self, pos: usize) -> Swip {
+        Swip::from_raw(self.get_u64(self.child_offset(pos)))
+    }
+
+    /// Point child position `pos` of an inner page at page `id`, unswizzled
+    pub fn set_child(&mut self, pos: usize, id: PageId) {
+        let offset = self.child_offset(pos);
+        self.set_u64(offset, id);
+    }
+
+    /// Page id of child position `pos` of an inner page, whether or not its
+    /// swip is swizzled
+    pub(crate) fn child_id(&self, pos: usize) -> PageId {
+        let swip = self.child_swip(pos);
+        match swip.as_ptr() {
+            // SAFETY: the buffer manager only leaves hot swips in resident pages
+            // and unswizzles them before the frame they point at is released
+            Some(ptr) => unsafe { (*ptr).id },
+            None => swip.into_raw(),
+        }
+    }
+
+    /// Copy of the page contents with every child swip unswizzled and the
+    /// checksum set, as it must appear on disk or in the log
+    pub(crate) fn cold_image(&self) -> Box<AlignedPage> {
+        let mut image = Box::new(AlignedPage(self.data));
+        if self.kind() == Some(PageKind::Inner) {
+            for pos in 0..self.child_count() {
+                if self.child_swip(pos).is_hot() {
+                    let at = self.child_offset(pos);
+                    image[at..at + 8].copy_from_slice(&self.child_id(pos).to_le_bytes());
+                }
+            }
+        }
+        let crc = crc32c::crc32c(&image[CHECKSUM + 4..]);
+        image[CHECKSUM..CHECKSUM + 4].copy_from_slice(&crc.to_le_bytes());
+        image
+    }
+
+    fn contiguous_free(&self) -> usize {
+        (self.get_u16(FREE_END) - self.get_u16(FREE_START)) as usize
+    }
+
+    fn slot(&self, index: usize) -> (usize, usize) {
+        let at = HEADER_SIZE + index * SLOT_SIZE;
+        (self.get_u16(at) as usize, self.get_u16(at + 2) as usize)
+    }
+
+    fn set_slot(&mut self, index: usize, offset: usize, len: usize) {
+        let at = HEADER_SIZE + index * SLOT_SIZE;
+        self.set_u16(at, offset as u16);
+        self.set_u16(at + 2, len as u16);
+    }
+
+    fn get_u16(&self, at: usize) -> u16 {
+        u16::from_le_bytes([self.data[at], self.data[at + 1]])
+    }
+
+    fn set_u16(&mut self, at: usize, value: u16) {
+        self.data[at..at + 2].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn get_u64(&self, at: usize) -> u64 {
+        u64::from_le_bytes(self.data[at..at + 8].try_into().unwrap())
+    }
+
+    fn set_u64(&mut self, at: usize, value: u64) {
+        self.data[at..at + 8].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+/// Bytes a cell of `len` bytes occupies once padded to the cell alignment
+fn padded(len: usize) -> usize {
+    len.next_multiple_of(CELL_ALIGN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+
+    fn leaf() -> Box<Page> {
+        let mut page = Box::new(Page::new(1));
+        page.init(PageKind::Leaf);
+        page
+    }
+
+    fn contents(page: &Page) -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..page.slot_count())
+            .map(|i| (page.key_at(i).to_vec(), page.value_at(i).to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn empty_page() {
+        let page = leaf();
+        let header = page.header();
+        assert_eq!(header.kind, Some(PageKind::Leaf));
+        assert_eq!(header.slot_count, 0);
+        assert_eq!(page.free_space(), PAGE_SIZE - HEADER_SIZE);
+        assert_eq!(page.lookup(b"missing"), None);
+    }
+
+    #[test]
+    fn insert_keeps_keys_sorted() {
+        let mut page = leaf();
+        for key in [b"m", b"c", b"x", b"a"] {
+            assert!(page.insert(key, b"v"));
+        }
+        let keys: Vec<_> = (0..page.slot_count()).map(|i| page.key_at(i)).collect();
+        assert_eq!(keys, [b"a", b"c", b"m", b"x"]);
+        assert_eq!(page.lookup(b"c"), Some(&b"v"[..]));
+    }
+
+    #[test]
+    fn replace_existing_key() {
+        let mut page = leaf();
+        assert!(page.insert(b"key", b"short"));
+        assert!(page.insert(b"key", b"a much longer value"));
+        assert_eq!(page.slot_count(), 1);
+        assert_eq!(page.lookup(b"key"), Some(&b"a much longer value"[..]));
+    }
+
+    #[test]
+    fn full_page_rejects_insert() {
+        let mut page = leaf();
+        let value = [7u8; 100];
+        let mut n = 0u32;
+        while page.insert(&n.to_be_bytes(), &value) {
+            n += 1;
+        }
+        let before = contents(&page);
+        assert!(!page.insert(&n.to_be_bytes(), &value));
+        // A failed replace must not drop the old value
+        assert!(!page.insert(&0u32.to_be_bytes(), &[0u8; 1000]));
+        assert_eq!(contents(&page), before);
+    }
+
+    #[test]
+    fn child_swips_are_aligned() {
+        let mut page = Box::new(Page::new(1));
+        page.init(PageKind::Inner);
+        page.set_lower(10);
+        for (i, key) in [&b"a"[..], b"bcd", b"efghijk"].iter().enumerate() {
+            assert!(page.insert_at(i, key, &(11 + i as u64).to_le_bytes()));
+        }
+        page.remove_at(1);
+        page.compact();
+        assert!(page.insert_at(1, b"xy", &20u64.to_le_bytes()));
+
+        let ids: Vec<_> = (0..page.child_count())
+            .map(|pos| page.child_id(pos))
+            .collect();
+        assert_eq!(ids, [10, 11, 20, 13]);
+        for pos in 0..page.child_count() {
+            assert_eq!(page.child_offset(pos) % 8, 0);
+        }
+    }
+
+    #[test]
+    fn fragmented_space_is_reused() {
+        let mut page = leaf();
+        let value = [1u8; 200];
+        let mut n = 0u32;
+        while page.insert(&n.to_be_bytes(), &value) {
+            n += 1;
+        }
+        // Punch holes in the middle of the cell area
+        for i in (1..n).step_by(2) {
+            assert!(page.delete(&i.to_be_bytes()));
+        }
+        assert!(page.header().fragmented > 0);
+
+        // Only fits once the holes are coalesced
+        let big = [2u8; 600];
+        assert!(page.insert(b"big", &big));
+        assert_eq!(page.header().fragmented, 0);
+        assert_eq!(page.lookup(b"big"), Some(&big[..]));
+        for i in (0..n).step_by(2) {
+            assert_eq!(page.lookup(&i.to_be_bytes()), Some(&value[..]));
+        }
+    }
+
+    #[test]
+    fn links_survive_cell_changes() {
+        let mut page = leaf();
+        page.set_next(5);
+        page.set_lower(9);
+        page.insert(b"a", b"1");
+        page.delete(b"a");
+        page.compact();
+        let header = page.header();
+        assert_eq!((header.next, header.lower), (5, 9));
+    }
+
+    #[test]
+    fn free_list_pages_hold_ids() {
+        let mut page = Box::new(Page::new(1));
+        page.init(PageKind::FreeList);
+        page.set_next(7);
+        let ids: Vec<PageId> = (2..2 + FREE_IDS_PER_PAGE as u64).collect();
+        page.set_free_ids(&ids);
+        assert_eq!(page.kind(), Some(PageKind::FreeList));
+        assert_eq!(page.free_ids(), ids);
+        assert_eq!(page.next(), 7);
+        page.set_free_ids(&[9]);
+        assert_eq!(page.free_ids(), [9]);
+    }
+
+    #[test]
+    fn lsn_and_checksum() {
+        let mut page = leaf();
+        page.insert(b"k", b"v");
+        page.set_lsn(42);
+        assert_eq!(page.lsn(), 42);
+
+        page.update_checksum();
+        assert!(page.verify_checksum());
+        page.data[PAGE_SIZE - 1] ^= 0xff;
+        assert!(!page.verify_checksum());
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Insert(Vec<u8>, Vec<u8>),
+        Delete(Vec<u8>),
+        Compact,
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        let key = prop::collection::vec(0u8..8, 1..4);
+        prop_oneof![
+            4 => (key.clone(), prop::collection::vec(any::<u8>(), 0..300))
+                .prop_map(|(k, v)| Op::Insert(k, v)),
+            2 => key.prop_map(Op::Delete),
+            1 => Just(Op::Compact),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn matches_btreemap(ops in prop::collection::vec(op(), 1..200)) {
+            let mut page = leaf();
+            let mut model = BTreeMap::new();
+            for op in ops {
+                match op {
+                    Op::Insert(k, v) => {
+                        let old = model.get(&k).map(|old: &Vec<u8>| Page::cell_size(&k, old));
+                        let needed = Page::cell_size(&k, &v);
+                        let room = match old {
+                            Some(old) => page.free_space() + old >= needed,
+                            None => page.free_space() >= needed,
+                        };
+                        prop_assert_eq!(page.insert(&k, &v), room);
+                        if room {
+                            model.insert(k, v);
+                        }
+                    }
+                    Op::Delete(k) => {
+                        prop_assert_eq!(page.delete(&k), model.remove(&k).is_some());
+                    }
+                    Op::Compact => page.compact(),
+                }
+                let expected: Vec<_> = model.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                prop_assert_eq!(contents(&page), expected);
+                let used: usize = model.iter().map(|(k, v)| Page::cell_size(k, v)).sum();
+                prop_assert_eq!(page.used_space(), used);
+            }
+        }
+    }
+}
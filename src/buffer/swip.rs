@@ -1,33 +1,186 @@
 //! Swizzled pointer (hot or cold)
+//!
+//! A swip is a single `u64`. A cold swip is the page id itself, so swips stored
+//! in pages on disk are plain page ids. A hot swip is the address of the page
+//! frame with the top bit set; user-space addresses never use that bit.
 
-use super::Page;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{Page, PageId};
+
+/// Tag marking a hot swip
+const HOT: u64 = 1 << 63;
 
 /// Swizzled pointer - the key innovation of LeanStore
-pub enum Swip {
-    /// Hot: Direct pointer to in-memory page (O(1) access)
-    Hot(*mut Page),
-    /// Cold: Disk offset (requires I/O to load)
-    Cold(u64),
-}
+///
+/// A swip is the only way a page is reached: while the page is resident the
+/// swip holds a direct pointer to its frame, otherwise it holds the page id
+/// that locates it on disk.
+#[repr(transparent)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Swip(u64);
 
 impl Swip {
     /// Create a cold (on-disk) swip
-    pub fn cold(offset: u64) -> Self {
-        Swip::Cold(offset)
+    pub fn cold(id: PageId) -> Self {
+        debug_assert!(id & HOT == 0, "page id {id} collides with the hot tag");
+        Swip(id)
     }
 
     /// Create a hot (in-memory) swip
     pub fn hot(ptr: *mut Page) -> Self {
-        Swip::Hot(ptr)
+        let addr = ptr as u64;
+        debug_assert!(addr & HOT == 0, "frame address uses the hot tag");
+        Swip(addr | HOT)
+    }
+
+    /// Reinterpret a raw swip, as stored in a page
+    pub fn from_raw(raw: u64) -> Self {
+        Swip(raw)
+    }
+
+    /// Raw encoding, as stored in a page
+    pub fn into_raw(self) -> u64 {
+        self.0
     }
 
     /// Check if this is a hot pointer
     pub fn is_hot(&self) -> bool {
-        matches!(self, Swip::Hot(_))
+        self.0 & HOT != 0
     }
 
     /// Check if this is a cold pointer
     pub fn is_cold(&self) -> bool {
-        matches!(self, Swip::Cold(_))
+        !self.is_hot()
+    }
+
+    /// Frame pointer of a hot swip
+    pub fn as_ptr(self) -> Option<*mut Page> {
+        self.is_hot().then_some((self.0 & !HOT) as *mut Page)
+    }
+
+    /// Page id of a cold swip
+    pub fn page_id(self) -> Option<PageId> {
+        self.is_cold().then_some(self.0)
+    }
+}
+
+impl fmt::Debug for Swip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.as_ptr(), self.page_id()) {
+            (Some(ptr), _) => f.debug_tuple("Hot").field(&ptr).finish(),
+            (_, Some(id)) => f.debug_tuple("Cold").field(&id).finish(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// A swip that can be swizzled in place while others read it
+#[repr(transparent)]
+pub struct AtomicSwip(AtomicU64);
+
+impl AtomicSwip {
+    /// Create a new atomic swip
+    pub fn new(swip: Swip) -> Self {
+        AtomicSwip(AtomicU64::new(swip.0))
+    }
+
+    /// View the 8 bytes at `ptr` as an atomic swip
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be 8-byte aligned, valid for reads and writes for `'a`, and
+    /// not accessed non-atomically while the returned reference is in use.
+    pub unsafe fn from_ptr<'a>(ptr: *mut u64) -> &'a Self {
+        // SAFETY: `AtomicSwip` is a transparent wrapper of `AtomicU64`, which
+        // has the size of `u64`; the caller guarantees alignment and validity
+        unsafe { &*ptr.cast::<Self>() }
+    }
+
+    /// Load the current swip
+    pub fn load(&self, order: Ordering) -> Swip {
+        Swip(self.0.load(order))
+    }
+
+    /// Replace the swip
+    pub fn store(&self, swip: Swip, order: Ordering) {
+        self.0.store(swip.0, order);
+    }
+
+    /// Replace the swip if it still equals `current`
+    ///
+    /// Returns the previous swip: `Ok` if the exchange happened, `Err` with
+    /// the swip found otherwise.
+    pub fn compare_exchange(
+        &self,
+        current: Swip,
+        new: Swip,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<Swip, Swip> {
+        self.0
+            .compare_exchange(current.0, new.0, success, failure)
+            .map(Swip)
+            .map_err(Swip)
+    }
+}
+
+impl fmt::Debug for AtomicSwip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.load(Ordering::Relaxed).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_in_eight_bytes() {
+        assert_eq!(size_of::<Swip>(), 8);
+        assert_eq!(size_of::<AtomicSwip>(), 8);
+    }
+
+    #[test]
+    fn cold_swip_is_the_page_id() {
+        let swip = Swip::cold(42);
+        assert!(swip.is_cold());
+        assert_eq!(swip.into_raw(), 42);
+        assert_eq!(swip.page_id(), Some(42));
+        assert_eq!(swip.as_ptr(), None);
+    }
+
+    #[test]
+    fn hot_swip_roundtrips_pointer() {
+        let mut page = Box::new(Page::new(7));
+        let ptr: *mut Page = &mut *page;
+        let swip = Swip::hot(ptr);
+        assert!(swip.is_hot());
+        assert_eq!(swip.as_ptr(), Some(ptr));
+        assert_eq!(swip.page_id(), None);
+        assert_eq!(Swip::from_raw(swip.into_raw()), swip);
+    }
+
+    #[test]
+    fn swizzle_in_place() {
+        let mut page = Box::new(Page::new(1));
+        let mut target = Box::new(Page::new(9));
+        let hot = Swip::hot(&mut *target);
+        page.data[64..72].copy_from_slice(&9u64.to_le_bytes());
+
+        // SAFETY: offset 64 of the 4096-aligned data array is 8-byte aligned
+        let slot = unsafe { AtomicSwip::from_ptr(page.data.as_mut_ptr().add(64).cast()) };
+        assert_eq!(slot.load(Ordering::Acquire), Swip::cold(9));
+        assert_eq!(
+            slot.compare_exchange(Swip::cold(8), hot, Ordering::AcqRel, Ordering::Acquire),
+            Err(Swip::cold(9))
+        );
+        assert_eq!(
+            slot.compare_exchange(Swip::cold(9), hot, Ordering::AcqRel, Ordering::Acquire),
+            Ok(Swip::cold(9))
+        );
+        slot.store(Swip::cold(9), Ordering::Release);
+        assert_eq!(u64::from_le_bytes(page.data[64..72].try_into().unwrap()), 9);
     }
 }
@@ -0,0 +1,156 @@
+//! Fuzzy checkpoints
+//!
+//! A checkpoint bounds the log that recovery replays: once every page of a
+//! committed state is in the data file and a superblock describes that
+//! state, the log before its commit record is no longer needed.
+//!
+//! [`Database::flush`](crate::Database::flush) does this with the writer
+//! shut out. The background checkpointer does it while writers carry on.
+//! It picks the last logged commit as its target and makes the log durable
+//! up to it. Then it walks the dirty pages in `PageId` order, copying a
+//! batch at a time under the database lock and writing each batch without
+//! it. Pages dirtied after the target are written too, once the log covers
+//! them, which does no harm as recovery replays them anyway. Finally it
+//! syncs the data file, publishes the target in a new superblock and
+//! recycles the log segments the target covers.
+//!
+//! A page of the target can only leave the cache unwritten if a later commit
+//! freed it, and freed pages are not released before that commit is
+//! durable ([`reclaim`](crate::txn::reclaim)), so recovery never needs it.
+//!
+//! The checkpointer runs whenever the log grows past a size set by
+//! [`Builder::checkpoint_size`](crate::Builder::checkpoint_size), or on
+//! demand through [`Database::checkpoint`](crate::Database::checkpoint).
+
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use parking_lot::{Condvar, Mutex, MutexGuard};
+
+use crate::buffer::PAGE_SIZE;
+use crate::header::{self, Superblock};
+use crate::{Inner, Result};
+
+/// Background thread writing checkpoints, and the lock that keeps
+/// checkpoints from overlapping
+pub(crate) struct Checkpointer {
+    /// Held throughout a checkpoint, fuzzy or not, and taken before the
+    /// database lock
+    running: Mutex<()>,
+    state: Mutex<State>,
+    /// Signalled when a checkpoint is requested or the thread should stop
+    wake: Condvar,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct State {
+    requested: bool,
+    closed: bool,
+}
+
+impl Checkpointer {
+    pub fn new() -> Self {
+        Self {
+            running: Mutex::new(()),
+            state: Mutex::new(State {
+                requested: false,
+                closed: false,
+            }),
+            wake: Condvar::new(),
+            thread: Mutex::new(None),
+        }
+    }
+
+    /// Start the background thread, checkpointing `inner` on request
+    pub fn start(self: &Arc<Self>, inner: Arc<Mutex<Inner>>) -> Result<()> {
+        let checkpointer = Arc::clone(self);
+        let handle = thread::Builder::new()
+            .name("qpdb-checkpoint".into())
+            .spawn(move || {
+                while checkpointer.wait() {
+                    // A failure leaves the previous checkpoint in place; the
+                    // next request tries again
+                    let _ = checkpointer.run(&inner);
+                }
+            })?;
+        *self.thread.lock() = Some(handle);
+        Ok(())
+    }
+
+    /// Have the background thread checkpoint soon
+    pub fn request(&self) {
+        self.state.lock().requested = true;
+        self.wake.notify_one();
+    }
+
+    /// Stop the background thread, letting a checkpoint in progress finish
+    pub fn stop(&self) {
+        self.state.lock().closed = true;
+        self.wake.notify_one();
+        if let Some(handle) = self.thread.lock().take() {
+            let _ = handle.join();
+        }
+    }
+
+    /// Keep other checkpoints out until the guard is dropped
+    pub fn lock(&self) -> MutexGuard<'_, ()> {
+        self.running.lock()
+    }
+
+    /// Wait for a request, returning false once the thread should stop
+    fn wait(&self) -> bool {
+        let mut state = self.state.lock();
+        while !state.requested && !state.closed {
+            self.wake.wait(&mut state);
+        }
+        state.requested = false;
+        !state.closed
+    }
+
+    /// Checkpoint the last logged commit of `inner` without holding its lock
+    /// for longer than a batch of page copies
+    pub fn run(&self, inner: &Mutex<Inner>) -> Result<()> {
+        let _running = self.lock();
+        let (target, log, storage) = {
+            let inner = &mut *inner.lock();
+            inner.wal.group().check()?;
+            if inner.logged.checkpoint_lsn <= inner.superblock.checkpoint_lsn {
+                return Ok(());
+            }
+            let target = Superblock {
+                generation: inner.superblock.generation + 1,
+                ..inner.logged
+            };
+            (target, inner.wal.group(), inner.pager.storage_handle())
+        };
+        // Pages reach the data file only once the log covers them
+        log.sync_to(target.checkpoint_lsn)?;
+
+        let mut after = 0;
+        loop {
+            let batch = {
+                let inner = &mut *inner.lock();
+                inner.pager.sync_with(&inner.wal);
+                inner.pager.begin_write(after)
+            };
+            let Some(&(last, ..)) = batch.last() else {
+                break;
+            };
+            let writes: Vec<_> = batch
+                .iter()
+                .map(|(id, _, image)| (&image[..], id * PAGE_SIZE as u64))
+                .collect();
+            let written = storage.write_batch(&writes);
+            inner.lock().pager.end_write(&batch, written.is_ok());
+            written?;
+            after = last;
+        }
+        storage.sync()?;
+        // Only this checkpoint writes superblocks while it holds the lock
+        header::write_superblock(&*storage, &target)?;
+
+        let inner = &mut *inner.lock();
+        inner.superblock = target;
+        inner.wal.recycle(target.checkpoint_lsn)
+    }
+}
@@ -0,0 +1,129 @@
+//! Online compaction
+//!
+//! Deletes leave free pages scattered through the file, but the file can only
+//! shrink once the pages at its end are free. Compaction gets there by
+//! copying live pages from the end of the file into the lowest free pages.
+//! Since every tree is copy-on-write, moving a page is an ordinary shadow of
+//! the page and the inner pages above it, so parents and swips are fixed up
+//! the same way any write fixes them, and the old copies stay readable for
+//! older snapshots until they are released.
+//!
+//! A page is only moved if there are enough free pages below it for the page
+//! and every copy made along with it, so nothing moves towards the end of the
+//! file. Pages of the persisted free list at the end of the file are moved
+//! by having the commit write the list afresh.
+//!
+//! Each step moves a bounded number of pages within one write transaction.
+//! Once a commit finds free pages at the end of the file it drops them from
+//! the page count, and [`Database::compact`](crate::Database::compact) cuts
+//! the file down after its final checkpoint.
+
+use std::collections::HashSet;
+
+use crate::btree::{BTree, PageStore};
+use crate::buffer::{PageId, PageKind};
+use crate::pager::Pager;
+use crate::txn::Retired;
+use crate::{Inner, Result};
+
+impl Inner {
+    /// Move up to `budget` pages, last first, from the end of the file into
+    /// lower free pages, returning where the pages still in use end
+    ///
+    /// Every page from the returned id on is free, awaits release or is
+    /// moved by the commit. Stops early at the first page that cannot move.
+    pub(crate) fn compact_step(&mut self, budget: usize) -> Result<PageId> {
+        // Pages only older snapshots reach cannot be moved, only released
+        let mut pending: HashSet<PageId> = self
+            .retired
+            .iter()
+            .flat_map(Retired::pages)
+            .copied()
+            .collect();
+        let tables = self.table_names()?;
+        let mut moved = 0;
+        let mut end = self.pager.page_count();
+        while moved < budget && end > 1 {
+            let id = end - 1;
+            if self.pager.in_free_list(id) {
+                self.pager.move_free_list();
+            } else if self.pager.is_committed(id)
+                && !pending.contains(&id)
+                && !self.pager.freed().contains(&id)
+            {
+                let page = self.pager.read(id)?;
+                let key = match page.kind() {
+                    Some(PageKind::Leaf | PageKind::Inner) if page.slot_count() > 0 => {
+                        page.key_at(0).to_vec()
+                    }
+                    Some(PageKind::Leaf | PageKind::Inner) => Vec::new(),
+                    _ => break,
+                };
+                if !self.relocate(&tables, &key, id)? {
+                    break;
+                }
+                moved += 1;
+            }
+            end = id;
+        }
+
+        // Copies land below the pages they replace, but possibly above the
+        // last page reached
+        pending.extend(self.pager.freed());
+        let in_use = |id: &PageId| {
+            !self.pager.is_free(*id) && !self.pager.in_free_list(*id) && !pending.contains(id)
+        };
+        Ok((end..self.pager.page_count())
+            .rev()
+            .find(in_use)
+            .map_or(end, |id| id + 1))
+    }
+
+    /// Move page `id` below itself within whichever tree holds it, returning
+    /// whether it moved
+    fn relocate(&mut self, tables: &[String], key: &[u8], id: PageId) -> Result<bool> {
+        for tree in [&mut self.tree, &mut self.catalog, &mut self.savepoints] {
+            if let Some(moved) = move_down(tree, &mut self.pager, key, id, 0)? {
+                return Ok(moved);
+            }
+        }
+        // A table's new root is recorded in the catalog, copying its path
+        let catalog = self.catalog.height(&mut self.pager)?;
+        for name in tables {
+            let moved =
+                self.with_table(name, |tree, pager| move_down(tree, pager, key, id, catalog))?;
+            if let Some(moved) = moved {
+                return Ok(moved);
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Move page `id` of `tree` and the pages copied with it into free pages
+/// below it, leaving room for `extra` more copies
+///
+/// Returns `None` if `tree` does not hold `id`, and otherwise whether there
+/// was room to move it.
+fn move_down(
+    tree: &mut BTree,
+    pager: &mut Pager,
+    key: &[u8],
+    id: PageId,
+    extra: usize,
+) -> Result<Option<bool>> {
+    let Some(path) = tree.path_to(pager, key, id)? else {
+        return Ok(None);
+    };
+    let copies = path
+        .iter()
+        .map(|&(parent, _)| parent)
+        .chain([id])
+        .filter(|&page| pager.is_committed(page))
+        .count();
+    if !pager.has_free_below(id, copies + extra) {
+        return Ok(Some(false));
+    }
+    tree.relocate(pager, id, path)?;
+    Ok(Some(true))
+}
@@ -0,0 +1,467 @@
+//! Optimistic concurrent write transactions
+//!
+//! A [`ConcurrentWriteTransaction`] does not hold the writer lock while it
+//! runs, so any number of them run alongside each other and alongside the
+//! single [`WriteTransaction`](crate::WriteTransaction). Each reads a
+//! snapshot, like a read transaction, and buffers its changes in memory,
+//! recording which keys it wrote and, unless it only asks for snapshot
+//! isolation, which keys and key ranges of the snapshot it read.
+//!
+//! Commits are validated and applied one at a time under the writer lock.
+//! A commit compares every recorded key and range between its snapshot and
+//! the latest committed state, and fails with [`Error::Conflict`] on the
+//! first one that changed. Otherwise it applies the buffered changes as an
+//! ordinary write transaction. Checking the written keys makes the first
+//! committer of two overlapping transactions win. Checking the reads as
+//! well means every transaction that commits read exactly what it would
+//! have read had it run at the moment of its commit, so transactions are
+//! serializable in commit order. Values are compared rather than commit
+//! histories, so a key that changed and changed back does not conflict.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::iter::Peekable;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+use std::vec;
+
+use parking_lot::Mutex;
+
+use crate::btree::{BTree, RangeState};
+use crate::txn::Snapshot;
+use crate::{Database, Durability, Error, Inner, KeyValue, MAX_ENTRY_SIZE, Range, Result, table};
+
+/// What the commit of a [`ConcurrentWriteTransaction`] checks for
+/// conflicts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Isolation {
+    /// Fail if a transaction committed since this one began wrote a key
+    /// this one writes: first committer wins, which allows write skew
+    Snapshot,
+    /// Also fail if such a transaction changed anything this one read,
+    /// including entries added to a scanned range
+    #[default]
+    Serializable,
+}
+
+/// Reads and buffered changes of the default tree or one table
+struct Keyspace {
+    /// Tree as of the snapshot
+    tree: BTree,
+    /// Whether the table did not exist at the snapshot
+    missing: bool,
+    /// Changes to apply on commit, `None` removing the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Keys read from the snapshot
+    reads: BTreeSet<Vec<u8>>,
+    /// Key ranges scanned in the snapshot
+    ranges: Vec<KeyRange>,
+}
+
+impl Keyspace {
+    fn new(tree: BTree, missing: bool) -> Self {
+        Self {
+            tree,
+            missing,
+            writes: BTreeMap::new(),
+            reads: BTreeSet::new(),
+            ranges: Vec::new(),
+        }
+    }
+}
+
+/// Keyspaces by table name, `None` for the default tree
+type Keyspaces = HashMap<Option<String>, Keyspace>;
+
+/// Bounds of a scanned key range
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// A buffered change, `None` removing the key
+type Write = (Vec<u8>, Option<Vec<u8>>);
+
+/// An optimistic read-write transaction, validated when it commits
+///
+/// Changes are buffered and visible through this handle only, until
+/// [`commit`](ConcurrentWriteTransaction::commit) applies them, unless a
+/// transaction that committed since this one began conflicts with them.
+/// Dropping the handle discards them.
+pub struct ConcurrentWriteTransaction<'db> {
+    db: &'db Database,
+    snapshot: Arc<Snapshot>,
+    keyspaces: Mutex<Keyspaces>,
+    isolation: Isolation,
+    durability: Durability,
+}
+
+impl<'db> ConcurrentWriteTransaction<'db> {
+    pub(crate) fn new(db: &'db Database) -> Self {
+        let snapshot = db.snapshots.current();
+        let default = Keyspace::new(BTree::new(snapshot.root), false);
+        Self {
+            db,
+            snapshot,
+            keyspaces: Mutex::new(HashMap::from([(None, default)])),
+            isolation: Isolation::default(),
+            durability: Durability::default(),
+        }
+    }
+
+    /// Choose what the commit promises (default [`Durability::Immediate`])
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// Choose which conflicts the commit checks for (default
+    /// [`Isolation::Serializable`])
+    pub fn set_isolation(&mut self, isolation: Isolation) {
+        self.isolation = isolation;
+    }
+
+    /// Look up the value stored under `key`, including uncommitted changes
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_in(None, key)
+    }
+
+    /// Insert or replace `key`, returning the previous value
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.write_in(None, key, Some(value))
+    }
+
+    /// Remove `key`, returning its value if it was present
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.write_in(None, key, None)
+    }
+
+    /// Iterate over all entries in key order, including uncommitted changes
+    pub fn iter(&self) -> ConcurrentRange<'_> {
+        self.range::<&[u8]>(..)
+    }
+
+    /// Iterate over the entries whose keys fall within `range`, in key order
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> ConcurrentRange<'_> {
+        self.range_in(None, range)
+    }
+
+    /// Open the table `name`, which is created on commit if it does not
+    /// exist
+    pub fn open_table(&self, name: &str) -> Result<ConcurrentTable<'_>> {
+        let space = Some(name.to_owned());
+        let keyspaces = &mut *self.keyspaces.lock();
+        if !keyspaces.contains_key(&space) {
+            let catalog = BTree::new(self.snapshot.catalog);
+            let pager = &mut self.db.inner.lock().pager;
+            let keyspace = match table::lookup(catalog, pager, name, None) {
+                Ok(tree) => Keyspace::new(tree, false),
+                Err(Error::TableNotFound(_)) => Keyspace::new(BTree::new(0), true),
+                Err(err) => return Err(err),
+            };
+            keyspaces.insert(space.clone(), keyspace);
+        }
+        Ok(ConcurrentTable { txn: self, space })
+    }
+
+    /// Validate the transaction against every commit since it began and
+    /// apply its changes, as durably as
+    /// [`set_durability`](ConcurrentWriteTransaction::set_durability) asks
+    ///
+    /// Fails with [`Error::Conflict`], leaving the database as it was, if
+    /// one of them changed a key or range this transaction depends on. The
+    /// transaction may then be retried from the start.
+    pub fn commit(self) -> Result<()> {
+        let keyspaces = self.keyspaces.into_inner();
+        // What was read from one snapshot is consistent as it is
+        if keyspaces
+            .values()
+            .all(|keyspace| keyspace.writes.is_empty() && !keyspace.missing)
+        {
+            return Ok(());
+        }
+        let mut txn = self.db.begin_write()?;
+        txn.set_durability(self.durability);
+        let serializable = self.isolation == Isolation::Serializable;
+        {
+            let inner = &mut *self.db.inner.lock();
+            for (space, keyspace) in &keyspaces {
+                inner.validate(space.as_deref(), keyspace, serializable)?;
+            }
+            for (space, keyspace) in &keyspaces {
+                inner.apply(space.as_deref(), keyspace)?;
+            }
+        }
+        txn.commit()
+    }
+
+    /// Discard every change; equivalent to dropping the transaction
+    pub fn abort(self) {}
+
+    fn get_in(&self, space: Option<&str>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let keyspaces = &mut *self.keyspaces.lock();
+        let keyspace = keyspace(keyspaces, space);
+        if let Some(value) = keyspace.writes.get(key) {
+            return Ok(value.clone());
+        }
+        if self.isolation == Isolation::Serializable {
+            keyspace.reads.insert(key.to_vec());
+        }
+        keyspace.tree.get(&mut self.db.inner.lock().pager, key)
+    }
+
+    fn write_in(
+        &self,
+        space: Option<&str>,
+        key: &[u8],
+        value: Option<&[u8]>,
+    ) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = value {
+            let size = key.len() + value.len();
+            if size > MAX_ENTRY_SIZE {
+                return Err(Error::ValueTooLarge {
+                    size,
+                    max: MAX_ENTRY_SIZE,
+                });
+            }
+        }
+        let old = self.get_in(space, key)?;
+        keyspace(&mut self.keyspaces.lock(), space)
+            .writes
+            .insert(key.to_vec(), value.map(<[u8]>::to_vec));
+        Ok(old)
+    }
+
+    fn range_in<K: AsRef<[u8]>>(
+        &self,
+        space: Option<&str>,
+        range: impl RangeBounds<K>,
+    ) -> ConcurrentRange<'_> {
+        let owned = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(key.as_ref().to_vec()),
+            Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_vec()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let (start, end) = (owned(range.start_bound()), owned(range.end_bound()));
+        let keyspaces = &mut *self.keyspaces.lock();
+        let keyspace = keyspace(keyspaces, space);
+        let writes = keyspace
+            .writes
+            .range((start.clone(), end.clone()))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .peekable();
+        ConcurrentRange {
+            txn: self,
+            space: space.map(str::to_owned),
+            snapshot: Range::new(
+                &self.db.inner,
+                keyspace.tree,
+                None,
+                (start.clone(), end.clone()),
+            )
+            .peekable(),
+            writes,
+            start,
+            end,
+            reached: None,
+        }
+    }
+}
+
+fn keyspace<'a>(keyspaces: &'a mut Keyspaces, space: Option<&str>) -> &'a mut Keyspace {
+    keyspaces
+        .get_mut(&space.map(str::to_owned))
+        .expect("keyspaces are added when tables are opened")
+}
+
+/// A named table opened by a [`ConcurrentWriteTransaction`]
+///
+/// Changes are buffered with the transaction's other changes.
+pub struct ConcurrentTable<'txn> {
+    txn: &'txn ConcurrentWriteTransaction<'txn>,
+    space: Option<String>,
+}
+
+impl ConcurrentTable<'_> {
+    /// Name of the table
+    pub fn name(&self) -> &str {
+        self.space.as_deref().expect("tables are named")
+    }
+
+    /// Look up the value stored under `key`, including uncommitted changes
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.txn.get_in(self.space.as_deref(), key)
+    }
+
+    /// Insert or replace `key`, returning the previous value
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.txn.write_in(self.space.as_deref(), key, Some(value))
+    }
+
+    /// Remove `key`, returning its value if it was present
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.txn.write_in(self.space.as_deref(), key, None)
+    }
+
+    /// Iterate over all entries in key order, including uncommitted changes
+    pub fn iter(&self) -> ConcurrentRange<'_> {
+        self.range::<&[u8]>(..)
+    }
+
+    /// Iterate over the entries whose keys fall within `range`, in key order
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> ConcurrentRange<'_> {
+        self.txn.range_in(self.space.as_deref(), range)
+    }
+}
+
+/// Iterator over a key range of a [`ConcurrentWriteTransaction`], in key
+/// order
+///
+/// Merges the snapshot with the changes buffered when it was created. The
+/// part of the range it walked is recorded as read once it is dropped.
+pub struct ConcurrentRange<'txn> {
+    txn: &'txn ConcurrentWriteTransaction<'txn>,
+    space: Option<String>,
+    snapshot: Peekable<Range<'txn>>,
+    writes: Peekable<vec::IntoIter<Write>>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    /// End of the part of the range walked so far
+    reached: Option<Bound<Vec<u8>>>,
+}
+
+impl Iterator for ConcurrentRange<'_> {
+    type Item = Result<KeyValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // Which comes first: the snapshot's entry or a buffered change
+            let order = match (self.snapshot.peek(), self.writes.peek()) {
+                (Some(Err(_)), _) => return self.snapshot.next(),
+                (None, None) => {
+                    self.reached = Some(self.end.clone());
+                    return None;
+                }
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(Ok((read, _))), Some((written, _))) => read.cmp(written),
+            };
+            if order == Ordering::Equal {
+                // Replaced by the buffered change
+                self.snapshot.next();
+            }
+            let from_writes = order != Ordering::Less;
+            let (key, value) = if from_writes {
+                self.writes.next().expect("peeked")
+            } else {
+                let (key, value) = self.snapshot.next().expect("peeked").expect("peeked");
+                (key, Some(value))
+            };
+            self.reached = Some(Bound::Included(key.clone()));
+            if let Some(value) = value {
+                return Some(Ok((key, value)));
+            }
+        }
+    }
+}
+
+impl Drop for ConcurrentRange<'_> {
+    fn drop(&mut self) {
+        if self.txn.isolation != Isolation::Serializable {
+            return;
+        }
+        let Some(reached) = self.reached.take() else {
+            return;
+        };
+        let start = std::mem::replace(&mut self.start, Bound::Unbounded);
+        keyspace(&mut self.txn.keyspaces.lock(), self.space.as_deref())
+            .ranges
+            .push((start, reached));
+    }
+}
+
+impl Inner {
+    /// Tree of the default tree or table `space` as of the open write
+    /// transaction, empty if the table does not exist
+    fn current_tree(&mut self, space: Option<&str>) -> Result<BTree> {
+        let Some(name) = space else {
+            return Ok(self.tree);
+        };
+        match table::lookup(self.catalog, &mut self.pager, name, None) {
+            Ok(tree) => Ok(tree),
+            Err(Error::TableNotFound(_)) => Ok(BTree::new(0)),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Fail with [`Error::Conflict`] if the keys written in `keyspace`, or
+    /// if `serializable` the keys and ranges read, differ between its
+    /// snapshot and the latest commit
+    fn validate(
+        &mut self,
+        space: Option<&str>,
+        keyspace: &Keyspace,
+        serializable: bool,
+    ) -> Result<()> {
+        let current = self.current_tree(space)?;
+        // Copy-on-write trees are unchanged as long as their roots are
+        if current == keyspace.tree {
+            return Ok(());
+        }
+        let conflict = |key: &[u8]| Error::Conflict {
+            table: space.map(str::to_owned),
+            key: key.to_vec(),
+        };
+        let reads = serializable
+            .then_some(&keyspace.reads)
+            .into_iter()
+            .flatten();
+        for key in keyspace.writes.keys().chain(reads) {
+            if keyspace.tree.get(&mut self.pager, key)? != current.get(&mut self.pager, key)? {
+                return Err(conflict(key));
+            }
+        }
+        if !serializable {
+            return Ok(());
+        }
+        for (start, end) in &keyspace.ranges {
+            let mut then = RangeState::new(start.clone(), end.clone());
+            let mut now = RangeState::new(start.clone(), end.clone());
+            loop {
+                let before = then.next(&keyspace.tree, &mut self.pager)?;
+                let after = now.next(&current, &mut self.pager)?;
+                match (before, after) {
+                    (None, None) => break,
+                    (Some(before), Some(after)) if before == after => {}
+                    (Some((key, _)), None) | (None, Some((key, _))) => return Err(conflict(&key)),
+                    (Some((before, _)), Some((after, _))) => {
+                        return Err(conflict(before.min(after).as_slice()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the changes buffered in `keyspace` to the open write
+    /// transaction
+    fn apply(&mut self, space: Option<&str>, keyspace: &Keyspace) -> Result<()> {
+        let write = |tree: &mut BTree, pager: &mut _| {
+            for (key, value) in &keyspace.writes {
+                match value {
+                    Some(value) => tree.insert(pager, key, value)?,
+                    None => tree.remove(pager, key)?,
+                };
+            }
+            Ok(())
+        };
+        match space {
+            None => write(&mut self.tree, &mut self.pager),
+            Some(name) => {
+                if keyspace.writes.is_empty() && !keyspace.missing {
+                    return Ok(());
+                }
+                self.create_table(name, None)?;
+                self.with_table(name, write)
+            }
+        }
+    }
+}
@@ -1,25 +1,149 @@
 use std::fmt;
+use std::path::PathBuf;
+
+use crate::buffer::PageId;
 
 /// Result type for qpdb operations
 pub type Result<T> = std::result::Result<T, Error>;
 
 /// Errors that can occur in qpdb
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// I/O error
     Io(std::io::Error),
+    /// The disk or the user's quota is full
+    OutOfSpace(std::io::Error),
     /// Database corruption
     Corruption(String),
+    /// A page read from the file does not match its checksum
+    ChecksumMismatch {
+        /// Page that failed verification
+        page: PageId,
+        /// Byte offset of the page within the file
+        offset: u64,
+        /// Checksum stored in the page
+        expected: u32,
+        /// Checksum computed over the page contents
+        actual: u32,
+    },
+    /// A page reference points past the end of the file
+    PageOutOfBounds {
+        /// Page referenced
+        page: PageId,
+        /// Number of pages in the file
+        page_count: u64,
+    },
+    /// The file was written by an incompatible version of qpdb
+    InvalidFormatVersion {
+        /// Format version recorded in the file
+        found: u32,
+        /// Format version this build reads and writes
+        supported: u32,
+    },
+    /// Another handle has the database open for writing, or holds it open
+    /// while this one wants to write
+    DatabaseLocked {
+        /// Path of the database file
+        path: PathBuf,
+    },
+    /// A write was attempted through a read-only handle, or opening read-only
+    /// would require recovery
+    ReadOnly,
+    /// The transaction read or wrote a key that a transaction committed since
+    /// it began has written
+    Conflict {
+        /// Table holding the key, or `None` for the default tree
+        table: Option<String>,
+        /// Conflicting key
+        key: Vec<u8>,
+    },
     /// Key not found
     NotFound,
+    /// Key plus value exceed the maximum entry size
+    ValueTooLarge {
+        /// Combined key and value size in bytes
+        size: usize,
+        /// Largest supported combined size in bytes
+        max: usize,
+    },
+    /// No table with this name exists
+    TableNotFound(String),
+    /// A table with this name already exists
+    TableExists(String),
+    /// The table is already open in this transaction
+    TableAlreadyOpen(String),
+    /// The table was created with different key or value types
+    TableTypeMismatch {
+        /// Table name
+        table: String,
+        /// Key and value types it was opened with
+        expected: String,
+        /// Key and value types recorded in the catalog
+        found: String,
+    },
+    /// A value could not be encoded
+    Encoding(String),
+    /// No persistent savepoint with this name exists
+    SavepointNotFound(String),
+    /// A persistent savepoint with this name already exists
+    SavepointExists(String),
+    /// The savepoint belongs to another transaction, or the transaction
+    /// rolled back past it
+    InvalidSavepoint,
+    /// Another handle is already closing the database
+    Closing,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::OutOfSpace(e) => write!(f, "Out of space: {}", e),
             Error::Corruption(msg) => write!(f, "Database corruption: {}", msg),
+            Error::ChecksumMismatch {
+                page,
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Checksum mismatch on page {} at offset {}: stored {:#010x}, computed {:#010x}",
+                page, offset, expected, actual
+            ),
+            Error::PageOutOfBounds { page, page_count } => {
+                write!(f, "Page {} out of bounds (page count {})", page, page_count)
+            }
+            Error::InvalidFormatVersion { found, supported } => write!(
+                f,
+                "Unsupported format version {} (expected {})",
+                found, supported
+            ),
+            Error::DatabaseLocked { path } => {
+                write!(f, "Database {} is locked by another handle", path.display())
+            }
+            Error::ReadOnly => write!(f, "Database is open read-only"),
+            Error::Conflict { table, key } => match table {
+                Some(table) => write!(f, "Conflict on key {:?} in table {:?}", key, table),
+                None => write!(f, "Conflict on key {:?}", key),
+            },
             Error::NotFound => write!(f, "Key not found"),
+            Error::ValueTooLarge { size, max } => {
+                write!(f, "Entry of {} bytes exceeds maximum of {}", size, max)
+            }
+            Error::TableNotFound(name) => write!(f, "Table {:?} not found", name),
+            Error::TableExists(name) => write!(f, "Table {:?} already exists", name),
+            Error::TableAlreadyOpen(name) => write!(f, "Table {:?} is already open", name),
+            Error::TableTypeMismatch {
+                table,
+                expected,
+                found,
+            } => write!(f, "Table {:?} holds {}, not {}", table, found, expected),
+            Error::Encoding(msg) => write!(f, "Encoding error: {}", msg),
+            Error::SavepointNotFound(name) => write!(f, "Savepoint {:?} not found", name),
+            Error::SavepointExists(name) => write!(f, "Savepoint {:?} already exists", name),
+            Error::InvalidSavepoint => write!(f, "Savepoint is no longer valid"),
+            Error::Closing => write!(f, "Database is already being closed"),
         }
     }
 }
@@ -27,7 +151,7 @@ impl fmt::Display for Error {
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Error::Io(e) => Some(e),
+            Error::Io(e) | Error::OutOfSpace(e) => Some(e),
             _ => None,
         }
     }
@@ -35,6 +159,25 @@ impl std::error::Error for Error {
 
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
-        Error::Io(err)
+        match err.kind() {
+            std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => {
+                Error::OutOfSpace(err)
+            }
+            _ => Error::Io(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn full_disks_are_out_of_space() {
+        let full = Error::from(std::io::Error::from(ErrorKind::StorageFull));
+        assert!(matches!(full, Error::OutOfSpace(_)));
+        let other = Error::from(std::io::Error::from(ErrorKind::PermissionDenied));
+        assert!(matches!(other, Error::Io(_)));
     }
 }
@@ -0,0 +1,275 @@
+//! On-disk file header and dual superblock
+//!
+//! Page 0 of every database file is the header page:
+//!
+//! | Offset | Size | Field                                  |
+//! |--------|------|----------------------------------------|
+//! | 0      | 8    | Magic number                           |
+//! | 8      | 4    | Format version                         |
+//! | 12     | 4    | Page size                              |
+//! | 16     | 4    | CRC32C of bytes 0..16                  |
+//! | 512    | 60   | Superblock slot 0                      |
+//! | 1024   | 60   | Superblock slot 1                      |
+//!
+//! Each superblock sits in its own 512-byte sector and carries its own checksum.
+//! Commits alternate between the two slots, so a torn superblock write leaves the
+//! previous one intact and open falls back to it.
+
+use crate::buffer::{PAGE_SIZE, PageId};
+use crate::storage::StorageBackend;
+use crate::wal::Lsn;
+use crate::{Error, Result};
+
+/// Magic number at the start of every database file
+pub(crate) const MAGIC: [u8; 8] = *b"qpdb\r\n\x1a\n";
+
+/// Current on-disk format version
+pub(crate) const FORMAT_VERSION: u32 = 7;
+
+/// Byte offsets of the two superblock slots within the header page
+const SUPERBLOCK_OFFSETS: [usize; 2] = [512, 1024];
+
+/// Encoded size of the fixed file header (including its checksum)
+const FILE_HEADER_SIZE: usize = 20;
+
+/// Encoded size of a superblock (including its checksum)
+const SUPERBLOCK_SIZE: usize = 60;
+
+/// Database state published by a commit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Superblock {
+    /// Monotonic commit counter; the valid slot with the highest value wins
+    pub generation: u64,
+    /// Number of pages in the file, including the header page
+    pub page_count: u64,
+    /// Root page of the primary tree (0 = empty)
+    pub root: PageId,
+    /// Head of the free page list (0 = empty)
+    pub free_list: PageId,
+    /// Every WAL record up to this LSN is reflected in the data file
+    pub checkpoint_lsn: Lsn,
+    /// Root page of the table catalog (0 = no tables)
+    pub catalog: PageId,
+    /// Root page of the persistent savepoint registry (0 = none)
+    pub savepoints: PageId,
+}
+
+impl Superblock {
+    /// Superblock for a freshly created database
+    fn initial() -> Self {
+        Self {
+            generation: 0,
+            page_count: 1,
+            root: 0,
+            free_list: 0,
+            checkpoint_lsn: 0,
+            catalog: 0,
+            savepoints: 0,
+        }
+    }
+
+    fn encode(&self) -> [u8; SUPERBLOCK_SIZE] {
+        let mut buf = [0u8; SUPERBLOCK_SIZE];
+        buf[0..8].copy_from_slice(&self.generation.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.page_count.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.root.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.free_list.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.checkpoint_lsn.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.catalog.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.savepoints.to_le_bytes());
+        let crc = crc32c::crc32c(&buf[..56]);
+        buf[56..60].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    /// Decode a superblock slot, returning `None` if its checksum does not match
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let crc = u32::from_le_bytes(buf[56..60].try_into().unwrap());
+        if crc32c::crc32c(&buf[..56]) != crc {
+            return None;
+        }
+        let field = |i: usize| u64::from_le_bytes(buf[i..i + 8].try_into().unwrap());
+        Some(Self {
+            generation: field(0),
+            page_count: field(8),
+            root: field(16),
+            free_list: field(24),
+            checkpoint_lsn: field(32),
+            catalog: field(40),
+            savepoints: field(48),
+        })
+    }
+
+    fn slot(&self) -> usize {
+        (self.generation % 2) as usize
+    }
+}
+
+fn encode_file_header(buf: &mut [u8]) {
+    buf[0..8].copy_from_slice(&MAGIC);
+    buf[8..12].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    buf[12..16].copy_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+    let crc = crc32c::crc32c(&buf[..16]);
+    buf[16..FILE_HEADER_SIZE].copy_from_slice(&crc.to_le_bytes());
+}
+
+fn check_file_header(buf: &[u8]) -> Result<()> {
+    if buf[0..8] != MAGIC {
+        return Err(Error::Corruption("not a qpdb file (bad magic)".into()));
+    }
+    let crc = u32::from_le_bytes(buf[16..20].try_into().unwrap());
+    if crc32c::crc32c(&buf[..16]) != crc {
+        return Err(Error::Corruption("file header checksum mismatch".into()));
+    }
+    let version = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        return Err(Error::InvalidFormatVersion {
+            found: version,
+            supported: FORMAT_VERSION,
+        });
+    }
+    let page_size = u32::from_le_bytes(buf[12..16].try_into().unwrap());
+    if page_size as usize != PAGE_SIZE {
+        return Err(Error::Corruption(format!(
+            "unsupported page size {} (expected {})",
+            page_size, PAGE_SIZE
+        )));
+    }
+    Ok(())
+}
+
+/// Write the header page of a new, empty database file
+pub(crate) fn init(storage: &dyn StorageBackend) -> Result<Superblock> {
+    let superblock = Superblock::initial();
+    let mut page = vec![0u8; PAGE_SIZE];
+    encode_file_header(&mut page);
+    // Both slots start out valid so either can be overwritten by the first commit
+    for offset in SUPERBLOCK_OFFSETS {
+        page[offset..offset + SUPERBLOCK_SIZE].copy_from_slice(&superblock.encode());
+    }
+    storage.write_at(&page, 0)?;
+    storage.sync()?;
+    Ok(superblock)
+}
+
+/// Validate the header page and return the newest valid superblock
+pub(crate) fn load(storage: &dyn StorageBackend) -> Result<Superblock> {
+    let len = storage.len()?;
+    if len < PAGE_SIZE as u64 {
+        return Err(Error::Corruption(format!(
+            "file is {} bytes, smaller than the header page",
+            len
+        )));
+    }
+    let mut page = vec![0u8; PAGE_SIZE];
+    storage.read_at(&mut page, 0)?;
+    check_file_header(&page)?;
+
+    SUPERBLOCK_OFFSETS
+        .iter()
+        .filter_map(|&offset| Superblock::decode(&page[offset..offset + SUPERBLOCK_SIZE]))
+        .max_by_key(|sb| sb.generation)
+        .ok_or_else(|| Error::Corruption("no valid superblock".into()))
+}
+
+/// Durably publish `superblock` into the slot selected by its generation
+pub(crate) fn write_superblock(
+    storage: &dyn StorageBackend,
+    superblock: &Superblock,
+) -> Result<()> {
+    let offset = SUPERBLOCK_OFFSETS[superblock.slot()];
+    storage.write_at(&superblock.encode(), offset as u64)?;
+    storage.sync()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+
+    fn temp_file() -> MemoryBackend {
+        MemoryBackend::new()
+    }
+
+    #[test]
+    fn init_then_load() {
+        let file = temp_file();
+        let created = init(&file).unwrap();
+        assert_eq!(load(&file).unwrap(), created);
+        assert_eq!(file.len().unwrap(), PAGE_SIZE as u64);
+    }
+
+    #[test]
+    fn newest_superblock_wins() {
+        let file = temp_file();
+        let mut sb = init(&file).unwrap();
+        for root in 1..=3 {
+            sb.generation += 1;
+            sb.root = root;
+            write_superblock(&file, &sb).unwrap();
+        }
+        let loaded = load(&file).unwrap();
+        assert_eq!(loaded.generation, 3);
+        assert_eq!(loaded.root, 3);
+    }
+
+    #[test]
+    fn torn_superblock_falls_back() {
+        let file = temp_file();
+        let mut sb = init(&file).unwrap();
+        sb.generation = 1;
+        sb.root = 7;
+        write_superblock(&file, &sb).unwrap();
+
+        // Corrupt the slot that was just written
+        let offset = SUPERBLOCK_OFFSETS[sb.slot()] as u64;
+        file.write_at(&[0xff; 4], offset + 8).unwrap();
+
+        let loaded = load(&file).unwrap();
+        assert_eq!(loaded.generation, 0);
+        assert_eq!(loaded.root, 0);
+    }
+
+    #[test]
+    fn both_superblocks_corrupt() {
+        let file = temp_file();
+        init(&file).unwrap();
+        for offset in SUPERBLOCK_OFFSETS {
+            file.write_at(&[0xff; 4], offset as u64).unwrap();
+        }
+        assert!(matches!(load(&file), Err(Error::Corruption(_))));
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let file = temp_file();
+        init(&file).unwrap();
+        file.write_at(b"sqlite", 0).unwrap();
+        assert!(matches!(load(&file), Err(Error::Corruption(_))));
+    }
+
+    #[test]
+    fn rejects_unknown_version() {
+        let file = temp_file();
+        let mut page = vec![0u8; PAGE_SIZE];
+        encode_file_header(&mut page);
+        page[8..12].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        let crc = crc32c::crc32c(&page[..16]);
+        page[16..20].copy_from_slice(&crc.to_le_bytes());
+        file.write_at(&page, 0).unwrap();
+
+        assert!(matches!(
+            load(&file),
+            Err(Error::InvalidFormatVersion { found, supported: FORMAT_VERSION })
+                if found == FORMAT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let file = temp_file();
+        file.write_at(&MAGIC, 0).unwrap();
+        assert!(matches!(load(&file), Err(Error::Corruption(_))));
+    }
+}
@@ -0,0 +1,163 @@
+//! Ordered iteration over the database
+//!
+//! Both [`Range`] and [`Cursor`] walk a fixed tree root and take the database
+//! lock for one step at a time, so writers proceed between steps. The root
+//! belongs to a snapshot, or to the write transaction that is borrowed, so
+//! the pages it reaches do not change while the iterator lives.
+
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+use crate::Inner;
+use crate::Result;
+use crate::btree::{self, BTree, Position, RangeState};
+use crate::txn::Snapshot;
+
+/// A key/value pair yielded by iteration
+pub type KeyValue = (Vec<u8>, Vec<u8>);
+
+/// Double-ended iterator over a key range, in key order
+pub struct Range<'a> {
+    inner: &'a Mutex<Inner>,
+    tree: BTree,
+    state: RangeState,
+    /// Keeps the pages of `tree` from being reclaimed
+    _snapshot: Option<Arc<Snapshot>>,
+}
+
+impl<'a> Range<'a> {
+    pub(crate) fn new<K: AsRef<[u8]>>(
+        inner: &'a Mutex<Inner>,
+        tree: BTree,
+        snapshot: Option<Arc<Snapshot>>,
+        range: impl RangeBounds<K>,
+    ) -> Self {
+        let owned = |bound: Bound<&K>| match bound {
+            Bound::Included(key) => Bound::Included(key.as_ref().to_vec()),
+            Bound::Excluded(key) => Bound::Excluded(key.as_ref().to_vec()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let state = RangeState::new(owned(range.start_bound()), owned(range.end_bound()));
+        Self {
+            inner,
+            tree,
+            state,
+            _snapshot: snapshot,
+        }
+    }
+}
+
+impl Iterator for Range<'_> {
+    type Item = Result<KeyValue>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pager = &mut self.inner.lock().pager;
+        self.state.next(&self.tree, pager).transpose()
+    }
+}
+
+impl DoubleEndedIterator for Range<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let pager = &mut self.inner.lock().pager;
+        self.state.next_back(&self.tree, pager).transpose()
+    }
+}
+
+/// Bidirectional cursor positioned on at most one entry
+///
+/// A fresh cursor is unpositioned; use one of the seek methods first. Each
+/// positioning method returns whether the cursor now points at an entry.
+pub struct Cursor<'a> {
+    inner: &'a Mutex<Inner>,
+    tree: BTree,
+    pos: Option<Position>,
+    current: Option<KeyValue>,
+    /// Keeps the pages of `tree` from being reclaimed
+    _snapshot: Option<Arc<Snapshot>>,
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(
+        inner: &'a Mutex<Inner>,
+        tree: BTree,
+        snapshot: Option<Arc<Snapshot>>,
+    ) -> Self {
+        Self {
+            inner,
+            tree,
+            pos: None,
+            current: None,
+            _snapshot: snapshot,
+        }
+    }
+
+    /// Position on the smallest entry
+    pub fn seek_to_first(&mut self) -> Result<bool> {
+        let pos = self.tree.first(&mut self.inner.lock().pager);
+        self.set(pos)
+    }
+
+    /// Position on the largest entry
+    pub fn seek_to_last(&mut self) -> Result<bool> {
+        let pos = self.tree.last(&mut self.inner.lock().pager);
+        self.set(pos)
+    }
+
+    /// Position on the first entry with a key `>= key`
+    pub fn seek(&mut self, key: &[u8]) -> Result<bool> {
+        let pos = self.tree.seek(&mut self.inner.lock().pager, key);
+        self.set(pos)
+    }
+
+    /// Position on the last entry with a key `<= key`
+    pub fn seek_for_prev(&mut self, key: &[u8]) -> Result<bool> {
+        let pos = self.tree.seek_for_prev(&mut self.inner.lock().pager, key);
+        self.set(pos)
+    }
+
+    /// Advance to the next entry
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<bool> {
+        let Some(pos) = &self.pos else {
+            return Ok(false);
+        };
+        let pos = btree::next(&mut self.inner.lock().pager, pos);
+        self.set(pos)
+    }
+
+    /// Move back to the previous entry
+    pub fn prev(&mut self) -> Result<bool> {
+        let Some(pos) = &self.pos else {
+            return Ok(false);
+        };
+        let pos = btree::prev(&mut self.inner.lock().pager, pos);
+        self.set(pos)
+    }
+
+    /// Whether the cursor points at an entry
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Key of the current entry
+    pub fn key(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(key, _)| key.as_slice())
+    }
+
+    /// Value of the current entry
+    pub fn value(&self) -> Option<&[u8]> {
+        self.current.as_ref().map(|(_, value)| value.as_slice())
+    }
+
+    fn set(&mut self, pos: Result<Option<Position>>) -> Result<bool> {
+        self.pos = None;
+        self.current = None;
+        if let Some(pos) = pos? {
+            self.current = Some(btree::entry(&mut self.inner.lock().pager, &pos)?);
+            self.pos = Some(pos);
+        }
+        Ok(self.pos.is_some())
+    }
+}
@@ -5,31 +5,853 @@
 
 #![warn(missing_docs, rust_2024_compatibility)]
 
+mod async_db;
+mod btree;
 pub mod buffer;
+mod checkpoint;
+mod compact;
+mod concurrent;
 /// Error types for qpdb
 pub mod error;
+mod header;
+mod iter;
+mod pager;
+mod savepoint;
+pub mod storage;
+mod table;
+mod txn;
+mod types;
+mod wal;
 
+use std::collections::{HashSet, VecDeque};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+
+pub use async_db::{AsyncDatabase, AsyncWriteTransaction, RangeStream};
+use btree::BTree;
+pub use btree::MAX_ENTRY_SIZE;
+pub use buffer::CacheStats;
+use buffer::{AtomicSwip, PAGE_SIZE, Page, PageId};
+use checkpoint::Checkpointer;
+pub use concurrent::{ConcurrentRange, ConcurrentTable, ConcurrentWriteTransaction, Isolation};
 pub use error::{Error, Result};
+use header::Superblock;
+pub use iter::{Cursor, KeyValue, Range};
+use pager::Pager;
+pub use savepoint::Savepoint;
+use storage::{FileBackend, StorageBackend};
+pub use table::{
+    ReadOnlyTable, ReadOnlyTypedTable, Table, TableDefinition, TypedRange, TypedTable,
+};
+pub use txn::{Durability, ReadTransaction, WriteTransaction};
+use txn::{Retired, Snapshot, Snapshots};
+pub use types::{Bincode, BincodeValue, Key, Value};
+pub use wal::CommitStats;
+use wal::{GroupCommit, Lsn, RecordBody, TxnId, Wal};
+
+/// Default log size beyond which the background checkpointer runs
+const DEFAULT_CHECKPOINT_SIZE: u64 = 16 << 20;
+
+/// Default memory budget for cached pages
+const DEFAULT_CACHE_SIZE: usize = 64 << 20;
+
+/// Smallest number of frames the cache is given, whatever the budget
+const MIN_CACHE_FRAMES: usize = 16;
+
+/// Default step by which the data file grows
+const DEFAULT_FILE_GROWTH: u64 = 1 << 20;
+
+/// Pages moved by each write transaction of [`Database::compact`]
+const COMPACT_STEP: usize = 256;
 
 /// Database handle
+///
+/// Reads and writes go through transactions: any number of
+/// [`ReadTransaction`]s, each seeing the data as of its start, alongside at
+/// most one [`WriteTransaction`] and any number of optimistic
+/// [`ConcurrentWriteTransaction`]s, which only wait for the writer to
+/// commit. The methods on the handle itself run a single-operation
+/// transaction each against the default tree; named tables are reached
+/// through transactions.
+///
+/// A transaction is durable once its commit returns: the modified pages are
+/// appended to the write-ahead log and the log is synced, unless the
+/// transaction asks for another [`Durability`]. Its changes are
+/// visible to readers, and the next write transaction may begin, as soon as
+/// they are in the log; the sync happens after, so that transactions
+/// committing at the same time share one ([group commit](CommitStats)).
+/// Data pages are written back by a background checkpointer once the log
+/// grows large, without holding up readers or writers, which bounds the log
+/// replayed after a crash; and by [`Database::flush`] and on drop.
+///
+/// A database file is locked while open: by one handle that may write, or by
+/// any number of [read-only](Builder::read_only) handles.
 pub struct Database {
-    _placeholder: (),
+    inner: Arc<Mutex<Inner>>,
+    /// Held by the open write transaction
+    writer: Mutex<()>,
+    /// Durability of the log, waited on by committers outside `inner`
+    log: Arc<GroupCommit>,
+    checkpointer: Arc<Checkpointer>,
+    snapshots: Snapshots,
+    /// Root of the tree as last published, followed by lock-free lookups
+    root: Arc<AtomicSwip>,
+    read_only: bool,
+    /// Whether dropping the handle skips the final checkpoint
+    crashed: bool,
+}
+
+struct Inner {
+    pager: Pager,
+    wal: Wal,
+    superblock: Superblock,
+    /// Default tree as modified by the open write transaction
+    tree: BTree,
+    /// Table catalog as modified by the open write transaction
+    catalog: BTree,
+    /// Persistent savepoint registry as modified by the open write
+    /// transaction
+    savepoints: BTree,
+    /// Whether the open write transaction restored a persistent savepoint
+    restored: bool,
+    /// Trees and page count as of the last commit
+    committed: BTree,
+    committed_catalog: BTree,
+    committed_savepoints: BTree,
+    committed_pages: u64,
+    /// Pages the persistent savepoints reach, never released
+    protected: HashSet<PageId>,
+    /// Replaced snapshots whose freed pages await release
+    retired: VecDeque<Retired>,
+    next_txn: TxnId,
+    /// State a checkpoint of the last logged commit would publish, with
+    /// that commit's LSN as its checkpoint LSN
+    logged: Superblock,
+    checkpointer: Arc<Checkpointer>,
+    /// Log size beyond which commits request a checkpoint
+    checkpoint_size: u64,
+}
+
+/// Options for opening a [`Database`]
+#[derive(Debug, Clone)]
+pub struct Builder {
+    cache_size: usize,
+    file_growth: u64,
+    checkpoint_size: u64,
+    read_only: bool,
+    direct_io: bool,
+    #[cfg(feature = "io-uring")]
+    io_uring: bool,
+}
+
+impl Default for Builder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder {
+    /// Default options
+    pub fn new() -> Self {
+        Self {
+            cache_size: DEFAULT_CACHE_SIZE,
+            file_growth: DEFAULT_FILE_GROWTH,
+            checkpoint_size: DEFAULT_CHECKPOINT_SIZE,
+            read_only: false,
+            direct_io: false,
+            #[cfg(feature = "io-uring")]
+            io_uring: false,
+        }
+    }
+
+    /// Memory budget for cached pages, in bytes (default 64 MiB)
+    ///
+    /// Pages beyond the budget are evicted and read back from disk on demand,
+    /// so datasets larger than the budget still work, only slower.
+    pub fn cache_size(&mut self, bytes: usize) -> &mut Self {
+        self.cache_size = bytes;
+        self
+    }
+
+    /// Step by which the data file is extended when it runs out of pages, in
+    /// bytes, rounded up to whole pages (default 1 MiB)
+    ///
+    /// Larger steps mean fewer file size changes while the database grows.
+    /// Pages freed by deletes and rewrites are reused before the file grows.
+    pub fn file_growth(&mut self, bytes: u64) -> &mut Self {
+        self.file_growth = bytes;
+        self
+    }
+
+    /// Size the write-ahead log may reach before the background checkpointer
+    /// writes dirty pages to the data file and recycles the log, in bytes
+    /// (default 16 MiB)
+    ///
+    /// Recovery after a crash replays at most about this much log. Smaller
+    /// sizes mean faster recovery but more page writes.
+    pub fn checkpoint_size(&mut self, bytes: u64) -> &mut Self {
+        self.checkpoint_size = bytes;
+        self
+    }
+
+    /// Open the database without write access (default false)
+    ///
+    /// Any number of read-only handles may share a database, but not with a
+    /// handle that can write. Writes through a read-only handle fail with
+    /// [`Error::ReadOnly`], and so does opening a database that needs
+    /// recovery after a crash, since recovery writes to the file.
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Bypass the OS page cache for the data file (default false)
+    ///
+    /// Pages are then cached once, in the buffer pool, rather than a second
+    /// time by the kernel, so the pool can be given the memory the kernel
+    /// would have spent; but every page the pool misses is read from the
+    /// device. Uses `O_DIRECT` on Linux, and buffered I/O on filesystems that
+    /// reject it and on other systems. The log stays buffered.
+    pub fn direct_io(&mut self, enabled: bool) -> &mut Self {
+        self.direct_io = enabled;
+        self
+    }
+
+    /// Read and write batches of data pages through io_uring (default false)
+    ///
+    /// Range scans read the pages ahead of them in batches, and checkpoints
+    /// write dirty pages back in batches; see
+    /// [`UringBackend`](storage::UringBackend). Falls back to plain file I/O
+    /// where the kernel does not offer io_uring. Only available with the
+    /// `io-uring` feature.
+    #[cfg(feature = "io-uring")]
+    pub fn io_uring(&mut self, enabled: bool) -> &mut Self {
+        self.io_uring = enabled;
+        self
+    }
+
+    /// Open a database at the given path, creating it if it does not exist
+    /// unless opening read-only
+    ///
+    /// Fails with [`Error::DatabaseLocked`] if another handle has the
+    /// database open in a conflicting mode.
+    pub fn open(&self, path: impl AsRef<Path>) -> Result<Database> {
+        let path = path.as_ref();
+        let mut options = OpenOptions::new();
+        options
+            .read(true)
+            .write(!self.read_only)
+            .create(!self.read_only)
+            .truncate(false);
+        let file = if self.direct_io {
+            storage::open_direct(&options, path)?
+        } else {
+            options.open(path)?
+        };
+        lock(&file, path, self.read_only)?;
+        let created = file.metadata()?.len() == 0;
+
+        let log_path = wal::wal_path(path);
+        let log = if self.read_only {
+            match File::open(&log_path) {
+                Ok(log) => Some(log),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+                Err(err) => return Err(err.into()),
+            }
+        } else {
+            let log = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&log_path)?;
+            Some(log)
+        };
+        let log = log.map(|log| Box::new(FileBackend::new(log)) as Box<dyn StorageBackend>);
+        let db = self.open_storage(self.data_backend(file)?, log)?;
+        if created {
+            sync_parent_dir(path)?;
+        }
+        Ok(db)
+    }
+
+    /// Open a database kept in `data` with its write-ahead log in `log`,
+    /// creating it if `data` is empty
+    ///
+    /// No lock is taken, so nothing else may use the same storage while the
+    /// database is open.
+    pub fn open_backend(
+        &self,
+        data: impl StorageBackend + 'static,
+        log: impl StorageBackend + 'static,
+    ) -> Result<Database> {
+        self.open_storage(Box::new(data), Some(Box::new(log)))
+    }
+
+    /// Backend for the data file
+    fn data_backend(&self, file: File) -> Result<Box<dyn StorageBackend>> {
+        #[cfg(feature = "io-uring")]
+        if self.io_uring {
+            // Kernels without io_uring, or that forbid it, get plain I/O
+            if let Ok(backend) = storage::UringBackend::new(file.try_clone()?) {
+                return Ok(Box::new(backend));
+            }
+        }
+        Ok(Box::new(FileBackend::new(file)))
+    }
+
+    /// Open the database in `data`, initializing it if empty or bringing it
+    /// up to date with `log`, which only a read-only handle may lack
+    fn open_storage(
+        &self,
+        data: Box<dyn StorageBackend>,
+        log: Option<Box<dyn StorageBackend>>,
+    ) -> Result<Database> {
+        let (superblock, wal) = if self.read_only {
+            let superblock = header::load(&*data)?;
+            (superblock, Wal::open_read_only(log, superblock)?)
+        } else {
+            let mut wal = Wal::open(log.expect("a writable database has a log"))?;
+            let superblock = if data.is_empty()? {
+                let superblock = header::init(&*data)?;
+                // A log left over from an unrelated, deleted database must not be replayed
+                wal.truncate()?;
+                superblock
+            } else {
+                let superblock = header::load(&*data)?;
+                wal::recover(&mut wal, &*data, superblock)?
+            };
+            (superblock, wal)
+        };
+
+        let frames = (self.cache_size / size_of::<Page>()).max(MIN_CACHE_FRAMES);
+        let mut pager = Pager::new(data, superblock.page_count, frames);
+        pager.set_growth(self.file_growth.div_ceil(PAGE_SIZE as u64));
+        pager.load_free_list(superblock.free_list)?;
+        pager.publish(superblock.root);
+        let root = pager.root_swip();
+        let log = wal.group();
+        let checkpointer = Arc::new(Checkpointer::new());
+        let tree = BTree::new(superblock.root);
+        let catalog = BTree::new(superblock.catalog);
+        let savepoints = BTree::new(superblock.savepoints);
+        let mut inner = Inner {
+            pager,
+            wal,
+            tree,
+            catalog,
+            savepoints,
+            restored: false,
+            committed: tree,
+            committed_catalog: catalog,
+            committed_savepoints: savepoints,
+            committed_pages: superblock.page_count,
+            protected: HashSet::new(),
+            retired: VecDeque::new(),
+            superblock,
+            next_txn: 1,
+            logged: superblock,
+            checkpointer: Arc::clone(&checkpointer),
+            checkpoint_size: self.checkpoint_size,
+        };
+        if !self.read_only {
+            inner.protect()?;
+        }
+        let inner = Arc::new(Mutex::new(inner));
+        if !self.read_only {
+            checkpointer.start(Arc::clone(&inner))?;
+        }
+        Ok(Database {
+            inner,
+            writer: Mutex::new(()),
+            log,
+            checkpointer,
+            snapshots: Snapshots::new(Snapshot {
+                root: superblock.root,
+                catalog: superblock.catalog,
+            }),
+            root,
+            read_only: self.read_only,
+            crashed: false,
+        })
+    }
 }
 
 impl Database {
-    /// Open a database at the given path
-    pub fn open(_path: impl AsRef<std::path::Path>) -> Result<Self> {
-        todo!("implement Database::open")
+    /// Open a database at the given path with default options, creating it if
+    /// it does not exist
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Builder::new().open(path)
+    }
+
+    /// Options for opening a database
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    /// Start a read-only transaction over the latest committed data
+    pub fn begin_read(&self) -> Result<ReadTransaction<'_>> {
+        Ok(ReadTransaction::new(self))
+    }
+
+    /// Start the write transaction, waiting for any other one to finish
+    ///
+    /// Must not be called again on a thread that holds a write transaction.
+    pub fn begin_write(&self) -> Result<WriteTransaction<'_>> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        WriteTransaction::new(self)
+    }
+
+    /// Start an optimistic write transaction, which runs alongside other
+    /// write transactions and is validated when it commits
+    pub fn begin_concurrent_write(&self) -> Result<ConcurrentWriteTransaction<'_>> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        Ok(ConcurrentWriteTransaction::new(self))
+    }
+
+    /// Look up the latest committed value stored under `key`
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = btree::optimistic::get(&self.root, None, key) {
+            return Ok(value);
+        }
+        let inner = &mut *self.inner.lock();
+        inner.committed.get(&mut inner.pager, key)
+    }
+
+    /// Insert or replace `key` and commit, returning the previous value
+    pub fn insert(&self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut txn = self.begin_write()?;
+        let old = txn.insert(key, value)?;
+        txn.commit()?;
+        Ok(old)
+    }
+
+    /// Remove `key` and commit, returning its value if it was present
+    pub fn remove(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut txn = self.begin_write()?;
+        let old = txn.remove(key)?;
+        txn.commit()?;
+        Ok(old)
+    }
+
+    /// Iterate over all committed entries in key order
+    pub fn iter(&self) -> Range<'_> {
+        self.range::<&[u8]>(..)
+    }
+
+    /// Iterate over the committed entries whose keys fall within `range`, in
+    /// key order
+    ///
+    /// The returned iterator is double-ended, so `.rev()` walks the range
+    /// backwards. It reads the snapshot committed when it was created.
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Range<'_> {
+        let snapshot = self.snapshots.current();
+        Range::new(
+            &self.inner,
+            BTree::new(snapshot.root),
+            Some(snapshot),
+            range,
+        )
+    }
+
+    /// Create an unpositioned cursor over the latest committed snapshot
+    pub fn cursor(&self) -> Cursor<'_> {
+        let snapshot = self.snapshots.current();
+        Cursor::new(&self.inner, BTree::new(snapshot.root), Some(snapshot))
+    }
+
+    /// Page cache activity since the database was opened
+    pub fn cache_stats(&self) -> CacheStats {
+        self.inner.lock().pager.stats()
+    }
+
+    /// Commit batching and latency since the database was opened
+    pub fn commit_stats(&self) -> CommitStats {
+        self.log.stats()
+    }
+
+    /// Wait for the commit record at `lsn` to be durable, sharing the log
+    /// sync with whoever else waits
+    fn wait_durable(&self, lsn: Lsn) -> Result<()> {
+        self.log.commit(lsn)?;
+        // The commit's pages may now be written back by eviction
+        let inner = &mut *self.inner.lock();
+        inner.pager.sync_with(&inner.wal);
+        Ok(())
+    }
+
+    /// Write all dirty pages to the data file, publish them in a new
+    /// superblock and truncate the write-ahead log
+    ///
+    /// Waits for the open write transaction, if any, to finish.
+    pub fn flush(&self) -> Result<()> {
+        let _writer = self.writer.lock();
+        self.checkpoint_all()
+    }
+
+    /// Close the handle the way a crash would leave the database
+    ///
+    /// The background threads stop, but nothing is checkpointed or synced on
+    /// the way out: commits survive as far as the log made them durable, and
+    /// opening the database again recovers them.
+    ///
+    /// A hook for the crash recovery tests, hidden from the documentation
+    /// and not part of the stable API.
+    #[doc(hidden)]
+    pub fn simulate_crash(mut self) {
+        self.crashed = true;
+    }
+
+    /// Checkpoint the last logged commit now, as the background checkpointer
+    /// does once the log grows large
+    ///
+    /// Unlike [`Database::flush`] this lets writers carry on: pages they
+    /// dirty meanwhile are written if the log covers them, and the log they
+    /// write is kept. Commits that skipped the log are not checkpointed.
+    pub fn checkpoint(&self) -> Result<()> {
+        self.checkpointer.run(&self.inner)
+    }
+
+    /// Checkpoint everything committed, with the writer lock held so that
+    /// nothing is left unlogged
+    fn checkpoint_all(&self) -> Result<()> {
+        let _running = self.checkpointer.lock();
+        self.inner.lock().flush()
+    }
+
+    /// Shrink the data file by moving pages from its end into free pages
+    /// nearer its start, then checkpointing and truncating the file
+    ///
+    /// Pages are moved a few hundred at a time, each batch committed as a
+    /// write transaction of its own, so other writers get a turn between
+    /// batches and readers are never held up for longer than one. Pages that
+    /// open read transactions can still see are not reused, so compacting
+    /// while old snapshots are held shrinks the file less.
+    pub fn compact(&self) -> Result<()> {
+        // Pages only ever move down, so the end of the pages in use drops
+        // with every step until nothing more can move
+        let mut end = PageId::MAX;
+        loop {
+            // Pages the last steps freed are what the next one moves into
+            self.snapshots.collect();
+            let txn = self.begin_write()?;
+            let reached = self.inner.lock().compact_step(COMPACT_STEP)?;
+            txn.commit()?;
+            if reached >= end {
+                break;
+            }
+            end = reached;
+        }
+        let _writer = self.writer.lock();
+        let _running = self.checkpointer.lock();
+        let inner = &mut *self.inner.lock();
+        inner.flush()?;
+        inner.pager.truncate()
+    }
+}
+
+impl Inner {
+    /// Log every page modified since the last commit and publish the new
+    /// tree to readers
+    ///
+    /// Returns the LSN of the commit record, which is written to the log but
+    /// not yet durable, or `None` if there was nothing to commit or nothing
+    /// to log under [`Durability::None`].
+    fn commit(&mut self, snapshots: &Snapshots, durability: Durability) -> Result<Option<Lsn>> {
+        // Pages of earlier commits synced since may be evicted, and pages
+        // they freed released
+        self.pager.sync_with(&self.wal);
+        txn::reclaim(
+            &mut self.retired,
+            &mut self.pager,
+            self.wal.durable_lsn(),
+            &self.protected,
+        );
+        self.pager.release_savepoints();
+        let trimmed = self.pager.trim();
+        if !trimmed
+            && !self.pager.has_unlogged()
+            && self.tree == self.committed
+            && self.catalog == self.committed_catalog
+            && self.savepoints == self.committed_savepoints
+        {
+            return Ok(None);
+        }
+        let protected = if self.savepoints != self.committed_savepoints || self.restored {
+            let previous = self.protected.clone();
+            self.protect()?;
+            Some(previous)
+        } else {
+            None
+        };
+        let lsn = match self.persist(durability) {
+            Ok(lsn) => lsn,
+            Err(err) => {
+                // The registry is rolled back with the transaction
+                if let Some(previous) = protected {
+                    self.protected = previous;
+                }
+                return Err(err);
+            }
+        };
+
+        self.committed = self.tree;
+        self.committed_catalog = self.catalog;
+        self.committed_savepoints = self.savepoints;
+        self.committed_pages = self.pager.page_count();
+        let previous = snapshots.publish(Snapshot {
+            root: self.tree.root(),
+            catalog: self.catalog.root(),
+        });
+        self.retired
+            .push_back(Retired::new(previous, self.pager.take_freed(), lsn));
+        self.pager.publish(self.tree.root());
+        txn::reclaim(
+            &mut self.retired,
+            &mut self.pager,
+            self.wal.durable_lsn(),
+            &self.protected,
+        );
+
+        if self.wal.size() > self.checkpoint_size {
+            self.checkpointer.request();
+        }
+        Ok(lsn)
+    }
+
+    /// Write the free list and log the commit as `durability` asks,
+    /// returning the LSN of the commit record if it was logged
+    fn persist(&mut self, durability: Durability) -> Result<Option<Lsn>> {
+        // Pages retired earlier are still pending, but only until a restart;
+        // protected pages are never free while their savepoints exist
+        let pending: Vec<PageId> = self
+            .retired
+            .iter()
+            .flat_map(Retired::pages)
+            .chain(self.pager.freed())
+            .filter(|id| !self.protected.contains(id))
+            .copied()
+            .collect();
+        let free_list = self.pager.write_free_list(&pending)?;
+
+        let (root, page_count, catalog, savepoints) = (
+            self.tree.root(),
+            self.pager.page_count(),
+            self.catalog.root(),
+            self.savepoints.root(),
+        );
+        if durability != Durability::None {
+            return Ok(Some(
+                self.log(root, page_count, free_list, catalog, savepoints)?,
+            ));
+        }
+        self.pager.defer();
+        // Deferred pages cannot be evicted until the log covers them, so
+        // too many are logged and synced after all
+        if !self.pager.deferred_over_budget() {
+            return Ok(None);
+        }
+        let lsn = self.log(root, page_count, free_list, catalog, savepoints)?;
+        self.wal.sync_to(lsn)?;
+        self.pager.sync_with(&self.wal);
+        Ok(Some(lsn))
+    }
+
+    /// Log the unlogged and deferred pages followed by a commit record for
+    /// the given state, and write them to the file, returning the commit
+    /// record's LSN
+    fn log(
+        &mut self,
+        root: PageId,
+        page_count: u64,
+        free_list: PageId,
+        catalog: PageId,
+        savepoints: PageId,
+    ) -> Result<Lsn> {
+        let txn = self.next_txn;
+        self.next_txn += 1;
+        self.pager.log(&mut self.wal, txn)?;
+        let commit = RecordBody::Commit {
+            root,
+            page_count,
+            free_list,
+            catalog,
+            savepoints,
+        };
+        let lsn = self.wal.append(txn, commit);
+        self.wal.write()?;
+        for retired in &mut self.retired {
+            retired.logged(lsn);
+        }
+        self.logged = Superblock {
+            generation: self.superblock.generation,
+            page_count,
+            root,
+            free_list,
+            checkpoint_lsn: lsn,
+            catalog,
+            savepoints,
+        };
+        Ok(lsn)
+    }
+
+    /// Discard every change since the last commit
+    fn rollback(&mut self) {
+        self.pager.rollback(self.committed_pages);
+        self.tree = self.committed;
+        self.catalog = self.committed_catalog;
+        self.savepoints = self.committed_savepoints;
+        self.restored = false;
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        debug_assert!(
+            !self.pager.has_unlogged(),
+            "checkpoint inside a write transaction"
+        );
+        // Whatever failed to commit must not reach the data file; recovery
+        // sorts it out from the log
+        self.wal.group().check()?;
+        if self.pager.has_deferred() {
+            // Pages reach the data file only once the log covers them
+            let lsn = self.log(
+                self.committed.root(),
+                self.committed_pages,
+                self.pager.free_list(),
+                self.committed_catalog.root(),
+                self.committed_savepoints.root(),
+            )?;
+            self.wal.sync_to(lsn)?;
+            self.pager.publish(self.committed.root());
+        }
+        let page_count = self.committed_pages;
+        let root = self.committed.root();
+        let catalog = self.committed_catalog.root();
+        let savepoints = self.committed_savepoints.root();
+        if !self.pager.is_dirty()
+            && self.superblock.root == root
+            && self.superblock.catalog == catalog
+            && self.superblock.savepoints == savepoints
+            && self.superblock.page_count == page_count
+        {
+            return Ok(());
+        }
+        // Pages must be durable before the superblock that references them, and
+        // the superblock before the log that could otherwise rebuild them
+        self.pager.flush(&mut self.wal)?;
+        let superblock = Superblock {
+            generation: self.superblock.generation + 1,
+            page_count,
+            root,
+            catalog,
+            savepoints,
+            free_list: self.pager.free_list(),
+            checkpoint_lsn: self.wal.durable_lsn(),
+        };
+        header::write_superblock(self.pager.storage(), &superblock)?;
+        self.superblock = superblock;
+        self.wal.truncate()?;
+        Ok(())
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        self.checkpointer.stop();
+        self.log.stop();
+        if self.crashed {
+            return;
+        }
+        // Best effort: errors cannot be reported from drop
+        let _ = self.inner.lock().flush();
+    }
+}
+
+/// Lock `file` against other handles: shared for a read-only handle,
+/// exclusive otherwise
+fn lock(file: &File, path: &Path, shared: bool) -> Result<()> {
+    let locked = if shared {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+    match locked {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(Error::DatabaseLocked {
+            path: path.to_owned(),
+        }),
+        Err(TryLockError::Error(err)) => Err(err.into()),
     }
 }
 
+/// Make a newly created file's directory entry durable
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::Write;
 
     #[test]
-    fn placeholder_test() {
-        // Placeholder until we implement actual functionality
-        assert_eq!(2 + 2, 4);
+    fn open_creates_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.qpdb");
+        let db = Database::open(&path).unwrap();
+        let superblock = db.inner.lock().superblock;
+        assert_eq!(superblock.page_count, 1);
+        assert_eq!(superblock.root, 0);
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn reopen_existing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.qpdb");
+        let created = Database::open(&path).unwrap().inner.lock().superblock;
+        let reopened = Database::open(&path).unwrap().inner.lock().superblock;
+        assert_eq!(created, reopened);
+    }
+
+    #[test]
+    fn flush_publishes_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.qpdb");
+        let db = Database::open(&path).unwrap();
+        db.insert(b"hello", b"world").unwrap();
+        db.flush().unwrap();
+        let superblock = db.inner.lock().superblock;
+        assert_eq!(superblock.generation, 1);
+        assert_ne!(superblock.root, 0);
+
+        // Nothing changed, so no new generation
+        db.flush().unwrap();
+        assert_eq!(db.inner.lock().superblock.generation, 1);
+    }
+
+    #[test]
+    fn open_rejects_foreign_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("not-a-db");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&[0xab; 8192]).unwrap();
+        drop(file);
+
+        assert!(matches!(Database::open(&path), Err(Error::Corruption(_))));
     }
 }
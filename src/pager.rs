@@ -0,0 +1,788 @@
+//! Page access between the B-tree and the buffer manager
+//!
+//! Pages are cached by the [`BufferManager`] within its memory budget. Pages
+//! allocated since the last commit are *unlogged*: private to the writer,
+//! written in place, and pinned so eviction cannot write changes to the file
+//! that the log does not cover yet. A commit copies them into the WAL, after
+//! which they are shared with readers and only ever copied
+//! ([`PageStore::shadow`]). Pages stay *dirty* until they are written back,
+//! either by eviction or by [`Pager::flush`].
+//!
+//! A commit that skips the log ([`Pager::defer`]) shares its pages with
+//! readers all the same, but they stay pinned as *deferred* pages until the
+//! next commit that is logged, or a checkpoint, logs them.
+//!
+//! Committed pages freed by the writer remain readable: they are handed back
+//! by [`Pager::take_freed`] and only become reusable through
+//! [`Pager::release`] once no snapshot can reach them.
+//!
+//! Every commit persists the free pages in a chain of free-list pages
+//! ([`Pager::write_free_list`]), including those still waiting for readers,
+//! since no snapshot survives a restart. The chain is built from pages that
+//! are free already and logged with the commit. Allocation prefers free pages
+//! and otherwise appends to the file, which grows a configurable number of
+//! pages at a time. Free pages are taken lowest first, and those left at the
+//! end of the file are dropped from the page count ([`Pager::trim`]).
+//!
+//! Written pages also stay exclusively latched until [`Pager::publish`], so
+//! optimistic readers never observe an operation half done.
+//!
+//! A savepoint within the write transaction ([`Pager::savepoint`]) *seals*
+//! the unlogged pages: they stay private, but are copied like committed pages
+//! before being written, so the trees they hold stay intact. Sealed pages
+//! copied or freed since are *superseded*; they are kept for
+//! [`Pager::rollback_to`] and only freed once the transaction commits
+//! ([`Pager::release_savepoints`]).
+
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+
+use crate::btree::PageStore;
+use crate::buffer::{
+    AlignedPage, AtomicSwip, BufferManager, CacheStats, FREE_IDS_PER_PAGE, PAGE_SIZE, Page, PageId,
+    PageKind,
+};
+use crate::storage::StorageBackend;
+use crate::wal::{Lsn, RecordBody, TxnId, Wal};
+use crate::{Error, Result};
+
+/// State of the pager at a savepoint within the write transaction
+#[derive(Debug, Clone)]
+pub(crate) struct PagerSavepoint {
+    page_count: u64,
+    sealed: BTreeSet<PageId>,
+    /// Lengths of the freed and superseded lists
+    freed: usize,
+    superseded: usize,
+}
+
+/// Page allocator and access layer over a database file
+pub(crate) struct Pager {
+    buffers: BufferManager,
+    unlogged: BTreeSet<PageId>,
+    /// Unlogged pages a savepoint may return to, written through copies
+    sealed: BTreeSet<PageId>,
+    /// Sealed pages copied or freed since they were sealed
+    superseded: Vec<PageId>,
+    /// Committed pages not logged yet, pinned until they are
+    deferred: BTreeSet<PageId>,
+    page_count: u64,
+    /// Pages reusable right away, taken lowest first
+    free: BTreeSet<PageId>,
+    /// Committed pages freed since the last commit
+    freed: Vec<PageId>,
+    /// Pages holding the persisted free list, head first
+    chain: Vec<PageId>,
+    /// Sorted ids recorded in the persisted free list
+    persisted: Vec<PageId>,
+    /// Whether the next commit writes a new chain even if `persisted` is
+    /// current
+    move_chain: bool,
+    /// Length of the data file in pages, as far as known
+    file_pages: u64,
+    /// Pages added to the file whenever it has to grow
+    growth: u64,
+}
+
+impl Pager {
+    /// Wrap a database file that currently holds `page_count` pages, caching
+    /// at most `capacity` of them
+    pub fn new(storage: Box<dyn StorageBackend>, page_count: u64, capacity: usize) -> Self {
+        Self {
+            buffers: BufferManager::new(storage, capacity),
+            unlogged: BTreeSet::new(),
+            sealed: BTreeSet::new(),
+            superseded: Vec::new(),
+            deferred: BTreeSet::new(),
+            page_count,
+            free: BTreeSet::new(),
+            freed: Vec::new(),
+            chain: Vec::new(),
+            persisted: Vec::new(),
+            move_chain: false,
+            file_pages: 0,
+            growth: 1,
+        }
+    }
+
+    /// Grow the file by `pages` pages at a time
+    pub fn set_growth(&mut self, pages: u64) {
+        self.growth = pages.max(1);
+    }
+
+    /// Underlying database file
+    pub fn storage(&self) -> &dyn StorageBackend {
+        self.buffers.storage()
+    }
+
+    /// Number of pages in the file, including the header page
+    pub fn page_count(&self) -> u64 {
+        self.page_count
+    }
+
+    /// Shared handle to the database file, for checkpoint writes made
+    /// without the pager
+    pub fn storage_handle(&self) -> Arc<dyn StorageBackend> {
+        self.buffers.storage_handle()
+    }
+
+    /// Page cache activity counters
+    pub fn stats(&self) -> CacheStats {
+        self.buffers.stats()
+    }
+
+    /// Whether the file is behind the cache
+    pub fn is_dirty(&self) -> bool {
+        self.buffers.is_dirty()
+    }
+
+    /// Swip to the published tree root, for optimistic readers
+    pub fn root_swip(&self) -> Arc<AtomicSwip> {
+        self.buffers.root_swip()
+    }
+
+    /// Make `root` the tree root seen by optimistic readers and release the
+    /// latches of every page written since the last publish
+    pub fn publish(&mut self, root: PageId) {
+        self.buffers.set_root(root);
+        self.buffers.unlatch_all();
+    }
+
+    /// Whether any page has been modified since it was last logged
+    pub fn has_unlogged(&self) -> bool {
+        !self.unlogged.is_empty() || !self.sealed.is_empty()
+    }
+
+    /// Whether committed pages wait to be logged
+    pub fn has_deferred(&self) -> bool {
+        !self.deferred.is_empty()
+    }
+
+    /// Whether deferred pages, which cannot be evicted, fill half the cache
+    pub fn deferred_over_budget(&self) -> bool {
+        self.deferred.len() > self.buffers.capacity() / 2
+    }
+
+    /// Committed pages freed since the last call, which snapshots may still
+    /// reach
+    pub fn take_freed(&mut self) -> Vec<PageId> {
+        std::mem::take(&mut self.freed)
+    }
+
+    /// Committed pages freed since the last commit
+    pub fn freed(&self) -> &[PageId] {
+        &self.freed
+    }
+
+    /// First page of the persisted free list (0 = none)
+    pub fn free_list(&self) -> PageId {
+        self.chain.first().copied().unwrap_or(0)
+    }
+
+    /// Read the free list persisted at `head` after opening the file
+    pub fn load_free_list(&mut self, head: PageId) -> Result<()> {
+        let mut id = head;
+        while id != 0 {
+            self.check_bounds(id)?;
+            if self.chain.len() as u64 >= self.page_count {
+                return Err(Error::Corruption("free list has a cycle".into()));
+            }
+            let page = self.buffers.fix(id)?;
+            if page.kind() != Some(PageKind::FreeList) {
+                return Err(Error::Corruption(format!(
+                    "page {} is not a free-list page",
+                    id
+                )));
+            }
+            let (ids, next) = (page.free_ids(), page.next());
+            for &free in &ids {
+                self.check_bounds(free)?;
+            }
+            self.free.extend(ids);
+            self.chain.push(id);
+            id = next;
+        }
+        self.persisted = self.free.iter().copied().collect();
+        Ok(())
+    }
+
+    /// Record every page that is free now or `pending` release in a chain of
+    /// free-list pages, returning the head of the chain
+    ///
+    /// The previous chain is left alone if the set is unchanged, unless
+    /// [`Pager::move_free_list`] asked for it to move. Otherwise its
+    /// pages are freed along with the commit's, and the new chain takes the
+    /// lowest free pages, growing the file only if there are too few.
+    pub fn write_free_list(&mut self, pending: &[PageId]) -> Result<PageId> {
+        let mut listed: Vec<PageId> = self.free.iter().chain(pending).copied().collect();
+        listed.sort_unstable();
+        if listed == self.persisted && !self.move_chain {
+            return Ok(self.free_list());
+        }
+        self.move_chain = false;
+
+        // A crash may still recover the commit that wrote the previous chain,
+        // so its pages are freed like any committed page
+        let mut pending = pending.to_vec();
+        for id in std::mem::take(&mut self.chain) {
+            self.freed.push(id);
+            pending.push(id);
+        }
+        let mut reusable = std::mem::take(&mut self.free);
+        let mut chain = Vec::new();
+        let mut count = reusable.len() + pending.len();
+        while chain.len() * FREE_IDS_PER_PAGE < count {
+            let id = match reusable.pop_first() {
+                Some(id) => {
+                    count -= 1;
+                    id
+                }
+                None => self.append()?,
+            };
+            chain.push(id);
+        }
+
+        let mut ids: Vec<PageId> = reusable.iter().chain(&pending).copied().collect();
+        ids.sort_unstable();
+        let mut chunks = ids.chunks(FREE_IDS_PER_PAGE);
+        for (i, &id) in chain.iter().enumerate() {
+            let page = self.claim(id, PageKind::FreeList)?;
+            page.set_free_ids(chunks.next().unwrap_or_default());
+            page.set_next(chain.get(i + 1).copied().unwrap_or(0));
+        }
+        self.free = reusable;
+        self.persisted = ids;
+        self.chain = chain;
+        Ok(self.free_list())
+    }
+
+    /// Make pages returned by [`Pager::take_freed`] available for reuse
+    pub fn release(&mut self, pages: impl IntoIterator<Item = PageId>) {
+        for id in pages {
+            self.buffers.discard(id);
+            self.deferred.remove(&id);
+            self.free.insert(id);
+        }
+    }
+
+    /// Whether page `id` is reusable right away
+    pub fn is_free(&self, id: PageId) -> bool {
+        self.free.contains(&id)
+    }
+
+    /// Whether at least `count` pages below `id` are reusable right away
+    pub fn has_free_below(&self, id: PageId, count: usize) -> bool {
+        count == 0 || self.free.range(..id).nth(count - 1).is_some()
+    }
+
+    /// Whether page `id` holds part of the persisted free list
+    pub fn in_free_list(&self, id: PageId) -> bool {
+        self.chain.contains(&id)
+    }
+
+    /// Have the next [`Pager::write_free_list`] write a new chain into the
+    /// lowest free pages, freeing the current one, even if the set of free
+    /// pages is unchanged
+    pub fn move_free_list(&mut self) {
+        self.move_chain = true;
+    }
+
+    /// Whether page `id` holds committed data: it is neither free, nor part
+    /// of the free list, nor written since the last commit
+    pub fn is_committed(&self, id: PageId) -> bool {
+        !self.free.contains(&id)
+            && !self.chain.contains(&id)
+            && !self.unlogged.contains(&id)
+            && !self.sealed.contains(&id)
+    }
+
+    /// Drop the free pages at the end of the file from the page count,
+    /// returning whether there were any
+    ///
+    /// The file keeps its length until [`Pager::truncate`].
+    pub fn trim(&mut self) -> bool {
+        let before = self.page_count;
+        while self.page_count > 1 && self.free.last() == Some(&(self.page_count - 1)) {
+            self.free.pop_last();
+            self.page_count -= 1;
+        }
+        self.page_count != before
+    }
+
+    /// Cut the file down to the page count
+    ///
+    /// Only safe once a checkpoint has recorded the page count, as the log
+    /// may otherwise refer to pages beyond it.
+    pub fn truncate(&mut self) -> Result<()> {
+        self.storage().set_len(self.page_count * PAGE_SIZE as u64)?;
+        self.storage().sync()?;
+        self.file_pages = self.page_count;
+        Ok(())
+    }
+
+    /// Drop every change since the last commit, restoring the page count of
+    /// that commit
+    pub fn rollback(&mut self, page_count: u64) {
+        self.buffers.unlatch_all();
+        let sealed = std::mem::take(&mut self.sealed);
+        self.superseded.clear();
+        self.unlogged.extend(sealed);
+        self.discard_unlogged(page_count);
+        self.freed.clear();
+    }
+
+    /// Seal the unlogged pages and record what [`Pager::rollback_to`] needs
+    /// to return to this point
+    pub fn savepoint(&mut self) -> PagerSavepoint {
+        self.sealed.append(&mut self.unlogged);
+        PagerSavepoint {
+            page_count: self.page_count,
+            sealed: self.sealed.clone(),
+            freed: self.freed.len(),
+            superseded: self.superseded.len(),
+        }
+    }
+
+    /// Drop every change since `savepoint` was taken
+    ///
+    /// Savepoints taken after it are no longer valid; it stays valid itself.
+    pub fn rollback_to(&mut self, savepoint: &PagerSavepoint) {
+        let sealed = std::mem::replace(&mut self.sealed, savepoint.sealed.clone());
+        self.unlogged
+            .extend(sealed.difference(&savepoint.sealed).copied());
+        self.discard_unlogged(savepoint.page_count);
+        self.freed.truncate(savepoint.freed);
+        self.superseded.truncate(savepoint.superseded);
+    }
+
+    /// Forget the savepoints of the write transaction before it commits:
+    /// superseded pages are freed and the other sealed pages are unlogged
+    /// pages again
+    pub fn release_savepoints(&mut self) {
+        for id in std::mem::take(&mut self.superseded) {
+            self.sealed.remove(&id);
+            self.buffers.discard(id);
+            self.free.insert(id);
+        }
+        self.unlogged.append(&mut self.sealed);
+    }
+
+    /// Discard the unlogged pages and go back to `page_count` pages, freeing
+    /// the pages below it
+    fn discard_unlogged(&mut self, page_count: u64) {
+        for id in std::mem::take(&mut self.unlogged) {
+            self.buffers.discard(id);
+            if id < page_count {
+                self.free.insert(id);
+            }
+        }
+        // Pages trimmed from the end since then are free again
+        self.free.extend(self.page_count..page_count);
+        self.free.retain(|&id| id < page_count);
+        self.page_count = page_count;
+    }
+
+    /// Stop treating `pages` as freed by the write transaction
+    pub fn keep_freed(&mut self, pages: &HashSet<PageId>) {
+        self.freed.retain(|id| !pages.contains(id));
+    }
+
+    /// Append the after-image of every unlogged and deferred page to the WAL
+    /// as part of `txn`, stamping each page with its record's LSN
+    ///
+    /// The pages stay dirty but become evictable once the log is durable.
+    /// Deferred pages are latched while being stamped, since readers may
+    /// see them, until the next [`Pager::publish`].
+    pub fn log(&mut self, wal: &mut Wal, txn: TxnId) -> Result<()> {
+        debug_assert!(self.sealed.is_empty(), "logging with savepoints left");
+        let deferred = std::mem::take(&mut self.deferred);
+        for id in std::mem::take(&mut self.unlogged) {
+            let page = self.buffers.fix_mut(id)?;
+            page.set_lsn(wal.next_lsn());
+            let image = page.cold_image();
+            wal.append(txn, RecordBody::Page { id, image });
+            self.buffers.unpin(id);
+        }
+        for id in deferred {
+            let page = self.buffers.fix_exclusive(id)?;
+            page.set_lsn(wal.next_lsn());
+            let image = page.cold_image();
+            wal.append(txn, RecordBody::Page { id, image });
+            self.buffers.unpin(id);
+        }
+        Ok(())
+    }
+
+    /// Commit the unlogged pages without logging them: they are shared with
+    /// readers but stay pinned until the next [`Pager::log`]
+    pub fn defer(&mut self) {
+        debug_assert!(self.sealed.is_empty(), "deferring with savepoints left");
+        self.deferred.append(&mut self.unlogged);
+    }
+
+    /// Record that the WAL is durable up to `wal.durable_lsn()`
+    pub fn sync_with(&mut self, wal: &Wal) {
+        self.buffers.set_durable_lsn(wal.durable_lsn());
+    }
+
+    /// Copy the next batch of pages after `after` that a checkpoint may
+    /// write, in `PageId` order; see [`BufferManager::begin_write`]
+    pub fn begin_write(&mut self, after: PageId) -> Vec<(PageId, Lsn, Box<AlignedPage>)> {
+        self.buffers.begin_write(after)
+    }
+
+    /// Finish writing a batch from [`Pager::begin_write`]
+    pub fn end_write(&mut self, batch: &[(PageId, Lsn, Box<AlignedPage>)], succeeded: bool) {
+        self.buffers.end_write(batch, succeeded);
+    }
+
+    /// Write all dirty pages back to the file and sync it
+    ///
+    /// Enforces the WAL-before-data rule: the log is synced up to the highest
+    /// page LSN before any page is written.
+    pub fn flush(&mut self, wal: &mut Wal) -> Result<()> {
+        debug_assert!(
+            self.unlogged.is_empty() && self.deferred.is_empty(),
+            "flushing pages missing from the WAL"
+        );
+        wal.sync_to(self.buffers.max_dirty_lsn())?;
+        self.sync_with(wal);
+        self.buffers.write_back()
+    }
+
+    /// Add a page at the end of the file, extending the file by a chunk if
+    /// it is full
+    fn append(&mut self) -> Result<PageId> {
+        let id = self.page_count;
+        if id >= self.file_pages {
+            self.file_pages = self.storage().len()? / PAGE_SIZE as u64;
+            if id >= self.file_pages {
+                self.file_pages = (id + 1).next_multiple_of(self.growth);
+                self.storage().set_len(self.file_pages * PAGE_SIZE as u64)?;
+            }
+        }
+        self.page_count += 1;
+        Ok(id)
+    }
+
+    /// Take over page `id` as a fresh, unlogged page of `kind`
+    fn claim(&mut self, id: PageId, kind: PageKind) -> Result<&mut Page> {
+        self.unlogged.insert(id);
+        self.buffers.pin(id);
+        let page = self.buffers.create(id)?;
+        page.init(kind);
+        Ok(page)
+    }
+
+    fn check_bounds(&self, id: PageId) -> Result<()> {
+        if id == 0 || id >= self.page_count {
+            return Err(Error::PageOutOfBounds {
+                page: id,
+                page_count: self.page_count,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl PageStore for Pager {
+    fn read(&mut self, id: PageId) -> Result<&Page> {
+        self.check_bounds(id)?;
+        self.buffers.fix(id)
+    }
+
+    fn child(&mut self, parent: PageId, pos: usize) -> Result<(PageId, &Page)> {
+        self.check_bounds(parent)?;
+        let id = self.buffers.fix(parent)?.child_id(pos);
+        self.check_bounds(id)?;
+        self.buffers.fix_child(parent, pos)
+    }
+
+    fn shadow(&mut self, id: PageId) -> Result<PageId> {
+        if self.unlogged.contains(&id) {
+            return Ok(id);
+        }
+        self.check_bounds(id)?;
+        let image = self.buffers.fix(id)?.cold_image();
+        let copy = self.allocate(PageKind::Free)?;
+        self.buffers.fix_exclusive(copy)?.data = image.0;
+        self.free(id)?;
+        Ok(copy)
+    }
+
+    fn write(&mut self, id: PageId) -> Result<&mut Page> {
+        debug_assert!(
+            self.unlogged.contains(&id),
+            "page {id} is shared and must be shadowed first"
+        );
+        self.check_bounds(id)?;
+        self.buffers.fix_exclusive(id)
+    }
+
+    fn allocate(&mut self, kind: PageKind) -> Result<PageId> {
+        let id = match self.free.pop_first() {
+            Some(id) => id,
+            None => self.append()?,
+        };
+        self.claim(id, kind)?;
+        Ok(id)
+    }
+
+    fn free(&mut self, id: PageId) -> Result<()> {
+        if self.unlogged.remove(&id) {
+            self.buffers.discard(id);
+            self.free.insert(id);
+        } else if self.sealed.contains(&id) {
+            self.superseded.push(id);
+        } else {
+            self.freed.push(id);
+        }
+        Ok(())
+    }
+
+    fn prefetch(&mut self, ids: &[PageId]) {
+        let ids: Vec<_> = ids
+            .iter()
+            .copied()
+            .filter(|&id| self.check_bounds(id).is_ok())
+            .collect();
+        self.buffers.prefetch(&ids);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+
+    #[test]
+    fn flush_and_reload() {
+        let mut wal = Wal::open(Box::new(MemoryBackend::new())).unwrap();
+        let file = MemoryBackend::new();
+        let mut pager = Pager::new(Box::new(file.clone()), 1, 16);
+        let id = pager.allocate(PageKind::Leaf).unwrap();
+        pager.write(id).unwrap().insert(b"k", b"v");
+        assert!(pager.is_dirty());
+        pager.log(&mut wal, 1).unwrap();
+        assert!(!pager.has_unlogged());
+        pager.flush(&mut wal).unwrap();
+        assert!(!pager.is_dirty());
+
+        let mut reopened = Pager::new(Box::new(file), pager.page_count(), 16);
+        assert_eq!(reopened.read(id).unwrap().lookup(b"k"), Some(&b"v"[..]));
+    }
+
+    #[test]
+    fn freed_pages_are_reused() {
+        let mut pager = Pager::new(Box::new(MemoryBackend::new()), 1, 16);
+        let a = pager.allocate(PageKind::Leaf).unwrap();
+        let b = pager.allocate(PageKind::Leaf).unwrap();
+        pager.free(a).unwrap();
+        assert_eq!(pager.allocate(PageKind::Inner).unwrap(), a);
+        assert_eq!(pager.page_count(), b + 1);
+    }
+
+    #[test]
+    fn flush_syncs_wal_first() {
+        let mut wal = Wal::open(Box::new(MemoryBackend::new())).unwrap();
+        let mut pager = Pager::new(Box::new(MemoryBackend::new()), 1, 16);
+        pager.allocate(PageKind::Leaf).unwrap();
+        pager.log(&mut wal, 1).unwrap();
+        assert_eq!(wal.durable_lsn(), 0);
+
+        pager.flush(&mut wal).unwrap();
+        assert_eq!(wal.durable_lsn(), 1);
+    }
+
+    #[test]
+    fn unlogged_pages_survive_a_full_cache() {
+        let mut wal = Wal::open(Box::new(MemoryBackend::new())).unwrap();
+        let mut pager = Pager::new(Box::new(MemoryBackend::new()), 1, 2);
+        let ids: Vec<_> = (0..4)
+            .map(|_| pager.allocate(PageKind::Leaf).unwrap())
+            .collect();
+        assert_eq!(pager.stats().resident, 4);
+
+        pager.log(&mut wal, 1).unwrap();
+        wal.sync().unwrap();
+        pager.sync_with(&wal);
+        pager.allocate(PageKind::Leaf).unwrap();
+        for &id in &ids {
+            assert_eq!(pager.read(id).unwrap().kind(), Some(PageKind::Leaf));
+        }
+        assert!(pager.stats().resident <= 2);
+        assert!(pager.stats().write_backs >= 2);
+    }
+
+    #[test]
+    fn shadow_copies_only_logged_pages() {
+        let mut wal = Wal::open(Box::new(MemoryBackend::new())).unwrap();
+        let mut pager = Pager::new(Box::new(MemoryBackend::new()), 1, 16);
+        let id = pager.allocate(PageKind::Leaf).unwrap();
+        pager.write(id).unwrap().insert(b"k", b"v");
+        assert_eq!(pager.shadow(id).unwrap(), id);
+        pager.log(&mut wal, 1).unwrap();
+
+        let copy = pager.shadow(id).unwrap();
+        assert_ne!(copy, id);
+        pager.write(copy).unwrap().insert(b"k2", b"v2");
+        assert_eq!(pager.read(id).unwrap().lookup(b"k2"), None);
+        assert_eq!(pager.read(copy).unwrap().lookup(b"k"), Some(&b"v"[..]));
+        assert_eq!(pager.take_freed(), vec![id]);
+    }
+
+    #[test]
+    fn deferred_pages_wait_for_the_next_log() {
+        let mut wal = Wal::open(Box::new(MemoryBackend::new())).unwrap();
+        let mut pager = Pager::new(Box::new(MemoryBackend::new()), 1, 2);
+        let deferred: Vec<_> = (0..3)
+            .map(|_| pager.allocate(PageKind::Leaf).unwrap())
+            .collect();
+        pager.defer();
+        assert!(pager.has_deferred() && pager.deferred_over_budget());
+        // Shared with readers now, so written through copies
+        assert_ne!(pager.shadow(deferred[0]).unwrap(), deferred[0]);
+
+        pager.log(&mut wal, 1).unwrap();
+        pager.publish(0);
+        assert!(!pager.has_deferred());
+        // The copy and the three deferred pages
+        assert_eq!(wal.read_records(0).unwrap().len(), 0);
+        wal.sync().unwrap();
+        assert_eq!(wal.read_records(0).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn rollback_discards_private_pages() {
+        let mut wal = Wal::open(Box::new(MemoryBackend::new())).unwrap();
+        let mut pager = Pager::new(Box::new(MemoryBackend::new()), 1, 16);
+        let id = pager.allocate(PageKind::Leaf).unwrap();
+        pager.log(&mut wal, 1).unwrap();
+        let committed = pager.page_count();
+
+        let copy = pager.shadow(id).unwrap();
+        pager.allocate(PageKind::Leaf).unwrap();
+        pager.rollback(committed);
+        assert!(!pager.has_unlogged());
+        assert!(pager.take_freed().is_empty());
+        assert_eq!(pager.page_count(), committed);
+        assert_eq!(pager.allocate(PageKind::Leaf).unwrap(), copy);
+    }
+
+    #[test]
+    fn savepoints_seal_private_pages() {
+        let mut pager = Pager::new(Box::new(MemoryBackend::new()), 1, 16);
+        let id = pager.allocate(PageKind::Leaf).unwrap();
+        pager.write(id).unwrap().insert(b"k", b"v");
+        let savepoint = pager.savepoint();
+
+        // Sealed pages are written through copies, and kept when freed
+        let copy = pager.shadow(id).unwrap();
+        assert_ne!(copy, id);
+        pager.write(copy).unwrap().insert(b"k2", b"v2");
+        assert_eq!(pager.read(id).unwrap().lookup(b"k2"), None);
+        pager.allocate(PageKind::Leaf).unwrap();
+        assert!(pager.freed().is_empty());
+
+        pager.rollback_to(&savepoint);
+        assert_eq!(pager.page_count(), id + 1);
+        assert_eq!(pager.read(id).unwrap().lookup(b"k"), Some(&b"v"[..]));
+        assert_eq!(pager.allocate(PageKind::Leaf).unwrap(), copy);
+
+        // Once released, superseded pages are free
+        assert_ne!(pager.shadow(id).unwrap(), id);
+        pager.release_savepoints();
+        assert_eq!(pager.allocate(PageKind::Leaf).unwrap(), id);
+        assert!(pager.take_freed().is_empty());
+    }
+
+    #[test]
+    fn free_list_roundtrip() {
+        let mut wal = Wal::open(Box::new(MemoryBackend::new())).unwrap();
+        let file = MemoryBackend::new();
+        let mut pager = Pager::new(Box::new(file.clone()), 1, 1024);
+        let pages: Vec<PageId> = (0..1200)
+            .map(|_| pager.allocate(PageKind::Leaf).unwrap())
+            .collect();
+        pager.log(&mut wal, 1).unwrap();
+        for &id in &pages[..1000] {
+            pager.free(id).unwrap();
+        }
+        let pending = pager.take_freed();
+        pager.release(pending[..600].iter().copied());
+        let head = pager.write_free_list(&pending[600..]).unwrap();
+        assert_ne!(head, 0);
+        // The chain occupies the lowest free pages
+        assert_eq!(head, pages[0]);
+        pager.log(&mut wal, 2).unwrap();
+        pager.flush(&mut wal).unwrap();
+
+        let mut reopened = Pager::new(Box::new(file), pager.page_count(), 16);
+        reopened.load_free_list(head).unwrap();
+        let free: Vec<PageId> = reopened.free.iter().copied().collect();
+        assert_eq!(free, pager.persisted);
+        assert_eq!(free.len(), 1000 - 2);
+        // Nothing changed, so the chain is kept
+        assert_eq!(reopened.write_free_list(&[]).unwrap(), head);
+        assert!(!reopened.has_unlogged());
+        // Unless asked to move, which frees the old chain
+        reopened.move_free_list();
+        assert_eq!(reopened.write_free_list(&[]).unwrap(), pages[2]);
+        assert_eq!(reopened.freed(), [pages[0], pages[1]]);
+    }
+
+    #[test]
+    fn file_grows_in_chunks() {
+        let file = MemoryBackend::new();
+        let mut pager = Pager::new(Box::new(file.clone()), 1, 16);
+        pager.set_growth(8);
+        pager.allocate(PageKind::Leaf).unwrap();
+        assert_eq!(file.len().unwrap(), 8 * PAGE_SIZE as u64);
+        for _ in 0..7 {
+            pager.allocate(PageKind::Leaf).unwrap();
+        }
+        assert_eq!(file.len().unwrap(), 16 * PAGE_SIZE as u64);
+        assert_eq!(pager.page_count(), 9);
+    }
+
+    #[test]
+    fn trim_drops_free_pages_at_the_end() {
+        let file = MemoryBackend::new();
+        let mut pager = Pager::new(Box::new(file.clone()), 1, 16);
+        pager.set_growth(8);
+        let ids: Vec<_> = (0..4)
+            .map(|_| pager.allocate(PageKind::Leaf).unwrap())
+            .collect();
+        pager.free(ids[1]).unwrap();
+        pager.free(ids[3]).unwrap();
+        assert!(pager.trim());
+        assert_eq!(pager.page_count(), ids[3]);
+        assert!(!pager.trim());
+        assert!(pager.has_free_below(ids[1] + 1, 1));
+        assert!(!pager.has_free_below(ids[1], 1));
+
+        pager.truncate().unwrap();
+        assert_eq!(file.len().unwrap(), ids[3] * PAGE_SIZE as u64);
+        // Rolling back frees the private pages and the trimmed one again
+        pager.rollback(ids[3] + 1);
+        for &id in &ids {
+            assert_eq!(pager.allocate(PageKind::Leaf).unwrap(), id);
+        }
+    }
+
+    #[test]
+    fn out_of_bounds_read() {
+        let mut pager = Pager::new(Box::new(MemoryBackend::new()), 1, 16);
+        assert!(matches!(
+            pager.read(0),
+            Err(Error::PageOutOfBounds { page: 0, .. })
+        ));
+        assert!(matches!(
+            pager.read(1),
+            Err(Error::PageOutOfBounds {
+                page: 1,
+                page_count: 1
+            })
+        ));
+    }
+}
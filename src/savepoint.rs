@@ -0,0 +1,230 @@
+//! Savepoints
+//!
+//! A [`Savepoint`] marks a point within a write transaction to roll back to
+//! without abandoning the whole transaction. It records the roots of the
+//! trees as modified so far and the allocator state. Private pages written
+//! before it are sealed by the pager, so later writes copy them rather than
+//! change them in place, and rolling back restores the roots, discards
+//! every page allocated since and forgets the pages freed since.
+//!
+//! A persistent savepoint instead names a committed state of the whole
+//! database, which a later transaction can restore. The registry of
+//! persistent savepoints is a B-tree keyed by name, its root published by
+//! every commit alongside the catalog's, and each entry holds the roots of
+//! the default tree and the catalog. Pages the savepoints reach are
+//! *protected*: they are not released when later commits free them, and
+//! not recorded in the free list. The protected set is not persisted but
+//! rebuilt by walking the savepoints when the database is opened, and again
+//! whenever a commit changes the registry. Pages no savepoint protects any
+//! more and no tree reaches are then freed by that commit.
+
+use std::collections::HashSet;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::btree::{BTree, PageStore, RangeState};
+use crate::buffer::PageId;
+use crate::pager::PagerSavepoint;
+use crate::txn::Retired;
+use crate::{Error, Inner, Result, table};
+
+/// Source of savepoint ids, unique within the process so a savepoint is
+/// never mistaken for one of another transaction
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A point within a [`WriteTransaction`](crate::WriteTransaction) to return
+/// to with [`rollback_to`](crate::WriteTransaction::rollback_to)
+///
+/// Valid until the transaction ends or rolls back to an earlier savepoint.
+#[derive(Debug)]
+pub struct Savepoint {
+    id: u64,
+}
+
+impl Savepoint {
+    pub(crate) fn new() -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+/// What rolling back to a savepoint restores
+pub(crate) struct SavepointState {
+    tree: BTree,
+    catalog: BTree,
+    savepoints: BTree,
+    restored: bool,
+    pager: PagerSavepoint,
+}
+
+/// What the registry records for a persistent savepoint
+///
+/// Encoded as the roots of the default tree and of the catalog.
+struct RegistryEntry {
+    tree: BTree,
+    catalog: BTree,
+}
+
+impl RegistryEntry {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(16);
+        out.extend_from_slice(&self.tree.root().to_le_bytes());
+        out.extend_from_slice(&self.catalog.root().to_le_bytes());
+        out
+    }
+
+    fn decode(name: &[u8], bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 16 {
+            return Err(Error::Corruption(format!(
+                "malformed registry entry for savepoint {:?}",
+                String::from_utf8_lossy(name)
+            )));
+        }
+        let root =
+            |at: usize| BTree::new(u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap()));
+        Ok(Self {
+            tree: root(0),
+            catalog: root(8),
+        })
+    }
+}
+
+impl Inner {
+    /// Record the state of the open write transaction
+    pub(crate) fn savepoint(&mut self) -> SavepointState {
+        SavepointState {
+            tree: self.tree,
+            catalog: self.catalog,
+            savepoints: self.savepoints,
+            restored: self.restored,
+            pager: self.pager.savepoint(),
+        }
+    }
+
+    /// Return the open write transaction to `state`
+    pub(crate) fn rollback_to(&mut self, state: &SavepointState) {
+        self.pager.rollback_to(&state.pager);
+        self.tree = state.tree;
+        self.catalog = state.catalog;
+        self.savepoints = state.savepoints;
+        self.restored = state.restored;
+    }
+
+    /// Record the last committed state as the persistent savepoint `name`
+    pub(crate) fn create_persistent_savepoint(&mut self, name: &str) -> Result<()> {
+        if self.registry_entry(name)?.is_some() {
+            return Err(Error::SavepointExists(name.to_owned()));
+        }
+        let entry = RegistryEntry {
+            tree: self.committed,
+            catalog: self.committed_catalog,
+        };
+        self.savepoints
+            .insert(&mut self.pager, name.as_bytes(), &entry.encode())?;
+        Ok(())
+    }
+
+    /// Remove the persistent savepoint `name`, returning whether it existed
+    pub(crate) fn delete_persistent_savepoint(&mut self, name: &str) -> Result<bool> {
+        Ok(self
+            .savepoints
+            .remove(&mut self.pager, name.as_bytes())?
+            .is_some())
+    }
+
+    /// Replace every tree of the open write transaction with the state
+    /// recorded as the persistent savepoint `name`
+    pub(crate) fn restore_persistent_savepoint(&mut self, name: &str) -> Result<()> {
+        let entry = self
+            .registry_entry(name)?
+            .ok_or_else(|| Error::SavepointNotFound(name.to_owned()))?;
+        for table in self.table_names()? {
+            self.delete_table(&table)?;
+        }
+        self.catalog.clear(&mut self.pager)?;
+        self.tree.clear(&mut self.pager)?;
+        // Pages the savepoint shares with the trees just cleared are in use
+        // again, which the commit sorts out
+        self.tree = entry.tree;
+        self.catalog = entry.catalog;
+        self.restored = true;
+        Ok(())
+    }
+
+    /// Names of the persistent savepoints, in order
+    pub(crate) fn persistent_savepoint_names(&mut self) -> Result<Vec<String>> {
+        let mut state = RangeState::new(Bound::Unbounded, Bound::Unbounded);
+        let mut names = Vec::new();
+        while let Some((name, _)) = state.next(&self.savepoints, &mut self.pager)? {
+            names.push(
+                String::from_utf8(name)
+                    .map_err(|_| Error::Corruption("savepoint name is not valid UTF-8".into()))?,
+            );
+        }
+        Ok(names)
+    }
+
+    fn registry_entry(&mut self, name: &str) -> Result<Option<RegistryEntry>> {
+        self.savepoints
+            .get(&mut self.pager, name.as_bytes())?
+            .map(|value| RegistryEntry::decode(name.as_bytes(), &value))
+            .transpose()
+    }
+
+    /// Rebuild the set of pages the persistent savepoints reach, for a
+    /// commit that changed the registry or restored a savepoint, or after
+    /// opening the database
+    ///
+    /// Pages that are no longer protected and that no tree reaches are freed
+    /// by the commit. After a restore, pages freed by clearing the trees
+    /// that the restored trees reach are kept.
+    pub(crate) fn protect(&mut self) -> Result<()> {
+        let mut protected = HashSet::new();
+        let mut state = RangeState::new(Bound::Unbounded, Bound::Unbounded);
+        while let Some((name, value)) = state.next(&self.savepoints, &mut self.pager)? {
+            let entry = RegistryEntry::decode(&name, &value)?;
+            entry.tree.collect_pages(&mut self.pager, &mut protected)?;
+            table::collect_pages(entry.catalog, &mut self.pager, &mut protected)?;
+        }
+        let dropped: Vec<PageId> = self
+            .protected
+            .iter()
+            .filter(|id| !protected.contains(id))
+            .copied()
+            .collect();
+        if dropped.is_empty() && !self.restored {
+            self.protected = protected;
+            return Ok(());
+        }
+
+        let mut live = protected.clone();
+        self.tree.collect_pages(&mut self.pager, &mut live)?;
+        table::collect_pages(self.catalog, &mut self.pager, &mut live)?;
+        self.savepoints.collect_pages(&mut self.pager, &mut live)?;
+        self.pager.keep_freed(&live);
+        for retired in &mut self.retired {
+            retired.keep(&live);
+        }
+        // Freed pages already await release
+        let pending: HashSet<PageId> = self
+            .retired
+            .iter()
+            .flat_map(Retired::pages)
+            .chain(self.pager.freed())
+            .copied()
+            .collect();
+        for id in dropped {
+            if !live.contains(&id) && !pending.contains(&id) && self.pager.is_committed(id) {
+                self.pager.free(id)?;
+            }
+        }
+        self.protected = protected;
+        self.restored = false;
+        Ok(())
+    }
+}
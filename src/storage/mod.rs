@@ -0,0 +1,707 @@
+//! Storage backends
+//!
+//! Every byte of the data file and the write-ahead log is read and written
+//! through a [`StorageBackend`]: positioned reads and writes, a durability
+//! barrier, and the length. [`Builder::open`](crate::Builder::open) uses a
+//! [`FileBackend`] for each; [`Builder::open_backend`](crate::Builder::open_backend)
+//! takes any pair.
+//!
+//! Besides plain files there is a [`MemoryBackend`], which makes for fast,
+//! disk-free tests, and a [`FaultyBackend`], an in-memory backend that knows
+//! which writes were synced. It can fail on demand and simulate a crash that
+//! loses or tears whatever was not synced, which is how the log and the
+//! page flusher are tested for crash safety.
+//!
+//! With the `io-uring` feature, a [`UringBackend`] submits the batches of
+//! page reads and writes that scans and checkpoints produce through io_uring.
+
+use std::fs::File;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use parking_lot::Mutex;
+
+use crate::buffer::{AlignedPage, PAGE_SIZE};
+
+#[cfg(feature = "io-uring")]
+mod uring;
+
+#[cfg(feature = "io-uring")]
+pub use uring::UringBackend;
+
+/// `EIO`, as returned by failing [`FaultyBackend`] operations
+const EIO: i32 = 5;
+
+/// Byte-addressed storage for the data file or the log
+pub trait StorageBackend: Send + Sync {
+    /// Fill `buf` with the bytes at `offset`, failing with
+    /// [`io::ErrorKind::UnexpectedEof`] past the end
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Write all of `buf` at `offset`, extending the storage if needed
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Make every write and length change so far durable
+    fn sync(&self) -> io::Result<()>;
+
+    /// Current length in bytes
+    fn len(&self) -> io::Result<u64>;
+
+    /// Whether the storage holds no bytes at all
+    fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Truncate or zero-extend to `len` bytes
+    fn set_len(&self, len: u64) -> io::Result<()>;
+
+    /// Fill each buffer with the bytes at its offset, as [`read_at`] would
+    ///
+    /// Backends that can have several reads in flight at once submit the
+    /// whole batch together; by default the reads run one after another.
+    ///
+    /// [`read_at`]: StorageBackend::read_at
+    fn read_batch(&self, reads: &mut [(&mut [u8], u64)]) -> io::Result<()> {
+        for (buf, offset) in reads {
+            self.read_at(buf, *offset)?;
+        }
+        Ok(())
+    }
+
+    /// Write each buffer at its offset, as [`write_at`] would
+    ///
+    /// Like [`read_batch`](StorageBackend::read_batch), the writes may be
+    /// submitted together and complete in any order, so they should not
+    /// overlap.
+    ///
+    /// [`write_at`]: StorageBackend::write_at
+    fn write_batch(&self, writes: &[(&[u8], u64)]) -> io::Result<()> {
+        for (buf, offset) in writes {
+            self.write_at(buf, *offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Storage in a file
+///
+/// A file opened with `O_DIRECT` (see [`Builder::direct_io`]) bypasses the OS
+/// page cache: page-aligned transfers go straight to the device, and anything
+/// else goes through an aligned bounce buffer. Should the filesystem reject
+/// direct transfers after all, the file falls back to buffered I/O for good.
+///
+/// [`Builder::direct_io`]: crate::Builder::direct_io
+#[derive(Debug)]
+pub struct FileBackend {
+    file: File,
+    /// The file is open with `O_DIRECT`
+    direct: AtomicBool,
+}
+
+impl FileBackend {
+    /// Use `file`, which must be open for reading (and writing, unless the
+    /// database is opened read-only)
+    pub fn new(file: File) -> Self {
+        let direct = direct::is_enabled(&file);
+        Self {
+            file,
+            direct: AtomicBool::new(direct),
+        }
+    }
+
+    /// Underlying file
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Whether transfers bypass the OS page cache
+    pub fn is_direct(&self) -> bool {
+        self.direct.load(Ordering::Relaxed)
+    }
+
+    /// Run `op` on a direct file, switching to buffered I/O and running it
+    /// again if the filesystem rejects it
+    fn direct<T>(&self, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        match op() {
+            Err(err) if direct::is_rejection(&err) => {
+                direct::disable(&self.file)?;
+                self.direct.store(false, Ordering::Relaxed);
+                op()
+            }
+            result => result,
+        }
+    }
+
+    /// Read the aligned span around `buf.len()` bytes at `offset` into a
+    /// bounce buffer and copy them out
+    fn bounce_read(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let (start, mut bounce) = bounce_span(buf.len(), offset);
+        let filled = self.direct(|| read_up_to(&self.file, bytes_of(&mut bounce), start))?;
+        let skip = (offset - start) as usize;
+        if filled < skip + buf.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.copy_from_slice(&bytes_of(&mut bounce)[skip..skip + buf.len()]);
+        Ok(())
+    }
+
+    /// Write `buf` at `offset` by rewriting the aligned span around it,
+    /// keeping the length the file would have had after a plain write
+    fn bounce_write(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let len = self.file.metadata()?.len();
+        let (start, mut bounce) = bounce_span(buf.len(), offset);
+        self.direct(|| read_up_to(&self.file, bytes_of(&mut bounce), start))?;
+        let skip = (offset - start) as usize;
+        bytes_of(&mut bounce)[skip..skip + buf.len()].copy_from_slice(buf);
+        self.direct(|| write_all_at(&self.file, bytes_of(&mut bounce), start))?;
+        let end = len.max(offset + buf.len() as u64);
+        if start + (bounce.len() * PAGE_SIZE) as u64 > end {
+            self.file.set_len(end)?;
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for FileBackend {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        if !self.is_direct() {
+            return read_exact_at(&self.file, buf, offset);
+        }
+        if !is_aligned(buf.as_ptr(), buf.len(), offset) {
+            return self.bounce_read(buf, offset);
+        }
+        self.direct(|| read_exact_at(&self.file, buf, offset))
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        if !self.is_direct() {
+            return write_all_at(&self.file, buf, offset);
+        }
+        if !is_aligned(buf.as_ptr(), buf.len(), offset) {
+            return self.bounce_write(buf, offset);
+        }
+        self.direct(|| write_all_at(&self.file, buf, offset))
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+}
+
+/// Whether a transfer of `len` bytes between `ptr` and `offset` meets the
+/// alignment direct I/O requires
+fn is_aligned(ptr: *const u8, len: usize, offset: u64) -> bool {
+    (ptr as usize).is_multiple_of(PAGE_SIZE)
+        && len.is_multiple_of(PAGE_SIZE)
+        && offset.is_multiple_of(PAGE_SIZE as u64)
+}
+
+/// Start and zeroed bounce buffer of the aligned span covering `len` bytes
+/// at `offset`
+fn bounce_span(len: usize, offset: u64) -> (u64, Vec<AlignedPage>) {
+    let start = offset - offset % PAGE_SIZE as u64;
+    let end = (offset + len as u64).next_multiple_of(PAGE_SIZE as u64);
+    let pages = ((end - start) / PAGE_SIZE as u64) as usize;
+    (start, vec![AlignedPage([0; PAGE_SIZE]); pages])
+}
+
+fn bytes_of(pages: &mut [AlignedPage]) -> &mut [u8] {
+    // SAFETY: `AlignedPage` is exactly `PAGE_SIZE` bytes with no padding, so
+    // the pages are contiguous bytes
+    unsafe { std::slice::from_raw_parts_mut(pages.as_mut_ptr().cast(), pages.len() * PAGE_SIZE) }
+}
+
+/// Fill `buf` from `offset` until it is full or the file ends, returning the
+/// number of bytes read
+fn read_up_to(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match positional::read_at(file, &mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// Fill `buf` from `offset`, failing with [`io::ErrorKind::UnexpectedEof`]
+/// if the file ends first
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    if read_up_to(file, buf, offset)? < buf.len() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Write all of `buf` at `offset`
+fn write_all_at(file: &File, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match positional::write_at(file, buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                buf = &buf[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Reads and writes at an offset, leaving the file position alone
+#[cfg(unix)]
+mod positional {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::fs::FileExt;
+
+    pub(super) fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        file.read_at(buf, offset)
+    }
+
+    pub(super) fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        file.write_at(buf, offset)
+    }
+}
+
+/// Reads and writes at an offset
+///
+/// These move the file position, but every transfer names its offset, so
+/// nothing relies on it.
+#[cfg(windows)]
+mod positional {
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::fs::FileExt;
+
+    pub(super) fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        file.seek_read(buf, offset)
+    }
+
+    pub(super) fn write_at(file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        file.seek_write(buf, offset)
+    }
+}
+
+/// Reads and writes at an offset, for systems without positional I/O: a seek
+/// then the transfer, under a lock so no other transfer moves the file
+/// position in between
+#[cfg(not(any(unix, windows)))]
+mod positional {
+    use std::fs::File;
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+
+    use parking_lot::Mutex;
+
+    /// Held from each seek until its transfer is done
+    static SEEK: Mutex<()> = Mutex::new(());
+
+    pub(super) fn read_at(mut file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let _seek = SEEK.lock();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read(buf)
+    }
+
+    pub(super) fn write_at(mut file: &File, buf: &[u8], offset: u64) -> io::Result<usize> {
+        let _seek = SEEK.lock();
+        file.seek(SeekFrom::Start(offset))?;
+        file.write(buf)
+    }
+}
+
+/// Opening and leaving `O_DIRECT` mode
+#[cfg(target_os = "linux")]
+mod direct {
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::Path;
+
+    /// Open `path` with `options` plus `O_DIRECT`, or without it if the
+    /// filesystem does not support direct I/O
+    pub(crate) fn open(options: &OpenOptions, path: &Path) -> io::Result<File> {
+        let mut direct = options.clone();
+        direct.custom_flags(libc::O_DIRECT);
+        match direct.open(path) {
+            Err(err) if is_rejection(&err) => options.open(path),
+            result => result,
+        }
+    }
+
+    pub(crate) fn is_enabled(file: &File) -> bool {
+        // SAFETY: F_GETFL only reads the flags of a descriptor we own
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        flags >= 0 && flags & libc::O_DIRECT != 0
+    }
+
+    pub(crate) fn disable(file: &File) -> io::Result<()> {
+        let fd = file.as_raw_fd();
+        // SAFETY: F_GETFL and F_SETFL only change the flags of a descriptor
+        // we own
+        let done = unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            flags >= 0 && libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_DIRECT) >= 0
+        };
+        if done {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Whether `err` is the filesystem turning down direct I/O
+    pub(crate) fn is_rejection(err: &io::Error) -> bool {
+        err.raw_os_error() == Some(libc::EINVAL)
+    }
+}
+
+/// Systems without `O_DIRECT` always use buffered I/O
+#[cfg(not(target_os = "linux"))]
+mod direct {
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::path::Path;
+
+    pub(crate) fn open(options: &OpenOptions, path: &Path) -> io::Result<File> {
+        options.open(path)
+    }
+
+    pub(crate) fn is_enabled(_file: &File) -> bool {
+        false
+    }
+
+    pub(crate) fn disable(_file: &File) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub(crate) fn is_rejection(_err: &io::Error) -> bool {
+        false
+    }
+}
+
+pub(crate) use direct::open as open_direct;
+
+/// Storage in memory, shared between clones
+///
+/// Useful for tests and scratch databases. A database reopened on a clone of
+/// the backend it was created on sees the same bytes.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackend {
+    data: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    /// Empty storage
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        read(&self.data.lock(), buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        write(&mut self.data.lock(), buf, offset);
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.data.lock().len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.data.lock().resize(len as usize, 0);
+        Ok(())
+    }
+}
+
+/// In-memory storage that fails and crashes on demand, shared between clones
+///
+/// Changes are only durable once synced. [`FaultyBackend::crash`] drops every
+/// change made since the last sync, and [`FaultyBackend::crash_torn`] keeps
+/// just the start of each write, like a power cut in the middle of writing
+/// pages. After [`FaultyBackend::fail_after`], operations start failing with
+/// `EIO` until [`FaultyBackend::heal`].
+#[derive(Debug, Clone, Default)]
+pub struct FaultyBackend {
+    state: Arc<Mutex<FaultState>>,
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    /// Contents as readers see them
+    current: Vec<u8>,
+    /// Contents as of the last sync
+    durable: Vec<u8>,
+    /// Changes since the last sync, oldest first
+    unsynced: Vec<Change>,
+    /// Operations left before every operation fails (`None` = never)
+    budget: Option<u64>,
+}
+
+#[derive(Debug)]
+enum Change {
+    Write { offset: u64, data: Vec<u8> },
+    SetLen(u64),
+}
+
+impl FaultState {
+    /// Count an operation against the budget, failing once it is spent
+    fn spend(&mut self) -> io::Result<()> {
+        match &mut self.budget {
+            Some(0) => Err(io::Error::from_raw_os_error(EIO)),
+            Some(left) => {
+                *left -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl FaultyBackend {
+    /// Empty storage that works until told otherwise
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Let the next `ops` reads, writes, syncs and length changes succeed and
+    /// fail every one after them with `EIO`
+    pub fn fail_after(&self, ops: u64) {
+        self.state.lock().budget = Some(ops);
+    }
+
+    /// Stop failing operations
+    pub fn heal(&self) {
+        self.state.lock().budget = None;
+    }
+
+    /// Whether changes were made since the last sync
+    pub fn has_unsynced(&self) -> bool {
+        !self.state.lock().unsynced.is_empty()
+    }
+
+    /// Lose every change made since the last sync
+    pub fn crash(&self) {
+        self.crash_torn(0);
+    }
+
+    /// Lose the changes made since the last sync, except for the first
+    /// `keep` bytes of each write
+    ///
+    /// Length changes survive if `keep` is not zero.
+    pub fn crash_torn(&self, keep: usize) {
+        let state = &mut *self.state.lock();
+        let mut data = state.durable.clone();
+        for change in state.unsynced.drain(..) {
+            match change {
+                Change::Write {
+                    offset,
+                    data: bytes,
+                } => {
+                    let kept = &bytes[..bytes.len().min(keep)];
+                    if !kept.is_empty() {
+                        write(&mut data, kept, offset);
+                    }
+                }
+                Change::SetLen(len) if keep > 0 => data.resize(len as usize, 0),
+                Change::SetLen(_) => {}
+            }
+        }
+        state.current = data.clone();
+        state.durable = data;
+    }
+}
+
+impl StorageBackend for FaultyBackend {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let state = &mut *self.state.lock();
+        state.spend()?;
+        read(&state.current, buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        let state = &mut *self.state.lock();
+        state.spend()?;
+        write(&mut state.current, buf, offset);
+        state.unsynced.push(Change::Write {
+            offset,
+            data: buf.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        let state = &mut *self.state.lock();
+        state.spend()?;
+        state.durable.clone_from(&state.current);
+        state.unsynced.clear();
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.state.lock().current.len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        let state = &mut *self.state.lock();
+        state.spend()?;
+        state.current.resize(len as usize, 0);
+        state.unsynced.push(Change::SetLen(len));
+        Ok(())
+    }
+}
+
+fn read(data: &[u8], buf: &mut [u8], offset: u64) -> io::Result<()> {
+    let start = offset as usize;
+    match data.get(start..start + buf.len()) {
+        Some(bytes) => {
+            buf.copy_from_slice(bytes);
+            Ok(())
+        }
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+fn write(data: &mut Vec<u8>, buf: &[u8], offset: u64) {
+    let start = offset as usize;
+    if data.len() < start + buf.len() {
+        data.resize(start + buf.len(), 0);
+    }
+    data[start..start + buf.len()].copy_from_slice(buf);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_reads_back_writes() {
+        let storage = MemoryBackend::new();
+        storage.write_at(b"world", 6).unwrap();
+        storage.write_at(b"hello", 0).unwrap();
+        let mut buf = [0; 11];
+        storage.clone().read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"hello\0world");
+        let err = storage.read_at(&mut buf, 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        storage.set_len(5).unwrap();
+        assert_eq!(storage.len().unwrap(), 5);
+    }
+
+    #[test]
+    fn batches_default_to_single_operations() {
+        let storage = MemoryBackend::new();
+        storage.write_batch(&[(b"one", 0), (b"two", 8)]).unwrap();
+        let (mut one, mut two) = ([0; 3], [0; 3]);
+        storage
+            .read_batch(&mut [(&mut two, 8), (&mut one, 0)])
+            .unwrap();
+        assert_eq!((&one, &two), (b"one", b"two"));
+        let mut past = [0; 3];
+        assert!(storage.read_batch(&mut [(&mut past, 10)]).is_err());
+    }
+
+    #[test]
+    fn files_read_back_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileBackend::new(File::create_new(dir.path().join("file")).unwrap());
+        storage.write_at(b"world", 6).unwrap();
+        storage.write_at(b"hello", 0).unwrap();
+        let mut buf = [0; 11];
+        storage.read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"hello\0world");
+        let err = storage.read_at(&mut buf, 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(storage.len().unwrap(), 11);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn direct_files_take_any_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = std::fs::OpenOptions::new();
+        options.read(true).write(true).create(true);
+        let storage = FileBackend::new(open_direct(&options, &dir.path().join("direct")).unwrap());
+        assert!(storage.is_direct());
+
+        let page = AlignedPage([7; PAGE_SIZE]);
+        storage.write_at(&page[..], 0).unwrap();
+        // Unaligned writes keep the neighbouring bytes and the exact length
+        storage.write_at(b"hello", 10).unwrap();
+        storage.write_at(b"tail", PAGE_SIZE as u64 + 3).unwrap();
+        assert_eq!(storage.len().unwrap(), PAGE_SIZE as u64 + 7);
+
+        let mut copy = AlignedPage([0; PAGE_SIZE]);
+        storage.read_at(&mut copy[..], 0).unwrap();
+        assert_eq!(&copy[8..17], b"\x07\x07hello\x07\x07");
+        let mut tail = [0; 6];
+        storage.read_at(&mut tail, PAGE_SIZE as u64 + 1).unwrap();
+        assert_eq!(&tail, b"\0\0tail");
+        let err = storage
+            .read_at(&mut tail, PAGE_SIZE as u64 + 2)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(storage.is_direct());
+    }
+
+    #[test]
+    fn crash_loses_unsynced_changes() {
+        let storage = FaultyBackend::new();
+        storage.write_at(b"synced", 0).unwrap();
+        storage.sync().unwrap();
+        storage.write_at(b"lost", 0).unwrap();
+        storage.set_len(100).unwrap();
+        assert!(storage.has_unsynced());
+        storage.crash();
+
+        let mut buf = [0; 6];
+        storage.read_at(&mut buf, 0).unwrap();
+        assert_eq!(&buf, b"synced");
+        assert_eq!(storage.len().unwrap(), 6);
+        assert!(!storage.has_unsynced());
+    }
+
+    #[test]
+    fn torn_crash_keeps_the_start_of_each_write() {
+        let storage = FaultyBackend::new();
+        storage.write_at(&[1; 8], 0).unwrap();
+        storage.write_at(&[2; 8], 8).unwrap();
+        storage.crash_torn(3);
+
+        let mut buf = [0; 11];
+        storage.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [1, 1, 1, 0, 0, 0, 0, 0, 2, 2, 2]);
+    }
+
+    #[test]
+    fn fails_on_demand() {
+        let storage = FaultyBackend::new();
+        storage.fail_after(1);
+        storage.write_at(b"ok", 0).unwrap();
+        let err = storage.sync().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(EIO));
+        assert!(storage.write_at(b"no", 0).is_err());
+        storage.heal();
+        storage.sync().unwrap();
+    }
+}
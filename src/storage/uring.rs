@@ -0,0 +1,403 @@
+//! io_uring backend for Linux
+//!
+//! Single reads and writes go through a [`FileBackend`], and so does
+//! anything but whole pages. Batches of pages, which prefetching scans and
+//! checkpoints hand over, are queued on an io_uring and submitted with one
+//! system call, so an NVMe device sees up to [`QUEUE_DEPTH`] requests at once
+//! instead of one at a time.
+//!
+//! Batched operations read into and write from the caller's buffers directly:
+//! the frames a prefetch fills and the page images a checkpoint writes. The
+//! buffers of a batch are registered with the ring while it runs, so the
+//! kernel maps them once per batch rather than once per operation, and no
+//! page is copied on the way.
+//!
+//! Both kinds of buffer are page-aligned, so batches work on files opened for
+//! direct I/O as well; a batch on a direct file with a buffer that is not is
+//! done page by page through the [`FileBackend`], as is a direct batch the
+//! filesystem rejects, after which the file uses buffered I/O. Should the
+//! ring itself fail, the operations in flight are waited for and the backend
+//! carries on through the [`FileBackend`] for good.
+
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+
+use io_uring::{IoUring, opcode, types};
+use parking_lot::Mutex;
+
+use super::{FileBackend, StorageBackend, direct, is_aligned};
+use crate::buffer::PAGE_SIZE;
+
+/// Operations submitted together, and buffers registered with the ring
+const QUEUE_DEPTH: usize = 64;
+
+/// `IORING_ENTER_GETEVENTS`, waiting for completions without submitting
+const ENTER_GETEVENTS: u32 = 1;
+
+/// Storage in a file, with batches submitted through io_uring
+///
+/// Only available with the `io-uring` feature.
+pub struct UringBackend {
+    file: FileBackend,
+    ring: Mutex<Ring>,
+}
+
+impl std::fmt::Debug for UringBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UringBackend")
+            .field("file", &self.file)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Ring with a table of [`QUEUE_DEPTH`] buffer slots, empty between batches
+struct Ring {
+    ring: IoUring,
+    /// The ring failed and is no longer used
+    broken: bool,
+}
+
+/// A page-sized transfer through the ring: buffer, length and file offset
+type Op = (*mut u8, usize, u64);
+
+impl UringBackend {
+    /// Use `file`, which must be open for reading (and writing, unless the
+    /// database is opened read-only)
+    ///
+    /// Fails if the kernel does not support io_uring with buffer updates
+    /// (Linux 5.13) or does not let this process use it.
+    pub fn new(file: File) -> io::Result<Self> {
+        let ring = IoUring::new(QUEUE_DEPTH as u32)?;
+        ring.submitter()
+            .register_buffers_sparse(QUEUE_DEPTH as u32)?;
+        Ok(Self {
+            file: FileBackend::new(file),
+            ring: Mutex::new(Ring {
+                ring,
+                broken: false,
+            }),
+        })
+    }
+
+    /// Underlying file
+    pub fn file(&self) -> &File {
+        self.file.file()
+    }
+
+    /// Whether a batch of `(buffer, length, offset)` transfers can go through
+    /// the ring: whole pages, and page-aligned buffers on a direct file
+    fn fits_ring(&self, batch: impl IntoIterator<Item = (*const u8, usize, u64)>) -> bool {
+        let direct = self.file.is_direct();
+        batch.into_iter().all(|(ptr, len, offset)| {
+            len == PAGE_SIZE
+                && offset.is_multiple_of(PAGE_SIZE as u64)
+                && (!direct || is_aligned(ptr, len, offset))
+        })
+    }
+
+    /// Run `ops` through the ring, or report that the batch must go through
+    /// the file instead by returning `None`
+    ///
+    /// # Safety
+    ///
+    /// Each buffer must be valid for its length, and for writes when reading,
+    /// for the duration of the call.
+    unsafe fn run(
+        &self,
+        ring: &mut Ring,
+        ops: &[Op],
+        write: bool,
+    ) -> io::Result<Option<Vec<usize>>> {
+        if ring.broken {
+            return Ok(None);
+        }
+        // SAFETY: passed on from the caller
+        match unsafe { ring.run(self.file(), ops, write) } {
+            Err(err) if self.file.is_direct() && direct::is_rejection(&err) => Ok(None),
+            Err(_) if ring.broken => Ok(None),
+            result => result.map(Some),
+        }
+    }
+}
+
+impl Ring {
+    /// Read or write `ops` through the ring and return how many bytes each
+    /// transferred
+    ///
+    /// The buffers are registered for the length of the call, and nothing is
+    /// left in flight when it returns. If the ring fails to submit, it is
+    /// marked broken.
+    ///
+    /// # Safety
+    ///
+    /// As for [`UringBackend::run`].
+    unsafe fn run(&mut self, file: &File, ops: &[Op], write: bool) -> io::Result<Vec<usize>> {
+        debug_assert!(ops.len() <= QUEUE_DEPTH);
+        let iovecs: Vec<_> = ops
+            .iter()
+            .map(|&(buf, len, _)| libc::iovec {
+                iov_base: buf.cast(),
+                iov_len: len,
+            })
+            .collect();
+        // SAFETY: the caller keeps the buffers valid until the call returns,
+        // and the slots are emptied again before it does
+        unsafe {
+            self.ring
+                .submitter()
+                .register_buffers_update(0, &iovecs, None)?
+        };
+        let fd = types::Fd(file.as_raw_fd());
+        for (index, &(buf, len, offset)) in ops.iter().enumerate() {
+            let entry = if write {
+                opcode::WriteFixed::new(fd, buf, len as u32, index as u16)
+                    .offset(offset)
+                    .build()
+            } else {
+                opcode::ReadFixed::new(fd, buf, len as u32, index as u16)
+                    .offset(offset)
+                    .build()
+            };
+            // SAFETY: the buffer is registered under `index` and outlives the
+            // operation, whose completion is reaped before returning
+            unsafe { self.ring.submission().push(&entry.user_data(index as u64)) }
+                .expect("the queue holds a whole batch");
+        }
+
+        let mut done = vec![0; ops.len()];
+        let mut result = Ok(());
+        let (mut submitted, mut reaped) = (0, 0);
+        while reaped < ops.len() {
+            match self.ring.submit_and_wait(ops.len() - reaped) {
+                Ok(count) => submitted += count,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    // The entries not submitted stay queued, so the ring can
+                    // no longer be entered
+                    self.broken = true;
+                    result = Err(err);
+                    break;
+                }
+            }
+            reaped += self.reap(&mut done, &mut result);
+        }
+        // Operations already in flight still use the buffers
+        while reaped < submitted {
+            // SAFETY: waits for completions only, submitting nothing
+            let waited = unsafe {
+                self.ring
+                    .submitter()
+                    .enter::<libc::sigset_t>(0, 1, ENTER_GETEVENTS, None)
+            };
+            if let Err(err) = waited
+                && !matches!(
+                    err.raw_os_error(),
+                    Some(libc::EINTR | libc::EAGAIN | libc::EBUSY)
+                )
+            {
+                // Returning would hand buffers the kernel may still write to
+                // back to the caller
+                eprintln!("qpdb: cannot wait for io_uring completions: {err}");
+                std::process::abort();
+            }
+            reaped += self.reap(&mut done, &mut result);
+        }
+
+        let empty = vec![
+            libc::iovec {
+                iov_base: std::ptr::null_mut(),
+                iov_len: 0,
+            };
+            ops.len()
+        ];
+        // SAFETY: empty slots refer to no memory
+        if unsafe {
+            self.ring
+                .submitter()
+                .register_buffers_update(0, &empty, None)
+        }
+        .is_err()
+        {
+            // A slot left pointing at the caller's buffer must never be used
+            self.broken = true;
+        }
+        result.map(|()| done)
+    }
+
+    /// Record the completions ready in `done`, or the error of any that
+    /// failed in `result`, and return how many there were
+    fn reap(&mut self, done: &mut [usize], result: &mut io::Result<()>) -> usize {
+        let mut reaped = 0;
+        for entry in self.ring.completion() {
+            reaped += 1;
+            match entry.result() {
+                len if len >= 0 => done[entry.user_data() as usize] = len as usize,
+                errno => *result = Err(io::Error::from_raw_os_error(-errno)),
+            }
+        }
+        reaped
+    }
+}
+
+impl StorageBackend for UringBackend {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.file.read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.file.write_at(buf, offset)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        self.file.len()
+    }
+
+    fn set_len(&self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn read_batch(&self, reads: &mut [(&mut [u8], u64)]) -> io::Result<()> {
+        let batch = reads
+            .iter()
+            .map(|(buf, offset)| (buf.as_ptr(), buf.len(), *offset));
+        if !self.fits_ring(batch) {
+            return self.file.read_batch(reads);
+        }
+        let ring = &mut *self.ring.lock();
+        for batch in reads.chunks_mut(QUEUE_DEPTH) {
+            let ops: Vec<Op> = batch
+                .iter_mut()
+                .map(|(buf, offset)| (buf.as_mut_ptr(), buf.len(), *offset))
+                .collect();
+            // SAFETY: the buffers are borrowed mutably for the whole call
+            let Some(done) = (unsafe { self.run(ring, &ops, false) })? else {
+                self.file.read_batch(batch)?;
+                continue;
+            };
+            for (&len, (buf, offset)) in done.iter().zip(batch) {
+                // Short reads only happen at the end of the file, where this
+                // reports the missing bytes
+                if len < buf.len() {
+                    self.file.read_at(&mut buf[len..], *offset + len as u64)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn write_batch(&self, writes: &[(&[u8], u64)]) -> io::Result<()> {
+        let batch = writes
+            .iter()
+            .map(|(buf, offset)| (buf.as_ptr(), buf.len(), *offset));
+        if !self.fits_ring(batch) {
+            return self.file.write_batch(writes);
+        }
+        let ring = &mut *self.ring.lock();
+        for batch in writes.chunks(QUEUE_DEPTH) {
+            // The kernel only reads from the buffers of writes
+            let ops: Vec<Op> = batch
+                .iter()
+                .map(|(buf, offset)| (buf.as_ptr().cast_mut(), buf.len(), *offset))
+                .collect();
+            // SAFETY: the buffers are borrowed for the whole call
+            let Some(done) = (unsafe { self.run(ring, &ops, true) })? else {
+                self.file.write_batch(batch)?;
+                continue;
+            };
+            for (&len, (buf, offset)) in done.iter().zip(batch) {
+                if len < buf.len() {
+                    self.file.write_at(&buf[len..], *offset + len as u64)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::AlignedPage;
+
+    #[test]
+    fn batches_match_single_operations() {
+        let storage = UringBackend::new(tempfile::tempfile().unwrap()).unwrap();
+        let pages: Vec<_> = (0..150u8).map(|n| [n; PAGE_SIZE]).collect();
+        let writes: Vec<_> = pages
+            .iter()
+            .enumerate()
+            .map(|(n, page)| (&page[..], (n * PAGE_SIZE) as u64))
+            .collect();
+        storage.write_batch(&writes).unwrap();
+
+        let mut single = [0; PAGE_SIZE];
+        storage.read_at(&mut single, 70 * PAGE_SIZE as u64).unwrap();
+        assert_eq!(single, pages[70]);
+        let mut copies = vec![[0; PAGE_SIZE]; 150];
+        let mut reads: Vec<_> = copies
+            .iter_mut()
+            .enumerate()
+            .rev()
+            .map(|(n, copy)| (&mut copy[..], (n * PAGE_SIZE) as u64))
+            .collect();
+        storage.read_batch(&mut reads).unwrap();
+        assert_eq!(copies, pages);
+        assert!(!storage.ring.lock().broken);
+    }
+
+    #[test]
+    fn batched_reads_past_the_end_fail() {
+        let storage = UringBackend::new(tempfile::tempfile().unwrap()).unwrap();
+        storage.write_at(&[1; PAGE_SIZE + 100], 0).unwrap();
+        let (mut first, mut second) = ([0; PAGE_SIZE], [0; PAGE_SIZE]);
+        let err = storage
+            .read_batch(&mut [(&mut first, 0), (&mut second, PAGE_SIZE as u64)])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(first, [1; PAGE_SIZE]);
+    }
+
+    #[test]
+    fn batches_work_on_direct_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut options = std::fs::OpenOptions::new();
+        options.read(true).write(true).create(true);
+        let file = direct::open(&options, &dir.path().join("direct")).unwrap();
+        let storage = UringBackend::new(file).unwrap();
+        assert!(storage.file.is_direct());
+
+        let pages = [AlignedPage([5; PAGE_SIZE]), AlignedPage([6; PAGE_SIZE])];
+        storage
+            .write_batch(&[(&pages[1][..], PAGE_SIZE as u64), (&pages[0][..], 0)])
+            .unwrap();
+        let mut copies = [AlignedPage([0; PAGE_SIZE]), AlignedPage([0; PAGE_SIZE])];
+        let [first, second] = &mut copies;
+        storage
+            .read_batch(&mut [(&mut first[..], 0), (&mut second[..], PAGE_SIZE as u64)])
+            .unwrap();
+        assert_eq!((copies[0][0], copies[1][0]), (5, 6));
+        // Unaligned buffers take the page by page path
+        let mut unaligned = [0; PAGE_SIZE];
+        storage.read_batch(&mut [(&mut unaligned, 0)]).unwrap();
+        assert_eq!(unaligned, [5; PAGE_SIZE]);
+        assert!(storage.file.is_direct());
+    }
+
+    #[test]
+    fn broken_rings_fall_back_to_the_file() {
+        let storage = UringBackend::new(tempfile::tempfile().unwrap()).unwrap();
+        storage.ring.lock().broken = true;
+        let page = [3; PAGE_SIZE];
+        storage.write_batch(&[(&page, PAGE_SIZE as u64)]).unwrap();
+        let mut copy = [0; PAGE_SIZE];
+        storage
+            .read_batch(&mut [(&mut copy, PAGE_SIZE as u64)])
+            .unwrap();
+        assert_eq!(copy, page);
+    }
+}
@@ -0,0 +1,518 @@
+//! Named tables
+//!
+//! Besides its default tree, a database holds any number of named tables,
+//! each an independent B-tree. The root of every table is recorded in the
+//! catalog, itself a B-tree keyed by table name whose root is published by
+//! every commit alongside the default tree's, so a commit that touches several
+//! tables is exactly as atomic as one that touches a single tree.
+//!
+//! A [`Table`] looks its root up in the catalog on every operation and writes
+//! it back when the operation moved it, so handles hold nothing but a name.
+//!
+//! A table may also be declared with a [`TableDefinition`], which fixes its
+//! key and value types. The catalog records their names when the table is
+//! created and opening it as other types fails. Typed handles are thin
+//! wrappers that encode and decode through the [`Key`] and [`Value`] traits.
+
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+use parking_lot::Mutex;
+
+use crate::btree::{BTree, RangeState};
+use crate::buffer::PageId;
+use crate::pager::Pager;
+use crate::types::{self, Key, Value};
+use crate::{Cursor, Database, Error, Inner, Range, Result};
+
+/// Key and value types a table was created with
+///
+/// Tables created through the untyped API record empty names and may be
+/// opened with any types only through that API.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct Schema {
+    key: String,
+    value: String,
+}
+
+impl Schema {
+    pub fn of<K: Key + ?Sized, V: Value + ?Sized>() -> Self {
+        Self {
+            key: K::type_name(),
+            value: V::type_name(),
+        }
+    }
+
+    fn describe(&self) -> String {
+        if *self == Schema::default() {
+            "untyped bytes".into()
+        } else {
+            format!("<{}, {}>", self.key, self.value)
+        }
+    }
+}
+
+/// What the catalog records for a table
+///
+/// Encoded as the root page id, the length of the key type name as a `u16`,
+/// then the key and value type names.
+struct CatalogEntry {
+    tree: BTree,
+    schema: Schema,
+}
+
+impl CatalogEntry {
+    fn encode(&self) -> Vec<u8> {
+        let key = self.schema.key.as_bytes();
+        let mut out = Vec::with_capacity(10 + key.len() + self.schema.value.len());
+        out.extend_from_slice(&self.tree.root().to_le_bytes());
+        out.extend_from_slice(&(key.len() as u16).to_le_bytes());
+        out.extend_from_slice(key);
+        out.extend_from_slice(self.schema.value.as_bytes());
+        out
+    }
+
+    fn decode(name: &str, bytes: &[u8]) -> Result<Self> {
+        let malformed =
+            || Error::Corruption(format!("malformed catalog entry for table {:?}", name));
+        if bytes.len() < 10 {
+            return Err(malformed());
+        }
+        let root = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let key_len = u16::from_le_bytes(bytes[8..10].try_into().unwrap()) as usize;
+        let names = &bytes[10..];
+        if names.len() < key_len {
+            return Err(malformed());
+        }
+        let (key, value) = names.split_at(key_len);
+        let text = |bytes: &[u8]| String::from_utf8(bytes.to_vec()).map_err(|_| malformed());
+        Ok(Self {
+            tree: BTree::new(root),
+            schema: Schema {
+                key: text(key)?,
+                value: text(value)?,
+            },
+        })
+    }
+
+    /// Fail unless the table was created with `schema`
+    fn check(&self, name: &str, schema: &Schema) -> Result<()> {
+        if self.schema != *schema {
+            return Err(Error::TableTypeMismatch {
+                table: name.to_owned(),
+                expected: schema.describe(),
+                found: self.schema.describe(),
+            });
+        }
+        Ok(())
+    }
+}
+
+fn lookup_entry(catalog: BTree, pager: &mut Pager, name: &str) -> Result<Option<CatalogEntry>> {
+    catalog
+        .get(pager, name.as_bytes())?
+        .map(|value| CatalogEntry::decode(name, &value))
+        .transpose()
+}
+
+/// Tree recorded for `name` in `catalog`, checked against `schema` if given
+pub(crate) fn lookup(
+    catalog: BTree,
+    pager: &mut Pager,
+    name: &str,
+    schema: Option<&Schema>,
+) -> Result<BTree> {
+    let entry =
+        lookup_entry(catalog, pager, name)?.ok_or_else(|| Error::TableNotFound(name.to_owned()))?;
+    if let Some(schema) = schema {
+        entry.check(name, schema)?;
+    }
+    Ok(entry.tree)
+}
+
+/// Names of the tables in `catalog`, in order
+pub(crate) fn list(inner: &Mutex<Inner>, catalog: BTree) -> Result<Vec<String>> {
+    Range::new::<&[u8]>(inner, catalog, None, ..)
+        .map(|entry| {
+            let (name, _) = entry?;
+            String::from_utf8(name)
+                .map_err(|_| Error::Corruption("table name is not valid UTF-8".into()))
+        })
+        .collect()
+}
+
+/// Add the pages of `catalog` and of every table it records to `pages`
+pub(crate) fn collect_pages(
+    catalog: BTree,
+    pager: &mut Pager,
+    pages: &mut HashSet<PageId>,
+) -> Result<()> {
+    catalog.collect_pages(pager, pages)?;
+    let mut state = RangeState::new(Bound::Unbounded, Bound::Unbounded);
+    while let Some((name, value)) = state.next(&catalog, pager)? {
+        let name = String::from_utf8_lossy(&name);
+        CatalogEntry::decode(&name, &value)?
+            .tree
+            .collect_pages(pager, pages)?;
+    }
+    Ok(())
+}
+
+impl Inner {
+    /// Create the table `name` in the open write transaction unless it
+    /// exists, in which case it is checked against `schema` if given
+    pub(crate) fn create_table(&mut self, name: &str, schema: Option<&Schema>) -> Result<()> {
+        match lookup_entry(self.catalog, &mut self.pager, name)? {
+            Some(entry) => match schema {
+                Some(schema) => entry.check(name, schema),
+                None => Ok(()),
+            },
+            None => {
+                let entry = CatalogEntry {
+                    tree: BTree::new(0),
+                    schema: schema.cloned().unwrap_or_default(),
+                };
+                self.put_entry(name, &entry)
+            }
+        }
+    }
+
+    fn put_entry(&mut self, name: &str, entry: &CatalogEntry) -> Result<()> {
+        self.catalog
+            .insert(&mut self.pager, name.as_bytes(), &entry.encode())?;
+        Ok(())
+    }
+
+    /// Names of the tables in the open write transaction's catalog, in order
+    pub(crate) fn table_names(&mut self) -> Result<Vec<String>> {
+        let mut state = RangeState::new(Bound::Unbounded, Bound::Unbounded);
+        let mut names = Vec::new();
+        while let Some((name, _)) = state.next(&self.catalog, &mut self.pager)? {
+            names.push(
+                String::from_utf8(name)
+                    .map_err(|_| Error::Corruption("table name is not valid UTF-8".into()))?,
+            );
+        }
+        Ok(names)
+    }
+
+    /// Run `op` against the table `name`, recording its new root if it moved
+    pub(crate) fn with_table<T>(
+        &mut self,
+        name: &str,
+        op: impl FnOnce(&mut BTree, &mut Pager) -> Result<T>,
+    ) -> Result<T> {
+        let mut entry = lookup_entry(self.catalog, &mut self.pager, name)?
+            .ok_or_else(|| Error::TableNotFound(name.to_owned()))?;
+        let before = entry.tree;
+        let result = op(&mut entry.tree, &mut self.pager)?;
+        if entry.tree != before {
+            self.put_entry(name, &entry)?;
+        }
+        Ok(result)
+    }
+
+    /// Give the table `from` the name `to`
+    pub(crate) fn rename_table(&mut self, from: &str, to: &str) -> Result<()> {
+        let entry = lookup_entry(self.catalog, &mut self.pager, from)?
+            .ok_or_else(|| Error::TableNotFound(from.to_owned()))?;
+        if lookup_entry(self.catalog, &mut self.pager, to)?.is_some() {
+            return Err(Error::TableExists(to.to_owned()));
+        }
+        self.put_entry(to, &entry)?;
+        self.catalog.remove(&mut self.pager, from.as_bytes())?;
+        Ok(())
+    }
+
+    /// Remove the table `name` and free its pages, returning whether it existed
+    pub(crate) fn delete_table(&mut self, name: &str) -> Result<bool> {
+        let Some(mut entry) = lookup_entry(self.catalog, &mut self.pager, name)? else {
+            return Ok(false);
+        };
+        entry.tree.clear(&mut self.pager)?;
+        self.catalog.remove(&mut self.pager, name.as_bytes())?;
+        Ok(true)
+    }
+}
+
+/// A named table opened by a [`WriteTransaction`](crate::WriteTransaction)
+///
+/// Changes become visible to others and durable when the transaction
+/// commits, together with every other table it modified.
+pub struct Table<'txn> {
+    db: &'txn Database,
+    /// Tables the transaction has open, from which this one is removed on drop
+    open: &'txn Mutex<HashSet<String>>,
+    name: String,
+}
+
+impl<'txn> Table<'txn> {
+    pub(crate) fn new(db: &'txn Database, open: &'txn Mutex<HashSet<String>>, name: &str) -> Self {
+        Self {
+            db,
+            open,
+            name: name.to_owned(),
+        }
+    }
+
+    /// Name of the table
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Look up the value stored under `key`, including uncommitted changes
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db
+            .inner
+            .lock()
+            .with_table(&self.name, |tree, pager| tree.get(pager, key))
+    }
+
+    /// Insert or replace `key`, returning the previous value
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db
+            .inner
+            .lock()
+            .with_table(&self.name, |tree, pager| tree.insert(pager, key, value))
+    }
+
+    /// Remove `key`, returning its value if it was present
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db
+            .inner
+            .lock()
+            .with_table(&self.name, |tree, pager| tree.remove(pager, key))
+    }
+
+    /// Iterate over all entries in key order, including uncommitted changes
+    pub fn iter(&self) -> Result<Range<'_>> {
+        self.range::<&[u8]>(..)
+    }
+
+    /// Iterate over the entries whose keys fall within `range`, in key order
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Result<Range<'_>> {
+        let tree = self.tree()?;
+        Ok(Range::new(&self.db.inner, tree, None, range))
+    }
+
+    /// Create an unpositioned cursor
+    pub fn cursor(&self) -> Result<Cursor<'_>> {
+        let tree = self.tree()?;
+        Ok(Cursor::new(&self.db.inner, tree, None))
+    }
+
+    fn tree(&self) -> Result<BTree> {
+        self.db
+            .inner
+            .lock()
+            .with_table(&self.name, |tree, _| Ok(*tree))
+    }
+}
+
+impl Drop for Table<'_> {
+    fn drop(&mut self) {
+        self.open.lock().remove(&self.name);
+    }
+}
+
+/// A named table as of the snapshot of a
+/// [`ReadTransaction`](crate::ReadTransaction)
+pub struct ReadOnlyTable<'txn> {
+    db: &'txn Database,
+    tree: BTree,
+}
+
+impl<'txn> ReadOnlyTable<'txn> {
+    pub(crate) fn new(db: &'txn Database, tree: BTree) -> Self {
+        Self { db, tree }
+    }
+
+    /// Look up the value stored under `key`
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let inner = &mut *self.db.inner.lock();
+        self.tree.get(&mut inner.pager, key)
+    }
+
+    /// Iterate over all entries in key order
+    pub fn iter(&self) -> Range<'_> {
+        self.range::<&[u8]>(..)
+    }
+
+    /// Iterate over the entries whose keys fall within `range`, in key order
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Range<'_> {
+        Range::new(&self.db.inner, self.tree, None, range)
+    }
+
+    /// Create an unpositioned cursor
+    pub fn cursor(&self) -> Cursor<'_> {
+        Cursor::new(&self.db.inner, self.tree, None)
+    }
+}
+
+/// Name and key/value types of a typed table
+///
+/// Usually declared once as a constant:
+///
+/// ```
+/// use qpdb::TableDefinition;
+///
+/// const USERS: TableDefinition<str, u64> = TableDefinition::new("users");
+/// ```
+pub struct TableDefinition<'a, K: Key + ?Sized, V: Value + ?Sized> {
+    name: &'a str,
+    _types: PhantomData<fn(&K, &V)>,
+}
+
+impl<'a, K: Key + ?Sized, V: Value + ?Sized> TableDefinition<'a, K, V> {
+    /// Define the table `name` holding `K` keys and `V` values
+    pub const fn new(name: &'a str) -> Self {
+        Self {
+            name,
+            _types: PhantomData,
+        }
+    }
+
+    /// Name of the table
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    pub(crate) fn schema(&self) -> Schema {
+        Schema::of::<K, V>()
+    }
+}
+
+impl<K: Key + ?Sized, V: Value + ?Sized> Clone for TableDefinition<'_, K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K: Key + ?Sized, V: Value + ?Sized> Copy for TableDefinition<'_, K, V> {}
+
+/// Encode both ends of a typed key range
+fn encode_range<K, Q>(range: impl RangeBounds<Q>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>)
+where
+    K: Key + ?Sized,
+    Q: Borrow<K>,
+{
+    let encode = |bound: Bound<&Q>| bound.map(|key| types::encode_key(key.borrow()));
+    (encode(range.start_bound()), encode(range.end_bound()))
+}
+
+fn decode_value<V: Value + ?Sized>(value: Option<Vec<u8>>) -> Result<Option<V::Owned>> {
+    value.map(|bytes| V::decode_value(&bytes)).transpose()
+}
+
+/// A typed table opened by a [`WriteTransaction`](crate::WriteTransaction)
+pub struct TypedTable<'txn, K: Key + ?Sized, V: Value + ?Sized> {
+    table: Table<'txn>,
+    _types: PhantomData<fn(&K, &V)>,
+}
+
+impl<'txn, K: Key + ?Sized, V: Value + ?Sized> TypedTable<'txn, K, V> {
+    pub(crate) fn new(table: Table<'txn>) -> Self {
+        Self {
+            table,
+            _types: PhantomData,
+        }
+    }
+
+    /// Name of the table
+    pub fn name(&self) -> &str {
+        self.table.name()
+    }
+
+    /// Look up the value stored under `key`, including uncommitted changes
+    pub fn get(&self, key: &K) -> Result<Option<V::Owned>> {
+        decode_value::<V>(self.table.get(&types::encode_key(key))?)
+    }
+
+    /// Insert or replace `key`, returning the previous value
+    pub fn insert(&mut self, key: &K, value: &V) -> Result<Option<V::Owned>> {
+        let value = types::encode_value(value)?;
+        decode_value::<V>(self.table.insert(&types::encode_key(key), &value)?)
+    }
+
+    /// Remove `key`, returning its value if it was present
+    pub fn remove(&mut self, key: &K) -> Result<Option<V::Owned>> {
+        decode_value::<V>(self.table.remove(&types::encode_key(key))?)
+    }
+
+    /// Iterate over all entries in key order, including uncommitted changes
+    pub fn iter(&self) -> Result<TypedRange<'_, K, V>> {
+        Ok(TypedRange::new(self.table.iter()?))
+    }
+
+    /// Iterate over the entries whose keys fall within `range`, in key order
+    pub fn range<Q: Borrow<K>>(&self, range: impl RangeBounds<Q>) -> Result<TypedRange<'_, K, V>> {
+        Ok(TypedRange::new(self.table.range(encode_range(range))?))
+    }
+}
+
+/// A typed table as of the snapshot of a
+/// [`ReadTransaction`](crate::ReadTransaction)
+pub struct ReadOnlyTypedTable<'txn, K: Key + ?Sized, V: Value + ?Sized> {
+    table: ReadOnlyTable<'txn>,
+    _types: PhantomData<fn(&K, &V)>,
+}
+
+impl<'txn, K: Key + ?Sized, V: Value + ?Sized> ReadOnlyTypedTable<'txn, K, V> {
+    pub(crate) fn new(table: ReadOnlyTable<'txn>) -> Self {
+        Self {
+            table,
+            _types: PhantomData,
+        }
+    }
+
+    /// Look up the value stored under `key`
+    pub fn get(&self, key: &K) -> Result<Option<V::Owned>> {
+        decode_value::<V>(self.table.get(&types::encode_key(key))?)
+    }
+
+    /// Iterate over all entries in key order
+    pub fn iter(&self) -> TypedRange<'_, K, V> {
+        TypedRange::new(self.table.iter())
+    }
+
+    /// Iterate over the entries whose keys fall within `range`, in key order
+    pub fn range<Q: Borrow<K>>(&self, range: impl RangeBounds<Q>) -> TypedRange<'_, K, V> {
+        TypedRange::new(self.table.range(encode_range(range)))
+    }
+}
+
+/// Double-ended iterator over a typed key range, in key order
+pub struct TypedRange<'a, K: Key + ?Sized, V: Value + ?Sized> {
+    range: Range<'a>,
+    _types: PhantomData<fn(&K, &V)>,
+}
+
+impl<'a, K: Key + ?Sized, V: Value + ?Sized> TypedRange<'a, K, V> {
+    fn new(range: Range<'a>) -> Self {
+        Self {
+            range,
+            _types: PhantomData,
+        }
+    }
+
+    fn decode(entry: Result<(Vec<u8>, Vec<u8>)>) -> Result<(K::Owned, V::Owned)> {
+        let (key, value) = entry?;
+        Ok((types::decode_key::<K>(&key)?, V::decode_value(&value)?))
+    }
+}
+
+impl<K: Key + ?Sized, V: Value + ?Sized> Iterator for TypedRange<'_, K, V> {
+    type Item = Result<(K::Owned, V::Owned)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.range.next().map(Self::decode)
+    }
+}
+
+impl<K: Key + ?Sized, V: Value + ?Sized> DoubleEndedIterator for TypedRange<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.range.next_back().map(Self::decode)
+    }
+}
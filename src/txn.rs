@@ -0,0 +1,476 @@
+//! Read and write transactions
+//!
+//! Every commit publishes a [`Snapshot`]: the roots of the trees it produced.
+//! Because the tree is copy-on-write, the pages reachable from a published
+//! root never change, so a [`ReadTransaction`] holding a snapshot sees the
+//! same data however many writers commit after it began.
+//!
+//! The current snapshot is swapped in through a `crossbeam-epoch` pointer, so
+//! beginning a read takes no lock: the old pointer is only destroyed once no
+//! thread can still be cloning it. A commit also retires the snapshot it
+//! replaces together with the pages it freed, and those pages are released
+//! once the retired snapshot and every older one have no readers left.
+//!
+//! There is at most one [`WriteTransaction`] at a time. Its pages are private
+//! until it commits; dropping it without committing discards them, and
+//! [savepoints](crate::Savepoint) discard those written since. Both kinds of
+//! transaction also open the named tables recorded in the catalog.
+
+use std::collections::{HashSet, VecDeque};
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use crossbeam_epoch::{self as epoch, Atomic, Owned};
+use parking_lot::{Mutex, MutexGuard};
+
+use crate::btree::{self, BTree};
+use crate::buffer::PageId;
+use crate::pager::Pager;
+use crate::savepoint::{Savepoint, SavepointState};
+use crate::table::{
+    self, ReadOnlyTable, ReadOnlyTypedTable, Schema, Table, TableDefinition, TypedTable,
+};
+use crate::types::{Key, Value};
+use crate::wal::Lsn;
+use crate::{Cursor, Database, Error, Range, Result};
+
+/// Committed state visible to readers
+#[derive(Debug)]
+pub(crate) struct Snapshot {
+    /// Root of the default tree (0 = empty)
+    pub root: PageId,
+    /// Root of the table catalog (0 = no tables)
+    pub catalog: PageId,
+}
+
+/// The latest committed snapshot, readable without locking
+pub(crate) struct Snapshots {
+    current: Atomic<Arc<Snapshot>>,
+}
+
+impl Snapshots {
+    /// Start with `snapshot` as the current one
+    pub fn new(snapshot: Snapshot) -> Self {
+        Self {
+            current: Atomic::new(Arc::new(snapshot)),
+        }
+    }
+
+    /// Take a reference to the current snapshot
+    pub fn current(&self) -> Arc<Snapshot> {
+        let guard = epoch::pin();
+        let current = self.current.load(Ordering::Acquire, &guard);
+        // SAFETY: the pointer is never null, and a replaced pointer is only
+        // destroyed once every guard that could have loaded it is dropped
+        Arc::clone(unsafe { current.deref() })
+    }
+
+    /// Make `snapshot` current, returning the one it replaces
+    pub fn publish(&self, snapshot: Snapshot) -> Arc<Snapshot> {
+        let guard = epoch::pin();
+        let old = self
+            .current
+            .swap(Owned::new(Arc::new(snapshot)), Ordering::AcqRel, &guard);
+        // SAFETY: `old` was swapped out, so only threads pinned before now can
+        // still reach it
+        let previous = Arc::clone(unsafe { old.deref() });
+        unsafe { guard.defer_destroy(old) };
+        guard.flush();
+        previous
+    }
+
+    /// Give the epoch a chance to advance, so replaced snapshots no reader
+    /// can load any more are dropped and the pages their commits freed can
+    /// be released
+    pub fn collect(&self) {
+        // Destruction waits for the epoch to advance twice past the swap,
+        // which each flush attempts once
+        for _ in 0..3 {
+            epoch::pin().flush();
+        }
+    }
+}
+
+impl Drop for Snapshots {
+    fn drop(&mut self) {
+        // SAFETY: `&mut self` rules out concurrent readers
+        unsafe {
+            let guard = epoch::unprotected();
+            drop(self.current.load(Ordering::Relaxed, guard).into_owned());
+        }
+    }
+}
+
+/// A replaced snapshot and the pages freed by the commit that replaced it
+pub(crate) struct Retired {
+    snapshot: Arc<Snapshot>,
+    pages: Vec<PageId>,
+    /// Commit record of the commit that freed the pages, `Lsn::MAX` until
+    /// an unlogged commit is logged
+    lsn: Lsn,
+}
+
+impl Retired {
+    pub fn new(snapshot: Arc<Snapshot>, pages: Vec<PageId>, lsn: Option<Lsn>) -> Self {
+        Self {
+            snapshot,
+            pages,
+            lsn: lsn.unwrap_or(Lsn::MAX),
+        }
+    }
+
+    /// Pages awaiting release
+    pub fn pages(&self) -> &[PageId] {
+        &self.pages
+    }
+
+    /// Stop awaiting the release of `pages`, which are in use again
+    pub fn keep(&mut self, pages: &HashSet<PageId>) {
+        self.pages.retain(|id| !pages.contains(id));
+    }
+
+    /// Record that the commit record at `lsn` covers an unlogged commit
+    pub fn logged(&mut self, lsn: Lsn) {
+        self.lsn = self.lsn.min(lsn);
+    }
+}
+
+/// Release the pages of retired snapshots nobody reads any more and whose
+/// commits are durable up to `durable`, oldest first
+///
+/// A page freed by one commit may also be reachable from any earlier
+/// snapshot, so release stops at the first snapshot still in use. Until the
+/// commit is durable a crash may recover a state that uses the pages, which a
+/// checkpoint running meanwhile may not have written yet.
+///
+/// Pages a persistent savepoint reaches, which are `protected`, are not
+/// released at all.
+pub(crate) fn reclaim(
+    retired: &mut VecDeque<Retired>,
+    pager: &mut Pager,
+    durable: Lsn,
+    protected: &HashSet<PageId>,
+) {
+    while let Some(oldest) = retired.front() {
+        if Arc::strong_count(&oldest.snapshot) > 1 || oldest.lsn > durable {
+            break;
+        }
+        let oldest = retired.pop_front().expect("queue is not empty");
+        pager.release(
+            oldest
+                .pages
+                .into_iter()
+                .filter(|id| !protected.contains(id)),
+        );
+    }
+}
+
+/// Read-only view of the database as of the moment it began
+///
+/// Commits made while the transaction is open are not visible to it.
+pub struct ReadTransaction<'db> {
+    db: &'db Database,
+    snapshot: Arc<Snapshot>,
+}
+
+impl<'db> ReadTransaction<'db> {
+    pub(crate) fn new(db: &'db Database) -> Self {
+        Self {
+            db,
+            snapshot: db.snapshots.current(),
+        }
+    }
+
+    /// Look up the value stored under `key`
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let root = self.snapshot.root;
+        if let Some(value) = btree::optimistic::get(&self.db.root, Some(root), key) {
+            return Ok(value);
+        }
+        let inner = &mut *self.db.inner.lock();
+        BTree::new(root).get(&mut inner.pager, key)
+    }
+
+    /// Iterate over all entries in key order
+    pub fn iter(&self) -> Range<'_> {
+        self.range::<&[u8]>(..)
+    }
+
+    /// Iterate over the entries whose keys fall within `range`, in key order
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Range<'_> {
+        Range::new(&self.db.inner, BTree::new(self.snapshot.root), None, range)
+    }
+
+    /// Create an unpositioned cursor
+    pub fn cursor(&self) -> Cursor<'_> {
+        Cursor::new(&self.db.inner, BTree::new(self.snapshot.root), None)
+    }
+
+    /// Open the table `name` as of this transaction's snapshot
+    pub fn open_table(&self, name: &str) -> Result<ReadOnlyTable<'_>> {
+        self.open(name, None)
+    }
+
+    /// Open a typed table as of this transaction's snapshot
+    ///
+    /// Fails if the table was created with other key or value types.
+    pub fn open_typed_table<K: Key + ?Sized, V: Value + ?Sized>(
+        &self,
+        definition: TableDefinition<'_, K, V>,
+    ) -> Result<ReadOnlyTypedTable<'_, K, V>> {
+        let table = self.open(definition.name(), Some(&definition.schema()))?;
+        Ok(ReadOnlyTypedTable::new(table))
+    }
+
+    fn open(&self, name: &str, schema: Option<&Schema>) -> Result<ReadOnlyTable<'_>> {
+        let catalog = BTree::new(self.snapshot.catalog);
+        let tree = table::lookup(catalog, &mut self.db.inner.lock().pager, name, schema)?;
+        Ok(ReadOnlyTable::new(self.db, tree))
+    }
+
+    /// Names of all tables, in order
+    pub fn list_tables(&self) -> Result<Vec<String>> {
+        table::list(&self.db.inner, BTree::new(self.snapshot.catalog))
+    }
+}
+
+/// What a write transaction's commit promises to survive
+///
+/// Commits are durable in commit order: recovery after a crash restores every
+/// transaction up to some point and none after it, so a transaction that
+/// survives brings every earlier one with it, whatever their levels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Durability {
+    /// Keep the changes in memory only; they are logged by the next commit
+    /// with a stronger level, by [`Database::flush`] or once such commits
+    /// fill half the cache, and a crash before that loses them
+    None,
+    /// Write the changes to the log without waiting for it to be synced,
+    /// which a background thread does shortly after; a crash in between
+    /// loses them
+    Eventual,
+    /// Sync the log before the commit returns
+    #[default]
+    Immediate,
+    /// Also write the pages to the data file and sync it before the commit
+    /// returns, so that the changes no longer depend on the log
+    Paranoid,
+}
+
+/// The single open read-write transaction
+///
+/// Changes are visible through this handle at once and to everybody else
+/// after [`commit`](WriteTransaction::commit), which makes them visible
+/// atomically and returns once they are durable. Dropping the handle without committing rolls every
+/// change back; [`rollback_to`](WriteTransaction::rollback_to) rolls back
+/// the changes since a [`savepoint`](WriteTransaction::savepoint).
+pub struct WriteTransaction<'db> {
+    db: &'db Database,
+    _writer: MutexGuard<'db, ()>,
+    /// Names of the tables with a live [`Table`] handle
+    open: Mutex<HashSet<String>>,
+    /// Savepoints still valid, oldest first
+    savepoints: Mutex<Vec<(u64, SavepointState)>>,
+    durability: Durability,
+    finished: bool,
+}
+
+impl<'db> WriteTransaction<'db> {
+    pub(crate) fn new(db: &'db Database) -> Result<Self> {
+        let writer = db.writer.lock();
+        // After the log failed, a failed commit may not have been rolled
+        // back cleanly
+        db.log.check()?;
+        Ok(Self {
+            db,
+            _writer: writer,
+            open: Mutex::new(HashSet::new()),
+            savepoints: Mutex::new(Vec::new()),
+            durability: Durability::default(),
+            finished: false,
+        })
+    }
+
+    /// Choose what the commit promises (default [`Durability::Immediate`])
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+    }
+
+    /// Look up the value stored under `key`, including uncommitted changes
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let inner = &mut *self.db.inner.lock();
+        inner.tree.get(&mut inner.pager, key)
+    }
+
+    /// Insert or replace `key`, returning the previous value
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let inner = &mut *self.db.inner.lock();
+        inner.tree.insert(&mut inner.pager, key, value)
+    }
+
+    /// Remove `key`, returning its value if it was present
+    pub fn remove(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let inner = &mut *self.db.inner.lock();
+        inner.tree.remove(&mut inner.pager, key)
+    }
+
+    /// Iterate over all entries in key order, including uncommitted changes
+    pub fn iter(&self) -> Range<'_> {
+        self.range::<&[u8]>(..)
+    }
+
+    /// Iterate over the entries whose keys fall within `range`, in key order
+    pub fn range<K: AsRef<[u8]>>(&self, range: impl RangeBounds<K>) -> Range<'_> {
+        let tree = self.db.inner.lock().tree;
+        Range::new(&self.db.inner, tree, None, range)
+    }
+
+    /// Create an unpositioned cursor
+    pub fn cursor(&self) -> Cursor<'_> {
+        let tree = self.db.inner.lock().tree;
+        Cursor::new(&self.db.inner, tree, None)
+    }
+
+    /// Open the table `name`, creating it if it does not exist
+    ///
+    /// A table can only be open once at a time within a transaction.
+    pub fn open_table(&self, name: &str) -> Result<Table<'_>> {
+        self.open(name, None)
+    }
+
+    /// Open a typed table, creating it if it does not exist
+    ///
+    /// Fails if the table was created with other key or value types.
+    pub fn open_typed_table<K: Key + ?Sized, V: Value + ?Sized>(
+        &self,
+        definition: TableDefinition<'_, K, V>,
+    ) -> Result<TypedTable<'_, K, V>> {
+        let table = self.open(definition.name(), Some(&definition.schema()))?;
+        Ok(TypedTable::new(table))
+    }
+
+    fn open(&self, name: &str, schema: Option<&Schema>) -> Result<Table<'_>> {
+        if !self.open.lock().insert(name.to_owned()) {
+            return Err(Error::TableAlreadyOpen(name.to_owned()));
+        }
+        // Constructed first so the name is released again on error
+        let table = Table::new(self.db, &self.open, name);
+        self.db.inner.lock().create_table(name, schema)?;
+        Ok(table)
+    }
+
+    /// Names of all tables, including uncommitted changes, in order
+    pub fn list_tables(&self) -> Result<Vec<String>> {
+        let catalog = self.db.inner.lock().catalog;
+        table::list(&self.db.inner, catalog)
+    }
+
+    /// Rename the table `from` to `to`, which must not exist yet
+    pub fn rename_table(&mut self, from: &str, to: &str) -> Result<()> {
+        self.db.inner.lock().rename_table(from, to)
+    }
+
+    /// Remove the table `name` and all of its entries, returning whether it
+    /// existed
+    ///
+    /// Its pages are reused once no reader can still see the table.
+    pub fn delete_table(&mut self, name: &str) -> Result<bool> {
+        self.db.inner.lock().delete_table(name)
+    }
+
+    /// Mark the current state of the transaction to return to with
+    /// [`rollback_to`](WriteTransaction::rollback_to)
+    pub fn savepoint(&self) -> Savepoint {
+        let savepoint = Savepoint::new();
+        let state = self.db.inner.lock().savepoint();
+        self.savepoints.lock().push((savepoint.id(), state));
+        savepoint
+    }
+
+    /// Undo every change made since `savepoint` was taken
+    ///
+    /// The savepoint stays valid, while those taken after it do not. Fails
+    /// with [`Error::InvalidSavepoint`] if it is not a valid savepoint of
+    /// this transaction.
+    pub fn rollback_to(&mut self, savepoint: &Savepoint) -> Result<()> {
+        let savepoints = self.savepoints.get_mut();
+        let pos = savepoints
+            .iter()
+            .position(|&(id, _)| id == savepoint.id())
+            .ok_or(Error::InvalidSavepoint)?;
+        savepoints.truncate(pos + 1);
+        self.db.inner.lock().rollback_to(&savepoints[pos].1);
+        Ok(())
+    }
+
+    /// Record the database as of the last commit, without this
+    /// transaction's changes, as the persistent savepoint `name`
+    ///
+    /// The savepoint is created when the transaction commits, and survives
+    /// until deleted. Pages it reaches are not reused meanwhile, so the file
+    /// grows as the data moves away from it.
+    pub fn persistent_savepoint(&mut self, name: &str) -> Result<()> {
+        self.db.inner.lock().create_persistent_savepoint(name)
+    }
+
+    /// Return every tree to the state recorded as the persistent savepoint
+    /// `name`, discarding their current contents
+    ///
+    /// The savepoint itself is kept. Like any change, the restore takes
+    /// effect when the transaction commits.
+    pub fn restore_persistent_savepoint(&mut self, name: &str) -> Result<()> {
+        self.db.inner.lock().restore_persistent_savepoint(name)
+    }
+
+    /// Remove the persistent savepoint `name`, returning whether it existed
+    ///
+    /// Pages only the savepoint reached are reused once the transaction
+    /// commits.
+    pub fn delete_persistent_savepoint(&mut self, name: &str) -> Result<bool> {
+        self.db.inner.lock().delete_persistent_savepoint(name)
+    }
+
+    /// Names of all persistent savepoints, including uncommitted changes, in
+    /// order
+    pub fn list_persistent_savepoints(&self) -> Result<Vec<String>> {
+        self.db.inner.lock().persistent_savepoint_names()
+    }
+
+    /// Make every change visible to new readers, and as durable as
+    /// [`set_durability`](WriteTransaction::set_durability) asks
+    ///
+    /// The next write transaction may begin while this one waits for the
+    /// log to be synced, so that its commit can share the sync.
+    pub fn commit(mut self) -> Result<()> {
+        let durability = self.durability;
+        let lsn = self
+            .db
+            .inner
+            .lock()
+            .commit(&self.db.snapshots, durability)?;
+        self.finished = true;
+        if durability == Durability::Paranoid {
+            // Before the next writer can begin, so nothing is left unlogged
+            return self.db.checkpoint_all();
+        }
+        let db = self.db;
+        drop(self);
+        match (lsn, durability) {
+            (_, Durability::None) | (None, _) => Ok(()),
+            (Some(_), Durability::Eventual) => db.log.sync_soon(),
+            (Some(lsn), _) => db.wait_durable(lsn),
+        }
+    }
+
+    /// Discard every change; equivalent to dropping the transaction
+    pub fn abort(self) {}
+}
+
+impl Drop for WriteTransaction<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.db.inner.lock().rollback();
+        }
+    }
+}
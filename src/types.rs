@@ -0,0 +1,518 @@
+//! Key and value codecs for typed tables
+//!
+//! A [`Key`] encodes to bytes that sort in the same order as the values they
+//! came from, so the B-tree's byte order is the key type's natural order and
+//! typed range scans come out sorted. Key encodings are also self-delimiting,
+//! which lets tuples of keys be encoded by concatenation:
+//!
+//! - unsigned integers are big-endian; signed integers are big-endian with
+//!   the sign bit flipped, so negative values sort first
+//! - strings and byte strings have every `0x00` escaped as `0x00 0xff` and end
+//!   with `0x00 0x00`
+//! - fixed-size byte arrays are stored as they are
+//!
+//! A [`Value`] only needs to round-trip. Any `serde` type can be stored as a
+//! value by wrapping it in [`Bincode`]; its encoding does not preserve order,
+//! so it is not available for keys.
+//!
+//! Type names are persisted in the catalog, so they must not change when the
+//! code is reorganized or rebuilt by another compiler. `serde` types name
+//! themselves through [`BincodeValue`] rather than [`std::any::type_name`],
+//! which is neither.
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::{Error, Result};
+
+/// Type usable as a table key
+pub trait Key {
+    /// Decoded form of a key
+    type Owned;
+
+    /// Name recorded in the catalog to catch opening a table as another type
+    fn type_name() -> String;
+
+    /// Append the order-preserving encoding of `self` to `out`
+    fn encode_key(&self, out: &mut Vec<u8>);
+
+    /// Decode a key from the front of `input`, advancing past it
+    fn decode_key(input: &mut &[u8]) -> Result<Self::Owned>;
+}
+
+/// Type usable as a table value
+pub trait Value {
+    /// Decoded form of a value
+    type Owned;
+
+    /// Name recorded in the catalog to catch opening a table as another type
+    fn type_name() -> String;
+
+    /// Append the encoding of `self` to `out`
+    fn encode_value(&self, out: &mut Vec<u8>) -> Result<()>;
+
+    /// Decode a value occupying all of `bytes`
+    fn decode_value(bytes: &[u8]) -> Result<Self::Owned>;
+}
+
+/// Encode `key` into a fresh buffer
+pub(crate) fn encode_key<K: Key + ?Sized>(key: &K) -> Vec<u8> {
+    let mut out = Vec::new();
+    key.encode_key(&mut out);
+    out
+}
+
+/// Encode `value` into a fresh buffer
+pub(crate) fn encode_value<V: Value + ?Sized>(value: &V) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    value.encode_value(&mut out)?;
+    Ok(out)
+}
+
+/// Decode a key occupying all of `bytes`
+pub(crate) fn decode_key<K: Key + ?Sized>(mut bytes: &[u8]) -> Result<K::Owned> {
+    let key = K::decode_key(&mut bytes)?;
+    if !bytes.is_empty() {
+        return Err(malformed(&K::type_name()));
+    }
+    Ok(key)
+}
+
+fn malformed(type_name: &str) -> Error {
+    Error::Corruption(format!("malformed {} encoding", type_name))
+}
+
+/// Split `n` bytes off the front of `input`
+fn take<'a>(input: &mut &'a [u8], n: usize, type_name: &str) -> Result<&'a [u8]> {
+    if input.len() < n {
+        return Err(malformed(type_name));
+    }
+    let (head, tail) = input.split_at(n);
+    *input = tail;
+    Ok(head)
+}
+
+macro_rules! integers {
+    ($($ty:ty => $flip:expr),* $(,)?) => {$(
+        impl Key for $ty {
+            type Owned = $ty;
+
+            fn type_name() -> String {
+                stringify!($ty).into()
+            }
+
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&(self ^ $flip).to_be_bytes());
+            }
+
+            fn decode_key(input: &mut &[u8]) -> Result<$ty> {
+                let bytes = take(input, size_of::<$ty>(), stringify!($ty))?;
+                Ok(<$ty>::from_be_bytes(bytes.try_into().unwrap()) ^ $flip)
+            }
+        }
+
+        impl Value for $ty {
+            type Owned = $ty;
+
+            fn type_name() -> String {
+                stringify!($ty).into()
+            }
+
+            fn encode_value(&self, out: &mut Vec<u8>) -> Result<()> {
+                out.extend_from_slice(&self.to_le_bytes());
+                Ok(())
+            }
+
+            fn decode_value(bytes: &[u8]) -> Result<$ty> {
+                let bytes = bytes.try_into().map_err(|_| malformed(stringify!($ty)))?;
+                Ok(<$ty>::from_le_bytes(bytes))
+            }
+        }
+    )*};
+}
+
+integers! {
+    u8 => 0, u16 => 0, u32 => 0, u64 => 0, u128 => 0,
+    i8 => i8::MIN, i16 => i16::MIN, i32 => i32::MIN, i64 => i64::MIN, i128 => i128::MIN,
+}
+
+/// Escape and terminate `bytes` so they sort and delimit correctly
+fn encode_escaped(bytes: &[u8], out: &mut Vec<u8>) {
+    for &byte in bytes {
+        out.push(byte);
+        if byte == 0 {
+            out.push(0xff);
+        }
+    }
+    out.extend_from_slice(&[0, 0]);
+}
+
+fn decode_escaped(input: &mut &[u8], type_name: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut rest = *input;
+    loop {
+        let Some(zero) = rest.iter().position(|&byte| byte == 0) else {
+            return Err(malformed(type_name));
+        };
+        out.extend_from_slice(&rest[..zero]);
+        match rest.get(zero + 1) {
+            Some(0) => {
+                *input = &rest[zero + 2..];
+                return Ok(out);
+            }
+            Some(0xff) => {
+                out.push(0);
+                rest = &rest[zero + 2..];
+            }
+            _ => return Err(malformed(type_name)),
+        }
+    }
+}
+
+fn into_string(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|_| malformed("string"))
+}
+
+impl Key for [u8] {
+    type Owned = Vec<u8>;
+
+    fn type_name() -> String {
+        "bytes".into()
+    }
+
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        encode_escaped(self, out);
+    }
+
+    fn decode_key(input: &mut &[u8]) -> Result<Vec<u8>> {
+        decode_escaped(input, "bytes")
+    }
+}
+
+impl Value for [u8] {
+    type Owned = Vec<u8>;
+
+    fn type_name() -> String {
+        "bytes".into()
+    }
+
+    fn encode_value(&self, out: &mut Vec<u8>) -> Result<()> {
+        out.extend_from_slice(self);
+        Ok(())
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Key for str {
+    type Owned = String;
+
+    fn type_name() -> String {
+        "string".into()
+    }
+
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        encode_escaped(self.as_bytes(), out);
+    }
+
+    fn decode_key(input: &mut &[u8]) -> Result<String> {
+        into_string(decode_escaped(input, "string")?)
+    }
+}
+
+impl Value for str {
+    type Owned = String;
+
+    fn type_name() -> String {
+        "string".into()
+    }
+
+    fn encode_value(&self, out: &mut Vec<u8>) -> Result<()> {
+        out.extend_from_slice(self.as_bytes());
+        Ok(())
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<String> {
+        into_string(bytes.to_vec())
+    }
+}
+
+/// Owned types encode exactly like the slices they deref to, so a table can
+/// be opened as either
+macro_rules! owned {
+    ($($owned:ty => $borrowed:ty),*) => {$(
+        impl Key for $owned {
+            type Owned = $owned;
+
+            fn type_name() -> String {
+                <$borrowed as Key>::type_name()
+            }
+
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                <$borrowed as Key>::encode_key(self, out)
+            }
+
+            fn decode_key(input: &mut &[u8]) -> Result<$owned> {
+                <$borrowed as Key>::decode_key(input)
+            }
+        }
+
+        impl Value for $owned {
+            type Owned = $owned;
+
+            fn type_name() -> String {
+                <$borrowed as Value>::type_name()
+            }
+
+            fn encode_value(&self, out: &mut Vec<u8>) -> Result<()> {
+                <$borrowed as Value>::encode_value(self, out)
+            }
+
+            fn decode_value(bytes: &[u8]) -> Result<$owned> {
+                <$borrowed as Value>::decode_value(bytes)
+            }
+        }
+    )*};
+}
+
+owned!(Vec<u8> => [u8], String => str);
+
+impl<const N: usize> Key for [u8; N] {
+    type Owned = [u8; N];
+
+    fn type_name() -> String {
+        format!("[u8; {}]", N)
+    }
+
+    fn encode_key(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+
+    fn decode_key(input: &mut &[u8]) -> Result<[u8; N]> {
+        Ok(take(input, N, &<Self as Key>::type_name())?
+            .try_into()
+            .unwrap())
+    }
+}
+
+impl<const N: usize> Value for [u8; N] {
+    type Owned = [u8; N];
+
+    fn type_name() -> String {
+        format!("[u8; {}]", N)
+    }
+
+    fn encode_value(&self, out: &mut Vec<u8>) -> Result<()> {
+        out.extend_from_slice(self);
+        Ok(())
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<[u8; N]> {
+        bytes
+            .try_into()
+            .map_err(|_| malformed(&<Self as Value>::type_name()))
+    }
+}
+
+/// Tuples of keys compare field by field, as their concatenated encodings
+/// do; as values they reuse the key encoding to delimit their fields
+macro_rules! tuples {
+    ($(($($name:ident $index:tt),+))*) => {$(
+        impl<$($name: Key),+> Key for ($($name,)+) {
+            type Owned = ($($name::Owned,)+);
+
+            fn type_name() -> String {
+                let names: &[String] = &[$($name::type_name()),+];
+                format!("({})", names.join(", "))
+            }
+
+            fn encode_key(&self, out: &mut Vec<u8>) {
+                $(self.$index.encode_key(out);)+
+            }
+
+            fn decode_key(input: &mut &[u8]) -> Result<Self::Owned> {
+                Ok(($($name::decode_key(input)?,)+))
+            }
+        }
+
+        impl<$($name: Key),+> Value for ($($name,)+) {
+            type Owned = ($($name::Owned,)+);
+
+            fn type_name() -> String {
+                <Self as Key>::type_name()
+            }
+
+            fn encode_value(&self, out: &mut Vec<u8>) -> Result<()> {
+                self.encode_key(out);
+                Ok(())
+            }
+
+            fn decode_value(bytes: &[u8]) -> Result<Self::Owned> {
+                decode_key::<Self>(bytes)
+            }
+        }
+    )*};
+}
+
+tuples! {
+    (A 0)
+    (A 0, B 1)
+    (A 0, B 1, C 2)
+    (A 0, B 1, C 2, D 3)
+}
+
+/// `serde` type that can be stored with [`Bincode`]
+///
+/// ```
+/// # use serde::{Deserialize, Serialize};
+/// #[derive(Serialize, Deserialize)]
+/// struct User {
+///     name: String,
+/// }
+///
+/// impl qpdb::BincodeValue for User {
+///     const TYPE_NAME: &'static str = "user";
+/// }
+/// ```
+pub trait BincodeValue: Serialize + DeserializeOwned {
+    /// Name recorded in the catalog for tables holding this type
+    ///
+    /// Keep it fixed once tables have been created with it: opening a table
+    /// under another name fails with
+    /// [`TableTypeMismatch`](crate::Error::TableTypeMismatch).
+    const TYPE_NAME: &'static str;
+}
+
+/// Value stored in its `bincode` encoding
+///
+/// Wrap any [`BincodeValue`] to store it: `table.insert(&key,
+/// &Bincode(user))`. Lookups return the unwrapped `T`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bincode<T>(pub T);
+
+impl<T: BincodeValue> Value for Bincode<T> {
+    type Owned = T;
+
+    fn type_name() -> String {
+        format!("bincode<{}>", T::TYPE_NAME)
+    }
+
+    fn encode_value(&self, out: &mut Vec<u8>) -> Result<()> {
+        let bytes = bincode::serde::encode_to_vec(&self.0, bincode::config::standard())
+            .map_err(|e| Error::Encoding(e.to_string()))?;
+        out.extend_from_slice(&bytes);
+        Ok(())
+    }
+
+    fn decode_value(bytes: &[u8]) -> Result<T> {
+        match bincode::serde::decode_from_slice(bytes, bincode::config::standard()) {
+            Ok((value, used)) if used == bytes.len() => Ok(value),
+            _ => Err(malformed(&Self::type_name())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        tags: Vec<String>,
+    }
+
+    impl BincodeValue for User {
+        const TYPE_NAME: &'static str = "user";
+    }
+
+    fn roundtrip_key<K: Key + ?Sized>(key: &K) -> K::Owned {
+        decode_key::<K>(&encode_key(key)).unwrap()
+    }
+
+    fn roundtrip_value<V: Value + ?Sized>(value: &V) -> V::Owned {
+        V::decode_value(&encode_value(value).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn keys_roundtrip() {
+        assert_eq!(roundtrip_key(&-5i32), -5);
+        assert_eq!(roundtrip_key(&u128::MAX), u128::MAX);
+        assert_eq!(roundtrip_key("a\0b\0"), "a\0b\0");
+        assert_eq!(roundtrip_key(&b"\0\xff\0"[..]), b"\0\xff\0");
+        assert_eq!(roundtrip_key(&[1u8, 2, 3]), [1, 2, 3]);
+        let tuple = (String::from("x\0"), -1i64, vec![0u8], 7u8);
+        assert_eq!(roundtrip_key(&tuple), tuple);
+    }
+
+    #[test]
+    fn values_roundtrip() {
+        assert_eq!(roundtrip_value(&i64::MIN), i64::MIN);
+        assert_eq!(roundtrip_value("text"), "text");
+        assert_eq!(roundtrip_value(&b"\0"[..]), b"\0");
+        assert_eq!(roundtrip_value(&(1u16, String::from("a"))), (1, "a".into()));
+
+        let user = User {
+            name: "ann".into(),
+            tags: vec!["admin".into()],
+        };
+        let encoded = encode_value(&Bincode(user.clone())).unwrap();
+        assert_eq!(<Bincode<User>>::decode_value(&encoded).unwrap(), user);
+    }
+
+    #[test]
+    fn malformed_input_is_corruption() {
+        assert!(matches!(
+            decode_key::<u32>(&[1, 2, 3]),
+            Err(Error::Corruption(_))
+        ));
+        assert!(matches!(
+            decode_key::<str>(b"abc"),
+            Err(Error::Corruption(_))
+        ));
+        assert!(matches!(
+            decode_key::<str>(b"a\0\x01"),
+            Err(Error::Corruption(_))
+        ));
+        assert!(matches!(
+            decode_key::<u8>(&[1, 2]),
+            Err(Error::Corruption(_))
+        ));
+        assert!(matches!(
+            <Bincode<User>>::decode_value(&[0xff; 3]),
+            Err(Error::Corruption(_))
+        ));
+    }
+
+    #[test]
+    fn type_names() {
+        assert_eq!(<(u32, String) as Key>::type_name(), "(u32, string)");
+        assert_eq!(<str as Key>::type_name(), <String as Key>::type_name());
+        assert_eq!(<[u8; 4] as Value>::type_name(), "[u8; 4]");
+        assert_eq!(<Bincode<User> as Value>::type_name(), "bincode<user>");
+    }
+
+    proptest! {
+        #[test]
+        fn signed_order_is_preserved(a: i64, b: i64) {
+            prop_assert_eq!(a.cmp(&b), encode_key(&a).cmp(&encode_key(&b)));
+        }
+
+        #[test]
+        fn byte_order_is_preserved(
+            a in prop::collection::vec(prop::sample::select(vec![0u8, 1, 0xff]), 0..6),
+            b in prop::collection::vec(prop::sample::select(vec![0u8, 1, 0xff]), 0..6),
+        ) {
+            prop_assert_eq!(a.cmp(&b), encode_key(&a[..]).cmp(&encode_key(&b[..])));
+        }
+
+        #[test]
+        fn tuple_order_is_preserved(a: (String, i16), b: (String, i16)) {
+            prop_assert_eq!(a.cmp(&b), encode_key(&a).cmp(&encode_key(&b)));
+        }
+    }
+}
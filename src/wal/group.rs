@@ -0,0 +1,365 @@
+//! Group commit
+//!
+//! A commit writes its records to the log while holding the database lock,
+//! but waits for them to become durable after letting the next writer in.
+//! Committers waiting on the log elect a leader: the first to find no sync
+//! in flight syncs everything written so far, while the others wait until
+//! the shared durable LSN passes their commit record and are woken together.
+//! Commits written while a sync is in flight are covered by the next one, so
+//! under load each sync makes a whole batch of commits durable.
+//!
+//! Commits that do not wait for the log leave the sync to a background
+//! thread, started by the first of them, which syncs shortly after each.
+//!
+//! A failed write or sync leaves it unknown what reached the disk, so it
+//! fails every commit not known to be durable, then and later, and the
+//! database takes no further writes.
+
+use std::io;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex, MutexGuard};
+
+use super::Lsn;
+use crate::storage::StorageBackend;
+use crate::{Error, Result};
+
+/// Time the background sync waits for more commits to cover
+const BACKGROUND_SYNC_DELAY: Duration = Duration::from_millis(50);
+
+/// Group commit activity since the database was opened
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommitStats {
+    /// Log syncs that made at least one commit durable
+    pub syncs: u64,
+    /// Commits made durable by those syncs
+    pub batched_commits: u64,
+    /// Most commits made durable by a single sync
+    pub largest_batch: u64,
+    /// Time spent syncing the log, including syncs for checkpoints
+    pub sync_time: Duration,
+    /// Commits that waited for the log to become durable
+    pub waits: u64,
+    /// Time those commits spent waiting
+    pub wait_time: Duration,
+    /// Longest time a commit waited
+    pub longest_wait: Duration,
+}
+
+impl CommitStats {
+    /// Commits made durable per log sync, on average
+    pub fn average_batch(&self) -> f64 {
+        if self.syncs == 0 {
+            return 0.0;
+        }
+        self.batched_commits as f64 / self.syncs as f64
+    }
+
+    /// Time a commit waited for the log to become durable, on average
+    pub fn average_wait(&self) -> Duration {
+        match u32::try_from(self.waits) {
+            Ok(0) => Duration::ZERO,
+            Ok(waits) => self.wait_time / waits,
+            Err(_) => Duration::from_secs_f64(self.wait_time.as_secs_f64() / self.waits as f64),
+        }
+    }
+}
+
+/// Durability of the log, shared by the writer and waiting committers
+pub(crate) struct GroupCommit {
+    /// `None` for the log of a read-only handle
+    file: Option<Arc<dyn StorageBackend>>,
+    state: Mutex<State>,
+    /// Signalled whenever a sync finishes
+    synced: Condvar,
+    /// Signalled when the background sync has work or should stop
+    wake: Condvar,
+    background: Mutex<Option<JoinHandle<()>>>,
+}
+
+struct State {
+    /// Highest LSN written to the file
+    written: Lsn,
+    /// Highest LSN on stable storage
+    durable: Lsn,
+    /// Commit records written since the last sync
+    pending: u64,
+    /// Whether a leader is syncing the log
+    syncing: bool,
+    /// Error of a failed write or sync, as it cannot be cloned
+    failed: Option<(io::ErrorKind, String)>,
+    /// Whether the background sync should stop
+    closed: bool,
+    stats: CommitStats,
+}
+
+impl State {
+    /// Fail if the log failed before
+    fn check(&self) -> Result<()> {
+        match &self.failed {
+            Some((kind, message)) => Err(io::Error::new(*kind, message.clone()).into()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl GroupCommit {
+    /// Track the log in `file`, durable up to `durable`
+    pub fn new(file: Option<Arc<dyn StorageBackend>>, durable: Lsn) -> Self {
+        Self {
+            file,
+            state: Mutex::new(State {
+                written: durable,
+                durable,
+                pending: 0,
+                syncing: false,
+                failed: None,
+                closed: false,
+                stats: CommitStats::default(),
+            }),
+            synced: Condvar::new(),
+            wake: Condvar::new(),
+            background: Mutex::new(None),
+        }
+    }
+
+    /// Highest LSN known to be on stable storage
+    pub fn durable(&self) -> Lsn {
+        self.state.lock().durable
+    }
+
+    /// Activity counters
+    pub fn stats(&self) -> CommitStats {
+        self.state.lock().stats
+    }
+
+    /// Record that the log file holds every record up to `lsn`, including
+    /// `commits` commit records not written before
+    pub fn written(&self, lsn: Lsn, commits: u64) {
+        let mut state = self.state.lock();
+        state.written = state.written.max(lsn);
+        state.pending += commits;
+    }
+
+    /// Fail if writing or syncing the log failed before, after which what
+    /// the log holds is unknown
+    pub fn check(&self) -> Result<()> {
+        self.state.lock().check()
+    }
+
+    /// Record that writing to the log failed
+    pub fn fail(&self, err: &io::Error) {
+        let mut state = self.state.lock();
+        state
+            .failed
+            .get_or_insert_with(|| (err.kind(), err.to_string()));
+        self.synced.notify_all();
+    }
+
+    /// Record that every record up to `lsn` is durable without a sync, as
+    /// after a checkpoint, and wake whoever waits for it
+    pub fn mark_durable(&self, lsn: Lsn) {
+        let mut state = self.state.lock();
+        state.written = state.written.max(lsn);
+        state.durable = state.durable.max(lsn);
+        state.pending = 0;
+        self.synced.notify_all();
+    }
+
+    /// Wait for the commit record at `lsn` to be durable, syncing the log if
+    /// nobody else is, and count the wait in the stats
+    pub fn commit(&self, lsn: Lsn) -> Result<()> {
+        let started = Instant::now();
+        let result = self.sync_to(lsn);
+        let waited = started.elapsed();
+        let stats = &mut self.state.lock().stats;
+        stats.waits += 1;
+        stats.wait_time += waited;
+        stats.longest_wait = stats.longest_wait.max(waited);
+        result
+    }
+
+    /// Make sure every record up to `lsn`, which must have been written, is
+    /// durable, syncing the log if nobody else is
+    pub fn sync_to(&self, lsn: Lsn) -> Result<()> {
+        let mut state = self.state.lock();
+        debug_assert!(lsn <= state.written, "waiting for unwritten records");
+        loop {
+            if state.durable >= lsn {
+                return Ok(());
+            }
+            state.check()?;
+            if !state.syncing {
+                break;
+            }
+            self.synced.wait(&mut state);
+        }
+        let file = self.file.as_deref().ok_or(Error::ReadOnly)?;
+
+        // Lead a sync covering every record written so far
+        let target = state.written;
+        let batch = std::mem::take(&mut state.pending);
+        state.syncing = true;
+        let started = Instant::now();
+        let result = MutexGuard::unlocked(&mut state, || file.sync());
+        state.syncing = false;
+        state.stats.sync_time += started.elapsed();
+        match &result {
+            Ok(()) => {
+                state.durable = state.durable.max(target);
+                if batch > 0 {
+                    state.stats.syncs += 1;
+                    state.stats.batched_commits += batch;
+                    state.stats.largest_batch = state.stats.largest_batch.max(batch);
+                }
+            }
+            Err(err) => state.failed = Some((err.kind(), err.to_string())),
+        }
+        self.synced.notify_all();
+        Ok(result?)
+    }
+
+    /// Have everything written so far synced shortly, by the background
+    /// thread, for a commit that does not wait for it
+    pub fn sync_soon(self: &Arc<Self>) -> Result<()> {
+        let mut background = self.background.lock();
+        if background.is_none() {
+            let log = Arc::clone(self);
+            let handle = thread::Builder::new()
+                .name("qpdb-log-sync".into())
+                .spawn(move || log.sync_in_background())?;
+            *background = Some(handle);
+        }
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Stop the background sync, if it was started, without a last sync
+    pub fn stop(&self) {
+        self.state.lock().closed = true;
+        self.wake.notify_all();
+        if let Some(handle) = self.background.lock().take() {
+            let _ = handle.join();
+        }
+    }
+
+    /// Body of the background thread
+    fn sync_in_background(&self) {
+        let mut state = self.state.lock();
+        while !state.closed {
+            if state.written <= state.durable || state.failed.is_some() {
+                self.wake.wait(&mut state);
+                continue;
+            }
+            // Let more commits gather first
+            let deadline = Instant::now() + BACKGROUND_SYNC_DELAY;
+            while !state.closed && !self.wake.wait_until(&mut state, deadline).timed_out() {}
+            if state.closed {
+                break;
+            }
+            let target = state.written;
+            // A failure is kept for the commits that follow
+            let _ = MutexGuard::unlocked(&mut state, || self.sync_to(target));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+    use crate::storage::{FaultyBackend, MemoryBackend};
+
+    /// Log whose syncs each wait for a go-ahead
+    struct GatedBackend {
+        inner: MemoryBackend,
+        gate: std::sync::Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl StorageBackend for GatedBackend {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+            self.inner.read_at(buf, offset)
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+            self.inner.write_at(buf, offset)
+        }
+
+        fn sync(&self) -> io::Result<()> {
+            self.gate.lock().unwrap().recv().unwrap();
+            Ok(())
+        }
+
+        fn len(&self) -> io::Result<u64> {
+            self.inner.len()
+        }
+
+        fn set_len(&self, len: u64) -> io::Result<()> {
+            self.inner.set_len(len)
+        }
+    }
+
+    #[test]
+    fn commits_written_during_a_sync_share_the_next() {
+        let (open, gate) = mpsc::channel();
+        let log = Arc::new(GroupCommit::new(
+            Some(Arc::new(GatedBackend {
+                inner: MemoryBackend::new(),
+                gate: std::sync::Mutex::new(gate),
+            })),
+            0,
+        ));
+        log.written(1, 1);
+        thread::scope(|scope| {
+            let first = scope.spawn(|| log.commit(1));
+            // Wait for the first commit to lead a sync
+            while !log.state.lock().syncing {
+                thread::yield_now();
+            }
+            log.written(2, 1);
+            log.written(3, 1);
+            let later: Vec<_> = [2, 3]
+                .map(|lsn| {
+                    let log = &log;
+                    scope.spawn(move || log.commit(lsn))
+                })
+                .into();
+            open.send(()).unwrap();
+            first.join().unwrap().unwrap();
+            open.send(()).unwrap();
+            for commit in later {
+                commit.join().unwrap().unwrap();
+            }
+        });
+
+        assert_eq!(log.durable(), 3);
+        let stats = log.stats();
+        assert_eq!((stats.syncs, stats.batched_commits), (2, 3));
+        assert_eq!(stats.largest_batch, 2);
+        assert_eq!(stats.waits, 3);
+        assert_eq!(stats.average_batch(), 1.5);
+    }
+
+    #[test]
+    fn failed_syncs_fail_every_later_commit() {
+        let file = FaultyBackend::new();
+        let log = GroupCommit::new(Some(Arc::new(file.clone())), 0);
+        log.written(1, 1);
+        log.commit(1).unwrap();
+
+        log.written(2, 1);
+        file.fail_after(0);
+        assert!(log.commit(2).is_err());
+        file.heal();
+        log.written(3, 1);
+        assert!(log.commit(3).is_err());
+        assert_eq!(log.durable(), 1);
+        // Records a checkpoint made durable count again
+        log.mark_durable(3);
+        log.sync_to(3).unwrap();
+    }
+}
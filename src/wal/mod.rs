@@ -0,0 +1,558 @@
+//! Write-ahead log
+//!
+//! The log lives next to the database file (`<path>-wal`). A commit appends
+//! the after-image of every page it modified followed by a commit record, then
+//! syncs the log, sharing the sync with concurrent commits ([`group`]). Data
+//! pages are written back lazily; before a dirty page reaches the data file
+//! the log must be durable up to that page's LSN.
+//!
+//! The file is a row of fixed-size segments, each starting with a header
+//! that names the LSN of its first record. Records fill one segment after
+//! another. Once a checkpoint covers every record in a segment the segment
+//! is recycled for later records, and free segments at the end of the file
+//! are cut off ([`Wal::recycle`]). A recycled segment may still hold stale
+//! records past the new ones, so reading stops wherever LSNs stop following
+//! each other.
+//!
+//! On open, [`recover`] replays every committed transaction the log holds
+//! past the last checkpoint into the data file, publishes the final state in
+//! a new superblock and truncates the log.
+
+mod group;
+mod record;
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::buffer::{PAGE_SIZE, PageId};
+use crate::header::{self, Superblock};
+use crate::storage::StorageBackend;
+use crate::{Error, Result};
+pub use group::CommitStats;
+pub(crate) use group::GroupCommit;
+pub(crate) use record::{Lsn, PageImage, Record, RecordBody, TxnId};
+
+/// Size of a log segment, header included
+const SEGMENT_SIZE: u64 = 1 << 20;
+
+/// Marks the start of a segment
+const SEGMENT_MAGIC: [u8; 4] = *b"qpwl";
+
+/// `magic | first_lsn u64 | crc u32`, the CRC32C covering the bytes before it
+const SEGMENT_HEADER_SIZE: usize = 16;
+
+/// Path of the log belonging to the database at `path`
+pub(crate) fn wal_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push("-wal");
+    PathBuf::from(name)
+}
+
+/// Header of a segment whose first record is `first`
+fn segment_header(first: Lsn) -> [u8; SEGMENT_HEADER_SIZE] {
+    let mut buf = [0u8; SEGMENT_HEADER_SIZE];
+    buf[0..4].copy_from_slice(&SEGMENT_MAGIC);
+    buf[4..12].copy_from_slice(&first.to_le_bytes());
+    let crc = crc32c::crc32c(&buf[..12]);
+    buf[12..16].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+/// LSN of the first record of the segment starting `buf`, if its header is
+/// valid
+fn segment_first_lsn(buf: &[u8]) -> Option<Lsn> {
+    let header = buf.get(..SEGMENT_HEADER_SIZE)?;
+    let crc = u32::from_le_bytes(header[12..16].try_into().unwrap());
+    if header[0..4] != SEGMENT_MAGIC || crc32c::crc32c(&header[..12]) != crc {
+        return None;
+    }
+    Some(u64::from_le_bytes(header[4..12].try_into().unwrap()))
+}
+
+/// Segmented log file with buffered writes
+pub(crate) struct Wal {
+    /// `None` for the log of a read-only handle, which is never written
+    file: Option<Arc<dyn StorageBackend>>,
+    /// Bytes not written yet, in runs that each go to one file offset
+    pending: Vec<(u64, Vec<u8>)>,
+    /// Commit records in `pending`
+    commits: u64,
+    /// Slot and first LSN of each segment holding records that no
+    /// checkpoint covers yet, oldest first
+    segments: VecDeque<(u64, Lsn)>,
+    /// Slots whose segments were recycled, reused lowest first
+    free: BTreeSet<u64>,
+    /// Number of segment slots the file spans
+    slots: u64,
+    /// Bytes used in the newest segment
+    used: u64,
+    next_lsn: Lsn,
+    group: Arc<GroupCommit>,
+}
+
+impl Wal {
+    /// Append to the log held in `file`
+    pub fn open(file: Box<dyn StorageBackend>) -> Result<Self> {
+        let file: Arc<dyn StorageBackend> = Arc::from(file);
+        let slots = file.len()?.div_ceil(SEGMENT_SIZE);
+        Ok(Self {
+            file: Some(Arc::clone(&file)),
+            pending: Vec::new(),
+            commits: 0,
+            segments: VecDeque::new(),
+            free: BTreeSet::new(),
+            slots,
+            used: 0,
+            next_lsn: 1,
+            group: Arc::new(GroupCommit::new(Some(file), 0)),
+        })
+    }
+
+    /// Log of a read-only handle, checking that `file`, if there is a log,
+    /// holds nothing that recovery would have to replay on top of
+    /// `superblock`
+    ///
+    /// Replaying writes to the data file, so a read-only handle cannot open a
+    /// database that was not closed cleanly and fails with
+    /// [`Error::ReadOnly`].
+    pub fn open_read_only(
+        file: Option<Box<dyn StorageBackend>>,
+        superblock: Superblock,
+    ) -> Result<Self> {
+        let mut wal = Self {
+            file: None,
+            pending: Vec::new(),
+            commits: 0,
+            segments: VecDeque::new(),
+            free: BTreeSet::new(),
+            slots: 0,
+            used: 0,
+            next_lsn: superblock.checkpoint_lsn + 1,
+            group: Arc::new(GroupCommit::new(None, superblock.checkpoint_lsn)),
+        };
+        if let Some(file) = file {
+            wal.file = Some(Arc::from(file));
+            let records = wal.read_records(superblock.checkpoint_lsn)?;
+            wal.file = None;
+            let replay = records.iter().any(|record| {
+                record.lsn > superblock.checkpoint_lsn
+                    && matches!(record.body, RecordBody::Commit { .. })
+            });
+            if replay {
+                return Err(Error::ReadOnly);
+            }
+        }
+        Ok(wal)
+    }
+
+    /// The log file, unless the handle is read-only
+    fn file(&self) -> Result<&dyn StorageBackend> {
+        self.file.as_deref().ok_or(Error::ReadOnly)
+    }
+
+    /// Decode the records from the segment holding the first record past
+    /// `checkpoint` on, stopping at the first torn, corrupt or stale record
+    ///
+    /// Records up to `checkpoint` in that first segment are included.
+    pub fn read_records(&self, checkpoint: Lsn) -> Result<Vec<Record>> {
+        let file = self.file()?;
+        let mut bytes = vec![0u8; file.len()? as usize];
+        file.read_at(&mut bytes, 0)?;
+        let mut segments: Vec<(Lsn, &[u8])> = bytes
+            .chunks(SEGMENT_SIZE as usize)
+            .filter_map(|segment| {
+                let first = segment_first_lsn(segment)?;
+                Some((first, &segment[SEGMENT_HEADER_SIZE..]))
+            })
+            .collect();
+        segments.sort_unstable_by_key(|&(first, _)| first);
+        // Segments before the one holding the checkpoint are stale
+        let Some(start) = segments
+            .iter()
+            .rposition(|&(first, _)| first <= checkpoint + 1)
+        else {
+            // Later segments cannot be replayed past the missing one
+            return match segments.first() {
+                None => Ok(Vec::new()),
+                Some(&(first, _)) => Err(Error::Corruption(format!(
+                    "log records {} to {} are missing",
+                    checkpoint + 1,
+                    first - 1
+                ))),
+            };
+        };
+        let mut records = Vec::new();
+        let mut next = segments[start].0;
+        for &(first, mut rest) in &segments[start..] {
+            if first != next {
+                break;
+            }
+            while let Some((record, used)) = Record::decode(rest) {
+                if record.lsn != next {
+                    break;
+                }
+                next += 1;
+                rest = &rest[used..];
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+
+    /// Continue numbering after `lsn`
+    pub fn set_last_lsn(&mut self, lsn: Lsn) {
+        self.next_lsn = lsn + 1;
+        self.group.mark_durable(lsn);
+    }
+
+    /// LSN that the next appended record will receive
+    pub fn next_lsn(&self) -> Lsn {
+        self.next_lsn
+    }
+
+    /// Highest LSN known to be on stable storage
+    pub fn durable_lsn(&self) -> Lsn {
+        self.group.durable()
+    }
+
+    /// Durability of the log, for committers to wait on without holding the
+    /// log itself
+    pub fn group(&self) -> Arc<GroupCommit> {
+        Arc::clone(&self.group)
+    }
+
+    /// Size of the segments no checkpoint has recycled yet, in bytes,
+    /// including unsynced records
+    pub fn size(&self) -> u64 {
+        match self.segments.len() as u64 {
+            0 => 0,
+            count => (count - 1) * SEGMENT_SIZE + self.used,
+        }
+    }
+
+    /// Buffer a record, returning its LSN
+    pub fn append(&mut self, txn: TxnId, body: RecordBody) -> Lsn {
+        let lsn = self.next_lsn;
+        self.next_lsn += 1;
+        if matches!(body, RecordBody::Commit { .. }) {
+            self.commits += 1;
+        }
+        let mut bytes = Vec::new();
+        Record { lsn, txn, body }.encode(&mut bytes);
+        if self.segments.is_empty() || self.used + bytes.len() as u64 > SEGMENT_SIZE {
+            self.start_segment(lsn);
+        }
+        let (slot, _) = *self.segments.back().expect("a segment was started");
+        self.buffer(slot * SEGMENT_SIZE + self.used, &bytes);
+        self.used += bytes.len() as u64;
+        lsn
+    }
+
+    /// Begin a segment in the lowest free slot, or a new one at the end of
+    /// the file, with `first` as its first record
+    fn start_segment(&mut self, first: Lsn) {
+        let slot = self.free.pop_first().unwrap_or_else(|| {
+            self.slots += 1;
+            self.slots - 1
+        });
+        self.segments.push_back((slot, first));
+        self.buffer(slot * SEGMENT_SIZE, &segment_header(first));
+        self.used = SEGMENT_HEADER_SIZE as u64;
+    }
+
+    /// Queue `bytes` to be written at `offset`
+    fn buffer(&mut self, offset: u64, bytes: &[u8]) {
+        match self.pending.last_mut() {
+            Some((start, run)) if *start + run.len() as u64 == offset => {
+                run.extend_from_slice(bytes)
+            }
+            _ => self.pending.push((offset, bytes.to_vec())),
+        }
+    }
+
+    /// Write buffered records to the file without syncing it, returning the
+    /// LSN of the last record written
+    pub fn write(&mut self) -> Result<Lsn> {
+        if !self.pending.is_empty() {
+            let writes: Vec<_> = self
+                .pending
+                .iter()
+                .map(|(offset, run)| (&run[..], *offset))
+                .collect();
+            if let Err(err) = self.file()?.write_batch(&writes) {
+                self.group.fail(&err);
+                return Err(err.into());
+            }
+            self.pending.clear();
+        }
+        let lsn = self.next_lsn - 1;
+        self.group.written(lsn, std::mem::take(&mut self.commits));
+        Ok(lsn)
+    }
+
+    /// Write buffered records and sync the log
+    pub fn sync(&mut self) -> Result<()> {
+        let lsn = self.write()?;
+        self.group.sync_to(lsn)
+    }
+
+    /// Make sure every record up to `lsn` is durable
+    pub fn sync_to(&mut self, lsn: Lsn) -> Result<()> {
+        if lsn > self.durable_lsn() {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Recycle the segments holding only records up to `checkpoint`, which a
+    /// durable superblock must cover, or truncate the log if that is all of
+    /// them
+    pub fn recycle(&mut self, checkpoint: Lsn) -> Result<()> {
+        if checkpoint + 1 >= self.next_lsn {
+            return self.truncate();
+        }
+        while self
+            .segments
+            .get(1)
+            .is_some_and(|&(_, first)| first <= checkpoint + 1)
+        {
+            let (slot, _) = self.segments.pop_front().expect("segment exists");
+            self.free.insert(slot);
+        }
+        let slots = self.slots;
+        while self.slots > 0 && self.free.remove(&(self.slots - 1)) {
+            self.slots -= 1;
+        }
+        if self.slots < slots {
+            // A crash undoing the cut only brings back stale segments
+            self.file()?.set_len(self.slots * SEGMENT_SIZE)?;
+        }
+        Ok(())
+    }
+
+    /// Discard the whole log once its effects are in the data file
+    pub fn truncate(&mut self) -> Result<()> {
+        self.pending.clear();
+        self.commits = 0;
+        self.file()?.set_len(0)?;
+        self.file()?.sync()?;
+        self.segments.clear();
+        self.free.clear();
+        (self.slots, self.used) = (0, 0);
+        self.group.mark_durable(self.next_lsn - 1);
+        Ok(())
+    }
+}
+
+/// Replay committed transactions from `wal` into `data`
+///
+/// Page images are applied in log order; since they are full images, replay
+/// is idempotent and also repairs data pages torn by a crash mid-write.
+/// Returns the superblock describing the recovered state.
+pub(crate) fn recover(
+    wal: &mut Wal,
+    data: &dyn StorageBackend,
+    superblock: Superblock,
+) -> Result<Superblock> {
+    let records = wal.read_records(superblock.checkpoint_lsn)?;
+    let last_lsn = records
+        .iter()
+        .map(|r| r.lsn)
+        .fold(superblock.checkpoint_lsn, Lsn::max);
+    wal.set_last_lsn(last_lsn);
+
+    let mut pending: HashMap<TxnId, Vec<(PageId, PageImage)>> = HashMap::new();
+    let mut recovered = superblock;
+    let mut replayed = false;
+    for record in records {
+        if record.lsn <= superblock.checkpoint_lsn {
+            continue;
+        }
+        match record.body {
+            RecordBody::Page { id, image } => {
+                pending.entry(record.txn).or_default().push((id, image));
+            }
+            RecordBody::Commit {
+                root,
+                page_count,
+                free_list,
+                catalog,
+                savepoints,
+            } => {
+                for (id, image) in pending.remove(&record.txn).unwrap_or_default() {
+                    data.write_at(&image[..], id * PAGE_SIZE as u64)?;
+                }
+                recovered.root = root;
+                recovered.page_count = page_count;
+                recovered.free_list = free_list;
+                recovered.catalog = catalog;
+                recovered.savepoints = savepoints;
+                replayed = true;
+            }
+        }
+    }
+    // Anything left in `pending` never committed and is dropped
+
+    if replayed {
+        data.sync()?;
+        recovered.generation += 1;
+        recovered.checkpoint_lsn = last_lsn;
+        header::write_superblock(data, &recovered)?;
+    }
+    wal.truncate()?;
+    Ok(recovered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::AlignedPage;
+    use crate::storage::MemoryBackend;
+
+    fn image(byte: u8) -> PageImage {
+        Box::new(AlignedPage([byte; PAGE_SIZE]))
+    }
+
+    fn commit(root: PageId, page_count: u64) -> RecordBody {
+        RecordBody::Commit {
+            root,
+            page_count,
+            free_list: 0,
+            catalog: 0,
+            savepoints: 0,
+        }
+    }
+
+    #[test]
+    fn append_sync_and_read_back() {
+        let log = MemoryBackend::new();
+        let mut wal = Wal::open(Box::new(log.clone())).unwrap();
+        let first = wal.append(
+            1,
+            RecordBody::Page {
+                id: 1,
+                image: image(1),
+            },
+        );
+        let second = wal.append(1, commit(1, 2));
+        assert_eq!((first, second), (1, 2));
+        assert_eq!(wal.durable_lsn(), 0);
+        wal.sync().unwrap();
+        assert_eq!(wal.durable_lsn(), 2);
+
+        let reopened = Wal::open(Box::new(log.clone())).unwrap();
+        let records = reopened.read_records(0).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].body, commit(1, 2));
+    }
+
+    #[test]
+    fn torn_tail_is_ignored() {
+        let log = MemoryBackend::new();
+        let mut wal = Wal::open(Box::new(log.clone())).unwrap();
+        wal.append(1, commit(1, 2));
+        wal.append(2, commit(2, 3));
+        wal.sync().unwrap();
+        let len = wal.size();
+        wal.file().unwrap().set_len(len - 3).unwrap();
+
+        let records = Wal::open(Box::new(log.clone()))
+            .unwrap()
+            .read_records(0)
+            .unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn recovery_applies_only_committed_transactions() {
+        let data = MemoryBackend::new();
+        let superblock = header::init(&data).unwrap();
+
+        let mut wal = Wal::open(Box::new(MemoryBackend::new())).unwrap();
+        wal.append(
+            1,
+            RecordBody::Page {
+                id: 1,
+                image: image(0xaa),
+            },
+        );
+        wal.append(1, commit(1, 3));
+        wal.append(
+            2,
+            RecordBody::Page {
+                id: 2,
+                image: image(0xbb),
+            },
+        );
+        wal.sync().unwrap();
+
+        let recovered = recover(&mut wal, &data, superblock).unwrap();
+        assert_eq!(recovered.root, 1);
+        assert_eq!(recovered.page_count, 3);
+        assert_eq!(recovered.checkpoint_lsn, 3);
+        assert_eq!(header::load(&data).unwrap(), recovered);
+        assert_eq!(wal.size(), 0);
+        assert_eq!(wal.next_lsn(), 4);
+
+        let mut page = [0u8; PAGE_SIZE];
+        data.read_at(&mut page, PAGE_SIZE as u64).unwrap();
+        assert_eq!(page, [0xaa; PAGE_SIZE]);
+        // Uncommitted image was never written
+        assert!(data.len().unwrap() < 3 * PAGE_SIZE as u64);
+    }
+
+    #[test]
+    fn recovery_skips_checkpointed_records() {
+        let data = MemoryBackend::new();
+        let mut superblock = header::init(&data).unwrap();
+        superblock.checkpoint_lsn = 10;
+
+        let mut wal = Wal::open(Box::new(MemoryBackend::new())).unwrap();
+        wal.set_last_lsn(9);
+        wal.append(1, commit(4, 5));
+        wal.sync().unwrap();
+
+        let recovered = recover(&mut wal, &data, superblock).unwrap();
+        assert_eq!(recovered, superblock);
+        assert_eq!(wal.next_lsn(), 11);
+    }
+
+    #[test]
+    fn checkpointed_segments_are_recycled() {
+        let log = MemoryBackend::new();
+        let mut wal = Wal::open(Box::new(log.clone())).unwrap();
+        let append_page = |wal: &mut Wal| {
+            let image = image(1);
+            wal.append(1, RecordBody::Page { id: 1, image })
+        };
+        while wal.segments.len() < 3 {
+            append_page(&mut wal);
+        }
+        wal.sync().unwrap();
+        let second = wal.segments[1].1;
+        wal.recycle(second).unwrap();
+        assert_eq!(wal.segments.len(), 2);
+
+        // Once the newest segment fills, the first one's slot is reused
+        while wal.segments.len() < 3 {
+            append_page(&mut wal);
+        }
+        let last = append_page(&mut wal);
+        wal.sync().unwrap();
+        assert_eq!(wal.segments[2].0, 0);
+        assert!(log.len().unwrap() <= 3 * SEGMENT_SIZE);
+
+        // Reading skips the stale records left in the reused slot
+        let records = Wal::open(Box::new(log.clone()))
+            .unwrap()
+            .read_records(second)
+            .unwrap();
+        let lsns: Vec<Lsn> = records.iter().map(|record| record.lsn).collect();
+        assert_eq!(lsns, (second..=last).collect::<Vec<_>>());
+
+        wal.recycle(last).unwrap();
+        assert_eq!((wal.size(), log.len().unwrap()), (0, 0));
+    }
+}
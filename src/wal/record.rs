@@ -0,0 +1,214 @@
+//! WAL record format
+//!
+//! ```text
+//! +---------+---------+---------+---------+--------+-----------------+
+//! | len u32 | crc u32 | lsn u64 | txn u64 | kind u8| payload (len-17)|
+//! +---------+---------+---------+---------+--------+-----------------+
+//! ```
+//!
+//! `len` counts every byte after the `crc` field and `crc` is the CRC32C of
+//! those same bytes, so a torn or partially written tail fails validation.
+
+use crate::buffer::{AlignedPage, PAGE_SIZE, PageId};
+
+/// Log sequence number
+pub(crate) type Lsn = u64;
+
+/// Transaction identifier
+pub(crate) type TxnId = u64;
+
+/// Full copy of a page's bytes
+pub(crate) type PageImage = Box<AlignedPage>;
+
+/// Size of the `len` and `crc` prefix
+const PREFIX_SIZE: usize = 8;
+
+/// Size of the fixed `lsn | txn | kind` part covered by `len`
+const FIXED_SIZE: usize = 17;
+
+const KIND_PAGE: u8 = 1;
+const KIND_COMMIT: u8 = 2;
+
+/// Payload of a log record
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) enum RecordBody {
+    /// Full after-image of a page
+    Page {
+        /// Page the image belongs to
+        id: PageId,
+        /// Page contents, with the header LSN set to this record's LSN
+        image: PageImage,
+    },
+    /// The transaction's page images are complete; publish this state
+    Commit {
+        /// Root page of the primary tree
+        root: PageId,
+        /// Number of pages in the file
+        page_count: u64,
+        /// Head of the free page list
+        free_list: PageId,
+        /// Root page of the table catalog
+        catalog: PageId,
+        /// Root page of the persistent savepoint registry
+        savepoints: PageId,
+    },
+}
+
+impl std::fmt::Debug for RecordBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordBody::Page { id, .. } => f.debug_struct("Page").field("id", id).finish(),
+            RecordBody::Commit {
+                root,
+                page_count,
+                free_list,
+                catalog,
+                savepoints,
+            } => f
+                .debug_struct("Commit")
+                .field("root", root)
+                .field("page_count", page_count)
+                .field("free_list", free_list)
+                .field("catalog", catalog)
+                .field("savepoints", savepoints)
+                .finish(),
+        }
+    }
+}
+
+/// A decoded log record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Record {
+    pub lsn: Lsn,
+    pub txn: TxnId,
+    pub body: RecordBody,
+}
+
+impl Record {
+    /// Append the encoded record to `out`
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(&[0; PREFIX_SIZE]);
+        out.extend_from_slice(&self.lsn.to_le_bytes());
+        out.extend_from_slice(&self.txn.to_le_bytes());
+        match &self.body {
+            RecordBody::Page { id, image } => {
+                out.push(KIND_PAGE);
+                out.extend_from_slice(&id.to_le_bytes());
+                out.extend_from_slice(&image[..]);
+            }
+            RecordBody::Commit {
+                root,
+                page_count,
+                free_list,
+                catalog,
+                savepoints,
+            } => {
+                out.push(KIND_COMMIT);
+                out.extend_from_slice(&root.to_le_bytes());
+                out.extend_from_slice(&page_count.to_le_bytes());
+                out.extend_from_slice(&free_list.to_le_bytes());
+                out.extend_from_slice(&catalog.to_le_bytes());
+                out.extend_from_slice(&savepoints.to_le_bytes());
+            }
+        }
+        let body = &out[start + PREFIX_SIZE..];
+        let len = body.len() as u32;
+        let crc = crc32c::crc32c(body);
+        out[start..start + 4].copy_from_slice(&len.to_le_bytes());
+        out[start + 4..start + 8].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    /// Decode the record at the start of `buf`
+    ///
+    /// Returns the record and its encoded size, or `None` if `buf` does not
+    /// start with a complete, valid record.
+    pub fn decode(buf: &[u8]) -> Option<(Self, usize)> {
+        let len = u32::from_le_bytes(buf.get(0..4)?.try_into().ok()?) as usize;
+        let crc = u32::from_le_bytes(buf.get(4..8)?.try_into().ok()?);
+        let body = buf.get(PREFIX_SIZE..PREFIX_SIZE + len)?;
+        if len < FIXED_SIZE || crc32c::crc32c(body) != crc {
+            return None;
+        }
+
+        let u64_at = |at: usize| u64::from_le_bytes(body[at..at + 8].try_into().unwrap());
+        let lsn = u64_at(0);
+        let txn = u64_at(8);
+        let payload = &body[FIXED_SIZE..];
+        let body = match (body[16], payload.len()) {
+            (KIND_PAGE, n) if n == 8 + PAGE_SIZE => RecordBody::Page {
+                id: u64::from_le_bytes(payload[..8].try_into().unwrap()),
+                image: Box::new(AlignedPage(payload[8..].try_into().unwrap())),
+            },
+            (KIND_COMMIT, 40) => RecordBody::Commit {
+                root: u64_at(FIXED_SIZE),
+                page_count: u64_at(FIXED_SIZE + 8),
+                free_list: u64_at(FIXED_SIZE + 16),
+                catalog: u64_at(FIXED_SIZE + 24),
+                savepoints: u64_at(FIXED_SIZE + 32),
+            },
+            _ => return None,
+        };
+        Some((Record { lsn, txn, body }, PREFIX_SIZE + len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page_record() -> Record {
+        let mut image = Box::new(AlignedPage([0; PAGE_SIZE]));
+        image[100] = 42;
+        Record {
+            lsn: 7,
+            txn: 3,
+            body: RecordBody::Page { id: 5, image },
+        }
+    }
+
+    fn commit_record() -> Record {
+        Record {
+            lsn: 8,
+            txn: 3,
+            body: RecordBody::Commit {
+                root: 5,
+                page_count: 6,
+                free_list: 0,
+                catalog: 4,
+                savepoints: 3,
+            },
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut buf = Vec::new();
+        page_record().encode(&mut buf);
+        commit_record().encode(&mut buf);
+
+        let (first, used) = Record::decode(&buf).unwrap();
+        assert_eq!(first, page_record());
+        let (second, rest) = Record::decode(&buf[used..]).unwrap();
+        assert_eq!(second, commit_record());
+        assert_eq!(used + rest, buf.len());
+    }
+
+    #[test]
+    fn torn_record_is_rejected() {
+        let mut buf = Vec::new();
+        page_record().encode(&mut buf);
+        for cut in [0, 4, 20, buf.len() - 1] {
+            assert!(Record::decode(&buf[..cut]).is_none());
+        }
+    }
+
+    #[test]
+    fn bit_flip_is_rejected() {
+        let mut buf = Vec::new();
+        commit_record().encode(&mut buf);
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert!(Record::decode(&buf).is_none());
+    }
+}
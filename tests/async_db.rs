@@ -0,0 +1,200 @@
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::key;
+use qpdb::{AsyncDatabase, Database, Error, KeyValue};
+
+async fn collect(mut stream: qpdb::RangeStream) -> Vec<KeyValue> {
+    let mut entries = Vec::new();
+    while let Some(entry) = stream.next().await {
+        entries.push(entry.unwrap());
+    }
+    entries
+}
+
+#[tokio::test]
+async fn single_operations_commit() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = AsyncDatabase::open(&path).await.unwrap();
+    assert_eq!(db.insert(b"a", b"1").await.unwrap(), None);
+    assert_eq!(
+        db.insert(b"a", b"2").await.unwrap().as_deref(),
+        Some(&b"1"[..])
+    );
+    db.insert(b"b", b"3").await.unwrap();
+    assert_eq!(db.remove(b"b").await.unwrap().as_deref(), Some(&b"3"[..]));
+    assert_eq!(db.get(b"a").await.unwrap().as_deref(), Some(&b"2"[..]));
+    assert_eq!(db.get(b"b").await.unwrap(), None);
+    // The same data through the synchronous handle
+    assert_eq!(db.database().iter().count(), 1);
+    db.close().await.unwrap();
+
+    let db = Database::open(&path).unwrap();
+    assert_eq!(db.get(b"a").unwrap().as_deref(), Some(&b"2"[..]));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn write_transactions_commit_or_roll_back() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = AsyncDatabase::open(dir.path().join("db.qpdb"))
+        .await
+        .unwrap();
+
+    let mut txn = db.begin_write().await.unwrap();
+    for n in 0..100 {
+        txn.insert(&key(n), b"new").await.unwrap();
+    }
+    assert_eq!(
+        txn.get(&key(7)).await.unwrap().as_deref(),
+        Some(&b"new"[..])
+    );
+    assert_eq!(db.get(&key(7)).await.unwrap(), None);
+
+    // A second writer waits for the first to finish
+    let waiting = tokio::spawn({
+        let db = db.clone();
+        async move {
+            let mut txn = db.begin_write().await.unwrap();
+            let old = txn.remove(&key(7)).await.unwrap();
+            txn.commit().await.unwrap();
+            old
+        }
+    });
+    for _ in 0..10 {
+        tokio::task::yield_now().await;
+    }
+    assert!(!waiting.is_finished());
+    txn.commit().await.unwrap();
+    assert_eq!(waiting.await.unwrap().as_deref(), Some(&b"new"[..]));
+    assert_eq!(collect(db.iter()).await.len(), 99);
+
+    let mut txn = db.begin_write().await.unwrap();
+    txn.remove(&key(0)).await.unwrap();
+    drop(txn);
+    let mut txn = db.begin_write().await.unwrap();
+    txn.insert(&key(1000), b"").await.unwrap();
+    txn.abort();
+    assert_eq!(collect(db.iter()).await.len(), 99);
+}
+
+#[tokio::test]
+async fn range_streams_read_a_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = AsyncDatabase::open(dir.path().join("db.qpdb"))
+        .await
+        .unwrap();
+    let mut txn = db.begin_write().await.unwrap();
+    for n in 0..1000 {
+        txn.insert(&key(n), &n.to_le_bytes()).await.unwrap();
+    }
+    txn.commit().await.unwrap();
+
+    let mut stream = db.range(key(100)..key(900));
+    let first = stream.next().await.unwrap().unwrap();
+    assert_eq!(first, (key(100).to_vec(), 100u32.to_le_bytes().to_vec()));
+    // Changes committed after the stream began stay out of it
+    db.remove(&key(500)).await.unwrap();
+    db.insert(&key(450), b"changed").await.unwrap();
+    let rest = collect(stream).await;
+    assert_eq!(rest.len(), 799);
+    assert!(
+        rest.iter()
+            .zip(101u32..)
+            .all(|((k, v), n)| *k == key(n) && *v == n.to_le_bytes())
+    );
+
+    assert_eq!(collect(db.iter()).await.len(), 999);
+    assert!(collect(db.range(key(2000)..)).await.is_empty());
+}
+
+#[tokio::test]
+async fn cold_pages_load_off_the_runtime() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = AsyncDatabase::open(&path).await.unwrap();
+    let mut txn = db.begin_write().await.unwrap();
+    for n in 0..5000 {
+        txn.insert(&key(n), &[n as u8; 100]).await.unwrap();
+    }
+    txn.commit().await.unwrap();
+    db.close().await.unwrap();
+
+    let db = Database::builder()
+        .cache_size(0)
+        .open_async(&path)
+        .await
+        .unwrap();
+    for n in (0..5000).step_by(97) {
+        let value = db.get(&key(n)).await.unwrap();
+        assert_eq!(value.as_deref(), Some(&[n as u8; 100][..]));
+    }
+    assert!(db.database().cache_stats().misses > 0);
+    assert_eq!(collect(db.iter()).await.len(), 5000);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn close_waits_for_every_other_handle() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = AsyncDatabase::open(&path).await.unwrap();
+    db.insert(b"a", b"1").await.unwrap();
+    let other = db.clone();
+    let stream = db.iter();
+
+    let closing = tokio::spawn(db.close());
+    for _ in 0..10 {
+        tokio::task::yield_now().await;
+    }
+    assert!(!closing.is_finished());
+    drop(stream);
+    drop(other);
+    closing.await.unwrap().unwrap();
+
+    // The file is unlocked once `close` returns
+    let db = AsyncDatabase::open(&path).await.unwrap();
+    assert_eq!(db.get(b"a").await.unwrap().as_deref(), Some(&b"1"[..]));
+    // Of two handles closing at once, one checkpoints and the other is told
+    let closes = [tokio::spawn(db.clone().close()), tokio::spawn(db.close())];
+    let mut results = Vec::new();
+    for close in closes {
+        results.push(close.await.unwrap());
+    }
+    assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+    assert!(
+        results
+            .iter()
+            .any(|result| matches!(result, Err(Error::Closing)))
+    );
+    Database::open(&path).unwrap();
+}
+
+#[tokio::test]
+async fn last_stream_closes_the_database() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = AsyncDatabase::open(&path).await.unwrap();
+    db.insert(b"a", b"1").await.unwrap();
+    let stream = db.iter();
+    drop(db);
+    // The stream holds the database open until it goes, inside a task
+    assert!(matches!(
+        Database::open(&path),
+        Err(Error::DatabaseLocked { .. })
+    ));
+    tokio::spawn(async move { drop(stream) }).await.unwrap();
+
+    // The database closes on the blocking pool, not on the runtime
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let db = loop {
+        match Database::open(&path) {
+            Ok(db) => break db,
+            Err(Error::DatabaseLocked { .. }) if Instant::now() < deadline => {
+                tokio::task::yield_now().await
+            }
+            Err(err) => panic!("{err}"),
+        }
+    };
+    assert_eq!(db.get(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+}
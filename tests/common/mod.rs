@@ -0,0 +1,27 @@
+//! Helpers shared by the integration tests
+
+// Each test crate uses only some of the helpers
+#![allow(dead_code)]
+
+use std::path::Path;
+
+use qpdb::Database;
+
+/// Key `n`, encoded so that keys sort in numeric order
+pub fn key(n: u32) -> [u8; 4] {
+    n.to_be_bytes()
+}
+
+/// Size of the file at `path` in bytes
+pub fn size(path: &Path) -> u64 {
+    std::fs::metadata(path).unwrap().len()
+}
+
+/// Simulate a crash of the database
+///
+/// The background threads are stopped before the handle goes, so nothing
+/// writes to the files afterwards, and no checkpoint runs on the way out.
+/// The files are left as a crashed process leaves them.
+pub fn crash(db: Database) {
+    db.simulate_crash();
+}
@@ -0,0 +1,149 @@
+//! Shrinking the data file with `Database::compact`
+
+mod common;
+
+use common::{crash, size};
+use qpdb::{Database, TableDefinition};
+
+const NUMBERS: TableDefinition<u32, str> = TableDefinition::new("numbers");
+
+/// Fill the default tree, then keep only every tenth entry
+fn fill_and_thin(db: &Database) {
+    for n in 0u32..5000 {
+        db.insert(&n.to_be_bytes(), &[7; 100]).unwrap();
+    }
+    for n in (0u32..5000).filter(|n| n % 10 != 0) {
+        db.remove(&n.to_be_bytes()).unwrap();
+    }
+}
+
+fn check_thinned(db: &Database) {
+    let keys: Vec<u32> = db
+        .iter()
+        .map(|entry| u32::from_be_bytes(entry.unwrap().0.try_into().unwrap()))
+        .collect();
+    assert_eq!(keys, (0u32..5000).step_by(10).collect::<Vec<_>>());
+}
+
+#[test]
+fn compact_shrinks_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = Database::open(&path).unwrap();
+    fill_and_thin(&db);
+    db.flush().unwrap();
+    let before = size(&path);
+
+    db.compact().unwrap();
+    let after = size(&path);
+    assert!(after * 4 < before, "{after} not well below {before}");
+    check_thinned(&db);
+
+    drop(db);
+    assert_eq!(size(&path), after);
+    let db = Database::open(&path).unwrap();
+    check_thinned(&db);
+    // The file grows again from where compaction left it
+    db.insert(b"new", b"entry").unwrap();
+    assert_eq!(db.get(b"new").unwrap().as_deref(), Some(&b"entry"[..]));
+}
+
+#[test]
+fn compact_right_after_freeing_most_of_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = Database::open(&path).unwrap();
+    let mut txn = db.begin_write().unwrap();
+    for n in 0u32..5000 {
+        txn.insert(&n.to_be_bytes(), &[7; 100]).unwrap();
+    }
+    txn.commit().unwrap();
+    // Freed all at once, so the free list is appended to the file
+    let mut txn = db.begin_write().unwrap();
+    for n in (0u32..5000).filter(|n| n % 10 != 0) {
+        txn.remove(&n.to_be_bytes()).unwrap();
+    }
+    txn.commit().unwrap();
+    db.flush().unwrap();
+    let before = size(&path);
+
+    db.compact().unwrap();
+    let after = size(&path);
+    assert!(after * 4 < before, "{after} not well below {before}");
+    check_thinned(&db);
+    // Nothing is left to move down, and nothing moves up
+    db.compact().unwrap();
+    assert_eq!(size(&path), after);
+    check_thinned(&db);
+}
+
+#[test]
+fn compact_moves_table_pages() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = Database::open(&path).unwrap();
+    let txn = db.begin_write().unwrap();
+    {
+        let mut scratch = txn.open_table("scratch").unwrap();
+        for n in 0u32..5000 {
+            scratch.insert(&n.to_be_bytes(), &[1; 100]).unwrap();
+        }
+        let mut numbers = txn.open_typed_table(NUMBERS).unwrap();
+        for n in 0u32..2000 {
+            numbers.insert(&n, &n.to_string()).unwrap();
+        }
+    }
+    txn.commit().unwrap();
+    let mut txn = db.begin_write().unwrap();
+    txn.delete_table("scratch").unwrap();
+    txn.commit().unwrap();
+    db.flush().unwrap();
+    let before = size(&path);
+
+    db.compact().unwrap();
+    assert!(size(&path) < before);
+    drop(db);
+
+    let db = Database::open(&path).unwrap();
+    let txn = db.begin_read().unwrap();
+    assert_eq!(txn.list_tables().unwrap(), ["numbers"]);
+    let numbers = txn.open_typed_table(NUMBERS).unwrap();
+    for n in 0u32..2000 {
+        assert_eq!(numbers.get(&n).unwrap(), Some(n.to_string()));
+    }
+}
+
+#[test]
+fn readers_keep_their_snapshot_during_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    fill_and_thin(&db);
+
+    let reader = db.begin_read().unwrap();
+    db.compact().unwrap();
+    db.insert(b"after", b"compaction").unwrap();
+    assert_eq!(reader.iter().count(), 500);
+    assert_eq!(reader.get(b"after").unwrap(), None);
+    drop(reader);
+    db.remove(b"after").unwrap();
+    check_thinned(&db);
+}
+
+#[test]
+fn compaction_survives_a_crash() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = Database::open(&path).unwrap();
+    fill_and_thin(&db);
+    db.compact().unwrap();
+    db.insert(b"after", b"compaction").unwrap();
+    crash(db);
+
+    let db = Database::open(&path).unwrap();
+    assert_eq!(
+        db.get(b"after").unwrap().as_deref(),
+        Some(&b"compaction"[..])
+    );
+    db.remove(b"after").unwrap();
+    check_thinned(&db);
+}
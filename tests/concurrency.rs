@@ -0,0 +1,126 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use qpdb::Database;
+
+const KEYS: u32 = 2000;
+
+fn value(key: u32, generation: u32) -> Vec<u8> {
+    let mut value = format!("{key}:{generation}:").into_bytes();
+    value.resize(120, b'.');
+    value
+}
+
+/// Readers look keys up while one thread rewrites them, checking every value
+/// they see is a whole value written for that key
+fn readers_alongside_writer(db: &Database) {
+    for key in 0..KEYS {
+        db.insert(&key.to_be_bytes(), &value(key, 0)).unwrap();
+    }
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        for reader in 0..4u32 {
+            let done = &done;
+            scope.spawn(move || {
+                let mut key = reader;
+                while !done.load(Ordering::Relaxed) {
+                    key = (key * 7 + 13) % KEYS;
+                    let found = db.get(&key.to_be_bytes()).unwrap().expect("key exists");
+                    let text = String::from_utf8(found).unwrap();
+                    let mut fields = text.split(':');
+                    assert_eq!(fields.next(), Some(key.to_string().as_str()));
+                    let generation: u32 = fields.next().unwrap().parse().unwrap();
+                    assert_eq!(text.as_bytes(), value(key, generation));
+                }
+            });
+        }
+        for generation in 1..=300u32 {
+            let key = generation * 31 % KEYS;
+            db.insert(&key.to_be_bytes(), &value(key, generation))
+                .unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+    });
+}
+
+#[test]
+fn readers_run_alongside_a_writer() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    readers_alongside_writer(&db);
+}
+
+#[test]
+fn readers_run_alongside_eviction() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::builder()
+        .cache_size(0)
+        .open(dir.path().join("db.qpdb"))
+        .unwrap();
+    readers_alongside_writer(&db);
+    assert!(db.cache_stats().evictions > 0);
+}
+
+#[test]
+fn concurrent_commits_share_log_syncs() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    thread::scope(|scope| {
+        for writer in 0..8u32 {
+            let db = &db;
+            scope.spawn(move || {
+                for n in 0..50 {
+                    let key = writer * 1000 + n;
+                    db.insert(&key.to_be_bytes(), &value(key, 0)).unwrap();
+                }
+            });
+        }
+    });
+
+    for writer in 0..8u32 {
+        for key in (0..50).map(|n| writer * 1000 + n) {
+            assert_eq!(db.get(&key.to_be_bytes()).unwrap(), Some(value(key, 0)));
+        }
+    }
+    let stats = db.commit_stats();
+    assert_eq!(stats.waits, 400);
+    assert_eq!(stats.batched_commits, 400);
+    assert!(stats.syncs >= 1 && stats.syncs <= 400);
+    assert!(stats.largest_batch >= 1);
+    assert!(stats.longest_wait >= stats.average_wait());
+}
+
+#[test]
+fn checkpoints_run_alongside_writers() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = Database::builder()
+        .cache_size(0)
+        .checkpoint_size(64 << 10)
+        .open(&path)
+        .unwrap();
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            while !done.load(Ordering::Relaxed) {
+                db.checkpoint().unwrap();
+            }
+        });
+        readers_alongside_writer(&db);
+        done.store(true, Ordering::Relaxed);
+    });
+
+    // A checkpoint does not wait for the open write transaction
+    let mut txn = db.begin_write().unwrap();
+    txn.insert(b"open", b"txn").unwrap();
+    db.checkpoint().unwrap();
+    txn.commit().unwrap();
+    drop(db);
+
+    let db = Database::open(&path).unwrap();
+    assert_eq!(db.iter().count(), KEYS as usize + 1);
+    for key in 0..KEYS {
+        let found = db.get(&key.to_be_bytes()).unwrap().expect("key exists");
+        assert!(found.starts_with(format!("{key}:").as_bytes()));
+    }
+}
@@ -0,0 +1,251 @@
+//! Optimistic write transactions running alongside each other
+
+mod common;
+
+use std::sync::Barrier;
+use std::thread;
+
+use common::key;
+use qpdb::{Database, Error, Isolation};
+
+fn counter(db: &Database, name: &[u8]) -> u64 {
+    db.get(name)
+        .unwrap()
+        .map_or(0, |value| u64::from_le_bytes(value.try_into().unwrap()))
+}
+
+fn conflict_key(result: qpdb::Result<()>) -> (Option<String>, Vec<u8>) {
+    match result {
+        Err(Error::Conflict { table, key }) => (table, key),
+        other => panic!("expected a conflict, got {:?}", other),
+    }
+}
+
+#[test]
+fn changes_are_buffered_until_commit() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    for n in 0..10 {
+        db.insert(&key(n), b"old").unwrap();
+    }
+
+    let mut txn = db.begin_concurrent_write().unwrap();
+    assert_eq!(
+        txn.insert(&key(3), b"new").unwrap().as_deref(),
+        Some(&b"old"[..])
+    );
+    assert_eq!(txn.remove(&key(4)).unwrap().as_deref(), Some(&b"old"[..]));
+    txn.insert(&key(20), b"added").unwrap();
+    assert_eq!(txn.get(&key(3)).unwrap().as_deref(), Some(&b"new"[..]));
+    assert_eq!(txn.get(&key(4)).unwrap(), None);
+    // Ranges merge the snapshot with the buffered changes
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = txn.range(key(2)..).map(Result::unwrap).collect();
+    let keys: Vec<u32> = entries
+        .iter()
+        .map(|(k, _)| u32::from_be_bytes(k[..].try_into().unwrap()))
+        .collect();
+    assert_eq!(keys, [2, 3, 5, 6, 7, 8, 9, 20]);
+    assert_eq!(entries[1].1, b"new");
+    assert_eq!(db.get(&key(3)).unwrap().as_deref(), Some(&b"old"[..]));
+
+    txn.commit().unwrap();
+    assert_eq!(db.get(&key(3)).unwrap().as_deref(), Some(&b"new"[..]));
+    assert_eq!(db.get(&key(4)).unwrap(), None);
+    assert_eq!(db.iter().count(), 10);
+
+    let mut txn = db.begin_concurrent_write().unwrap();
+    txn.insert(b"dropped", b"").unwrap();
+    drop(txn);
+    assert_eq!(db.get(b"dropped").unwrap(), None);
+}
+
+#[test]
+fn first_committer_wins() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    let mut first = db.begin_concurrent_write().unwrap();
+    let mut second = db.begin_concurrent_write().unwrap();
+    first.set_isolation(Isolation::Snapshot);
+    second.set_isolation(Isolation::Snapshot);
+    first.insert(b"k", b"first").unwrap();
+    second.insert(b"k", b"second").unwrap();
+    second.insert(b"other", b"second").unwrap();
+    first.commit().unwrap();
+
+    assert_eq!(conflict_key(second.commit()), (None, b"k".to_vec()));
+    assert_eq!(db.get(b"k").unwrap().as_deref(), Some(&b"first"[..]));
+    assert_eq!(db.get(b"other").unwrap(), None);
+}
+
+#[test]
+fn disjoint_writers_all_commit() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    let barrier = Barrier::new(8);
+    thread::scope(|scope| {
+        for writer in 0..8u32 {
+            let (db, barrier) = (&db, &barrier);
+            scope.spawn(move || {
+                let mut txn = db.begin_concurrent_write().unwrap();
+                for n in 0..100 {
+                    txn.insert(&key(writer * 1000 + n), b"v").unwrap();
+                }
+                // Every transaction began before any committed
+                barrier.wait();
+                txn.commit().unwrap();
+            });
+        }
+    });
+    assert_eq!(db.iter().count(), 800);
+}
+
+#[test]
+fn lost_updates_are_detected() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    let threads = 4;
+    let increments = 50;
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| {
+                for _ in 0..increments {
+                    loop {
+                        let mut txn = db.begin_concurrent_write().unwrap();
+                        let value = txn
+                            .get(b"counter")
+                            .unwrap()
+                            .map_or(0, |value| u64::from_le_bytes(value.try_into().unwrap()));
+                        txn.insert(b"counter", &(value + 1).to_le_bytes()).unwrap();
+                        match txn.commit() {
+                            Ok(()) => break,
+                            Err(Error::Conflict { .. }) => continue,
+                            Err(err) => panic!("{err}"),
+                        }
+                    }
+                }
+            });
+        }
+    });
+    assert_eq!(counter(&db, b"counter"), threads * increments);
+}
+
+/// Two transactions each read the key the other writes
+fn write_skew(db: &Database, isolation: Isolation) -> qpdb::Result<()> {
+    db.insert(b"a", b"on").unwrap();
+    db.insert(b"b", b"on").unwrap();
+    let mut first = db.begin_concurrent_write().unwrap();
+    let mut second = db.begin_concurrent_write().unwrap();
+    first.set_isolation(isolation);
+    second.set_isolation(isolation);
+    // Either may turn its key off as long as the other is still on
+    if first.get(b"b").unwrap().as_deref() == Some(b"on") {
+        first.insert(b"a", b"off").unwrap();
+    }
+    if second.get(b"a").unwrap().as_deref() == Some(b"on") {
+        second.insert(b"b", b"off").unwrap();
+    }
+    first.commit().unwrap();
+    second.commit()
+}
+
+#[test]
+fn serializable_transactions_prevent_write_skew() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    write_skew(&db, Isolation::Snapshot).unwrap();
+    assert_eq!(db.get(b"a").unwrap().as_deref(), Some(&b"off"[..]));
+    assert_eq!(db.get(b"b").unwrap().as_deref(), Some(&b"off"[..]));
+
+    assert_eq!(
+        conflict_key(write_skew(&db, Isolation::Serializable)),
+        (None, b"a".to_vec())
+    );
+    assert_eq!(db.get(b"b").unwrap().as_deref(), Some(&b"on"[..]));
+}
+
+#[test]
+fn scanned_ranges_detect_phantoms() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    for n in [10, 20, 30, 40] {
+        db.insert(&key(n), b"").unwrap();
+    }
+
+    let mut txn = db.begin_concurrent_write().unwrap();
+    let count = txn.range(key(15)..key(35)).count() as u32;
+    txn.insert(b"count", &count.to_le_bytes()).unwrap();
+    // Only the part of a range walked counts as read
+    assert_eq!(txn.range(key(35)..).next().unwrap().unwrap().0, key(40));
+    db.insert(&key(50), b"").unwrap();
+    db.insert(&key(45), b"").unwrap();
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_concurrent_write().unwrap();
+    let count = txn.range(key(15)..key(35)).count() as u32;
+    txn.insert(b"count", &count.to_le_bytes()).unwrap();
+    db.insert(&key(25), b"").unwrap();
+    assert_eq!(conflict_key(txn.commit()), (None, key(25).to_vec()));
+}
+
+#[test]
+fn table_conflicts_name_the_table() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    let first = db.begin_concurrent_write().unwrap();
+    let second = db.begin_concurrent_write().unwrap();
+    first
+        .open_table("stock")
+        .unwrap()
+        .insert(b"apples", b"3")
+        .unwrap();
+    let mut table = second.open_table("stock").unwrap();
+    assert_eq!(table.get(b"apples").unwrap(), None);
+    table.insert(b"apples", b"5").unwrap();
+    drop(table);
+    first.commit().unwrap();
+    assert_eq!(
+        conflict_key(second.commit()),
+        (Some("stock".to_owned()), b"apples".to_vec())
+    );
+
+    let read = db.begin_read().unwrap();
+    let stock = read.open_table("stock").unwrap();
+    assert_eq!(stock.get(b"apples").unwrap().as_deref(), Some(&b"3"[..]));
+}
+
+#[test]
+fn optimistic_and_single_writers_mix() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    db.insert(b"shared", b"0").unwrap();
+
+    let mut optimistic = db.begin_concurrent_write().unwrap();
+    optimistic.get(b"shared").unwrap();
+    optimistic.insert(b"mine", b"").unwrap();
+    // The single writer does not wait for optimistic transactions
+    let mut writer = db.begin_write().unwrap();
+    writer.insert(b"shared", b"1").unwrap();
+    writer.commit().unwrap();
+    assert_eq!(
+        conflict_key(optimistic.commit()),
+        (None, b"shared".to_vec())
+    );
+
+    // A value changed and changed back does not conflict
+    let mut optimistic = db.begin_concurrent_write().unwrap();
+    optimistic.get(b"shared").unwrap();
+    optimistic.insert(b"mine", b"").unwrap();
+    db.insert(b"shared", b"2").unwrap();
+    db.insert(b"shared", b"1").unwrap();
+    optimistic.commit().unwrap();
+    assert!(db.get(b"mine").unwrap().is_some());
+}
+
+#[test]
+fn read_only_handles_refuse_concurrent_writes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    Database::open(&path).unwrap();
+    let db = Database::builder().read_only(true).open(&path).unwrap();
+    assert!(matches!(db.begin_concurrent_write(), Err(Error::ReadOnly)));
+}
@@ -0,0 +1,321 @@
+use std::collections::BTreeMap;
+
+use proptest::prelude::*;
+use qpdb::{Database, Error, MAX_ENTRY_SIZE};
+
+#[test]
+fn data_survives_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    {
+        let db = Database::open(&path).unwrap();
+        for n in 0u32..2000 {
+            db.insert(&n.to_be_bytes(), format!("value {}", n).as_bytes())
+                .unwrap();
+        }
+        for n in (0u32..2000).step_by(3) {
+            db.remove(&n.to_be_bytes()).unwrap();
+        }
+    }
+
+    let db = Database::open(&path).unwrap();
+    for n in 0u32..2000 {
+        let expected = (n % 3 != 0).then(|| format!("value {}", n).into_bytes());
+        assert_eq!(db.get(&n.to_be_bytes()).unwrap(), expected);
+    }
+}
+
+#[test]
+fn oversized_entry_is_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    let value = vec![0u8; MAX_ENTRY_SIZE + 1];
+    assert!(matches!(
+        db.insert(b"", &value),
+        Err(Error::ValueTooLarge { .. })
+    ));
+    assert_eq!(db.get(b"").unwrap(), None);
+}
+
+#[test]
+fn dataset_larger_than_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let value = [7u8; 200];
+    {
+        let db = Database::builder().cache_size(0).open(&path).unwrap();
+        for n in 0u32..5000 {
+            db.insert(&n.to_be_bytes(), &value).unwrap();
+        }
+        for n in (0u32..5000).step_by(7) {
+            assert_eq!(
+                db.get(&n.to_be_bytes()).unwrap().as_deref(),
+                Some(&value[..])
+            );
+        }
+        let stats = db.cache_stats();
+        assert!(stats.resident <= 16, "{stats:?}");
+        assert!(stats.evictions > 0 && stats.write_backs > 0);
+        assert_eq!(db.iter().count(), 5000);
+    }
+
+    let db = Database::builder().cache_size(0).open(&path).unwrap();
+    let keys: Vec<u32> = keys(db.iter());
+    assert_eq!(keys, (0..5000).collect::<Vec<_>>());
+}
+
+/// Fill a database too large for a small cache, then scan it cold
+fn scan_cold(builder: &mut qpdb::Builder) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    {
+        let db = builder.open(&path).unwrap();
+        for n in 0u32..5000 {
+            db.insert(&n.to_be_bytes(), &[3; 200]).unwrap();
+        }
+    }
+
+    let db = builder.open(&path).unwrap();
+    assert_eq!(keys(db.iter()), (0..5000).collect::<Vec<_>>());
+    assert_eq!(keys(db.iter().rev()).len(), 5000);
+    let stats = db.cache_stats();
+    // Most leaves were read ahead of the scan rather than on demand
+    assert!(stats.prefetched > stats.misses, "{stats:?}");
+}
+
+#[test]
+fn scans_read_ahead() {
+    scan_cold(Database::builder().cache_size(256 << 10));
+}
+
+#[cfg(feature = "io-uring")]
+#[test]
+fn scans_read_ahead_through_io_uring() {
+    scan_cold(Database::builder().cache_size(256 << 10).io_uring(true));
+    scan_cold(
+        Database::builder()
+            .cache_size(256 << 10)
+            .io_uring(true)
+            .direct_io(true),
+    );
+}
+
+#[test]
+fn direct_io_files_open_either_way() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    scan_cold(Database::builder().cache_size(256 << 10).direct_io(true));
+
+    let db = Database::builder()
+        .cache_size(0)
+        .direct_io(true)
+        .open(&path)
+        .unwrap();
+    for n in 0u32..3000 {
+        db.insert(&n.to_be_bytes(), &n.to_le_bytes()).unwrap();
+    }
+    drop(db);
+    for direct in [false, true] {
+        let db = Database::builder().direct_io(direct).open(&path).unwrap();
+        assert_eq!(db.iter().count(), 3000);
+        assert_eq!(
+            db.get(&1234u32.to_be_bytes()).unwrap().as_deref(),
+            Some(&1234u32.to_le_bytes()[..])
+        );
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(16))]
+
+    #[test]
+    fn reopen_matches_model(
+        batches in prop::collection::vec(
+            prop::collection::vec((any::<u8>(), prop::option::of(0usize..200)), 1..300),
+            1..4,
+        )
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db.qpdb");
+        let mut model = BTreeMap::new();
+        for batch in batches {
+            let db = Database::open(&path).unwrap();
+            for (key, value) in batch {
+                match value {
+                    Some(len) => {
+                        let value = vec![key; len];
+                        prop_assert_eq!(db.insert(&[key], &value).unwrap(), model.insert(key, value));
+                    }
+                    None => prop_assert_eq!(db.remove(&[key]).unwrap(), model.remove(&key)),
+                }
+            }
+        }
+        let db = Database::open(&path).unwrap();
+        for key in 0..=255u8 {
+            prop_assert_eq!(db.get(&[key]).unwrap(), model.get(&key).cloned());
+        }
+    }
+}
+
+fn numbered(db: &Database, keys: impl IntoIterator<Item = u32>) {
+    for n in keys {
+        db.insert(&n.to_be_bytes(), &n.to_le_bytes()).unwrap();
+    }
+}
+
+fn keys(iter: impl Iterator<Item = qpdb::Result<qpdb::KeyValue>>) -> Vec<u32> {
+    iter.map(|entry| u32::from_be_bytes(entry.unwrap().0.try_into().unwrap()))
+        .collect()
+}
+
+#[test]
+fn iter_and_rev() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    numbered(&db, (0..5000).rev());
+
+    assert_eq!(keys(db.iter()), (0..5000).collect::<Vec<_>>());
+    assert_eq!(keys(db.iter().rev()), (0..5000).rev().collect::<Vec<_>>());
+}
+
+#[test]
+fn range_bounds() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    numbered(&db, (0..1000).map(|n| n * 10));
+
+    let k = |n: u32| n.to_be_bytes();
+    assert_eq!(keys(db.range(k(100)..k(150))), [100, 110, 120, 130, 140]);
+    assert_eq!(keys(db.range(k(95)..=k(120))), [100, 110, 120]);
+    assert_eq!(keys(db.range(k(9970)..)), [9970, 9980, 9990]);
+    assert_eq!(keys(db.range(..k(20))), [0, 10]);
+    assert_eq!(keys(db.range(k(100)..k(140)).rev()), [130, 120, 110, 100]);
+    assert!(keys(db.range(k(101)..k(109))).is_empty());
+}
+
+#[test]
+fn range_meets_in_the_middle() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    numbered(&db, 0..2000);
+
+    let mut range = db.iter();
+    let mut seen = Vec::new();
+    while let Some(front) = range.next() {
+        seen.push(front.unwrap().0);
+        let Some(back) = range.next_back() else { break };
+        seen.push(back.unwrap().0);
+    }
+    seen.sort();
+    seen.dedup();
+    assert_eq!(seen.len(), 2000);
+}
+
+#[test]
+fn cursor_navigation() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    numbered(&db, (0..3000).map(|n| n * 2));
+
+    let mut cursor = db.cursor();
+    assert!(!cursor.valid());
+    assert!(cursor.seek(&1001u32.to_be_bytes()).unwrap());
+    assert_eq!(cursor.key(), Some(&1002u32.to_be_bytes()[..]));
+    assert_eq!(cursor.value(), Some(&1002u32.to_le_bytes()[..]));
+    assert!(cursor.prev().unwrap());
+    assert_eq!(cursor.key(), Some(&1000u32.to_be_bytes()[..]));
+
+    assert!(cursor.seek_for_prev(&1001u32.to_be_bytes()).unwrap());
+    assert_eq!(cursor.key(), Some(&1000u32.to_be_bytes()[..]));
+    assert!(cursor.next().unwrap());
+    assert_eq!(cursor.key(), Some(&1002u32.to_be_bytes()[..]));
+
+    assert!(cursor.seek_to_last().unwrap());
+    assert_eq!(cursor.key(), Some(&5998u32.to_be_bytes()[..]));
+    assert!(!cursor.next().unwrap());
+    assert!(!cursor.valid());
+
+    assert!(cursor.seek_to_first().unwrap());
+    assert!(!cursor.prev().unwrap());
+    assert_eq!(cursor.key(), None);
+}
+
+#[test]
+fn free_pages_are_reused_after_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let grown = {
+        let db = Database::builder()
+            .file_growth(64 << 10)
+            .open(&path)
+            .unwrap();
+        for n in 0u32..3000 {
+            db.insert(&n.to_be_bytes(), &[1; 100]).unwrap();
+        }
+        for n in 0u32..3000 {
+            db.remove(&n.to_be_bytes()).unwrap();
+        }
+        drop(db);
+        std::fs::metadata(&path).unwrap().len()
+    };
+    assert_eq!(grown % (64 << 10), 0);
+
+    let db = Database::open(&path).unwrap();
+    for n in 0u32..3000 {
+        db.insert(&n.to_be_bytes(), &[2; 100]).unwrap();
+    }
+    drop(db);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), grown);
+}
+
+#[test]
+fn a_database_has_one_writing_handle() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = Database::open(&path).unwrap();
+    assert!(matches!(
+        Database::open(&path),
+        Err(Error::DatabaseLocked { path: locked }) if locked == path
+    ));
+    assert!(matches!(
+        Database::builder().read_only(true).open(&path),
+        Err(Error::DatabaseLocked { .. })
+    ));
+    drop(db);
+    Database::open(&path).unwrap();
+}
+
+#[test]
+fn read_only_handles_share_the_database() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    Database::open(&path).unwrap().insert(b"k", b"v").unwrap();
+
+    let first = Database::builder().read_only(true).open(&path).unwrap();
+    let second = Database::builder().read_only(true).open(&path).unwrap();
+    assert_eq!(first.get(b"k").unwrap().as_deref(), Some(&b"v"[..]));
+    assert_eq!(second.iter().count(), 1);
+    assert!(matches!(first.insert(b"k", b"w"), Err(Error::ReadOnly)));
+    assert!(matches!(first.begin_write(), Err(Error::ReadOnly)));
+    assert!(matches!(
+        Database::open(&path),
+        Err(Error::DatabaseLocked { .. })
+    ));
+    drop((first, second));
+
+    assert_eq!(
+        Database::open(&path).unwrap().get(b"k").unwrap().as_deref(),
+        Some(&b"v"[..])
+    );
+}
+
+#[test]
+fn read_only_open_requires_an_existing_database() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    assert!(matches!(
+        Database::builder().read_only(true).open(&path),
+        Err(Error::Io(err)) if err.kind() == std::io::ErrorKind::NotFound
+    ));
+    assert!(!path.exists());
+}
@@ -0,0 +1,350 @@
+//! Crash safety under injected faults: the database runs on
+//! [`FaultyBackend`]s, which fail on demand and lose or tear unsynced writes
+//! when they "crash".
+
+mod common;
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use std::time::{Duration, Instant};
+
+use common::key;
+use qpdb::storage::{FaultyBackend, MemoryBackend, StorageBackend};
+use qpdb::{Builder, Database, Durability};
+
+/// Small enough a cache that pages are evicted and written back mid-workload
+fn builder() -> Builder {
+    let mut builder = Database::builder();
+    builder.cache_size(64 << 10);
+    builder
+}
+
+/// Crash a database on `data` and `log`, losing whatever was not synced
+fn crash(db: Database, data: &FaultyBackend, log: &FaultyBackend) {
+    db.simulate_crash();
+    for storage in [data, log] {
+        storage.heal();
+        storage.crash();
+    }
+}
+
+/// Insert keys one commit at a time, checkpointing every so often, and
+/// return how many commits succeeded before the first error
+fn workload(db: &Database, count: u32) -> u32 {
+    for n in 0..count {
+        if db.insert(&key(n), &[n as u8; 300]).is_err() {
+            return n;
+        }
+        if n % 40 == 39 && db.flush().is_err() {
+            return n + 1;
+        }
+    }
+    count
+}
+
+/// After a crash, every acknowledged commit is there, and of the later ones
+/// at most the one in flight
+fn check(db: &Database, acknowledged: u32) {
+    for n in 0..acknowledged {
+        assert_eq!(
+            db.get(&key(n)).unwrap().as_deref(),
+            Some(&[n as u8; 300][..]),
+            "commit {n} of {acknowledged} lost"
+        );
+    }
+    let count = db.iter().count() as u32;
+    assert!(
+        count == acknowledged || count == acknowledged + 1,
+        "{count} keys after {acknowledged} commits"
+    );
+    db.insert(b"after", b"crash").unwrap();
+}
+
+#[test]
+fn in_memory_databases_reopen() {
+    let (data, log) = (MemoryBackend::new(), MemoryBackend::new());
+    let db = Database::builder()
+        .open_backend(data.clone(), log.clone())
+        .unwrap();
+    db.insert(b"key", b"value").unwrap();
+    drop(db);
+    assert!(log.is_empty().unwrap());
+
+    let db = Database::builder().open_backend(data, log).unwrap();
+    assert_eq!(db.get(b"key").unwrap().as_deref(), Some(&b"value"[..]));
+}
+
+#[test]
+fn committed_writes_survive_losing_unsynced_ones() {
+    let (data, log) = (FaultyBackend::new(), FaultyBackend::new());
+    let db = builder().open_backend(data.clone(), log.clone()).unwrap();
+    assert_eq!(workload(&db, 500), 500);
+    // Rewriting leaves all over the tree dirties more pages than the cache
+    // holds, so evictions since the last checkpoint leave unsynced data
+    // pages behind
+    for n in (0..500).step_by(25) {
+        db.insert(&key(n), &[n as u8; 300]).unwrap();
+    }
+    assert!(data.has_unsynced());
+    crash(db, &data, &log);
+
+    let db = builder().open_backend(data, log).unwrap();
+    check(&db, 500);
+}
+
+#[test]
+fn torn_checkpoint_is_repaired_from_log() {
+    let (data, log) = (FaultyBackend::new(), FaultyBackend::new());
+    let db = Database::builder()
+        .open_backend(data.clone(), log.clone())
+        .unwrap();
+    for n in 0..300 {
+        db.insert(&key(n), &[n as u8; 300]).unwrap();
+    }
+    // Write back some pages but fail before the data file is synced, then
+    // tear them all
+    data.fail_after(10);
+    assert!(db.flush().is_err());
+    db.simulate_crash();
+    data.heal();
+    assert!(data.has_unsynced());
+    data.crash_torn(512);
+    log.crash();
+
+    let db = Database::builder().open_backend(data, log).unwrap();
+    for n in 0..300 {
+        assert_eq!(
+            db.get(&key(n)).unwrap().as_deref(),
+            Some(&[n as u8; 300][..])
+        );
+    }
+}
+
+/// Fail the log or the data file after each number of operations in turn,
+/// crash, and check what comes back
+fn crash_at_every_point(fail_log: bool) {
+    let mut ops = 0;
+    loop {
+        let (data, log) = (FaultyBackend::new(), FaultyBackend::new());
+        let db = builder().open_backend(data.clone(), log.clone()).unwrap();
+        let faulty = if fail_log { &log } else { &data };
+        faulty.fail_after(ops);
+        let acknowledged = workload(&db, 120);
+        crash(db, &data, &log);
+
+        let db = builder()
+            .open_backend(data, log)
+            .unwrap_or_else(|err| panic!("reopening after failing at {ops}: {err}"));
+        check(&db, acknowledged);
+        if acknowledged == 120 {
+            break;
+        }
+        ops += 3;
+    }
+}
+
+#[test]
+fn log_failures_lose_no_acknowledged_commit() {
+    crash_at_every_point(true);
+}
+
+#[test]
+fn data_failures_lose_no_acknowledged_commit() {
+    crash_at_every_point(false);
+}
+
+#[test]
+fn concurrent_commits_lose_no_acknowledged_one() {
+    for ops in [5, 20, 60] {
+        let (data, log) = (FaultyBackend::new(), FaultyBackend::new());
+        let db = builder().open_backend(data.clone(), log.clone()).unwrap();
+        log.fail_after(ops);
+        let acknowledged = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            for writer in 0..4u32 {
+                let (db, acknowledged) = (&db, &acknowledged);
+                scope.spawn(move || {
+                    for n in 0..30 {
+                        let key = key(writer * 100 + n);
+                        if db.insert(&key, &[7; 300]).is_err() {
+                            return;
+                        }
+                        acknowledged.lock().unwrap().push(key);
+                    }
+                });
+            }
+        });
+        crash(db, &data, &log);
+
+        let db = builder().open_backend(data, log).unwrap();
+        for key in acknowledged.into_inner().unwrap() {
+            assert!(db.get(&key).unwrap().is_some(), "failing at {ops}");
+        }
+    }
+}
+
+/// Insert `key` in a transaction of its own committed with `durability`
+fn commit(db: &Database, key: &[u8], durability: Durability) {
+    let mut txn = db.begin_write().unwrap();
+    txn.set_durability(durability);
+    txn.insert(key, b"value").unwrap();
+    txn.commit().unwrap();
+}
+
+#[test]
+fn unlogged_commits_need_a_later_log_or_checkpoint() {
+    let (data, log) = (FaultyBackend::new(), FaultyBackend::new());
+    let db = builder().open_backend(data.clone(), log.clone()).unwrap();
+    commit(&db, b"immediate", Durability::Immediate);
+    commit(&db, b"lost", Durability::None);
+    assert!(db.get(b"lost").unwrap().is_some());
+    crash(db, &data, &log);
+
+    let db = builder().open_backend(data.clone(), log.clone()).unwrap();
+    assert!(db.get(b"immediate").unwrap().is_some());
+    assert!(db.get(b"lost").unwrap().is_none());
+    // A logged commit takes the unlogged ones before it along
+    commit(&db, b"carried", Durability::None);
+    commit(&db, b"carrier", Durability::Immediate);
+    commit(&db, b"checkpointed", Durability::None);
+    db.flush().unwrap();
+    crash(db, &data, &log);
+
+    let db = builder().open_backend(data, log).unwrap();
+    for key in [&b"immediate"[..], b"carried", b"carrier", b"checkpointed"] {
+        assert!(db.get(key).unwrap().is_some());
+    }
+    assert_eq!(db.iter().count(), 4);
+}
+
+#[test]
+fn unlogged_commits_fill_no_more_than_the_cache() {
+    let (data, log) = (FaultyBackend::new(), FaultyBackend::new());
+    let db = builder().open_backend(data.clone(), log.clone()).unwrap();
+    for n in 0..300 {
+        let mut txn = db.begin_write().unwrap();
+        txn.set_durability(Durability::None);
+        txn.insert(&key(n), &[n as u8; 300]).unwrap();
+        txn.commit().unwrap();
+    }
+    assert!(db.cache_stats().resident <= 16);
+    crash(db, &data, &log);
+
+    // Logging along the way kept a prefix of the commits
+    let db = builder().open_backend(data, log).unwrap();
+    let count = db.iter().count() as u32;
+    assert!(count > 0);
+    check(&db, count);
+}
+
+#[test]
+fn eventual_commits_are_synced_in_the_background() {
+    let (data, log) = (FaultyBackend::new(), FaultyBackend::new());
+    let db = builder().open_backend(data.clone(), log.clone()).unwrap();
+    for n in 0..20 {
+        commit(&db, &key(n), Durability::Eventual);
+    }
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while log.has_unsynced() {
+        assert!(Instant::now() < deadline, "log never synced");
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    assert!(db.commit_stats().syncs >= 1);
+    assert_eq!(db.commit_stats().waits, 0);
+    crash(db, &data, &log);
+
+    let db = builder().open_backend(data, log).unwrap();
+    assert_eq!(db.iter().count(), 20);
+}
+
+#[test]
+fn eventual_commits_survive_in_order() {
+    let (data, log) = (FaultyBackend::new(), FaultyBackend::new());
+    let db = builder().open_backend(data.clone(), log.clone()).unwrap();
+    for n in 0..200 {
+        let mut txn = db.begin_write().unwrap();
+        txn.set_durability(Durability::Eventual);
+        txn.insert(&key(n), &[n as u8; 300]).unwrap();
+        txn.commit().unwrap();
+    }
+    crash(db, &data, &log);
+
+    let db = builder().open_backend(data, log).unwrap();
+    let count = db.iter().count() as u32;
+    check(&db, count);
+}
+
+#[test]
+fn paranoid_commits_survive_losing_the_log() {
+    let (data, log) = (FaultyBackend::new(), FaultyBackend::new());
+    let db = builder().open_backend(data.clone(), log.clone()).unwrap();
+    commit(&db, b"immediate", Durability::Immediate);
+    commit(&db, b"paranoid", Durability::Paranoid);
+    assert!(!data.has_unsynced());
+    crash(db, &data, &log);
+
+    let db = builder().open_backend(data, FaultyBackend::new()).unwrap();
+    assert!(db.get(b"immediate").unwrap().is_some());
+    assert!(db.get(b"paranoid").unwrap().is_some());
+}
+
+#[test]
+fn background_checkpoints_bound_the_log() {
+    let (data, log) = (FaultyBackend::new(), FaultyBackend::new());
+    let db = builder()
+        .checkpoint_size(256 << 10)
+        .open_backend(data.clone(), log.clone())
+        .unwrap();
+    let mut longest = 0;
+    for n in 0..1500 {
+        db.insert(&key(n), &[n as u8; 300]).unwrap();
+        longest = longest.max(log.len().unwrap());
+    }
+    // Without checkpoints the log would hold every page image written
+    assert!(longest < 4 << 20, "log grew to {longest} bytes");
+    crash(db, &data, &log);
+
+    let db = builder().open_backend(data, log).unwrap();
+    check(&db, 1500);
+}
+
+#[test]
+fn checkpoints_alongside_commits_survive_a_crash() {
+    let (data, log) = (FaultyBackend::new(), FaultyBackend::new());
+    let db = builder().open_backend(data.clone(), log.clone()).unwrap();
+    let done = AtomicBool::new(false);
+    let acknowledged = thread::scope(|scope| {
+        scope.spawn(|| {
+            while !done.load(Ordering::Relaxed) {
+                db.checkpoint().unwrap();
+            }
+        });
+        let mut acknowledged = 0;
+        for n in 0..600 {
+            let mut txn = db.begin_write().unwrap();
+            txn.set_durability(if n % 3 == 0 {
+                Durability::Eventual
+            } else {
+                Durability::Immediate
+            });
+            txn.insert(&key(n), &[n as u8; 300]).unwrap();
+            txn.commit().unwrap();
+            if n % 3 != 0 {
+                acknowledged = n + 1;
+            }
+        }
+        done.store(true, Ordering::Relaxed);
+        acknowledged
+    });
+    crash(db, &data, &log);
+
+    let db = builder().open_backend(data, log).unwrap();
+    let count = db.iter().count() as u32;
+    assert!(
+        count >= acknowledged,
+        "{count} keys after {acknowledged} commits"
+    );
+    check(&db, count);
+}
@@ -0,0 +1,192 @@
+//! Crash recovery: a "crash" closes the handle without the final checkpoint,
+//! leaving the files as a crashed process would.
+
+mod common;
+
+use std::fs::OpenOptions;
+use std::os::unix::fs::FileExt;
+
+use common::crash;
+use qpdb::{Database, Error};
+
+#[test]
+fn committed_writes_survive_crash() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+
+    let db = Database::open(&path).unwrap();
+    for n in 0u32..500 {
+        db.insert(&n.to_be_bytes(), b"before").unwrap();
+    }
+    db.flush().unwrap();
+    for n in 250u32..1000 {
+        db.insert(&n.to_be_bytes(), b"after").unwrap();
+    }
+    for n in 0u32..100 {
+        db.remove(&n.to_be_bytes()).unwrap();
+    }
+    crash(db);
+
+    let db = Database::open(&path).unwrap();
+    for n in 0u32..1000 {
+        let expected: Option<&[u8]> = match n {
+            0..100 => None,
+            100..250 => Some(b"before"),
+            _ => Some(b"after"),
+        };
+        assert_eq!(db.get(&n.to_be_bytes()).unwrap().as_deref(), expected);
+    }
+    assert_eq!(db.iter().count(), 900);
+}
+
+#[test]
+fn recovery_is_repeatable() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+
+    let db = Database::open(&path).unwrap();
+    db.insert(b"a", b"1").unwrap();
+    crash(db);
+
+    // Crash again right after recovery, then write more
+    let db = Database::open(&path).unwrap();
+    db.insert(b"b", b"2").unwrap();
+    crash(db);
+
+    let db = Database::open(&path).unwrap();
+    assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+    assert_eq!(db.get(b"b").unwrap(), Some(b"2".to_vec()));
+}
+
+#[test]
+fn torn_data_page_is_repaired_from_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+
+    let db = Database::open(&path).unwrap();
+    db.insert(b"key", b"value").unwrap();
+    crash(db);
+
+    // Simulate a partially written data page for the root leaf
+    let file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.write_all_at(&[0xee; 512], 4096).unwrap();
+    drop(file);
+
+    let db = Database::open(&path).unwrap();
+    assert_eq!(db.get(b"key").unwrap(), Some(b"value".to_vec()));
+}
+
+#[test]
+fn damaged_data_page_is_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+
+    let db = Database::open(&path).unwrap();
+    db.insert(b"key", b"value").unwrap();
+    drop(db);
+
+    // Flip one bit in the checkpointed root leaf, which the log no longer covers
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    let mut byte = [0];
+    file.read_exact_at(&mut byte, 4096 + 3000).unwrap();
+    file.write_all_at(&[byte[0] ^ 1], 4096 + 3000).unwrap();
+    drop(file);
+
+    let db = Database::open(&path).unwrap();
+    assert!(matches!(
+        db.get(b"key"),
+        Err(Error::ChecksumMismatch {
+            page: 1,
+            offset: 4096,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn log_is_truncated_by_flush() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let wal = dir.path().join("db.qpdb-wal");
+
+    let db = Database::open(&path).unwrap();
+    db.insert(b"key", b"value").unwrap();
+    assert!(wal.metadata().unwrap().len() > 0);
+    db.flush().unwrap();
+    assert_eq!(wal.metadata().unwrap().len(), 0);
+}
+
+#[test]
+fn free_pages_are_reused_after_crash() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = Database::builder().file_growth(0).open(&path).unwrap();
+    for n in 0u32..3000 {
+        db.insert(&n.to_be_bytes(), &[1; 100]).unwrap();
+    }
+    db.flush().unwrap();
+    let size = std::fs::metadata(&path).unwrap().len();
+    for n in 0u32..3000 {
+        db.remove(&n.to_be_bytes()).unwrap();
+    }
+    crash(db);
+
+    // The free list comes back from the log and covers the refill
+    let db = Database::builder().file_growth(0).open(&path).unwrap();
+    assert_eq!(db.iter().count(), 0);
+    for n in 0u32..3000 {
+        db.insert(&n.to_be_bytes(), &[2; 100]).unwrap();
+    }
+    drop(db);
+    assert!(std::fs::metadata(&path).unwrap().len() <= size + 4 * 4096);
+}
+
+#[test]
+fn read_only_open_refuses_to_recover() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = Database::open(&path).unwrap();
+    db.insert(b"key", b"value").unwrap();
+    crash(db);
+
+    assert!(matches!(
+        Database::builder().read_only(true).open(&path),
+        Err(Error::ReadOnly)
+    ));
+    // A writable handle recovers, after which reading only is fine
+    drop(Database::open(&path).unwrap());
+    let db = Database::builder().read_only(true).open(&path).unwrap();
+    assert_eq!(db.get(b"key").unwrap().as_deref(), Some(&b"value"[..]));
+}
+
+#[test]
+fn missing_log_segment_is_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = Database::builder()
+        .checkpoint_size(u64::MAX)
+        .open(&path)
+        .unwrap();
+    // Enough page images for the log to span several segments
+    for n in 0u32..3000 {
+        db.insert(&n.to_be_bytes(), &[1; 200]).unwrap();
+    }
+    crash(db);
+
+    // Lose the header of the first segment, which holds the records after
+    // the last checkpoint
+    let wal = dir.path().join("db.qpdb-wal");
+    assert!(wal.metadata().unwrap().len() > 1 << 20);
+    let file = OpenOptions::new().write(true).open(&wal).unwrap();
+    file.write_all_at(&[0; 16], 0).unwrap();
+    drop(file);
+
+    assert!(matches!(
+        Database::open(&path),
+        Err(Error::Corruption(message)) if message.starts_with("log records 1 to ")
+    ));
+}
@@ -0,0 +1,276 @@
+//! Savepoints within a write transaction and persistent named savepoints
+
+mod common;
+
+use common::{crash, key, size};
+use qpdb::buffer::PAGE_SIZE;
+use qpdb::{Database, Error};
+
+fn keys(db: &Database) -> Vec<u32> {
+    db.iter()
+        .map(|entry| u32::from_be_bytes(entry.unwrap().0.try_into().unwrap()))
+        .collect()
+}
+
+fn table_len(db: &Database, name: &str) -> Option<usize> {
+    let read = db.begin_read().unwrap();
+    let table = read.open_table(name).ok()?;
+    Some(table.iter().count())
+}
+
+/// Overwrite every value of the default tree in batches of commits
+fn churn(db: &Database, count: u32, value: u8) {
+    for batch in (0..count).collect::<Vec<_>>().chunks(500) {
+        let mut txn = db.begin_write().unwrap();
+        for &n in batch {
+            txn.insert(&key(n), &[value; 200]).unwrap();
+        }
+        txn.commit().unwrap();
+    }
+}
+
+#[test]
+fn rollback_to_undoes_later_changes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    {
+        let db = Database::open(&path).unwrap();
+        let mut txn = db.begin_write().unwrap();
+        for n in 0..300 {
+            txn.insert(&key(n), &[1; 100]).unwrap();
+        }
+        let savepoint = txn.savepoint();
+        // Enough to split and rewrite the pages written before the savepoint
+        for n in 300..3000 {
+            txn.insert(&key(n), &[2; 100]).unwrap();
+        }
+        for n in (0..300).step_by(3) {
+            txn.remove(&key(n)).unwrap();
+        }
+        txn.open_table("later").unwrap().insert(b"k", b"v").unwrap();
+        txn.rollback_to(&savepoint).unwrap();
+
+        assert_eq!(txn.iter().count(), 300);
+        assert_eq!(txn.get(&key(3)).unwrap(), Some(vec![1; 100]));
+        assert!(txn.list_tables().unwrap().is_empty());
+        // The transaction carries on from the savepoint
+        txn.insert(&key(5000), b"after").unwrap();
+        txn.commit().unwrap();
+
+        let mut expected: Vec<u32> = (0..300).collect();
+        expected.push(5000);
+        assert_eq!(keys(&db), expected);
+    }
+    let db = Database::open(&path).unwrap();
+    assert_eq!(keys(&db).len(), 301);
+    assert_eq!(db.get(&key(299)).unwrap(), Some(vec![1; 100]));
+}
+
+#[test]
+fn savepoints_nest() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    let mut txn = db.begin_write().unwrap();
+    txn.insert(b"a", b"1").unwrap();
+    let outer = txn.savepoint();
+    txn.insert(b"b", b"2").unwrap();
+    let inner = txn.savepoint();
+    txn.insert(b"c", b"3").unwrap();
+
+    txn.rollback_to(&inner).unwrap();
+    assert_eq!(txn.iter().count(), 2);
+    txn.insert(b"d", b"4").unwrap();
+    // Rolling back to the same savepoint again works
+    txn.rollback_to(&inner).unwrap();
+    assert_eq!(txn.get(b"d").unwrap(), None);
+
+    txn.rollback_to(&outer).unwrap();
+    assert_eq!(txn.iter().count(), 1);
+    assert!(matches!(
+        txn.rollback_to(&inner),
+        Err(Error::InvalidSavepoint)
+    ));
+    txn.rollback_to(&outer).unwrap();
+    txn.commit().unwrap();
+    assert_eq!(db.get(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+    assert_eq!(db.iter().count(), 1);
+}
+
+#[test]
+fn savepoints_belong_to_their_transaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    let txn = db.begin_write().unwrap();
+    let savepoint = txn.savepoint();
+    txn.commit().unwrap();
+
+    let mut txn = db.begin_write().unwrap();
+    assert!(matches!(
+        txn.rollback_to(&savepoint),
+        Err(Error::InvalidSavepoint)
+    ));
+}
+
+#[test]
+fn rolled_back_pages_are_reused() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    let mut txn = db.begin_write().unwrap();
+    let savepoint = txn.savepoint();
+    for round in 0..20 {
+        for n in 0..1000 {
+            txn.insert(&key(n), &[round; 200]).unwrap();
+        }
+        txn.rollback_to(&savepoint).unwrap();
+    }
+    for n in 0..1000 {
+        txn.insert(&key(n), &[0; 200]).unwrap();
+    }
+    txn.commit().unwrap();
+
+    db.flush().unwrap();
+    assert_eq!(keys(&db), (0..1000).collect::<Vec<_>>());
+
+    let fresh = dir.path().join("fresh.qpdb");
+    let db = Database::open(&fresh).unwrap();
+    let mut txn = db.begin_write().unwrap();
+    for n in 0..1000 {
+        txn.insert(&key(n), &[0; 200]).unwrap();
+    }
+    txn.commit().unwrap();
+    db.flush().unwrap();
+    assert_eq!(size(&dir.path().join("db.qpdb")), size(&fresh));
+}
+
+#[test]
+fn persistent_savepoints_restore_the_database() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    {
+        let db = Database::open(&path).unwrap();
+        let txn = db.begin_write().unwrap();
+        let mut table = txn.open_table("t").unwrap();
+        for n in 0..500 {
+            table.insert(&key(n), b"table").unwrap();
+        }
+        drop(table);
+        txn.commit().unwrap();
+        churn(&db, 2000, 1);
+
+        let mut txn = db.begin_write().unwrap();
+        // Records the committed state, not the insert before it
+        txn.insert(b"uncommitted", b"").unwrap();
+        txn.persistent_savepoint("v1").unwrap();
+        assert!(matches!(
+            txn.persistent_savepoint("v1"),
+            Err(Error::SavepointExists(name)) if name == "v1"
+        ));
+        txn.commit().unwrap();
+    }
+    {
+        // Rewrite everything often enough that unprotected pages would be
+        // reused, across a restart
+        let db = Database::open(&path).unwrap();
+        for value in 2..6 {
+            churn(&db, 3000, value);
+        }
+        let mut txn = db.begin_write().unwrap();
+        txn.delete_table("t").unwrap();
+        txn.commit().unwrap();
+        assert_eq!(table_len(&db, "t"), None);
+    }
+
+    let db = Database::open(&path).unwrap();
+    let mut txn = db.begin_write().unwrap();
+    assert_eq!(txn.list_persistent_savepoints().unwrap(), ["v1"]);
+    assert!(matches!(
+        txn.restore_persistent_savepoint("v2"),
+        Err(Error::SavepointNotFound(name)) if name == "v2"
+    ));
+    txn.restore_persistent_savepoint("v1").unwrap();
+    txn.commit().unwrap();
+
+    assert_eq!(keys(&db), (0..2000).collect::<Vec<_>>());
+    assert!(db.iter().all(|entry| entry.unwrap().1 == [1; 200]));
+    assert_eq!(table_len(&db, "t"), Some(500));
+    // The savepoint stays, and the restored state can be changed again
+    churn(&db, 2000, 9);
+    let mut txn = db.begin_write().unwrap();
+    txn.restore_persistent_savepoint("v1").unwrap();
+    txn.commit().unwrap();
+    assert!(db.iter().all(|entry| entry.unwrap().1 == [1; 200]));
+}
+
+#[test]
+fn deleting_a_savepoint_frees_its_pages() {
+    let dir = tempfile::tempdir().unwrap();
+    let size = |name: &str| size(&dir.path().join(name));
+    // The same history, with and without a savepoint along the way
+    for (name, saved) in [("plain.qpdb", false), ("saved.qpdb", true)] {
+        let db = Database::open(dir.path().join(name)).unwrap();
+        churn(&db, 3000, 0);
+        let mut txn = db.begin_write().unwrap();
+        if saved {
+            txn.persistent_savepoint("old").unwrap();
+        }
+        txn.commit().unwrap();
+        churn(&db, 3000, 1);
+
+        let mut txn = db.begin_write().unwrap();
+        assert_eq!(txn.delete_persistent_savepoint("old").unwrap(), saved);
+        txn.commit().unwrap();
+        churn(&db, 3000, 2);
+        db.compact().unwrap();
+        assert_eq!(keys(&db), (0..3000).collect::<Vec<_>>());
+    }
+    // Only the emptied registry is left
+    assert!(size("saved.qpdb") <= size("plain.qpdb") + PAGE_SIZE as u64);
+}
+
+#[test]
+fn aborted_savepoint_changes_leave_no_trace() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    churn(&db, 1000, 0);
+    let mut txn = db.begin_write().unwrap();
+    txn.persistent_savepoint("kept").unwrap();
+    txn.commit().unwrap();
+    churn(&db, 1000, 1);
+
+    let mut txn = db.begin_write().unwrap();
+    txn.delete_persistent_savepoint("kept").unwrap();
+    txn.restore_persistent_savepoint("kept").unwrap_err();
+    drop(txn);
+
+    // Still protected: its pages were not reused by the churn
+    churn(&db, 1000, 2);
+    let mut txn = db.begin_write().unwrap();
+    let savepoint = txn.savepoint();
+    txn.restore_persistent_savepoint("kept").unwrap();
+    assert_eq!(txn.get(&key(0)).unwrap(), Some(vec![0; 200]));
+    txn.rollback_to(&savepoint).unwrap();
+    assert_eq!(txn.get(&key(0)).unwrap(), Some(vec![2; 200]));
+    txn.commit().unwrap();
+    assert!(db.iter().all(|entry| entry.unwrap().1 == [2; 200]));
+}
+
+#[test]
+fn persistent_savepoints_survive_a_crash() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = Database::open(&path).unwrap();
+    churn(&db, 1000, 0);
+    let mut txn = db.begin_write().unwrap();
+    txn.persistent_savepoint("before").unwrap();
+    txn.commit().unwrap();
+    churn(&db, 1000, 1);
+    crash(db);
+
+    let db = Database::open(&path).unwrap();
+    churn(&db, 1000, 2);
+    let mut txn = db.begin_write().unwrap();
+    txn.restore_persistent_savepoint("before").unwrap();
+    txn.commit().unwrap();
+    assert!(db.iter().all(|entry| entry.unwrap().1 == [0; 200]));
+    assert_eq!(keys(&db).len(), 1000);
+}
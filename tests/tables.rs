@@ -0,0 +1,301 @@
+mod common;
+
+use common::{crash, key};
+use qpdb::{Bincode, BincodeValue, Database, Error, TableDefinition};
+use serde::{Deserialize, Serialize};
+
+#[test]
+fn tables_are_independent_keyspaces() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    db.insert(b"k", b"default").unwrap();
+
+    let txn = db.begin_write().unwrap();
+    let mut users = txn.open_table("users").unwrap();
+    let mut orders = txn.open_table("orders").unwrap();
+    users.insert(b"k", b"user").unwrap();
+    orders.insert(b"k", b"order").unwrap();
+    assert_eq!(users.get(b"k").unwrap().as_deref(), Some(&b"user"[..]));
+    assert_eq!(orders.get(b"k").unwrap().as_deref(), Some(&b"order"[..]));
+    drop((users, orders));
+    txn.commit().unwrap();
+
+    assert_eq!(db.get(b"k").unwrap().as_deref(), Some(&b"default"[..]));
+    let read = db.begin_read().unwrap();
+    assert_eq!(read.list_tables().unwrap(), ["orders", "users"]);
+    let users = read.open_table("users").unwrap();
+    assert_eq!(users.get(b"k").unwrap().as_deref(), Some(&b"user"[..]));
+    assert_eq!(users.iter().count(), 1);
+}
+
+#[test]
+fn multi_table_commits_are_atomic() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    {
+        let db = Database::open(&path).unwrap();
+        let txn = db.begin_write().unwrap();
+        for name in ["a", "b", "c"] {
+            let mut table = txn.open_table(name).unwrap();
+            for n in 0..500 {
+                table.insert(&key(n), name.as_bytes()).unwrap();
+            }
+        }
+        txn.commit().unwrap();
+
+        let before = db.begin_read().unwrap();
+        let txn = db.begin_write().unwrap();
+        for name in ["a", "b"] {
+            let mut table = txn.open_table(name).unwrap();
+            for n in 0..500 {
+                table.remove(&key(n)).unwrap();
+            }
+        }
+        // Not visible before the commit, and rolled back by the drop
+        assert_eq!(before.open_table("a").unwrap().iter().count(), 500);
+        drop(txn);
+        drop(before);
+        assert_eq!(
+            db.begin_read()
+                .unwrap()
+                .open_table("b")
+                .unwrap()
+                .iter()
+                .count(),
+            500
+        );
+    }
+
+    let db = Database::open(&path).unwrap();
+    let read = db.begin_read().unwrap();
+    for name in ["a", "b", "c"] {
+        let table = read.open_table(name).unwrap();
+        assert_eq!(table.iter().count(), 500);
+        assert_eq!(
+            table.get(&key(7)).unwrap().as_deref(),
+            Some(name.as_bytes())
+        );
+    }
+}
+
+#[test]
+fn tables_survive_recovery() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    {
+        let db = Database::open(&path).unwrap();
+        let txn = db.begin_write().unwrap();
+        txn.open_table("logged")
+            .unwrap()
+            .insert(b"k", b"v")
+            .unwrap();
+        txn.commit().unwrap();
+        // Skip the checkpoint on drop so open replays the log
+        crash(db);
+    }
+
+    let db = Database::open(&path).unwrap();
+    let read = db.begin_read().unwrap();
+    let table = read.open_table("logged").unwrap();
+    assert_eq!(table.get(b"k").unwrap().as_deref(), Some(&b"v"[..]));
+}
+
+#[test]
+fn missing_tables_are_reported() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    let read = db.begin_read().unwrap();
+    assert!(matches!(
+        read.open_table("nope"),
+        Err(Error::TableNotFound(name)) if name == "nope"
+    ));
+    assert!(read.list_tables().unwrap().is_empty());
+
+    let mut txn = db.begin_write().unwrap();
+    assert!(matches!(
+        txn.rename_table("nope", "other"),
+        Err(Error::TableNotFound(_))
+    ));
+    assert!(!txn.delete_table("nope").unwrap());
+}
+
+#[test]
+fn a_table_opens_once_per_transaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    let txn = db.begin_write().unwrap();
+    let table = txn.open_table("t").unwrap();
+    assert!(matches!(
+        txn.open_table("t"),
+        Err(Error::TableAlreadyOpen(_))
+    ));
+    drop(table);
+    txn.open_table("t").unwrap();
+}
+
+#[test]
+fn rename_keeps_the_contents() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    let mut txn = db.begin_write().unwrap();
+    txn.open_table("old").unwrap().insert(b"k", b"v").unwrap();
+    txn.open_table("taken").unwrap();
+    assert!(matches!(
+        txn.rename_table("old", "taken"),
+        Err(Error::TableExists(_))
+    ));
+    txn.rename_table("old", "new").unwrap();
+    txn.commit().unwrap();
+
+    let read = db.begin_read().unwrap();
+    assert_eq!(read.list_tables().unwrap(), ["new", "taken"]);
+    let table = read.open_table("new").unwrap();
+    assert_eq!(table.get(b"k").unwrap().as_deref(), Some(&b"v"[..]));
+}
+
+#[test]
+fn deleted_tables_give_back_their_pages() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = Database::open(&path).unwrap();
+    let fill = |name: &str| {
+        let txn = db.begin_write().unwrap();
+        let mut table = txn.open_table(name).unwrap();
+        for n in 0..3000 {
+            table.insert(&key(n), &[0; 100]).unwrap();
+        }
+        drop(table);
+        txn.commit().unwrap();
+    };
+    fill("first");
+    db.flush().unwrap();
+    let size = std::fs::metadata(&path).unwrap().len();
+
+    let reader = db.begin_read().unwrap();
+    let mut txn = db.begin_write().unwrap();
+    assert!(txn.delete_table("first").unwrap());
+    assert!(txn.list_tables().unwrap().is_empty());
+    txn.commit().unwrap();
+    // Still readable through the older snapshot
+    assert_eq!(reader.open_table("first").unwrap().iter().count(), 3000);
+    drop(reader);
+
+    // Reclaimed at the next commit and reused by the new table
+    db.insert(b"nudge", b"").unwrap();
+    fill("second");
+    db.flush().unwrap();
+    assert!(std::fs::metadata(&path).unwrap().len() <= size + 8 * 8192);
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct User {
+    name: String,
+    age: u8,
+}
+
+impl BincodeValue for User {
+    const TYPE_NAME: &'static str = "user";
+}
+
+const USERS: TableDefinition<str, Bincode<User>> = TableDefinition::new("users");
+const SCORES: TableDefinition<(i32, String), u64> = TableDefinition::new("scores");
+
+#[test]
+fn typed_tables_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    let ann = User {
+        name: "Ann".into(),
+        age: 41,
+    };
+
+    let txn = db.begin_write().unwrap();
+    let mut users = txn.open_typed_table(USERS).unwrap();
+    assert_eq!(users.insert("ann", &Bincode(ann.clone())).unwrap(), None);
+    assert_eq!(users.get("ann").unwrap(), Some(ann.clone()));
+    drop(users);
+    txn.commit().unwrap();
+
+    let read = db.begin_read().unwrap();
+    let users = read.open_typed_table(USERS).unwrap();
+    assert_eq!(users.get("ann").unwrap(), Some(ann.clone()));
+    assert_eq!(users.get("bob").unwrap(), None);
+    let all: Vec<_> = users.iter().map(Result::unwrap).collect();
+    assert_eq!(all, [("ann".to_string(), ann)]);
+}
+
+#[test]
+fn typed_ranges_follow_the_key_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    let txn = db.begin_write().unwrap();
+    let mut scores = txn.open_typed_table(SCORES).unwrap();
+    let mut expected = Vec::new();
+    for level in [-300, -2, -1, 0, 1, 256] {
+        for name in ["", "a", "a\0", "b"] {
+            let key = (level, name.to_string());
+            scores.insert(&key, &(level as u64)).unwrap();
+            expected.push((key, level as u64));
+        }
+    }
+    expected.sort();
+    let all: Vec<_> = scores.iter().unwrap().map(Result::unwrap).collect();
+    assert_eq!(all, expected);
+
+    let from = (-1, String::new());
+    let to = (1, String::new());
+    let middle: Vec<_> = scores
+        .range(from.clone()..to.clone())
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    let wanted: Vec<_> = expected
+        .iter()
+        .map(|(key, _)| key.clone())
+        .filter(|key| (&from..&to).contains(&key))
+        .collect();
+    assert_eq!(middle, wanted);
+
+    let last = scores.iter().unwrap().next_back().unwrap().unwrap();
+    assert_eq!(last, expected.last().unwrap().clone());
+
+    let ints = TableDefinition::<i64, str>::new("ints");
+    let mut ints = txn.open_typed_table(ints).unwrap();
+    for n in [5i64, -7, 0, i64::MIN, i64::MAX] {
+        ints.insert(&n, &n.to_string()).unwrap();
+    }
+    let keys: Vec<_> = ints.range(-7..=5).unwrap().map(|e| e.unwrap().0).collect();
+    assert_eq!(keys, [-7, 0, 5]);
+}
+
+#[test]
+fn opening_as_other_types_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    let txn = db.begin_write().unwrap();
+    txn.open_typed_table(SCORES).unwrap();
+    txn.open_table("raw").unwrap();
+    let other = TableDefinition::<(i32, String), u32>::new("scores");
+    assert!(matches!(
+        txn.open_typed_table(other),
+        Err(Error::TableTypeMismatch { table, .. }) if table == "scores"
+    ));
+    let typed_raw = TableDefinition::<[u8], [u8]>::new("raw");
+    assert!(matches!(
+        txn.open_typed_table(typed_raw),
+        Err(Error::TableTypeMismatch { .. })
+    ));
+    // Owned and borrowed forms of a type encode alike
+    txn.open_typed_table(TableDefinition::<String, Vec<u8>>::new("names"))
+        .unwrap();
+    txn.open_typed_table(TableDefinition::<str, [u8]>::new("names"))
+        .unwrap();
+    txn.commit().unwrap();
+
+    let read = db.begin_read().unwrap();
+    assert!(matches!(
+        read.open_typed_table(other),
+        Err(Error::TableTypeMismatch { .. })
+    ));
+    read.open_typed_table(SCORES).unwrap();
+}
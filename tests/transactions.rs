@@ -0,0 +1,162 @@
+mod common;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use common::{crash, key};
+use qpdb::Database;
+
+#[test]
+fn read_transaction_sees_a_stable_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    for n in 0..500 {
+        db.insert(&key(n), b"old").unwrap();
+    }
+
+    let read = db.begin_read().unwrap();
+    for n in 0..500 {
+        db.insert(&key(n), b"new").unwrap();
+    }
+    db.remove(&key(0)).unwrap();
+
+    assert_eq!(read.get(&key(0)).unwrap().as_deref(), Some(&b"old"[..]));
+    assert_eq!(read.get(&key(499)).unwrap().as_deref(), Some(&b"old"[..]));
+    assert!(read.iter().all(|entry| entry.unwrap().1 == b"old"));
+    assert_eq!(read.iter().count(), 500);
+
+    assert_eq!(db.get(&key(0)).unwrap(), None);
+    assert_eq!(db.get(&key(499)).unwrap().as_deref(), Some(&b"new"[..]));
+}
+
+#[test]
+fn uncommitted_changes_are_private() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::open(dir.path().join("db.qpdb")).unwrap();
+    db.insert(b"a", b"1").unwrap();
+
+    let mut write = db.begin_write().unwrap();
+    write.insert(b"b", b"2").unwrap();
+    write.remove(b"a").unwrap();
+    assert_eq!(write.get(b"b").unwrap().as_deref(), Some(&b"2"[..]));
+    assert_eq!(write.iter().count(), 1);
+
+    assert_eq!(db.get(b"a").unwrap().as_deref(), Some(&b"1"[..]));
+    assert_eq!(db.begin_read().unwrap().get(b"b").unwrap(), None);
+
+    write.commit().unwrap();
+    assert_eq!(db.get(b"a").unwrap(), None);
+    assert_eq!(db.get(b"b").unwrap().as_deref(), Some(&b"2"[..]));
+}
+
+#[test]
+fn dropping_a_write_transaction_rolls_back() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    {
+        let db = Database::open(&path).unwrap();
+        for n in 0..200 {
+            db.insert(&key(n), b"kept").unwrap();
+        }
+        let pages = db.cache_stats();
+
+        let mut write = db.begin_write().unwrap();
+        for n in 0..2000 {
+            write.insert(&key(n), b"discarded").unwrap();
+        }
+        drop(write);
+        assert_eq!(db.iter().count(), 200);
+        assert!(db.cache_stats().resident <= pages.resident + 1);
+
+        let mut write = db.begin_write().unwrap();
+        write.remove(&key(0)).unwrap();
+        write.abort();
+        assert_eq!(db.get(&key(0)).unwrap().as_deref(), Some(&b"kept"[..]));
+    }
+
+    let db = Database::open(&path).unwrap();
+    assert_eq!(db.iter().count(), 200);
+    assert!(db.iter().all(|entry| entry.unwrap().1 == b"kept"));
+}
+
+#[test]
+fn commits_are_atomic_across_restarts() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    {
+        let db = Database::open(&path).unwrap();
+        let mut write = db.begin_write().unwrap();
+        for n in 0..1000 {
+            write.insert(&key(n), b"committed").unwrap();
+        }
+        write.commit().unwrap();
+
+        let mut write = db.begin_write().unwrap();
+        for n in 1000..2000 {
+            write.insert(&key(n), b"lost").unwrap();
+        }
+        // Leak the open transaction so nothing rolls it back or checkpoints
+        std::mem::forget(write);
+        crash(db);
+    }
+
+    let db = Database::open(&path).unwrap();
+    assert_eq!(db.iter().count(), 1000);
+    assert_eq!(db.get(&key(1500)).unwrap(), None);
+}
+
+#[test]
+fn snapshot_ranges_run_alongside_commits() {
+    let dir = tempfile::tempdir().unwrap();
+    let db = Database::builder()
+        .cache_size(0)
+        .open(dir.path().join("db.qpdb"))
+        .unwrap();
+    for n in 0..1000 {
+        db.insert(&key(n), &0u32.to_be_bytes()).unwrap();
+    }
+
+    let done = AtomicBool::new(false);
+    thread::scope(|scope| {
+        for _ in 0..3 {
+            scope.spawn(|| {
+                while !done.load(Ordering::Relaxed) {
+                    // Every commit rewrites all keys, so a snapshot holds one
+                    // generation throughout
+                    let read = db.begin_read().unwrap();
+                    let values: Vec<_> = read.iter().map(|entry| entry.unwrap().1).collect();
+                    assert_eq!(values.len(), 1000);
+                    assert!(values.iter().all(|value| *value == values[0]));
+                }
+            });
+        }
+        for generation in 1..=20u32 {
+            let mut write = db.begin_write().unwrap();
+            for n in 0..1000 {
+                write.insert(&key(n), &generation.to_be_bytes()).unwrap();
+            }
+            write.commit().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+    });
+}
+
+#[test]
+fn replaced_pages_are_reused() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("db.qpdb");
+    let db = Database::open(&path).unwrap();
+    for n in 0..2000 {
+        db.insert(&key(n), &[0; 64]).unwrap();
+    }
+    db.flush().unwrap();
+    let size = std::fs::metadata(&path).unwrap().len();
+
+    for round in 1..=10u8 {
+        for n in 0..2000 {
+            db.insert(&key(n), &[round; 64]).unwrap();
+        }
+    }
+    db.flush().unwrap();
+    assert!(std::fs::metadata(&path).unwrap().len() <= size * 2);
+}